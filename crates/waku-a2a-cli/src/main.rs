@@ -1,8 +1,29 @@
 use anyhow::Result;
-use clap::{Parser, Subcommand};
-use waku_a2a_core::Task;
-use waku_a2a_node::WakuA2ANode;
-use waku_a2a_transport::nwaku_rest::NwakuRestTransport;
+use clap::{Parser, Subcommand, ValueEnum};
+use futures::StreamExt;
+use std::path::PathBuf;
+use std::time::Duration;
+use waku_a2a_core::topics::{InboxScheme, TopicScheme, DEFAULT_EPOCH_SECS};
+use waku_a2a_core::{SkillFilter, Task};
+use waku_a2a_node::{Outbox, OutboxEntry, OutboxStatus, RateLimited, WakuA2ANode};
+use waku_a2a_transport::autosharding::{
+    AutoSharding, PubsubRouting, TWN_CLUSTER_ID, TWN_SHARD_COUNT,
+};
+#[cfg(unix)]
+use waku_a2a_transport::ipc::{IpcHub, IpcHubConfig, IpcTransport};
+use waku_a2a_transport::mqtt::{MqttConfig, MqttTransport, QoS};
+use waku_a2a_transport::nwaku_rest::{ClientMode, NwakuRestTransport};
+use waku_a2a_transport::p2p::{P2pConfig, P2pTransport};
+use waku_a2a_transport::rate_limit::{RateLimit, RateLimitedTransport};
+use waku_a2a_transport::retry::RetryPolicy;
+use waku_a2a_transport::WakuTransport;
+
+/// How long `--p2p` waits for a first peer before running the command.
+const P2P_PEER_WAIT: Duration = Duration::from_secs(5);
+
+/// Socket of the IPC hub when `hub --socket` or `--ipc` don't name one.
+#[cfg(unix)]
+const DEFAULT_IPC_SOCKET: &str = "/tmp/waku-a2a.sock";
 
 #[derive(Parser)]
 #[command(name = "waku-a2a", about = "A2A protocol over Waku decentralized transport")]
@@ -11,6 +32,112 @@ struct Cli {
     #[arg(long, default_value = "http://localhost:8645", global = true)]
     waku: String,
 
+    /// Topic namespace (e.g. "staging"); only agents in the same namespace
+    /// see each other
+    #[arg(long, global = true)]
+    namespace: Option<String>,
+
+    /// Waku cluster ID used for autosharding
+    #[arg(long, default_value_t = TWN_CLUSTER_ID, global = true)]
+    cluster_id: u16,
+
+    /// Number of autosharded shards in the cluster
+    #[arg(long, default_value_t = TWN_SHARD_COUNT, global = true)]
+    shards: u32,
+
+    /// Publish everything on this pubsub topic instead of autosharding
+    /// (e.g. "/waku/2/default-waku/proto" for legacy nodes)
+    #[arg(long, global = true)]
+    pubsub_topic: Option<String>,
+
+    /// Multiaddr of the store node for history queries (default: nwaku's
+    /// own store)
+    #[arg(long, global = true)]
+    store_peer: Option<String>,
+
+    /// Seconds before a request to nwaku times out
+    #[arg(long, default_value_t = 30, global = true)]
+    request_timeout: u64,
+
+    /// Retries of requests to nwaku that failed transiently (0 to disable)
+    #[arg(long, default_value_t = 3, global = true)]
+    retries: u32,
+
+    /// File recording sent tasks, so tasks that couldn't be delivered are
+    /// retried on the next run (e.g. "~/.waku-a2a/outbox.jsonl")
+    #[arg(long, global = true)]
+    outbox: Option<PathBuf>,
+
+    /// Publish at most this many messages per epoch (e.g. the RLN message
+    /// limit of the network); publishes beyond it wait for the next epoch
+    #[arg(long, global = true)]
+    rate_limit: Option<u32>,
+
+    /// Epoch length in seconds for --rate-limit (nwaku's
+    /// --rln-relay-epoch-sec)
+    #[arg(long, default_value_t = 1, global = true)]
+    rate_epoch: u64,
+
+    /// Light-client mode: publish with Lightpush and receive through Filter
+    /// instead of relaying
+    #[arg(long, global = true)]
+    light: bool,
+
+    /// Run an embedded Waku Relay node instead of talking to nwaku
+    #[arg(long, global = true)]
+    p2p: bool,
+
+    /// Multiaddr the embedded node listens on (repeatable; default
+    /// "/ip4/0.0.0.0/tcp/0")
+    #[arg(long, global = true, requires = "p2p")]
+    listen: Vec<String>,
+
+    /// Multiaddr of a peer for the embedded node to dial (repeatable)
+    #[arg(long, global = true, requires = "p2p")]
+    peer: Vec<String>,
+
+    /// Don't look for peers on the local network with mDNS
+    #[arg(long, global = true, requires = "p2p")]
+    no_mdns: bool,
+
+    /// Go through the IPC hub on this Unix socket (default
+    /// "/tmp/waku-a2a.sock") instead of nwaku; see `waku-a2a hub`
+    #[cfg(unix)]
+    #[arg(
+        long,
+        global = true,
+        num_args = 0..=1,
+        default_missing_value = DEFAULT_IPC_SOCKET,
+        conflicts_with_all = ["p2p", "mqtt"]
+    )]
+    ipc: Option<PathBuf>,
+
+    /// Go through the MQTT broker at this address (host:port) instead of
+    /// nwaku
+    #[arg(long, global = true, conflicts_with = "p2p")]
+    mqtt: Option<String>,
+
+    /// QoS of MQTT publishes and subscriptions (0, 1 or 2)
+    #[arg(
+        long,
+        default_value_t = 1,
+        global = true,
+        value_parser = clap::value_parser!(u8).range(0..=2)
+    )]
+    mqtt_qos: u8,
+
+    /// MQTT topic levels in front of content topics
+    #[arg(long, default_value = "waku", global = true)]
+    mqtt_topic_prefix: String,
+
+    /// Username for the MQTT broker
+    #[arg(long, global = true, requires = "mqtt")]
+    mqtt_username: Option<String>,
+
+    /// Password for the MQTT broker
+    #[arg(long, global = true, requires = "mqtt_username")]
+    mqtt_password: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -27,6 +154,17 @@ enum Commands {
         #[command(subcommand)]
         action: TaskAction,
     },
+    /// Run an IPC hub that agents on this host connect to with --ipc
+    #[cfg(unix)]
+    Hub {
+        /// Unix socket to listen on
+        #[arg(long, default_value = DEFAULT_IPC_SOCKET)]
+        socket: PathBuf,
+        /// Bridge to the Waku network through nwaku (or the embedded node
+        /// with --p2p, or an MQTT broker with --mqtt)
+        #[arg(long)]
+        bridge: bool,
+    },
 }
 
 #[derive(Subcommand)]
@@ -42,13 +180,40 @@ enum AgentAction {
         /// Enable X25519+ChaCha20-Poly1305 encryption
         #[arg(long)]
         encrypt: bool,
+        /// How the task inbox topic is derived from the agent's key
+        #[arg(long, value_enum, default_value = "plain")]
+        inbox: InboxMode,
+        /// Epoch length in seconds for `--inbox rotating`
+        #[arg(long, default_value_t = DEFAULT_EPOCH_SECS)]
+        epoch_secs: u64,
     },
     /// Discover agents on the network
-    Discover,
+    Discover {
+        /// Also list agents announced in the last 24 hours (needs a store
+        /// node)
+        #[arg(long)]
+        history: bool,
+        /// Only show agents advertising this skill ID
+        #[arg(long)]
+        skill: Option<String>,
+        /// Only show agents with a skill carrying this tag
+        #[arg(long)]
+        tag: Option<String>,
+    },
     /// Print this agent's IntroBundle (for sharing out-of-band)
     Bundle,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum InboxMode {
+    /// Inbox named by the public key
+    Plain,
+    /// Inbox named by a hash of the public key
+    Hashed,
+    /// Hashed inbox that changes every epoch
+    Rotating,
+}
+
 #[derive(Subcommand)]
 enum TaskAction {
     /// Send a task to an agent
@@ -66,12 +231,53 @@ enum TaskAction {
         #[arg(long)]
         id: String,
     },
+    /// Deliver pending tasks in the outbox and list them all (needs
+    /// --outbox)
+    Outbox,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
-    let transport = NwakuRestTransport::new(&cli.waku);
+    let routing = match cli.pubsub_topic {
+        Some(ref topic) => PubsubRouting::Static(topic.clone()),
+        None => PubsubRouting::Auto(AutoSharding::new(cli.cluster_id, cli.shards)?),
+    };
+    let mut connection = None;
+    let transport: Box<dyn WakuTransport> = if let Some(transport) = connect_ipc(&cli).await? {
+        transport
+    } else if let Some(ref broker) = cli.mqtt {
+        Box::new(connect_mqtt(&cli, broker).await?)
+    } else if cli.p2p {
+        Box::new(start_p2p(&cli, routing).await?)
+    } else {
+        let mut transport = NwakuRestTransport::new(&cli.waku)
+            .with_routing(routing)
+            .with_request_timeout(Duration::from_secs(cli.request_timeout))
+            .with_retry_policy(RetryPolicy::default().with_max_retries(cli.retries));
+        if let Some(ref peer) = cli.store_peer {
+            transport = transport.with_store_peer(peer);
+        }
+        if cli.light {
+            transport = transport.with_mode(ClientMode::Light);
+        }
+        if is_long_running(&cli.command) {
+            report_health(&transport).await;
+            connection = Some(transport.watch_connection());
+        }
+        Box::new(transport)
+    };
+    let transport: Box<dyn WakuTransport> = match cli.rate_limit {
+        Some(messages) => Box::new(RateLimitedTransport::new(
+            transport,
+            RateLimit::new(messages, Duration::from_secs(cli.rate_epoch)),
+        )),
+        None => transport,
+    };
+    let topics = match cli.namespace {
+        Some(ref ns) => TopicScheme::namespaced(ns)?,
+        None => TopicScheme::default(),
+    };
 
     match cli.command {
         Commands::Agent { action } => match action {
@@ -79,56 +285,116 @@ async fn main() -> Result<()> {
                 name,
                 capabilities,
                 encrypt,
+                inbox,
+                epoch_secs,
             } => {
                 let caps: Vec<String> =
                     capabilities.split(',').map(|s| s.trim().to_string()).collect();
+                let inbox = match inbox {
+                    InboxMode::Plain => InboxScheme::Plain,
+                    InboxMode::Hashed => InboxScheme::Hashed,
+                    InboxMode::Rotating => InboxScheme::Rotating { epoch_secs },
+                };
                 let node = if encrypt {
                     WakuA2ANode::new_encrypted(&name, &format!("{} agent", name), caps, transport)
                 } else {
                     WakuA2ANode::new(&name, &format!("{} agent", name), caps, transport)
+                }
+                .with_topic_scheme(topics)
+                .with_inbox_scheme(inbox);
+                let node = match cli.outbox {
+                    Some(ref path) => node.with_outbox(Outbox::open(path)?),
+                    None => node,
                 };
                 println!("Agent: {}", node.card.name);
                 println!("Pubkey: {}", node.pubkey());
+                if !inbox.is_plain() {
+                    println!("Inbox: {:?}", inbox);
+                }
                 if encrypt {
                     let bundle = node.card.intro_bundle.as_ref().unwrap();
                     println!("Encryption: ENABLED (X25519+ChaCha20-Poly1305)");
                     println!("X25519 pubkey: {}", bundle.agent_pubkey);
                 }
                 println!("Listening for tasks...\n");
+                let outbox_connection = connection.clone();
+                if let Some(mut connection) = connection {
+                    tokio::spawn(async move {
+                        while connection.changed().await.is_ok() {
+                            let state = *connection.borrow_and_update();
+                            println!("nwaku connection: {:?}", state);
+                        }
+                    });
+                }
 
                 // Announce on startup
                 if let Err(e) = node.announce().await {
                     eprintln!("Warning: announce failed (is nwaku running?): {}", e);
                 }
+                match node.catch_up().await {
+                    Ok(stats) if stats.inbox_messages > 0 => {
+                        println!("Caught up on {} missed message(s)", stats.inbox_messages)
+                    }
+                    Ok(_) => {}
+                    Err(e) => eprintln!("Warning: store catch-up failed: {:#}", e),
+                }
 
-                // Poll loop
-                loop {
-                    match node.poll_tasks().await {
-                        Ok(tasks) => {
-                            for task in tasks {
-                                println!("Received task {} from {}", task.id, task.from);
-                                if let Some(text) = task.text() {
-                                    println!("  Message: {}", text);
-                                    // Echo behavior by default
-                                    let response = format!("Echo: {}", text);
-                                    if let Err(e) = node.respond(&task, &response).await {
-                                        eprintln!("  Failed to respond: {}", e);
-                                    } else {
-                                        println!("  Responded: {}", response);
-                                    }
-                                }
+                let serve = async {
+                    let mut tasks = std::pin::pin!(node.incoming_tasks());
+                    while let Some(task) = tasks.next().await {
+                        println!("Received task {} from {}", task.id, task.from);
+                        if let Some(text) = task.text() {
+                            println!("  Message: {}", text);
+                            // Echo behavior by default
+                            let response = format!("Echo: {}", text);
+                            let mut result = node.respond(&task, &response).await;
+                            let retry_after = result.as_ref().err().and_then(|e| {
+                                e.downcast_ref::<RateLimited>().map(|l| l.retry_after)
+                            });
+                            if let Some(retry_after) = retry_after {
+                                println!(
+                                    "  Rate limited, responding in {:.1}s",
+                                    retry_after.as_secs_f64()
+                                );
+                                tokio::time::sleep(retry_after).await;
+                                result = node.respond(&task, &response).await;
+                            }
+                            if let Err(e) = result {
+                                eprintln!("  Failed to respond: {}", e);
+                            } else {
+                                println!("  Responded: {}", response);
                             }
-                        }
-                        Err(e) => {
-                            eprintln!("Poll error (is nwaku running?): {}", e);
                         }
                     }
-                    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+                };
+                tokio::select! {
+                    () = serve => {}
+                    () = node.run_outbox(outbox_connection), if node.outbox().is_some() => {}
                 }
             }
-            AgentAction::Discover => {
-                let node = WakuA2ANode::new("discovery-client", "temporary", vec![], transport);
-                match node.discover().await {
+            AgentAction::Discover {
+                history,
+                skill,
+                tag,
+            } => {
+                let node = WakuA2ANode::new("discovery-client", "temporary", vec![], transport)
+                    .with_topic_scheme(topics);
+                if history {
+                    if let Err(e) = node.catch_up().await {
+                        eprintln!("Warning: store query failed: {:#}", e);
+                    }
+                }
+                let result = if skill.is_some() || tag.is_some() {
+                    let filter = SkillFilter {
+                        id: skill,
+                        tag,
+                        ..SkillFilter::default()
+                    };
+                    node.discover_by_skill(&filter).await
+                } else {
+                    node.discover().await
+                };
+                match result {
                     Ok(cards) => {
                         if cards.is_empty() {
                             println!("No agents found. (Are agents announcing on the network?)");
@@ -139,6 +405,16 @@ async fn main() -> Result<()> {
                                 println!("  Description: {}", card.description);
                                 println!("  Capabilities: {}", card.capabilities.join(", "));
                                 println!("  Pubkey: {}", card.public_key);
+                                println!("  Protocol versions: {:?}", card.supported_versions());
+                                if !card.inbox.is_plain() {
+                                    println!("  Inbox: {:?}", card.inbox);
+                                }
+                                for skill in &card.skills {
+                                    println!("  Skill: {} — {}", skill.id, skill.description);
+                                    if !skill.tags.is_empty() {
+                                        println!("    Tags: {}", skill.tags.join(", "));
+                                    }
+                                }
                                 if let Some(ref bundle) = card.intro_bundle {
                                     println!("  Encryption: YES (X25519: {})", bundle.agent_pubkey);
                                 }
@@ -158,9 +434,41 @@ async fn main() -> Result<()> {
                 println!("{}", json);
             }
         },
+        #[cfg(unix)]
+        Commands::Hub { socket, bridge } => {
+            let mut config = IpcHubConfig::new(&socket);
+            if bridge {
+                config = config.with_upstream(transport);
+            }
+            let hub = IpcHub::start(config).await?;
+            println!("IPC hub listening on {}", hub.socket().display());
+            if bridge {
+                match cli.mqtt {
+                    Some(ref broker) => println!("Bridging to the MQTT broker at {}", broker),
+                    None if cli.p2p => println!("Bridging to the embedded Waku node"),
+                    None => println!("Bridging to {}", cli.waku),
+                }
+            }
+            if let Some(mut connection) = connection {
+                tokio::spawn(async move {
+                    while connection.changed().await.is_ok() {
+                        let state = *connection.borrow_and_update();
+                        println!("nwaku connection: {:?}", state);
+                    }
+                });
+            }
+            tokio::signal::ctrl_c().await?;
+            println!("Stopping hub ({} client(s) connected)", hub.client_count());
+        }
         Commands::Task { action } => match action {
             TaskAction::Send { to, text } => {
-                let node = WakuA2ANode::new("cli-sender", "CLI client", vec![], transport);
+                let node = WakuA2ANode::new("cli-sender", "CLI client", vec![], transport)
+                    .with_topic_scheme(topics);
+                let node = match cli.outbox {
+                    Some(ref path) => node.with_outbox(Outbox::open(path)?),
+                    None => node,
+                };
+                drain_outbox(&node).await;
                 println!("Sending task to {}...", &to[..12.min(to.len())]);
                 let task = Task::new(node.pubkey(), &to, &text);
                 match node.send_task(&task).await {
@@ -168,6 +476,8 @@ async fn main() -> Result<()> {
                         println!("Task ID: {}", task.id);
                         if acked {
                             println!("Status: ACKed by recipient");
+                        } else if node.outbox_status(&task.id) == Some(OutboxStatus::Queued) {
+                            println!("Status: Queued in the outbox (nwaku unreachable; retried on the next run)");
                         } else {
                             println!("Status: Sent (no ACK — recipient may be offline)");
                         }
@@ -179,7 +489,14 @@ async fn main() -> Result<()> {
                 }
             }
             TaskAction::Status { id } => {
-                let node = WakuA2ANode::new("cli-poller", "CLI client", vec![], transport);
+                let node = WakuA2ANode::new("cli-poller", "CLI client", vec![], transport)
+                    .with_topic_scheme(topics);
+                if let Some(ref path) = cli.outbox {
+                    match Outbox::open(path)?.get(&id) {
+                        Some(entry) => print_outbox_entry(&entry),
+                        None => println!("Task {} is not in the outbox", id),
+                    }
+                }
                 println!("Polling for task {} responses...", id);
                 // Poll the sender's task topic for responses
                 match node.poll_tasks().await {
@@ -202,8 +519,133 @@ async fn main() -> Result<()> {
                     }
                 }
             }
+            TaskAction::Outbox => {
+                let Some(ref path) = cli.outbox else {
+                    anyhow::bail!("--outbox is required");
+                };
+                let node = WakuA2ANode::new("cli-sender", "CLI client", vec![], transport)
+                    .with_topic_scheme(topics)
+                    .with_outbox(Outbox::open(path)?);
+                drain_outbox(&node).await;
+                let entries = node.outbox().map(Outbox::entries).unwrap_or_default();
+                if entries.is_empty() {
+                    println!("The outbox is empty");
+                }
+                for entry in entries {
+                    print_outbox_entry(&entry);
+                }
+            }
         },
     }
 
     Ok(())
 }
+
+/// Deliver tasks left in the outbox by earlier runs.
+async fn drain_outbox<T: WakuTransport>(node: &WakuA2ANode<T>) {
+    let pending = node.outbox().map_or(0, |o| o.pending().len());
+    if pending == 0 {
+        return;
+    }
+    println!("Delivering {} task(s) from the outbox...", pending);
+    match node.drain_outbox().await {
+        Ok(acked) => println!("{} of them ACKed", acked),
+        Err(e) => eprintln!("Warning: outbox not drained: {:#}", e),
+    }
+}
+
+fn print_outbox_entry(entry: &OutboxEntry) {
+    println!("Task: {}", entry.task_id);
+    println!("  To: {}", entry.to);
+    println!(
+        "  Delivery: {:?} after {} attempt(s)",
+        entry.status, entry.attempts
+    );
+    if let Some(ref error) = entry.last_error {
+        println!("  Last error: {}", error);
+    }
+}
+
+/// Print nwaku's health before an agent starts.
+async fn report_health(transport: &NwakuRestTransport) {
+    match transport.health().await {
+        Ok(health) => {
+            println!("nwaku: {}", health.node_health);
+            for (protocol, status) in &health.protocols {
+                println!("  {}: {}", protocol, status);
+            }
+        }
+        Err(e) => eprintln!("Warning: nwaku health check failed: {:#}", e),
+    }
+}
+
+/// Start the embedded relay node and give it a moment to find peers.
+/// Commands that keep running and so report nwaku's health as it changes.
+fn is_long_running(command: &Commands) -> bool {
+    match command {
+        Commands::Agent {
+            action: AgentAction::Run { .. },
+        } => true,
+        #[cfg(unix)]
+        Commands::Hub { bridge, .. } => *bridge,
+        _ => false,
+    }
+}
+
+#[cfg(unix)]
+async fn connect_ipc(cli: &Cli) -> Result<Option<Box<dyn WakuTransport>>> {
+    match cli.ipc {
+        Some(ref socket) => Ok(Some(Box::new(IpcTransport::connect(socket).await?))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(not(unix))]
+async fn connect_ipc(_cli: &Cli) -> Result<Option<Box<dyn WakuTransport>>> {
+    Ok(None)
+}
+
+async fn connect_mqtt(cli: &Cli, broker: &str) -> Result<MqttTransport> {
+    let qos = match cli.mqtt_qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    };
+    let mut config = MqttConfig::new(broker)
+        .with_qos(qos)
+        .with_topic_prefix(&cli.mqtt_topic_prefix)
+        .with_request_timeout(Duration::from_secs(cli.request_timeout));
+    if let Some(ref username) = cli.mqtt_username {
+        config = config.with_credentials(username, cli.mqtt_password.as_deref().unwrap_or(""));
+    }
+    MqttTransport::connect(config).await
+}
+
+async fn start_p2p(cli: &Cli, routing: PubsubRouting) -> Result<P2pTransport> {
+    let mut config = P2pConfig::default()
+        .with_routing(routing)
+        .with_mdns(!cli.no_mdns)
+        .with_peers(
+            cli.peer
+                .iter()
+                .map(|addr| addr.parse())
+                .collect::<Result<_, _>>()?,
+        );
+    if !cli.listen.is_empty() {
+        config = config.with_listen(
+            cli.listen
+                .iter()
+                .map(|addr| addr.parse())
+                .collect::<Result<_, _>>()?,
+        );
+    }
+    let transport = P2pTransport::start(config).await?;
+    println!("Peer ID: {}", transport.peer_id());
+    for addr in transport.listen_addrs() {
+        println!("Listening on {}/p2p/{}", addr, transport.peer_id());
+    }
+    if !transport.wait_for_peers(P2P_PEER_WAIT).await {
+        eprintln!("Warning: no peers found yet");
+    }
+    Ok(transport)
+}
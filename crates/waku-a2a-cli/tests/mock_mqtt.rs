@@ -0,0 +1,74 @@
+//! End-to-end tests of the `waku-a2a` binary over an in-process MQTT broker.
+
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::process::{ChildStdout, Command};
+use waku_a2a_mock_mqtt::{MockBroker, QoS};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn cli(broker: &MockBroker) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_waku-a2a"));
+    command
+        .args(["--mqtt", &broker.address(), "--mqtt-qos", "2"])
+        .kill_on_drop(true);
+    command
+}
+
+/// Read stdout lines until one starts with `prefix`, returning the rest.
+async fn read_until(lines: &mut Lines<BufReader<ChildStdout>>, prefix: &str) -> String {
+    tokio::time::timeout(TIMEOUT, async {
+        while let Some(line) = lines.next_line().await.unwrap() {
+            if let Some(rest) = line.trim().strip_prefix(prefix) {
+                return rest.trim().to_string();
+            }
+        }
+        panic!("stdout closed before {:?}", prefix);
+    })
+    .await
+    .unwrap_or_else(|_| panic!("timed out waiting for {:?}", prefix))
+}
+
+#[tokio::test]
+async fn test_send_task_over_mqtt() {
+    let broker = MockBroker::start().await.unwrap();
+
+    let mut agent = cli(&broker)
+        .args(["agent", "run", "--name", "echo"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(agent.stdout.take().unwrap()).lines();
+    let pubkey = read_until(&mut stdout, "Pubkey:").await;
+    let inbox = format!("waku/waku-a2a/1/task/{}/proto", pubkey);
+    tokio::time::timeout(TIMEOUT, async {
+        while !broker.subscriptions().contains(&inbox) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("agent subscribes to its inbox");
+
+    let output = tokio::time::timeout(
+        TIMEOUT,
+        cli(&broker)
+            .args(["task", "send", "--to", &pubkey, "--text", "hello"])
+            .output(),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let stdout_text = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout_text.contains("Status: ACKed by recipient"),
+        "{}",
+        stdout_text
+    );
+    assert_eq!(read_until(&mut stdout, "Responded:").await, "Echo: hello");
+    assert!(broker
+        .published()
+        .iter()
+        .any(|p| p.topic == inbox && p.qos == QoS::ExactlyOnce));
+}
@@ -0,0 +1,84 @@
+//! End-to-end tests of the `waku-a2a` binary against in-process fake nwaku
+//! nodes.
+
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::process::{ChildStdout, Command};
+use waku_a2a_mock_nwaku::MockNetwork;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn cli(waku: &str) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_waku-a2a"));
+    command.arg("--waku").arg(waku).kill_on_drop(true);
+    command
+}
+
+/// Read stdout lines until one starts with `prefix`, returning the rest.
+async fn read_until(lines: &mut Lines<BufReader<ChildStdout>>, prefix: &str) -> String {
+    tokio::time::timeout(TIMEOUT, async {
+        while let Some(line) = lines.next_line().await.unwrap() {
+            if let Some(rest) = line.trim().strip_prefix(prefix) {
+                return rest.trim().to_string();
+            }
+        }
+        panic!("stdout closed before {:?}", prefix);
+    })
+    .await
+    .unwrap_or_else(|_| panic!("timed out waiting for {:?}", prefix))
+}
+
+#[tokio::test]
+async fn test_send_task_to_running_agent() {
+    let network = MockNetwork::new();
+    let (agent_node, client_node) = (
+        network.start_node().await.unwrap(),
+        network.start_node().await.unwrap(),
+    );
+
+    let mut agent = cli(&agent_node.url())
+        .args(["agent", "run", "--name", "echo"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(agent.stdout.take().unwrap()).lines();
+    assert_eq!(read_until(&mut stdout, "nwaku:").await, "Ready");
+    let pubkey = read_until(&mut stdout, "Pubkey:").await;
+    // Polling the relay cache means the inbox is subscribed
+    assert!(
+        agent_node
+            .wait_for_request(
+                |r| r.method == "GET" && r.path.starts_with("/relay/v1/messages/"),
+                TIMEOUT,
+            )
+            .await
+    );
+
+    let output = cli(&client_node.url())
+        .args(["agent", "discover", "--history"])
+        .output()
+        .await
+        .unwrap();
+    let stdout_text = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout_text.contains("Name: echo"), "{}", stdout_text);
+    assert!(stdout_text.contains(&pubkey), "{}", stdout_text);
+
+    let output = tokio::time::timeout(
+        TIMEOUT,
+        cli(&client_node.url())
+            .args(["task", "send", "--to", &pubkey, "--text", "hello"])
+            .output(),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let stdout_text = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout_text.contains("Status: ACKed by recipient"),
+        "{}",
+        stdout_text
+    );
+    assert_eq!(read_until(&mut stdout, "Responded:").await, "Echo: hello");
+}
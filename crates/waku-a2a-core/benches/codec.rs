@@ -0,0 +1,74 @@
+//! Size and CPU comparison of the envelope codecs.
+//!
+//! Run with `cargo bench -p waku-a2a-core --bench codec`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use waku_a2a_core::codec::{CborCodec, Codec, JsonCodec};
+use waku_a2a_core::jsonrpc::JsonRpcCodec;
+use waku_a2a_core::{A2AEnvelope, Task, TaskState, TaskStatusUpdate, PROTOCOL_VERSION};
+use waku_a2a_crypto::AgentIdentity;
+
+const FROM: &str = "02a1633cafcc01ebfb6d78e39f687a1f0995c62fc95f51ead10a02ee0be551b5dc";
+const TO: &str = "03b2d8a5e9f1c4a7b6e3d2c1f0a9b8c7d6e5f4a3b2c1d0e9f8a7b6c5d4e3f2a1b0";
+
+fn envelopes() -> Vec<(&'static str, A2AEnvelope)> {
+    let task = Task::new(FROM, TO, &"Summarize the following document. ".repeat(8));
+    let response = task.respond(&"The document describes a protocol. ".repeat(8));
+    let update = TaskStatusUpdate::new(&task.id, 3, TaskState::Working, Some("60% done"), false);
+
+    let sender = AgentIdentity::generate();
+    let recipient = AgentIdentity::generate();
+    let their_pubkey = AgentIdentity::parse_public_key(&recipient.public_key_hex()).unwrap();
+    let encrypted = sender
+        .shared_key(&their_pubkey)
+        .encrypt(&serde_json::to_vec(&task).unwrap())
+        .unwrap();
+
+    vec![
+        ("task", A2AEnvelope::Task(task)),
+        ("response", A2AEnvelope::Task(response)),
+        ("status", A2AEnvelope::TaskStatus(update)),
+        (
+            "encrypted_task",
+            A2AEnvelope::EncryptedTask {
+                encrypted,
+                sender_pubkey: sender.public_key_hex(),
+                compression: None,
+            },
+        ),
+    ]
+}
+
+fn bench_codecs(c: &mut Criterion) {
+    let codecs: [&dyn Codec; 3] = [&JsonCodec, &CborCodec, &JsonRpcCodec];
+
+    for (name, envelope) in envelopes() {
+        let sizes: Vec<String> = codecs
+            .iter()
+            .map(|codec| {
+                let bytes = codec.encode(&envelope, PROTOCOL_VERSION).unwrap();
+                format!("{}={}B", codec.id(), bytes.len())
+            })
+            .collect();
+        println!("{}: {}", name, sizes.join(" "));
+
+        let mut group = c.benchmark_group(name);
+        for codec in codecs {
+            let bytes = codec.encode(&envelope, PROTOCOL_VERSION).unwrap();
+            group.bench_function(format!("{}/encode", codec.id()), |b| {
+                b.iter(|| {
+                    codec
+                        .encode(black_box(&envelope), PROTOCOL_VERSION)
+                        .unwrap()
+                })
+            });
+            group.bench_function(format!("{}/decode", codec.id()), |b| {
+                b.iter(|| codec.decode(black_box(&bytes)).unwrap())
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_codecs);
+criterion_main!(benches);
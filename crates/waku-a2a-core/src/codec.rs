@@ -0,0 +1,456 @@
+//! Envelope codecs: how an `A2AEnvelope` is turned into Waku payload bytes.
+//!
+//! - `JsonCodec` ("json"): the native JSON envelope. Understood by every node.
+//! - `CborCodec` ("cbor"): the same structure as CBOR, with the encrypted
+//!   payload's nonce and ciphertext, fragment data and compressed data as raw
+//!   byte strings instead of base64.
+//! - `jsonrpc::JsonRpcCodec` ("jsonrpc"): A2A JSON-RPC 2.0 where possible.
+//!
+//! Decoding never needs to know the codec: CBOR payloads start with the
+//! self-described CBOR tag, everything else is parsed as JSON. `decode` also
+//! unwraps `A2AEnvelope::Compressed`.
+
+use crate::compression::{Compression, MIN_COMPRESS_SIZE};
+use crate::{A2AEnvelope, EnvelopeError};
+use anyhow::{Context, Result};
+use base64::Engine;
+use ciborium::Value as CborValue;
+use serde::{Serialize, Serializer};
+use serde_json::{Map, Number, Value};
+use waku_a2a_crypto::EncryptedPayload;
+
+/// Self-described CBOR tag 55799 (RFC 8949 §3.4.6), prefixed to every CBOR payload.
+const CBOR_MAGIC: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+/// Envelope wire encoding, selected per node and advertised on its card.
+pub trait Codec: Send + Sync {
+    /// Identifier advertised in `AgentCard::codecs`.
+    fn id(&self) -> &'static str;
+
+    /// Encode an envelope, stamping it with the given protocol version.
+    fn encode(&self, envelope: &A2AEnvelope, version: u32) -> Result<Vec<u8>>;
+
+    /// Decode a payload produced by this codec.
+    fn decode(&self, payload: &[u8]) -> Result<A2AEnvelope, EnvelopeError>;
+}
+
+/// Native JSON envelopes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+/// Compact binary envelopes (CBOR).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CborCodec;
+
+impl Codec for JsonCodec {
+    fn id(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, envelope: &A2AEnvelope, version: u32) -> Result<Vec<u8>> {
+        envelope
+            .encode_versioned(version)
+            .context("Failed to serialize envelope")
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<A2AEnvelope, EnvelopeError> {
+        let value: Value =
+            serde_json::from_slice(payload).map_err(|e| EnvelopeError::Malformed(e.to_string()))?;
+        A2AEnvelope::from_value(value)
+    }
+}
+
+impl Codec for CborCodec {
+    fn id(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn encode(&self, envelope: &A2AEnvelope, version: u32) -> Result<Vec<u8>> {
+        let mut out = CBOR_MAGIC.to_vec();
+        let written = match binary_form(envelope) {
+            Some(binary) => ciborium::into_writer(
+                &Versioned {
+                    v: version,
+                    envelope: &binary,
+                },
+                &mut out,
+            ),
+            None if matches!(envelope, A2AEnvelope::Unknown(_)) => {
+                ciborium::into_writer(envelope, &mut out)
+            }
+            None => ciborium::into_writer(
+                &Versioned {
+                    v: version,
+                    envelope,
+                },
+                &mut out,
+            ),
+        };
+        written.context("Failed to write CBOR")?;
+        Ok(out)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<A2AEnvelope, EnvelopeError> {
+        let cbor: CborValue = ciborium::from_reader(payload)
+            .map_err(|e| EnvelopeError::Malformed(format!("invalid CBOR: {}", e)))?;
+        let value = cbor_to_json(cbor).map_err(|e| EnvelopeError::Malformed(e.to_string()))?;
+        A2AEnvelope::from_value(value)
+    }
+}
+
+/// Decode a payload in any supported codec, detected from its first bytes.
+/// Compressed envelopes are decompressed and decoded in turn.
+pub fn decode(payload: &[u8]) -> Result<A2AEnvelope, EnvelopeError> {
+    match decode_raw(payload)? {
+        A2AEnvelope::Compressed { compression, data } => {
+            let compressed = base64::engine::general_purpose::STANDARD
+                .decode(data.as_bytes())
+                .map_err(|e| EnvelopeError::Malformed(format!("invalid compressed data: {}", e)))?;
+            let inner = compression
+                .decompress(&compressed)
+                .map_err(|e| EnvelopeError::Malformed(e.to_string()))?;
+            match decode_raw(&inner)? {
+                A2AEnvelope::Compressed { .. } => Err(EnvelopeError::Malformed(
+                    "nested compressed envelope".to_string(),
+                )),
+                envelope => Ok(envelope),
+            }
+        }
+        envelope => Ok(envelope),
+    }
+}
+
+fn decode_raw(payload: &[u8]) -> Result<A2AEnvelope, EnvelopeError> {
+    if payload.starts_with(&CBOR_MAGIC) {
+        CborCodec.decode(payload)
+    } else {
+        JsonCodec.decode(payload)
+    }
+}
+
+/// Compress an encoded envelope into an `A2AEnvelope::Compressed` payload in
+/// the same codec. Returns `None` if that wouldn't make it smaller.
+pub fn compress(
+    codec: &dyn Codec,
+    compression: Compression,
+    payload: &[u8],
+    version: u32,
+) -> Result<Option<Vec<u8>>> {
+    if payload.len() < MIN_COMPRESS_SIZE {
+        return Ok(None);
+    }
+    let compressed = compression.compress(payload)?;
+    let wrapped = codec.encode(
+        &A2AEnvelope::Compressed {
+            compression,
+            data: base64::engine::general_purpose::STANDARD.encode(compressed),
+        },
+        version,
+    )?;
+    Ok((wrapped.len() < payload.len()).then_some(wrapped))
+}
+
+/// Codec IDs this build can decode, as advertised on its `AgentCard`.
+pub fn supported_codecs() -> Vec<String> {
+    ["json", "cbor", "jsonrpc"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// Look up a codec by its advertised ID.
+pub fn by_id(id: &str) -> Option<Box<dyn Codec>> {
+    match id {
+        "json" => Some(Box::new(JsonCodec)),
+        "cbor" => Some(Box::new(CborCodec)),
+        "jsonrpc" => Some(Box::new(crate::jsonrpc::JsonRpcCodec)),
+        _ => None,
+    }
+}
+
+/// An envelope with its protocol version next to its own fields.
+#[derive(Serialize)]
+struct Versioned<'a, T> {
+    v: u32,
+    #[serde(flatten)]
+    envelope: &'a T,
+}
+
+/// Envelopes with base64 fields, which CBOR carries as byte strings.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BinaryEnvelope<'a> {
+    EncryptedTask {
+        encrypted: BinaryEncrypted<'a>,
+        sender_pubkey: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        compression: Option<Compression>,
+    },
+    EncryptedStatus {
+        encrypted: BinaryEncrypted<'a>,
+        sender_pubkey: &'a str,
+    },
+    Fragment {
+        message_id: &'a str,
+        index: u32,
+        total: u32,
+        digest: &'a str,
+        data: Base64Bytes<'a>,
+    },
+    Compressed {
+        compression: Compression,
+        data: Base64Bytes<'a>,
+    },
+}
+
+#[derive(Serialize)]
+struct BinaryEncrypted<'a> {
+    nonce: Base64Bytes<'a>,
+    ciphertext: Base64Bytes<'a>,
+}
+
+impl<'a> BinaryEncrypted<'a> {
+    fn new(encrypted: &'a EncryptedPayload) -> Self {
+        Self {
+            nonce: Base64Bytes(&encrypted.nonce),
+            ciphertext: Base64Bytes(&encrypted.ciphertext),
+        }
+    }
+}
+
+/// A base64 field written as the bytes it encodes, or as text if it isn't
+/// valid base64.
+struct Base64Bytes<'a>(&'a str);
+
+impl Serialize for Base64Bytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match base64::engine::general_purpose::STANDARD.decode(self.0.as_bytes()) {
+            Ok(bytes) => serializer.serialize_bytes(&bytes),
+            Err(_) => serializer.serialize_str(self.0),
+        }
+    }
+}
+
+/// The CBOR form of envelopes with binary fields.
+fn binary_form(envelope: &A2AEnvelope) -> Option<BinaryEnvelope<'_>> {
+    Some(match envelope {
+        A2AEnvelope::EncryptedTask {
+            encrypted,
+            sender_pubkey,
+            compression,
+        } => BinaryEnvelope::EncryptedTask {
+            encrypted: BinaryEncrypted::new(encrypted),
+            sender_pubkey,
+            compression: *compression,
+        },
+        A2AEnvelope::EncryptedStatus {
+            encrypted,
+            sender_pubkey,
+        } => BinaryEnvelope::EncryptedStatus {
+            encrypted: BinaryEncrypted::new(encrypted),
+            sender_pubkey,
+        },
+        A2AEnvelope::Fragment(fragment) => BinaryEnvelope::Fragment {
+            message_id: &fragment.message_id,
+            index: fragment.index,
+            total: fragment.total,
+            digest: &fragment.digest,
+            data: Base64Bytes(&fragment.data),
+        },
+        A2AEnvelope::Compressed { compression, data } => BinaryEnvelope::Compressed {
+            compression: *compression,
+            data: Base64Bytes(data),
+        },
+        _ => return None,
+    })
+}
+
+/// Byte strings map back to base64 text, the JSON representation of the
+/// only binary fields we emit.
+fn cbor_to_json(value: CborValue) -> Result<Value> {
+    Ok(match value {
+        CborValue::Null => Value::Null,
+        CborValue::Bool(b) => Value::Bool(b),
+        CborValue::Integer(i) => {
+            let i = i128::from(i);
+            if let Ok(v) = i64::try_from(i) {
+                Value::from(v)
+            } else {
+                Value::from(u64::try_from(i).context("CBOR integer out of range")?)
+            }
+        }
+        CborValue::Float(f) => Number::from_f64(f)
+            .map(Value::Number)
+            .context("non-finite CBOR float")?,
+        CborValue::Text(s) => Value::String(s),
+        CborValue::Bytes(b) => Value::String(base64::engine::general_purpose::STANDARD.encode(b)),
+        CborValue::Array(items) => {
+            Value::Array(items.into_iter().map(cbor_to_json).collect::<Result<_>>()?)
+        }
+        CborValue::Map(entries) => {
+            let mut map = Map::new();
+            for (k, v) in entries {
+                let CborValue::Text(key) = k else {
+                    anyhow::bail!("CBOR map keys must be text");
+                };
+                map.insert(key, cbor_to_json(v)?);
+            }
+            Value::Object(map)
+        }
+        CborValue::Tag(_, inner) => cbor_to_json(*inner)?,
+        _ => anyhow::bail!("unsupported CBOR value"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Task, PROTOCOL_VERSION};
+    use waku_a2a_crypto::EncryptedPayload;
+
+    fn encrypted_envelope() -> A2AEnvelope {
+        A2AEnvelope::EncryptedTask {
+            encrypted: EncryptedPayload {
+                nonce: "AAECAwQFBgcICQoL".to_string(),
+                ciphertext: base64::engine::general_purpose::STANDARD.encode([7u8; 300]),
+            },
+            sender_pubkey: "aabbccdd".to_string(),
+            compression: None,
+        }
+    }
+
+    #[test]
+    fn test_cbor_roundtrip() {
+        let task = Task::new("02aa", "03bb", "Summarize the following document");
+        for envelope in [
+            A2AEnvelope::Task(task.clone()),
+            A2AEnvelope::Task(task.respond("Done")),
+            A2AEnvelope::Ack {
+                message_id: "m1".to_string(),
+            },
+            encrypted_envelope(),
+            A2AEnvelope::EncryptedStatus {
+                encrypted: EncryptedPayload {
+                    nonce: "AAECAwQFBgcICQoL".to_string(),
+                    ciphertext: "Y2lwaGVydGV4dA==".to_string(),
+                },
+                sender_pubkey: "aabbccdd".to_string(),
+            },
+            A2AEnvelope::Fragment(crate::fragment::split(&[9u8; 64], "m1").remove(0)),
+        ] {
+            let bytes = CborCodec.encode(&envelope, PROTOCOL_VERSION).unwrap();
+            assert!(bytes.starts_with(&CBOR_MAGIC));
+            assert_eq!(CborCodec.decode(&bytes).unwrap(), envelope);
+            assert_eq!(decode(&bytes).unwrap(), envelope);
+        }
+    }
+
+    #[test]
+    fn test_cbor_is_smaller() {
+        let envelope = encrypted_envelope();
+        let json = JsonCodec.encode(&envelope, PROTOCOL_VERSION).unwrap();
+        let cbor = CborCodec.encode(&envelope, PROTOCOL_VERSION).unwrap();
+        // 300 bytes of ciphertext take 400 as base64; CBOR carries them raw
+        assert!(
+            json.len() - cbor.len() >= 100,
+            "{} vs {}",
+            cbor.len(),
+            json.len()
+        );
+    }
+
+    #[test]
+    fn test_cbor_wire_form() {
+        let cbor = CborCodec
+            .encode(&encrypted_envelope(), PROTOCOL_VERSION)
+            .unwrap();
+        let value: CborValue = ciborium::from_reader(&cbor[..]).unwrap();
+        let CborValue::Tag(_, inner) = value else {
+            panic!("missing self-describe tag");
+        };
+        let CborValue::Map(entries) = *inner else {
+            panic!("envelope is not a map");
+        };
+        let get = |key: &str| {
+            entries
+                .iter()
+                .find(|(k, _)| matches!(k, CborValue::Text(t) if t == key))
+                .map(|(_, v)| v.clone())
+        };
+        assert_eq!(get("v"), Some(CborValue::Integer(PROTOCOL_VERSION.into())));
+        assert_eq!(get("type"), Some(CborValue::Text("encrypted_task".into())));
+        let Some(CborValue::Map(encrypted)) = get("encrypted") else {
+            panic!("missing encrypted payload");
+        };
+        assert!(encrypted.iter().any(
+            |(k, v)| matches!(k, CborValue::Text(t) if t == "ciphertext")
+                && *v == CborValue::Bytes(vec![7u8; 300])
+        ));
+    }
+
+    #[test]
+    fn test_compressed_envelopes() {
+        let task = Task::new("02aa", "03bb", &"Summarize this paragraph. ".repeat(200));
+        let envelope = A2AEnvelope::Task(task);
+        for codec in [&JsonCodec as &dyn Codec, &CborCodec] {
+            let payload = codec.encode(&envelope, PROTOCOL_VERSION).unwrap();
+            let compressed = compress(codec, Compression::Zstd, &payload, PROTOCOL_VERSION)
+                .unwrap()
+                .unwrap();
+            assert!(compressed.len() * 5 < payload.len());
+            assert_eq!(decode(&compressed).unwrap(), envelope);
+        }
+
+        // Small or incompressible payloads stay as they are
+        let ack = JsonCodec
+            .encode(
+                &A2AEnvelope::Ack {
+                    message_id: "m1".to_string(),
+                },
+                PROTOCOL_VERSION,
+            )
+            .unwrap();
+        assert!(
+            compress(&JsonCodec, Compression::Zstd, &ack, PROTOCOL_VERSION)
+                .unwrap()
+                .is_none()
+        );
+
+        // Nested compression is rejected
+        let inner = compress(
+            &JsonCodec,
+            Compression::Zstd,
+            &JsonCodec.encode(&envelope, PROTOCOL_VERSION).unwrap(),
+            PROTOCOL_VERSION,
+        )
+        .unwrap()
+        .unwrap();
+        let nested = JsonCodec
+            .encode(
+                &A2AEnvelope::Compressed {
+                    compression: Compression::Zstd,
+                    data: base64::engine::general_purpose::STANDARD
+                        .encode(Compression::Zstd.compress(&inner).unwrap()),
+                },
+                PROTOCOL_VERSION,
+            )
+            .unwrap();
+        assert!(matches!(decode(&nested), Err(EnvelopeError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_auto_detect() {
+        let envelope = A2AEnvelope::Task(Task::new("02aa", "03bb", "hi"));
+        for id in supported_codecs() {
+            let codec = by_id(&id).unwrap();
+            assert_eq!(codec.id(), id);
+            let bytes = codec.encode(&envelope, PROTOCOL_VERSION).unwrap();
+            assert_eq!(decode(&bytes).unwrap(), envelope, "codec {}", id);
+        }
+        assert!(by_id("protobuf").is_none());
+        assert!(matches!(
+            decode(&[0xd9, 0xd9, 0xf7, 0xff]),
+            Err(EnvelopeError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,104 @@
+//! Optional payload compression.
+//!
+//! Plaintext envelopes are wrapped in `A2AEnvelope::Compressed`; encrypted
+//! tasks are compressed before encryption and flagged with
+//! `EncryptedTask::compression`. Agents list the algorithms they accept in
+//! `AgentCard::compression`.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+
+/// Largest accepted decompressed payload. Guards against decompression bombs.
+pub const MAX_DECOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
+
+/// Payloads smaller than this aren't worth compressing.
+pub const MIN_COMPRESS_SIZE: usize = 256;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression algorithm.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+}
+
+impl Compression {
+    /// Identifier advertised in `AgentCard::compression`.
+    pub fn id(&self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::Zstd => {
+                zstd::stream::encode_all(data, ZSTD_LEVEL).context("zstd compression failed")
+            }
+        }
+    }
+
+    /// Decompress, failing if the output would exceed `MAX_DECOMPRESSED_SIZE`.
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.decompress_with_limit(data, MAX_DECOMPRESSED_SIZE)
+    }
+
+    pub fn decompress_with_limit(&self, data: &[u8], limit: usize) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        match self {
+            Compression::Zstd => {
+                let decoder =
+                    zstd::stream::read::Decoder::new(data).context("invalid zstd stream")?;
+                decoder
+                    .take(limit as u64 + 1)
+                    .read_to_end(&mut out)
+                    .context("zstd decompression failed")?;
+            }
+        }
+        if out.len() > limit {
+            bail!("decompressed payload exceeds {} bytes", limit);
+        }
+        Ok(out)
+    }
+}
+
+/// Compression algorithms this build can decompress, as advertised on its
+/// `AgentCard`.
+pub fn supported_compression() -> Vec<String> {
+    vec![Compression::Zstd.id().to_string()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let data = "The quick brown fox jumps over the lazy dog. ".repeat(100);
+        let compressed = Compression::Zstd.compress(data.as_bytes()).unwrap();
+        assert!(compressed.len() < data.len() / 10);
+        assert_eq!(
+            Compression::Zstd.decompress(&compressed).unwrap(),
+            data.as_bytes()
+        );
+        assert!(Compression::Zstd.decompress(b"not zstd").is_err());
+    }
+
+    #[test]
+    fn test_decompression_limit() {
+        let bomb = Compression::Zstd.compress(&vec![0u8; 1024 * 1024]).unwrap();
+        assert!(bomb.len() < 1024);
+        assert!(Compression::Zstd
+            .decompress_with_limit(&bomb, 64 * 1024)
+            .is_err());
+        assert_eq!(
+            Compression::Zstd
+                .decompress_with_limit(&bomb, 1024 * 1024)
+                .unwrap()
+                .len(),
+            1024 * 1024
+        );
+    }
+}
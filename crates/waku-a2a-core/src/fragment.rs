@@ -0,0 +1,420 @@
+//! Fragmentation of payloads that exceed the Waku relay message size.
+//!
+//! An oversized encoded envelope (plaintext or `EncryptedTask`) is split into
+//! numbered `Fragment`s that each carry the SHA-256 digest of the whole
+//! payload. `Reassembler` collects them in any order, verifies the digest,
+//! and bounds memory use and waiting time for incomplete messages.
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Largest payload published as a single Waku message. nwaku relay caps
+/// messages at 150 KiB including protobuf framing.
+pub const MAX_MESSAGE_SIZE: usize = 140 * 1024;
+
+/// Raw bytes per fragment. Leaves room for base64 and envelope overhead.
+pub const FRAGMENT_SIZE: usize = 96 * 1024;
+
+/// One numbered piece of a fragmented payload.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Fragment {
+    /// ID of the fragmented message (the task ID for tasks, so SDS ACKs match).
+    pub message_id: String,
+    /// Zero-based fragment number.
+    pub index: u32,
+    /// Number of fragments in the message.
+    pub total: u32,
+    /// Hex SHA-256 of the reassembled payload.
+    pub digest: String,
+    /// Base64-encoded fragment bytes.
+    pub data: String,
+}
+
+/// True if the payload has to be fragmented before publishing.
+pub fn needs_fragmentation(payload: &[u8]) -> bool {
+    payload.len() > MAX_MESSAGE_SIZE
+}
+
+/// Split a payload into fragments of at most `FRAGMENT_SIZE` bytes.
+pub fn split(payload: &[u8], message_id: &str) -> Vec<Fragment> {
+    split_with_size(payload, message_id, FRAGMENT_SIZE)
+}
+
+/// Split a payload into fragments of at most `size` bytes.
+pub fn split_with_size(payload: &[u8], message_id: &str, size: usize) -> Vec<Fragment> {
+    let digest = digest_hex(payload);
+    let chunks: Vec<&[u8]> = if payload.is_empty() {
+        vec![payload]
+    } else {
+        payload.chunks(size.max(1)).collect()
+    };
+    let total = chunks.len() as u32;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| Fragment {
+            message_id: message_id.to_string(),
+            index: i as u32,
+            total,
+            digest: digest.clone(),
+            data: base64::engine::general_purpose::STANDARD.encode(chunk),
+        })
+        .collect()
+}
+
+fn digest_hex(payload: &[u8]) -> String {
+    hex::encode(Sha256::digest(payload))
+}
+
+/// Bounds applied by `Reassembler`.
+#[derive(Debug, Clone, Copy)]
+pub struct ReassemblyLimits {
+    /// Incomplete messages older than this are dropped.
+    pub timeout: Duration,
+    /// Largest accepted `total` per message.
+    pub max_fragments: u32,
+    /// Bytes buffered across all incomplete messages. The oldest messages
+    /// are evicted to make room.
+    pub max_buffered_bytes: usize,
+    /// Reassembled message IDs remembered to recognise late duplicates.
+    /// The oldest are forgotten first.
+    pub max_completed: usize,
+}
+
+impl Default for ReassemblyLimits {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(120),
+            max_fragments: 256,
+            max_buffered_bytes: 32 * 1024 * 1024,
+            max_completed: 4096,
+        }
+    }
+}
+
+/// Result of feeding a fragment to the `Reassembler`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FragmentOutcome {
+    /// All fragments arrived and the digest matches.
+    Complete(Vec<u8>),
+    /// Still waiting; lists the fragment indices received so far.
+    Pending(Vec<u32>),
+    /// Fragment of a message that was already reassembled.
+    Duplicate,
+    /// Fragment (or the whole message) was invalid and has been dropped.
+    Rejected(String),
+}
+
+struct Partial {
+    total: u32,
+    digest: String,
+    chunks: BTreeMap<u32, Vec<u8>>,
+    bytes: usize,
+    started: Instant,
+}
+
+/// Collects fragments until their message is complete.
+#[derive(Default)]
+pub struct Reassembler {
+    limits: ReassemblyLimits,
+    partial: HashMap<String, Partial>,
+    completed: HashSet<String>,
+    completed_order: VecDeque<String>,
+    buffered_bytes: usize,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limits(limits: ReassemblyLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// Bytes currently held for incomplete messages.
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    /// Number of incomplete messages.
+    pub fn pending_messages(&self) -> usize {
+        self.partial.len()
+    }
+
+    pub fn insert(&mut self, fragment: Fragment, now: Instant) -> FragmentOutcome {
+        let Fragment {
+            message_id,
+            index,
+            total,
+            digest,
+            data,
+        } = fragment;
+
+        if self.completed.contains(&message_id) {
+            return FragmentOutcome::Duplicate;
+        }
+        if total == 0 || index >= total {
+            return FragmentOutcome::Rejected(format!("fragment {}/{} out of range", index, total));
+        }
+        if total > self.limits.max_fragments {
+            return FragmentOutcome::Rejected(format!(
+                "{} fragments exceeds limit of {}",
+                total, self.limits.max_fragments
+            ));
+        }
+        let chunk = match base64::engine::general_purpose::STANDARD.decode(data.as_bytes()) {
+            Ok(chunk) => chunk,
+            Err(e) => return FragmentOutcome::Rejected(format!("invalid fragment data: {}", e)),
+        };
+
+        if let Some(partial) = self.partial.get(&message_id) {
+            if partial.total != total || partial.digest != digest {
+                return FragmentOutcome::Rejected(format!(
+                    "fragment {} doesn't match message {}",
+                    index, message_id
+                ));
+            }
+            if partial.chunks.contains_key(&index) {
+                return FragmentOutcome::Pending(partial.chunks.keys().copied().collect());
+            }
+        }
+
+        if !self.make_room(chunk.len(), &message_id) {
+            return FragmentOutcome::Rejected(format!(
+                "message {} exceeds reassembly buffer",
+                message_id
+            ));
+        }
+
+        let partial = self
+            .partial
+            .entry(message_id.clone())
+            .or_insert_with(|| Partial {
+                total,
+                digest,
+                chunks: BTreeMap::new(),
+                bytes: 0,
+                started: now,
+            });
+        partial.bytes += chunk.len();
+        self.buffered_bytes += chunk.len();
+        partial.chunks.insert(index, chunk);
+
+        if partial.chunks.len() < partial.total as usize {
+            return FragmentOutcome::Pending(partial.chunks.keys().copied().collect());
+        }
+
+        let partial = self.remove(&message_id).expect("partial just inserted");
+        let payload: Vec<u8> = partial.chunks.into_values().flatten().collect();
+        if digest_hex(&payload) != partial.digest {
+            return FragmentOutcome::Rejected(format!("digest mismatch for {}", message_id));
+        }
+        self.complete(message_id);
+        FragmentOutcome::Complete(payload)
+    }
+
+    /// Indices received so far for an incomplete message.
+    pub fn received(&self, message_id: &str) -> Vec<u32> {
+        self.partial
+            .get(message_id)
+            .map(|p| p.chunks.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Drop incomplete messages older than the timeout. Returns their IDs.
+    pub fn expire(&mut self, now: Instant) -> Vec<String> {
+        let expired: Vec<String> = self
+            .partial
+            .iter()
+            .filter(|(_, p)| now.saturating_duration_since(p.started) >= self.limits.timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &expired {
+            self.remove(id);
+        }
+        expired
+    }
+
+    /// Remember a reassembled message, forgetting the oldest beyond
+    /// `max_completed`.
+    fn complete(&mut self, message_id: String) {
+        if !self.completed.insert(message_id.clone()) {
+            return;
+        }
+        self.completed_order.push_back(message_id);
+        if self.completed_order.len() > self.limits.max_completed {
+            if let Some(oldest) = self.completed_order.pop_front() {
+                self.completed.remove(&oldest);
+            }
+        }
+    }
+
+    fn remove(&mut self, message_id: &str) -> Option<Partial> {
+        let partial = self.partial.remove(message_id)?;
+        self.buffered_bytes -= partial.bytes;
+        Some(partial)
+    }
+
+    /// Evict the oldest other incomplete messages until `needed` more bytes fit.
+    fn make_room(&mut self, needed: usize, keep: &str) -> bool {
+        while self.buffered_bytes + needed > self.limits.max_buffered_bytes {
+            let oldest = self
+                .partial
+                .iter()
+                .filter(|(id, _)| id.as_str() != keep)
+                .min_by_key(|(_, p)| p.started)
+                .map(|(id, _)| id.clone());
+            match oldest {
+                Some(id) => {
+                    self.remove(&id);
+                }
+                None => {
+                    self.remove(keep);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_split_and_reassemble_out_of_order() {
+        let data = payload(1000);
+        let mut fragments = split_with_size(&data, "m1", 300);
+        assert_eq!(fragments.len(), 4);
+        fragments.reverse();
+
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+        let last = fragments.pop().unwrap();
+        for fragment in fragments.clone() {
+            assert!(matches!(
+                reassembler.insert(fragment, now),
+                FragmentOutcome::Pending(_)
+            ));
+        }
+        // Duplicates don't count twice
+        assert_eq!(
+            reassembler.insert(fragments[0].clone(), now),
+            FragmentOutcome::Pending(vec![1, 2, 3])
+        );
+        assert_eq!(reassembler.received("m1"), vec![1, 2, 3]);
+
+        assert_eq!(
+            reassembler.insert(last.clone(), now),
+            FragmentOutcome::Complete(data)
+        );
+        assert_eq!(reassembler.buffered_bytes(), 0);
+        assert_eq!(reassembler.insert(last, now), FragmentOutcome::Duplicate);
+    }
+
+    #[test]
+    fn test_rejects_corrupted_fragment() {
+        let mut fragments = split_with_size(&payload(100), "m1", 50);
+        fragments[1].data = base64::engine::general_purpose::STANDARD.encode([0u8; 50]);
+
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+        reassembler.insert(fragments[0].clone(), now);
+        assert!(matches!(
+            reassembler.insert(fragments[1].clone(), now),
+            FragmentOutcome::Rejected(_)
+        ));
+        assert_eq!(reassembler.pending_messages(), 0);
+
+        let mut bad_total = fragments[0].clone();
+        bad_total.index = 5;
+        assert!(matches!(
+            reassembler.insert(bad_total, now),
+            FragmentOutcome::Rejected(_)
+        ));
+    }
+
+    #[test]
+    fn test_expires_incomplete_messages() {
+        let fragments = split_with_size(&payload(100), "m1", 50);
+        let start = Instant::now();
+        let mut reassembler = Reassembler::new();
+        reassembler.insert(fragments[0].clone(), start);
+
+        let limits = ReassemblyLimits::default();
+        assert!(reassembler.expire(start + limits.timeout / 2).is_empty());
+        assert_eq!(reassembler.expire(start + limits.timeout), vec!["m1"]);
+        assert_eq!(reassembler.buffered_bytes(), 0);
+    }
+
+    #[test]
+    fn test_memory_limits() {
+        let mut reassembler = Reassembler::with_limits(ReassemblyLimits {
+            max_fragments: 4,
+            max_buffered_bytes: 150,
+            ..ReassemblyLimits::default()
+        });
+        let start = Instant::now();
+
+        let too_many = split_with_size(&payload(100), "big", 10);
+        assert!(matches!(
+            reassembler.insert(too_many[0].clone(), start),
+            FragmentOutcome::Rejected(_)
+        ));
+
+        // Oldest incomplete message is evicted to make room
+        let old = split_with_size(&payload(200), "old", 100);
+        let new = split_with_size(&payload(200), "new", 100);
+        reassembler.insert(old[0].clone(), start);
+        reassembler.insert(new[0].clone(), start + Duration::from_secs(1));
+        assert!(reassembler.received("old").is_empty());
+        assert_eq!(reassembler.received("new"), vec![0]);
+
+        // A single message that can never fit is dropped
+        assert!(matches!(
+            reassembler.insert(new[1].clone(), start),
+            FragmentOutcome::Rejected(_)
+        ));
+        assert_eq!(reassembler.buffered_bytes(), 0);
+    }
+
+    #[test]
+    fn test_forgets_oldest_completed_messages() {
+        let mut reassembler = Reassembler::with_limits(ReassemblyLimits {
+            max_completed: 2,
+            ..ReassemblyLimits::default()
+        });
+        let now = Instant::now();
+        let messages: Vec<Vec<Fragment>> = ["m0", "m1", "m2"]
+            .iter()
+            .map(|id| split_with_size(&payload(20), id, 20))
+            .collect();
+        for fragments in &messages {
+            assert!(matches!(
+                reassembler.insert(fragments[0].clone(), now),
+                FragmentOutcome::Complete(_)
+            ));
+        }
+
+        assert_eq!(
+            reassembler.insert(messages[2][0].clone(), now),
+            FragmentOutcome::Duplicate
+        );
+        assert!(matches!(
+            reassembler.insert(messages[0][0].clone(), now),
+            FragmentOutcome::Complete(_)
+        ));
+    }
+}
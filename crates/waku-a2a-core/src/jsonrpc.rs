@@ -0,0 +1,1051 @@
+//! Google A2A JSON-RPC 2.0 wire compatibility.
+//!
+//! `A2AEnvelope` is our own compact format. This module mirrors the A2A spec
+//! objects (`Message`, `Task`, `TaskStatusUpdateEvent`, ...) with their exact
+//! camelCase field names so envelopes can be exchanged with standard A2A
+//! clients and SDKs through a bridge.
+//!
+//! Mapping to internal types:
+//! - `message/send` / `message/stream` request ↔ `A2AEnvelope::Task` without result
+//! - response with a `Task` result ↔ `A2AEnvelope::Task` with result
+//! - response with a `status-update` event ↔ `A2AEnvelope::TaskStatus`
+//!
+//! Waku routing (`from`/`to` pubkeys) and the status update `seq` have no A2A
+//! equivalent and travel in the `metadata` objects.
+
+use crate::codec::{Codec, JsonCodec};
+use crate::topics::InboxScheme;
+use crate::{A2AEnvelope, EnvelopeError, Message, Part, Task, TaskState, TaskStatusUpdate};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+pub const JSONRPC_VERSION: &str = "2.0";
+
+/// Standard JSON-RPC and A2A-specific error codes.
+pub mod error_codes {
+    pub const PARSE_ERROR: i64 = -32700;
+    pub const INVALID_REQUEST: i64 = -32600;
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    pub const INVALID_PARAMS: i64 = -32602;
+    pub const INTERNAL_ERROR: i64 = -32603;
+    pub const TASK_NOT_FOUND: i64 = -32001;
+    pub const TASK_NOT_CANCELABLE: i64 = -32002;
+    pub const PUSH_NOTIFICATION_NOT_SUPPORTED: i64 = -32003;
+    pub const UNSUPPORTED_OPERATION: i64 = -32004;
+    pub const CONTENT_TYPE_NOT_SUPPORTED: i64 = -32005;
+    pub const INVALID_AGENT_RESPONSE: i64 = -32006;
+}
+
+/// Encodes envelopes as A2A JSON-RPC 2.0 where a mapping exists, and as
+/// native JSON otherwise (agent cards, ACKs and encrypted tasks have no
+/// JSON-RPC form). Codec ID "jsonrpc".
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonRpcCodec;
+
+impl Codec for JsonRpcCodec {
+    fn id(&self) -> &'static str {
+        "jsonrpc"
+    }
+
+    /// The version only applies to native envelopes; JSON-RPC messages carry none.
+    fn encode(&self, envelope: &A2AEnvelope, version: u32) -> Result<Vec<u8>> {
+        if let Some(msg) = JsonRpcMessage::from_envelope(envelope) {
+            return serde_json::to_vec(&msg).context("Failed to serialize JSON-RPC message");
+        }
+        JsonCodec.encode(envelope, version)
+    }
+
+    fn decode(&self, payload: &[u8]) -> std::result::Result<A2AEnvelope, EnvelopeError> {
+        JsonCodec.decode(payload)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// JSON-RPC framing
+// ---------------------------------------------------------------------------
+
+/// JSON-RPC request/response identifier.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum JsonRpcId {
+    Number(i64),
+    String(String),
+    Null,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct JsonRpcRequest {
+    pub jsonrpc: String,
+    pub id: JsonRpcId,
+    #[serde(flatten)]
+    pub call: A2ARequest,
+}
+
+/// A2A methods, tagged by `method` with their `params`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "method", content = "params")]
+pub enum A2ARequest {
+    #[serde(rename = "message/send")]
+    SendMessage(MessageSendParams),
+    #[serde(rename = "message/stream")]
+    StreamMessage(MessageSendParams),
+    #[serde(rename = "tasks/get")]
+    GetTask(TaskQueryParams),
+    #[serde(rename = "tasks/cancel")]
+    CancelTask(TaskIdParams),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct JsonRpcResponse {
+    pub jsonrpc: String,
+    pub id: JsonRpcId,
+    #[serde(flatten)]
+    pub outcome: JsonRpcOutcome,
+}
+
+/// Either a `result` or an `error` member.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonRpcOutcome {
+    Result(Box<A2AResult>),
+    Error(JsonRpcError),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct JsonRpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+/// Result payloads, discriminated by their `kind` member.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum A2AResult {
+    Task(A2ATask),
+    Message(A2AMessage),
+    StatusUpdate(TaskStatusUpdateEvent),
+    ArtifactUpdate(TaskArtifactUpdateEvent),
+}
+
+/// Any JSON-RPC message we may see on the wire.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum JsonRpcMessage {
+    Request(JsonRpcRequest),
+    Response(JsonRpcResponse),
+}
+
+impl JsonRpcRequest {
+    pub fn new(id: JsonRpcId, call: A2ARequest) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id,
+            call,
+        }
+    }
+}
+
+impl JsonRpcResponse {
+    pub fn result(id: JsonRpcId, result: A2AResult) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id,
+            outcome: JsonRpcOutcome::Result(Box::new(result)),
+        }
+    }
+
+    /// The `result` member, if this is not an error response.
+    pub fn as_result(&self) -> Option<&A2AResult> {
+        match &self.outcome {
+            JsonRpcOutcome::Result(result) => Some(result),
+            JsonRpcOutcome::Error(_) => None,
+        }
+    }
+
+    pub fn error(id: JsonRpcId, code: i64, message: &str) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id,
+            outcome: JsonRpcOutcome::Error(JsonRpcError {
+                code,
+                message: message.to_string(),
+                data: None,
+            }),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// A2A objects
+// ---------------------------------------------------------------------------
+
+macro_rules! kind_marker {
+    ($name:ident, $value:literal) => {
+        #[doc = concat!("`kind` discriminator, always `\"", $value, "\"`.")]
+        #[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+        pub enum $name {
+            #[default]
+            #[serde(rename = $value)]
+            Kind,
+        }
+    };
+}
+
+kind_marker!(MessageKind, "message");
+kind_marker!(TaskKind, "task");
+kind_marker!(StatusUpdateKind, "status-update");
+kind_marker!(ArtifactUpdateKind, "artifact-update");
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum A2ARole {
+    User,
+    Agent,
+}
+
+/// A2A `Message`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct A2AMessage {
+    pub role: A2ARole,
+    pub parts: Vec<A2APart>,
+    pub message_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reference_task_ids: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+    /// Optional on input: spec examples omit it inside `history`.
+    #[serde(default)]
+    pub kind: MessageKind,
+}
+
+/// A2A `Part`, tagged by `kind`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum A2APart {
+    Text {
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<Map<String, Value>>,
+    },
+    File {
+        file: FileContent,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<Map<String, Value>>,
+    },
+    Data {
+        data: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<Map<String, Value>>,
+    },
+}
+
+/// File part content: inline base64 `bytes` or a `uri`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FileContent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uri: Option<String>,
+}
+
+/// A2A `TaskState` (kebab-case, American "canceled").
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum A2ATaskState {
+    Submitted,
+    Working,
+    InputRequired,
+    Completed,
+    Canceled,
+    Failed,
+    Rejected,
+    AuthRequired,
+    Unknown,
+}
+
+/// A2A `TaskStatus`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct A2ATaskStatus {
+    pub state: A2ATaskState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<A2AMessage>,
+    /// ISO 8601 timestamp.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+}
+
+/// A2A `Artifact`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct A2AArtifact {
+    pub artifact_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub parts: Vec<A2APart>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+/// A2A `Task`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct A2ATask {
+    pub id: String,
+    pub context_id: String,
+    pub status: A2ATaskStatus,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<A2AMessage>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub artifacts: Vec<A2AArtifact>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+    pub kind: TaskKind,
+}
+
+/// A2A `TaskStatusUpdateEvent` (streamed by `message/stream`).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskStatusUpdateEvent {
+    pub task_id: String,
+    pub context_id: String,
+    pub kind: StatusUpdateKind,
+    pub status: A2ATaskStatus,
+    #[serde(rename = "final")]
+    pub is_final: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+/// A2A `TaskArtifactUpdateEvent` (streamed by `message/stream`).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskArtifactUpdateEvent {
+    pub task_id: String,
+    pub context_id: String,
+    pub kind: ArtifactUpdateKind,
+    pub artifact: A2AArtifact,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub append: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_chunk: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+/// Params of `message/send` and `message/stream`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageSendParams {
+    pub message: A2AMessage,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub configuration: Option<MessageSendConfiguration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageSendConfiguration {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub accepted_output_modes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_length: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocking: Option<bool>,
+}
+
+/// Params of `tasks/get`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskQueryParams {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_length: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+/// Params of `tasks/cancel`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskIdParams {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+// ---------------------------------------------------------------------------
+// Conversions
+// ---------------------------------------------------------------------------
+
+impl From<&TaskState> for A2ATaskState {
+    fn from(state: &TaskState) -> Self {
+        match state {
+            TaskState::Submitted => A2ATaskState::Submitted,
+            TaskState::Working => A2ATaskState::Working,
+            TaskState::InputRequired => A2ATaskState::InputRequired,
+            TaskState::Completed => A2ATaskState::Completed,
+            TaskState::Failed => A2ATaskState::Failed,
+            TaskState::Cancelled => A2ATaskState::Canceled,
+        }
+    }
+}
+
+impl A2ATaskState {
+    /// Map to the internal state. `rejected` folds into `Failed` and
+    /// `auth-required` into `InputRequired`; `unknown` has no mapping.
+    pub fn to_internal(self) -> Result<TaskState> {
+        Ok(match self {
+            A2ATaskState::Submitted => TaskState::Submitted,
+            A2ATaskState::Working => TaskState::Working,
+            A2ATaskState::InputRequired | A2ATaskState::AuthRequired => TaskState::InputRequired,
+            A2ATaskState::Completed => TaskState::Completed,
+            A2ATaskState::Canceled => TaskState::Cancelled,
+            A2ATaskState::Failed | A2ATaskState::Rejected => TaskState::Failed,
+            A2ATaskState::Unknown => bail!("A2A task state 'unknown' has no internal mapping"),
+        })
+    }
+}
+
+impl A2APart {
+    pub fn text(text: &str) -> Self {
+        A2APart::Text {
+            text: text.to_string(),
+            metadata: None,
+        }
+    }
+}
+
+fn parts_from_internal(parts: &[Part]) -> Vec<A2APart> {
+    parts
+        .iter()
+        .map(|p| match p {
+            Part::Text { text } => A2APart::text(text),
+        })
+        .collect()
+}
+
+fn parts_to_internal(parts: &[A2APart]) -> Result<Vec<Part>> {
+    parts
+        .iter()
+        .map(|p| match p {
+            A2APart::Text { text, .. } => Ok(Part::Text { text: text.clone() }),
+            A2APart::File { .. } => bail!("file parts are not supported"),
+            A2APart::Data { .. } => bail!("data parts are not supported"),
+        })
+        .collect()
+}
+
+impl A2AMessage {
+    /// Convert an internal message, generating a fresh `messageId`.
+    pub fn from_internal(message: &Message, task_id: Option<&str>) -> Self {
+        let role = if message.role == "agent" {
+            A2ARole::Agent
+        } else {
+            A2ARole::User
+        };
+        Self {
+            role,
+            parts: parts_from_internal(&message.parts),
+            message_id: Uuid::new_v4().to_string(),
+            task_id: task_id.map(str::to_string),
+            context_id: task_id.map(str::to_string),
+            reference_task_ids: Vec::new(),
+            metadata: None,
+            kind: MessageKind::Kind,
+        }
+    }
+
+    pub fn to_internal(&self) -> Result<Message> {
+        let role = match self.role {
+            A2ARole::User => "user",
+            A2ARole::Agent => "agent",
+        };
+        Ok(Message {
+            role: role.to_string(),
+            parts: parts_to_internal(&self.parts)?,
+        })
+    }
+}
+
+fn routing_metadata(task: &Task) -> Map<String, Value> {
+    let mut meta = Map::new();
+    meta.insert("from".to_string(), Value::String(task.from.clone()));
+    meta.insert("to".to_string(), Value::String(task.to.clone()));
+    if !task.reply_inbox.is_plain() {
+        if let Ok(inbox) = serde_json::to_value(task.reply_inbox) {
+            meta.insert("reply_inbox".to_string(), inbox);
+        }
+    }
+    meta
+}
+
+fn metadata_inbox(meta: &Option<Map<String, Value>>) -> InboxScheme {
+    meta.as_ref()
+        .and_then(|m| m.get("reply_inbox"))
+        .and_then(|v| serde_json::from_value(v.clone()).ok())
+        .unwrap_or_default()
+}
+
+fn metadata_str(meta: &Option<Map<String, Value>>, key: &str) -> String {
+    meta.as_ref()
+        .and_then(|m| m.get(key))
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+impl A2ATask {
+    /// Convert an internal task. The request message becomes `history[0]`
+    /// and the result, if any, a single artifact.
+    pub fn from_internal(task: &Task) -> Self {
+        let artifacts = task
+            .result
+            .iter()
+            .map(|result| A2AArtifact {
+                artifact_id: Uuid::new_v4().to_string(),
+                name: Some("result".to_string()),
+                description: None,
+                parts: parts_from_internal(&result.parts),
+                metadata: None,
+            })
+            .collect();
+        Self {
+            id: task.id.clone(),
+            context_id: task.id.clone(),
+            status: A2ATaskStatus {
+                state: (&task.state).into(),
+                message: None,
+                timestamp: None,
+            },
+            history: vec![A2AMessage::from_internal(&task.message, Some(&task.id))],
+            artifacts,
+            metadata: Some(routing_metadata(task)),
+            kind: TaskKind::Kind,
+        }
+    }
+
+    /// Convert to an internal task. The request is the first user message in
+    /// `history`; the result is taken from the artifacts, the status message,
+    /// or the last agent message, in that order.
+    pub fn to_internal(&self) -> Result<Task> {
+        let message = match self.history.iter().find(|m| m.role == A2ARole::User) {
+            Some(m) => m.to_internal()?,
+            None => Message {
+                role: "user".to_string(),
+                parts: Vec::new(),
+            },
+        };
+
+        let result = if !self.artifacts.is_empty() {
+            let mut parts = Vec::new();
+            for artifact in &self.artifacts {
+                parts.extend(parts_to_internal(&artifact.parts)?);
+            }
+            Some(Message {
+                role: "agent".to_string(),
+                parts,
+            })
+        } else if let Some(m) = &self.status.message {
+            Some(m.to_internal()?)
+        } else {
+            self.history
+                .iter()
+                .rev()
+                .find(|m| m.role == A2ARole::Agent)
+                .map(A2AMessage::to_internal)
+                .transpose()?
+        };
+
+        Ok(Task {
+            id: self.id.clone(),
+            from: metadata_str(&self.metadata, "from"),
+            to: metadata_str(&self.metadata, "to"),
+            state: self.status.state.to_internal()?,
+            message,
+            result,
+            reply_inbox: metadata_inbox(&self.metadata),
+        })
+    }
+}
+
+impl TaskStatusUpdateEvent {
+    pub fn from_internal(update: &TaskStatusUpdate) -> Self {
+        let mut meta = Map::new();
+        meta.insert("seq".to_string(), Value::from(update.seq));
+        Self {
+            task_id: update.task_id.clone(),
+            context_id: update.task_id.clone(),
+            kind: StatusUpdateKind::Kind,
+            status: A2ATaskStatus {
+                state: (&update.state).into(),
+                message: update
+                    .message
+                    .as_ref()
+                    .map(|m| A2AMessage::from_internal(m, Some(&update.task_id))),
+                timestamp: None,
+            },
+            is_final: update.is_final,
+            metadata: Some(meta),
+        }
+    }
+
+    /// Convert to an internal update. Events without a `seq` in their
+    /// metadata (e.g. from a standard A2A server) get seq 0.
+    pub fn to_internal(&self) -> Result<TaskStatusUpdate> {
+        let seq = self
+            .metadata
+            .as_ref()
+            .and_then(|m| m.get("seq"))
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0);
+        Ok(TaskStatusUpdate {
+            task_id: self.task_id.clone(),
+            seq,
+            state: self.status.state.to_internal()?,
+            message: self
+                .status
+                .message
+                .as_ref()
+                .map(A2AMessage::to_internal)
+                .transpose()?,
+            is_final: self.is_final,
+        })
+    }
+}
+
+impl JsonRpcMessage {
+    /// JSON-RPC form of an envelope, if it has one.
+    ///
+    /// Status events get a `{task_id}/{seq}` id rather than the id of the
+    /// originating request: SDS deduplicates on the top-level `id`, so events
+    /// of one task must not share it.
+    pub fn from_envelope(envelope: &A2AEnvelope) -> Option<Self> {
+        match envelope {
+            A2AEnvelope::Task(task) if task.result.is_none() => {
+                let mut message = A2AMessage::from_internal(&task.message, Some(&task.id));
+                message.role = A2ARole::User;
+                Some(JsonRpcMessage::Request(JsonRpcRequest::new(
+                    JsonRpcId::String(task.id.clone()),
+                    A2ARequest::SendMessage(MessageSendParams {
+                        message,
+                        configuration: None,
+                        metadata: Some(routing_metadata(task)),
+                    }),
+                )))
+            }
+            A2AEnvelope::Task(task) => Some(JsonRpcMessage::Response(JsonRpcResponse::result(
+                JsonRpcId::String(task.id.clone()),
+                A2AResult::Task(A2ATask::from_internal(task)),
+            ))),
+            A2AEnvelope::TaskStatus(update) => {
+                Some(JsonRpcMessage::Response(JsonRpcResponse::result(
+                    JsonRpcId::String(format!("{}/{}", update.task_id, update.seq)),
+                    A2AResult::StatusUpdate(TaskStatusUpdateEvent::from_internal(update)),
+                )))
+            }
+            _ => None,
+        }
+    }
+
+    /// Internal envelope for this message. Fails for methods and results
+    /// without an internal equivalent and for error responses.
+    ///
+    /// A task result without any result message, e.g. a `working`
+    /// acknowledgement, becomes a status update: as a task envelope it would
+    /// read as a new request.
+    pub fn to_envelope(&self) -> Result<A2AEnvelope> {
+        match self {
+            JsonRpcMessage::Request(req) => match &req.call {
+                A2ARequest::SendMessage(params) | A2ARequest::StreamMessage(params) => {
+                    let message = params.message.to_internal()?;
+                    Ok(A2AEnvelope::Task(Task {
+                        id: params
+                            .message
+                            .task_id
+                            .clone()
+                            .unwrap_or_else(|| params.message.message_id.clone()),
+                        from: metadata_str(&params.metadata, "from"),
+                        to: metadata_str(&params.metadata, "to"),
+                        state: TaskState::Submitted,
+                        message,
+                        result: None,
+                        reply_inbox: metadata_inbox(&params.metadata),
+                    }))
+                }
+                A2ARequest::GetTask(_) => bail!("tasks/get has no envelope equivalent"),
+                A2ARequest::CancelTask(_) => bail!("tasks/cancel has no envelope equivalent"),
+            },
+            JsonRpcMessage::Response(resp) => match &resp.outcome {
+                JsonRpcOutcome::Error(e) => {
+                    bail!("JSON-RPC error {}: {}", e.code, e.message)
+                }
+                JsonRpcOutcome::Result(result) => match result.as_ref() {
+                    A2AResult::Task(task) => {
+                        let task = task.to_internal()?;
+                        if task.result.is_some() {
+                            return Ok(A2AEnvelope::Task(task));
+                        }
+                        Ok(A2AEnvelope::TaskStatus(TaskStatusUpdate {
+                            task_id: task.id,
+                            seq: 0,
+                            is_final: matches!(
+                                task.state,
+                                TaskState::Completed | TaskState::Failed | TaskState::Cancelled
+                            ),
+                            state: task.state,
+                            message: None,
+                        }))
+                    }
+                    A2AResult::Message(message) => {
+                        // Direct reply without a task: treat as a completed task
+                        let id = message
+                            .task_id
+                            .clone()
+                            .unwrap_or_else(|| message.message_id.clone());
+                        Ok(A2AEnvelope::Task(Task {
+                            id,
+                            from: metadata_str(&message.metadata, "from"),
+                            to: metadata_str(&message.metadata, "to"),
+                            state: TaskState::Completed,
+                            message: Message {
+                                role: "user".to_string(),
+                                parts: Vec::new(),
+                            },
+                            result: Some(message.to_internal()?),
+                            reply_inbox: metadata_inbox(&message.metadata),
+                        }))
+                    }
+                    A2AResult::StatusUpdate(event) => {
+                        Ok(A2AEnvelope::TaskStatus(event.to_internal()?))
+                    }
+                    A2AResult::ArtifactUpdate(_) => {
+                        bail!("artifact-update events have no envelope equivalent")
+                    }
+                },
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PROTOCOL_VERSION;
+
+    // Example payloads from the A2A specification.
+
+    const SEND_REQUEST: &str = r#"{
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "message/send",
+        "params": {
+            "message": {
+                "role": "user",
+                "parts": [{"kind": "text", "text": "tell me a joke"}],
+                "messageId": "9229e770-767c-417b-a0b0-f0741243c589"
+            },
+            "metadata": {}
+        }
+    }"#;
+
+    const SEND_RESPONSE: &str = r#"{
+        "jsonrpc": "2.0",
+        "id": 1,
+        "result": {
+            "id": "363422be-b0f9-4692-a24d-278670e7c7f1",
+            "contextId": "c295ea44-7543-4f78-b524-7a38915ad6e4",
+            "status": {"state": "completed"},
+            "artifacts": [{
+                "artifactId": "9b6934dd-37e3-4eb1-8766-962efaab63a1",
+                "name": "joke",
+                "parts": [{"kind": "text", "text": "Why did the chicken cross the road? To get to the other side!"}]
+            }],
+            "history": [{
+                "role": "user",
+                "parts": [{"kind": "text", "text": "tell me a joke"}],
+                "messageId": "9229e770-767c-417b-a0b0-f0741243c589",
+                "taskId": "363422be-b0f9-4692-a24d-278670e7c7f1",
+                "contextId": "c295ea44-7543-4f78-b524-7a38915ad6e4"
+            }],
+            "kind": "task",
+            "metadata": {}
+        }
+    }"#;
+
+    const GET_REQUEST: &str = r#"{
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "tasks/get",
+        "params": {"id": "363422be-b0f9-4692-a24d-278670e7c7f1", "historyLength": 10}
+    }"#;
+
+    const CANCEL_REQUEST: &str = r#"{
+        "jsonrpc": "2.0",
+        "id": 2,
+        "method": "tasks/cancel",
+        "params": {"id": "363422be-b0f9-4692-a24d-278670e7c7f1"}
+    }"#;
+
+    const STATUS_EVENT: &str = r#"{
+        "jsonrpc": "2.0",
+        "id": 1,
+        "result": {
+            "taskId": "225d6247-06ba-4cda-a08b-33ae35c8dcfa",
+            "contextId": "05217e44-7e9f-473e-ab4f-2c2dde50a2b1",
+            "kind": "status-update",
+            "status": {"state": "completed", "timestamp": "2025-04-02T16:59:35.331844"},
+            "final": true
+        }
+    }"#;
+
+    const ARTIFACT_EVENT: &str = r#"{
+        "jsonrpc": "2.0",
+        "id": 1,
+        "result": {
+            "taskId": "225d6247-06ba-4cda-a08b-33ae35c8dcfa",
+            "contextId": "05217e44-7e9f-473e-ab4f-2c2dde50a2b1",
+            "kind": "artifact-update",
+            "artifact": {
+                "artifactId": "9b6934dd-37e3-4eb1-8766-962efaab63a1",
+                "parts": [{"kind": "text", "text": "<section 1...>"}]
+            },
+            "append": false,
+            "lastChunk": false
+        }
+    }"#;
+
+    const WORKING_RESPONSE: &str = r#"{
+        "jsonrpc": "2.0",
+        "id": 1,
+        "result": {
+            "id": "363422be-b0f9-4692-a24d-278670e7c7f1",
+            "contextId": "c295ea44-7543-4f78-b524-7a38915ad6e4",
+            "status": {"state": "working"},
+            "history": [{
+                "role": "user",
+                "parts": [{"kind": "text", "text": "tell me a joke"}],
+                "messageId": "9229e770-767c-417b-a0b0-f0741243c589",
+                "kind": "message"
+            }],
+            "kind": "task"
+        }
+    }"#;
+
+    const ERROR_RESPONSE: &str = r#"{
+        "jsonrpc": "2.0",
+        "id": 2,
+        "error": {"code": -32002, "message": "Task cannot be canceled"}
+    }"#;
+
+    fn parse(json: &str) -> JsonRpcMessage {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_spec_send_request() {
+        let JsonRpcMessage::Request(req) = parse(SEND_REQUEST) else {
+            panic!("expected request");
+        };
+        assert_eq!(req.id, JsonRpcId::Number(1));
+        let A2ARequest::SendMessage(params) = &req.call else {
+            panic!("expected message/send");
+        };
+        assert_eq!(params.message.role, A2ARole::User);
+        assert_eq!(params.message.parts, vec![A2APart::text("tell me a joke")]);
+
+        let A2AEnvelope::Task(task) = A2AEnvelope::decode(SEND_REQUEST.as_bytes()).unwrap() else {
+            panic!("expected task envelope");
+        };
+        assert_eq!(task.id, "9229e770-767c-417b-a0b0-f0741243c589");
+        assert_eq!(task.state, TaskState::Submitted);
+        assert_eq!(task.text(), Some("tell me a joke"));
+    }
+
+    #[test]
+    fn test_spec_send_response() {
+        let msg = parse(SEND_RESPONSE);
+        let JsonRpcMessage::Response(resp) = &msg else {
+            panic!("expected response");
+        };
+        let Some(A2AResult::Task(a2a_task)) = resp.as_result() else {
+            panic!("expected task result");
+        };
+        assert_eq!(a2a_task.context_id, "c295ea44-7543-4f78-b524-7a38915ad6e4");
+        assert_eq!(a2a_task.status.state, A2ATaskState::Completed);
+
+        let A2AEnvelope::Task(task) = msg.to_envelope().unwrap() else {
+            panic!("expected task envelope");
+        };
+        assert_eq!(task.text(), Some("tell me a joke"));
+        assert_eq!(
+            task.result_text(),
+            Some("Why did the chicken cross the road? To get to the other side!")
+        );
+        assert_eq!(task.state, TaskState::Completed);
+    }
+
+    #[test]
+    fn test_spec_working_response_is_not_a_request() {
+        let msg = parse(WORKING_RESPONSE);
+        let A2AEnvelope::TaskStatus(update) = msg.to_envelope().unwrap() else {
+            panic!("expected task status envelope");
+        };
+        assert_eq!(update.task_id, "363422be-b0f9-4692-a24d-278670e7c7f1");
+        assert_eq!(update.state, TaskState::Working);
+        assert!(update.message.is_none());
+        assert!(!update.is_final);
+    }
+
+    #[test]
+    fn test_spec_tasks_get_and_cancel() {
+        let JsonRpcMessage::Request(req) = parse(GET_REQUEST) else {
+            panic!("expected request");
+        };
+        assert_eq!(
+            req.call,
+            A2ARequest::GetTask(TaskQueryParams {
+                id: "363422be-b0f9-4692-a24d-278670e7c7f1".to_string(),
+                history_length: Some(10),
+                metadata: None,
+            })
+        );
+        let roundtrip = serde_json::to_value(&req).unwrap();
+        assert_eq!(
+            roundtrip,
+            serde_json::from_str::<Value>(GET_REQUEST).unwrap()
+        );
+
+        let msg = parse(CANCEL_REQUEST);
+        assert!(matches!(
+            &msg,
+            JsonRpcMessage::Request(JsonRpcRequest { call: A2ARequest::CancelTask(p), .. })
+                if p.id == "363422be-b0f9-4692-a24d-278670e7c7f1"
+        ));
+        assert!(msg.to_envelope().is_err());
+    }
+
+    #[test]
+    fn test_spec_stream_events() {
+        let msg = parse(STATUS_EVENT);
+        let roundtrip = serde_json::to_value(&msg).unwrap();
+        assert_eq!(
+            roundtrip,
+            serde_json::from_str::<Value>(STATUS_EVENT).unwrap()
+        );
+        let A2AEnvelope::TaskStatus(update) = msg.to_envelope().unwrap() else {
+            panic!("expected task status envelope");
+        };
+        assert_eq!(update.task_id, "225d6247-06ba-4cda-a08b-33ae35c8dcfa");
+        assert_eq!(update.state, TaskState::Completed);
+        assert!(update.is_final);
+
+        let msg = parse(ARTIFACT_EVENT);
+        let roundtrip = serde_json::to_value(&msg).unwrap();
+        assert_eq!(
+            roundtrip,
+            serde_json::from_str::<Value>(ARTIFACT_EVENT).unwrap()
+        );
+        let JsonRpcMessage::Response(resp) = &msg else {
+            panic!("expected response");
+        };
+        assert!(matches!(
+            resp.as_result(),
+            Some(A2AResult::ArtifactUpdate(_))
+        ));
+    }
+
+    #[test]
+    fn test_spec_error_response() {
+        let msg = parse(ERROR_RESPONSE);
+        let roundtrip = serde_json::to_value(&msg).unwrap();
+        assert_eq!(
+            roundtrip,
+            serde_json::from_str::<Value>(ERROR_RESPONSE).unwrap()
+        );
+        let JsonRpcMessage::Response(resp) = &msg else {
+            panic!("expected response");
+        };
+        assert!(matches!(
+            &resp.outcome,
+            JsonRpcOutcome::Error(e) if e.code == error_codes::TASK_NOT_CANCELABLE
+        ));
+        assert!(msg.to_envelope().is_err());
+    }
+
+    #[test]
+    fn test_task_state_mapping() {
+        assert_eq!(
+            serde_json::to_string(&A2ATaskState::from(&TaskState::Cancelled)).unwrap(),
+            "\"canceled\""
+        );
+        assert_eq!(
+            serde_json::to_string(&A2ATaskState::from(&TaskState::InputRequired)).unwrap(),
+            "\"input-required\""
+        );
+        assert_eq!(
+            A2ATaskState::Rejected.to_internal().unwrap(),
+            TaskState::Failed
+        );
+        assert!(A2ATaskState::Unknown.to_internal().is_err());
+    }
+
+    #[test]
+    fn test_envelope_roundtrip_through_jsonrpc() {
+        let task = Task::new("02aa", "03bb", "hello");
+        let response = task.respond("hi there");
+        let update = TaskStatusUpdate::new(&task.id, 4, TaskState::Working, Some("50%"), false);
+        let mut hidden = Task::new("02aa", "03bb", "reply privately");
+        hidden.reply_inbox = InboxScheme::Rotating { epoch_secs: 600 };
+
+        for envelope in [
+            A2AEnvelope::Task(task.clone()),
+            A2AEnvelope::Task(response),
+            A2AEnvelope::Task(hidden.respond("done")),
+            A2AEnvelope::Task(hidden),
+            A2AEnvelope::TaskStatus(update),
+        ] {
+            let bytes = JsonRpcCodec.encode(&envelope, PROTOCOL_VERSION).unwrap();
+            let value: Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(value["jsonrpc"], "2.0");
+            assert_eq!(A2AEnvelope::decode(&bytes).unwrap(), envelope);
+        }
+
+        let request: Value = serde_json::from_slice(
+            &JsonRpcCodec
+                .encode(&A2AEnvelope::Task(task), PROTOCOL_VERSION)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(request["method"], "message/send");
+        assert_eq!(request["params"]["message"]["kind"], "message");
+        assert!(request["params"]["message"]["messageId"].is_string());
+    }
+
+    #[test]
+    fn test_native_fallback() {
+        let ack = A2AEnvelope::Ack {
+            message_id: "m1".to_string(),
+        };
+        let bytes = JsonRpcCodec.encode(&ack, PROTOCOL_VERSION).unwrap();
+        assert_eq!(bytes, ack.encode().unwrap());
+        assert_eq!(A2AEnvelope::decode(&bytes).unwrap(), ack);
+    }
+}
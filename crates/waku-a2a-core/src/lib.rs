@@ -1,7 +1,27 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use topics::InboxScheme;
 use uuid::Uuid;
 use waku_a2a_crypto::{EncryptedPayload, IntroBundle};
 
+pub mod codec;
+pub mod compression;
+pub mod fragment;
+pub mod jsonrpc;
+pub mod topics;
+
+/// Wire protocol version written as `v` into every envelope this build sends.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version this build still decodes. Envelopes without a `v`
+/// predate versioning and count as version 1.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Protocol versions this build speaks, as advertised on its `AgentCard`.
+pub fn supported_protocol_versions() -> Vec<u32> {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).collect()
+}
+
 /// Agent identity and capability advertisement.
 /// Equivalent to A2A's AgentCard — broadcast on the discovery topic.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -15,6 +35,58 @@ pub struct AgentCard {
     /// X25519 intro bundle for encrypted sessions (None = no encryption)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub intro_bundle: Option<IntroBundle>,
+    /// Typed skill descriptions (A2A `AgentSkill`). Absent on older cards.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skills: Vec<AgentSkill>,
+    /// Wire protocol versions this agent can decode. Empty on cards that
+    /// predate versioning, which speak version 1 only.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub protocol_versions: Vec<u32>,
+    /// Envelope codecs this agent can decode (see `codec`). Empty on older
+    /// cards, which only understand native JSON.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub codecs: Vec<String>,
+    /// Compression algorithms this agent can decompress (see `compression`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub compression: Vec<String>,
+    /// How the agent's task inbox topic is derived (see `topics`).
+    #[serde(default, skip_serializing_if = "InboxScheme::is_plain")]
+    pub inbox: InboxScheme,
+}
+
+/// A unit of functionality an agent offers (A2A `AgentSkill`).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AgentSkill {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Example prompts or requests this skill handles.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<String>,
+    /// Accepted input MIME types, e.g. "text/plain" (empty = unspecified).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub input_modes: Vec<String>,
+    /// Produced output MIME types (empty = unspecified).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub output_modes: Vec<String>,
+    /// JSON Schema describing the expected input.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_schema: Option<serde_json::Value>,
+    /// JSON Schema describing the produced output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_schema: Option<serde_json::Value>,
+}
+
+/// Criteria for selecting agents by skill. Unset fields match anything;
+/// a card matches if at least one of its skills matches every set field.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SkillFilter {
+    pub id: Option<String>,
+    pub tag: Option<String>,
+    pub input_mode: Option<String>,
+    pub output_mode: Option<String>,
 }
 
 /// Task lifecycle states (A2A spec).
@@ -53,6 +125,28 @@ pub struct Task {
     pub message: Message,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub result: Option<Message>,
+    /// Inbox scheme the sender listens on, so replies reach it without a
+    /// prior card lookup.
+    #[serde(default, skip_serializing_if = "InboxScheme::is_plain")]
+    pub reply_inbox: InboxScheme,
+}
+
+/// Incremental task status event (A2A `TaskStatusUpdateEvent`).
+///
+/// Published by the agent working on a task to the requester's task topic.
+/// `seq` increases by one per update of the same task so the requester can
+/// restore ordering and notice lost updates.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TaskStatusUpdate {
+    pub task_id: String,
+    pub seq: u64,
+    pub state: TaskState,
+    /// Optional progress message (e.g. "searching...", partial output)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<Message>,
+    /// True for the last update of the task; no further updates follow.
+    #[serde(rename = "final")]
+    pub is_final: bool,
 }
 
 /// Wire envelope for all messages on Waku topics.
@@ -65,7 +159,233 @@ pub enum A2AEnvelope {
     EncryptedTask {
         encrypted: EncryptedPayload,
         sender_pubkey: String,
+        /// Set if the task JSON was compressed before encryption.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compression: Option<compression::Compression>,
     },
+    TaskStatus(TaskStatusUpdate),
+    /// `TaskStatus` for a task that arrived encrypted, encrypted the same way.
+    EncryptedStatus {
+        encrypted: EncryptedPayload,
+        sender_pubkey: String,
+    },
+    /// Piece of an envelope too large for a single Waku message.
+    Fragment(fragment::Fragment),
+    /// Another encoded envelope, compressed. `data` is base64.
+    Compressed {
+        compression: compression::Compression,
+        data: String,
+    },
+    /// Envelope of a type this build doesn't know, e.g. from a peer running a
+    /// newer protocol version. Keeps the raw JSON for logging or forwarding.
+    #[serde(untagged)]
+    Unknown(serde_json::Value),
+}
+
+/// Why a payload could not be decoded into an envelope.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnvelopeError {
+    /// Not valid JSON, or a known envelope type with invalid fields.
+    Malformed(String),
+    /// Sent with a protocol version older than `MIN_PROTOCOL_VERSION`.
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeError::Malformed(e) => write!(f, "malformed envelope: {}", e),
+            EnvelopeError::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported protocol version {} (supported {}..={})",
+                v, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+impl AgentCard {
+    /// Protocol versions this agent speaks.
+    pub fn supported_versions(&self) -> Vec<u32> {
+        if self.protocol_versions.is_empty() {
+            vec![1]
+        } else {
+            self.protocol_versions.clone()
+        }
+    }
+
+    /// Highest protocol version spoken by both this build and the agent.
+    pub fn negotiate_version(&self) -> Option<u32> {
+        self.supported_versions()
+            .into_iter()
+            .filter(|v| (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(v))
+            .max()
+    }
+
+    /// True if the agent can decode envelopes in the given codec.
+    pub fn supports_codec(&self, id: &str) -> bool {
+        if self.codecs.is_empty() {
+            id == "json"
+        } else {
+            self.codecs.iter().any(|c| c == id)
+        }
+    }
+
+    /// True if the agent accepts payloads compressed with `compression`.
+    pub fn supports_compression(&self, compression: compression::Compression) -> bool {
+        self.compression.iter().any(|c| c == compression.id())
+    }
+
+    /// Look up a skill by ID.
+    pub fn skill(&self, id: &str) -> Option<&AgentSkill> {
+        self.skills.iter().find(|s| s.id == id)
+    }
+
+    /// True if any skill on this card satisfies the filter.
+    pub fn matches_skill(&self, filter: &SkillFilter) -> bool {
+        self.skills.iter().any(|s| filter.matches(s))
+    }
+}
+
+impl AgentSkill {
+    pub fn new(id: &str, name: &str, description: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            name: name.to_string(),
+            description: description.to_string(),
+            tags: Vec::new(),
+            examples: Vec::new(),
+            input_modes: Vec::new(),
+            output_modes: Vec::new(),
+            input_schema: None,
+            output_schema: None,
+        }
+    }
+
+    /// True if this skill accepts the given input MIME type.
+    /// A skill without declared input modes accepts anything.
+    pub fn accepts(&self, mime: &str) -> bool {
+        self.input_modes.is_empty() || self.input_modes.iter().any(|m| m == mime)
+    }
+
+    /// True if this skill can produce the given output MIME type.
+    /// A skill without declared output modes may produce anything.
+    pub fn produces(&self, mime: &str) -> bool {
+        self.output_modes.is_empty() || self.output_modes.iter().any(|m| m == mime)
+    }
+}
+
+impl SkillFilter {
+    pub fn id(id: &str) -> Self {
+        Self {
+            id: Some(id.to_string()),
+            ..Self::default()
+        }
+    }
+
+    pub fn tag(tag: &str) -> Self {
+        Self {
+            tag: Some(tag.to_string()),
+            ..Self::default()
+        }
+    }
+
+    pub fn matches(&self, skill: &AgentSkill) -> bool {
+        self.id.as_ref().is_none_or(|id| &skill.id == id)
+            && self.tag.as_ref().is_none_or(|tag| skill.tags.contains(tag))
+            && self.input_mode.as_ref().is_none_or(|m| skill.accepts(m))
+            && self.output_mode.as_ref().is_none_or(|m| skill.produces(m))
+    }
+}
+
+impl A2AEnvelope {
+    /// Wire `type` tags of all known variants.
+    const KNOWN_TYPES: &'static [&'static str] = &[
+        "agent_card",
+        "task",
+        "ack",
+        "encrypted_task",
+        "task_status",
+        "encrypted_status",
+        "fragment",
+        "compressed",
+    ];
+
+    /// The `type` tag of this envelope, if it has one.
+    pub fn type_name(&self) -> Option<String> {
+        let value = match self {
+            A2AEnvelope::Unknown(raw) => raw.clone(),
+            known => serde_json::to_value(known).ok()?,
+        };
+        value.get("type")?.as_str().map(str::to_string)
+    }
+
+    /// Serialize with the current protocol version.
+    pub fn encode(&self) -> serde_json::Result<Vec<u8>> {
+        self.encode_versioned(PROTOCOL_VERSION)
+    }
+
+    /// Serialize with an explicit protocol version (e.g. negotiated with the
+    /// recipient). `Unknown` envelopes are re-emitted unchanged.
+    pub fn encode_versioned(&self, version: u32) -> serde_json::Result<Vec<u8>> {
+        let mut value = serde_json::to_value(self)?;
+        if let (false, Some(obj)) = (
+            matches!(self, A2AEnvelope::Unknown(_)),
+            value.as_object_mut(),
+        ) {
+            obj.insert("v".to_string(), version.into());
+        }
+        serde_json::to_vec(&value)
+    }
+
+    /// Decode a payload in any supported codec (see `codec::decode`).
+    /// Unknown envelope types decode to `Unknown` rather than failing.
+    pub fn decode(payload: &[u8]) -> Result<Self, EnvelopeError> {
+        codec::decode(payload)
+    }
+
+    /// Decode a JSON value in native or A2A JSON-RPC form (detected by a
+    /// top-level `jsonrpc` member).
+    pub fn from_value(value: serde_json::Value) -> Result<Self, EnvelopeError> {
+        if value.get("jsonrpc").is_some() {
+            let msg: jsonrpc::JsonRpcMessage = serde_json::from_value(value)
+                .map_err(|e| EnvelopeError::Malformed(e.to_string()))?;
+            return msg
+                .to_envelope()
+                .map_err(|e| EnvelopeError::Malformed(e.to_string()));
+        }
+
+        let version = Self::wire_version(&value);
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(EnvelopeError::UnsupportedVersion(version));
+        }
+
+        let envelope: A2AEnvelope =
+            serde_json::from_value(value).map_err(|e| EnvelopeError::Malformed(e.to_string()))?;
+        // Untagged fallback also catches known types with bad fields
+        if let A2AEnvelope::Unknown(raw) = &envelope {
+            if let Some(t) = raw.get("type").and_then(|t| t.as_str()) {
+                if Self::KNOWN_TYPES.contains(&t) {
+                    return Err(EnvelopeError::Malformed(format!(
+                        "invalid '{}' envelope",
+                        t
+                    )));
+                }
+            }
+        }
+        Ok(envelope)
+    }
+
+    /// Protocol version of a raw envelope (`v`, or 1 if absent).
+    pub fn wire_version(value: &serde_json::Value) -> u32 {
+        value
+            .get("v")
+            .and_then(|v| v.as_u64())
+            .map(|v| v.min(u32::MAX as u64) as u32)
+            .unwrap_or(1)
+    }
 }
 
 impl Task {
@@ -82,6 +402,7 @@ impl Task {
                 }],
             },
             result: None,
+            reply_inbox: InboxScheme::Plain,
         }
     }
 
@@ -92,40 +413,61 @@ impl Task {
             to: self.from.clone(),
             state: TaskState::Completed,
             message: self.message.clone(),
-            result: Some(Message {
-                role: "agent".to_string(),
-                parts: vec![Part::Text {
-                    text: text.to_string(),
-                }],
-            }),
+            result: Some(Message::agent_text(text)),
+            reply_inbox: InboxScheme::Plain,
         }
     }
 
     pub fn text(&self) -> Option<&str> {
-        self.message.parts.iter().find_map(|p| match p {
-            Part::Text { text } => Some(text.as_str()),
-        })
+        self.message.text()
     }
 
     pub fn result_text(&self) -> Option<&str> {
-        self.result.as_ref().and_then(|m| {
-            m.parts.iter().find_map(|p| match p {
-                Part::Text { text } => Some(text.as_str()),
-            })
-        })
+        self.result.as_ref().and_then(|m| m.text())
     }
 }
 
-/// Waku content topic helpers.
-pub mod topics {
-    pub const DISCOVERY: &str = "/waku-a2a/1/discovery/proto";
+impl Message {
+    /// Agent-authored text message.
+    pub fn agent_text(text: &str) -> Self {
+        Self {
+            role: "agent".to_string(),
+            parts: vec![Part::Text {
+                text: text.to_string(),
+            }],
+        }
+    }
 
-    pub fn task_topic(recipient_pubkey: &str) -> String {
-        format!("/waku-a2a/1/task/{}/proto", recipient_pubkey)
+    /// First text part, if any.
+    pub fn text(&self) -> Option<&str> {
+        self.parts
+            .iter()
+            .map(|p| match p {
+                Part::Text { text } => text.as_str(),
+            })
+            .next()
     }
+}
 
-    pub fn ack_topic(message_id: &str) -> String {
-        format!("/waku-a2a/1/ack/{}/proto", message_id)
+impl TaskStatusUpdate {
+    pub fn new(
+        task_id: &str,
+        seq: u64,
+        state: TaskState,
+        text: Option<&str>,
+        is_final: bool,
+    ) -> Self {
+        Self {
+            task_id: task_id.to_string(),
+            seq,
+            state,
+            message: text.map(Message::agent_text),
+            is_final,
+        }
+    }
+
+    pub fn text(&self) -> Option<&str> {
+        self.message.as_ref().and_then(|m| m.text())
     }
 }
 
@@ -164,12 +506,26 @@ mod tests {
             capabilities: vec!["text".to_string()],
             public_key: "02abcdef".to_string(),
             intro_bundle: None,
+            skills: vec![],
+            protocol_versions: vec![],
+            codecs: vec![],
+            compression: vec![],
+            inbox: InboxScheme::Plain,
         };
         let json = serde_json::to_string(&card).unwrap();
         let deserialized: AgentCard = serde_json::from_str(&json).unwrap();
         assert_eq!(card, deserialized);
         // intro_bundle should be absent when None
         assert!(!json.contains("intro_bundle"));
+        assert!(!json.contains("inbox"));
+
+        let hidden = AgentCard {
+            inbox: InboxScheme::Hashed,
+            ..card
+        };
+        let json = serde_json::to_string(&hidden).unwrap();
+        assert!(json.contains(r#""inbox":{"mode":"hashed"}"#));
+        assert_eq!(serde_json::from_str::<AgentCard>(&json).unwrap(), hidden);
     }
 
     #[test]
@@ -181,6 +537,11 @@ mod tests {
             capabilities: vec!["text".to_string()],
             public_key: "02abcdef".to_string(),
             intro_bundle: Some(IntroBundle::new("aabbccdd")),
+            skills: vec![],
+            protocol_versions: vec![],
+            codecs: vec![],
+            compression: vec![],
+            inbox: InboxScheme::Plain,
         };
         let json = serde_json::to_string(&card).unwrap();
         let deserialized: AgentCard = serde_json::from_str(&json).unwrap();
@@ -188,6 +549,61 @@ mod tests {
         assert!(json.contains("intro_bundle"));
     }
 
+    #[test]
+    fn test_agent_card_with_skills() {
+        let mut skill = AgentSkill::new("summarize", "Summarize", "Summarizes documents");
+        skill.tags = vec!["nlp".to_string()];
+        skill.examples = vec!["Summarize this article".to_string()];
+        skill.input_modes = vec!["text/plain".to_string()];
+        skill.output_modes = vec!["application/json".to_string()];
+        skill.input_schema = Some(serde_json::json!({"type": "string"}));
+        let card = AgentCard {
+            name: "writer".to_string(),
+            description: "Writes things".to_string(),
+            version: "0.1.0".to_string(),
+            capabilities: vec!["text".to_string()],
+            public_key: "02abcdef".to_string(),
+            intro_bundle: None,
+            skills: vec![skill],
+            protocol_versions: vec![],
+            codecs: vec![],
+            compression: vec![],
+            inbox: InboxScheme::Plain,
+        };
+        let json = serde_json::to_string(&card).unwrap();
+        assert!(json.contains("\"input_modes\":[\"text/plain\"]"));
+        assert!(!json.contains("output_schema"));
+        let deserialized: AgentCard = serde_json::from_str(&json).unwrap();
+        assert_eq!(card, deserialized);
+        assert_eq!(card.skill("summarize").unwrap().name, "Summarize");
+        assert!(card.skill("translate").is_none());
+    }
+
+    #[test]
+    fn test_skill_filter() {
+        let mut skill = AgentSkill::new("summarize", "Summarize", "Summarizes documents");
+        skill.tags = vec!["nlp".to_string()];
+        skill.input_modes = vec!["text/plain".to_string()];
+
+        assert!(SkillFilter::default().matches(&skill));
+        assert!(SkillFilter::id("summarize").matches(&skill));
+        assert!(!SkillFilter::id("translate").matches(&skill));
+        assert!(SkillFilter::tag("nlp").matches(&skill));
+        assert!(!SkillFilter::tag("vision").matches(&skill));
+
+        let filter = SkillFilter {
+            input_mode: Some("image/png".to_string()),
+            ..SkillFilter::tag("nlp")
+        };
+        assert!(!filter.matches(&skill));
+        // No declared output modes = unconstrained
+        let filter = SkillFilter {
+            output_mode: Some("text/plain".to_string()),
+            ..SkillFilter::default()
+        };
+        assert!(filter.matches(&skill));
+    }
+
     #[test]
     fn test_envelope_serialization() {
         let task = Task::new("02aa", "03bb", "test");
@@ -213,6 +629,7 @@ mod tests {
                 ciphertext: "Y2lwaGVydGV4dA==".to_string(),
             },
             sender_pubkey: "aabbccdd".to_string(),
+            compression: None,
         };
         let json = serde_json::to_string(&envelope).unwrap();
         let deserialized: A2AEnvelope = serde_json::from_str(&json).unwrap();
@@ -220,6 +637,24 @@ mod tests {
         assert!(json.contains("encrypted_task"));
     }
 
+    #[test]
+    fn test_task_status_envelope_serialization() {
+        let update =
+            TaskStatusUpdate::new("task-1", 3, TaskState::Working, Some("thinking"), false);
+        let envelope = A2AEnvelope::TaskStatus(update.clone());
+        let json = serde_json::to_string(&envelope).unwrap();
+        assert!(json.contains("\"type\":\"task_status\""));
+        assert!(json.contains("\"final\":false"));
+        let deserialized: A2AEnvelope = serde_json::from_str(&json).unwrap();
+        assert_eq!(envelope, deserialized);
+        assert_eq!(update.text(), Some("thinking"));
+
+        let done = TaskStatusUpdate::new("task-1", 4, TaskState::Completed, None, true);
+        let json = serde_json::to_string(&done).unwrap();
+        assert!(!json.contains("message"));
+        assert!(json.contains("\"final\":true"));
+    }
+
     #[test]
     fn test_task_state_serialization() {
         let states = vec![
@@ -255,5 +690,133 @@ mod tests {
         let card: AgentCard = serde_json::from_str(json).unwrap();
         assert_eq!(card.name, "echo");
         assert!(card.intro_bundle.is_none());
+        assert!(card.skills.is_empty());
+        assert!(!card.matches_skill(&SkillFilter::default()));
+        assert_eq!(card.supported_versions(), vec![1]);
+        assert_eq!(card.negotiate_version(), Some(1));
+    }
+
+    #[test]
+    fn test_envelope_versioning() {
+        let envelope = A2AEnvelope::Task(Task::new("02aa", "03bb", "test"));
+        let bytes = envelope.encode().unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(value["v"], PROTOCOL_VERSION);
+        assert_eq!(A2AEnvelope::decode(&bytes).unwrap(), envelope);
+
+        // Pre-versioning payloads (no `v`) are version 1
+        let legacy = serde_json::to_vec(&envelope).unwrap();
+        assert_eq!(A2AEnvelope::decode(&legacy).unwrap(), envelope);
+
+        let ancient = br#"{"v":0,"type":"ack","message_id":"m"}"#;
+        assert_eq!(
+            A2AEnvelope::decode(ancient),
+            Err(EnvelopeError::UnsupportedVersion(0))
+        );
+    }
+
+    #[test]
+    fn test_unknown_envelope_preserved() {
+        let json = br#"{"v":7,"type":"task_cancel","task_id":"t1","reason":"timeout"}"#;
+        let envelope = A2AEnvelope::decode(json).unwrap();
+        let A2AEnvelope::Unknown(ref raw) = envelope else {
+            panic!("expected Unknown envelope");
+        };
+        assert_eq!(raw["reason"], "timeout");
+        assert_eq!(envelope.type_name().as_deref(), Some("task_cancel"));
+        // Forwarding re-emits the original JSON untouched
+        let reencoded: serde_json::Value =
+            serde_json::from_slice(&envelope.encode().unwrap()).unwrap();
+        assert_eq!(&reencoded, raw);
+
+        // Newer versions of known types still decode
+        let newer = br#"{"v":2,"type":"ack","message_id":"m","batch":["m2"]}"#;
+        assert!(matches!(
+            A2AEnvelope::decode(newer),
+            Ok(A2AEnvelope::Ack { .. })
+        ));
+
+        // A known type with broken fields is malformed, not unknown
+        let broken = br#"{"type":"task","id":42}"#;
+        assert!(matches!(
+            A2AEnvelope::decode(broken),
+            Err(EnvelopeError::Malformed(_))
+        ));
+        assert!(matches!(
+            A2AEnvelope::decode(b"not json"),
+            Err(EnvelopeError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_known_types_cover_all_variants() {
+        let task = Task::new("02aa", "03bb", "test");
+        let samples = vec![
+            A2AEnvelope::AgentCard(AgentCard {
+                name: "a".to_string(),
+                description: String::new(),
+                version: "0.1.0".to_string(),
+                capabilities: vec![],
+                public_key: "02aa".to_string(),
+                intro_bundle: None,
+                skills: vec![],
+                protocol_versions: vec![],
+                codecs: vec![],
+                compression: vec![],
+                inbox: InboxScheme::Plain,
+            }),
+            A2AEnvelope::TaskStatus(TaskStatusUpdate::new(
+                &task.id,
+                0,
+                TaskState::Working,
+                None,
+                false,
+            )),
+            A2AEnvelope::Task(task),
+            A2AEnvelope::Ack {
+                message_id: "m".to_string(),
+            },
+            A2AEnvelope::EncryptedTask {
+                encrypted: EncryptedPayload {
+                    nonce: String::new(),
+                    ciphertext: String::new(),
+                },
+                sender_pubkey: String::new(),
+                compression: None,
+            },
+            A2AEnvelope::EncryptedStatus {
+                encrypted: EncryptedPayload {
+                    nonce: String::new(),
+                    ciphertext: String::new(),
+                },
+                sender_pubkey: String::new(),
+            },
+            A2AEnvelope::Fragment(fragment::split(b"data", "m").remove(0)),
+            A2AEnvelope::Compressed {
+                compression: compression::Compression::Zstd,
+                data: String::new(),
+            },
+        ];
+        assert_eq!(samples.len(), A2AEnvelope::KNOWN_TYPES.len());
+        for envelope in samples {
+            let name = envelope.type_name().unwrap();
+            assert!(
+                A2AEnvelope::KNOWN_TYPES.contains(&name.as_str()),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_version_negotiation() {
+        let mut card: AgentCard = serde_json::from_str(
+            r#"{"name":"a","description":"","version":"0.1.0","capabilities":[],"public_key":"02aa"}"#,
+        )
+        .unwrap();
+        card.protocol_versions = vec![PROTOCOL_VERSION, PROTOCOL_VERSION + 1];
+        assert_eq!(card.negotiate_version(), Some(PROTOCOL_VERSION));
+        card.protocol_versions = vec![PROTOCOL_VERSION + 1];
+        assert_eq!(card.negotiate_version(), None);
     }
 }
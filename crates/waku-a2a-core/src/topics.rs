@@ -0,0 +1,416 @@
+//! Waku content topic helpers.
+//!
+//! Topics follow `/{app}/{version}[/{namespace}]/{kind}/.../proto`. The
+//! optional namespace separates fleets (staging, testnet, production) or
+//! tenants sharing one Waku network: nodes only see traffic in their own
+//! namespace.
+//!
+//! Task inboxes are named by the recipient's public key unless the agent
+//! advertises another `InboxScheme`: a hash of the key, optionally rotating
+//! per time epoch. Peers with an established X25519 session can also use a
+//! pairwise inbox derived from their shared secret, which no third party can
+//! link to either agent.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Discovery topic of the default scheme.
+pub const DISCOVERY: &str = "/waku-a2a/1/discovery/proto";
+
+pub const DEFAULT_APP: &str = "waku-a2a";
+pub const DEFAULT_VERSION: u32 = 1;
+
+/// Default epoch length for `InboxScheme::Rotating`.
+pub const DEFAULT_EPOCH_SECS: u64 = 3600;
+
+/// Most epochs of a rotating inbox a time range is expanded to. Longer
+/// ranges keep the newest epochs.
+pub const MAX_LISTEN_EPOCHS: u64 = 256;
+
+/// How an agent's task inbox topic is derived. Advertised on its card.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum InboxScheme {
+    /// `/task/{pubkey}/proto`: readable by every observer.
+    #[default]
+    Plain,
+    /// `/task/{hash(pubkey)}/proto`.
+    Hashed,
+    /// `/task/{hash(pubkey, epoch)}/proto`, changing every `epoch_secs`.
+    Rotating { epoch_secs: u64 },
+    /// Mode from a newer version. Treated as `Plain` when sending.
+    #[serde(other)]
+    Unsupported,
+}
+
+impl InboxScheme {
+    pub fn is_plain(&self) -> bool {
+        matches!(self, InboxScheme::Plain | InboxScheme::Unsupported)
+    }
+
+    /// Epoch number at `unix_secs`, for rotating schemes.
+    pub fn epoch(&self, unix_secs: u64) -> Option<u64> {
+        match self {
+            InboxScheme::Rotating { epoch_secs } => Some(unix_secs / (*epoch_secs).max(1)),
+            _ => None,
+        }
+    }
+
+    /// Epochs a recipient listens on between two times: those in the range
+    /// and one on either side, to tolerate clock skew and messages sent just
+    /// before a rollover. At most `MAX_LISTEN_EPOCHS`, the newest ones.
+    fn listen_epochs(&self, from_secs: u64, to_secs: u64) -> Vec<Option<u64>> {
+        match self.epoch_range(from_secs, to_secs) {
+            Some((first, last)) => (first..=last).map(Some).collect(),
+            None => vec![None],
+        }
+    }
+
+    fn epoch_range(&self, from_secs: u64, to_secs: u64) -> Option<(u64, u64)> {
+        let last = self.epoch(to_secs)?.saturating_add(1);
+        let first = self
+            .epoch(from_secs)?
+            .saturating_sub(1)
+            .max(last.saturating_sub(MAX_LISTEN_EPOCHS - 1));
+        Some((first, last))
+    }
+
+    /// Earliest time the topics between `from_secs` and `to_secs` cover:
+    /// `from_secs` unless the range spans more than `MAX_LISTEN_EPOCHS`.
+    pub fn covered_since(&self, from_secs: u64, to_secs: u64) -> u64 {
+        match (self, self.epoch_range(from_secs, to_secs)) {
+            (InboxScheme::Rotating { epoch_secs }, Some((first, _))) => {
+                from_secs.max((first + 1).saturating_mul((*epoch_secs).max(1)))
+            }
+            _ => from_secs,
+        }
+    }
+}
+
+/// Application name, version and namespace that make up content topics.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopicScheme {
+    app: String,
+    version: u32,
+    namespace: Option<String>,
+}
+
+impl Default for TopicScheme {
+    fn default() -> Self {
+        Self {
+            app: DEFAULT_APP.to_string(),
+            version: DEFAULT_VERSION,
+            namespace: None,
+        }
+    }
+}
+
+impl TopicScheme {
+    pub fn new(app: &str, version: u32) -> Result<Self> {
+        validate_segment("app", app)?;
+        Ok(Self {
+            app: app.to_string(),
+            version,
+            namespace: None,
+        })
+    }
+
+    /// Default app and version in the given namespace.
+    pub fn namespaced(namespace: &str) -> Result<Self> {
+        Self::default().with_namespace(namespace)
+    }
+
+    pub fn with_namespace(mut self, namespace: &str) -> Result<Self> {
+        validate_segment("namespace", namespace)?;
+        self.namespace = Some(namespace.to_string());
+        Ok(self)
+    }
+
+    pub fn app(&self) -> &str {
+        &self.app
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    fn prefix(&self) -> String {
+        match &self.namespace {
+            Some(ns) => format!("/{}/{}/{}", self.app, self.version, ns),
+            None => format!("/{}/{}", self.app, self.version),
+        }
+    }
+
+    /// AgentCard broadcasts.
+    pub fn discovery(&self) -> String {
+        format!("{}/discovery/proto", self.prefix())
+    }
+
+    /// Task inbox of an agent.
+    pub fn task_topic(&self, recipient_pubkey: &str) -> String {
+        format!("{}/task/{}/proto", self.prefix(), recipient_pubkey)
+    }
+
+    /// Task inbox of an agent under its advertised `InboxScheme`, at time
+    /// `unix_secs`.
+    pub fn inbox_topic(
+        &self,
+        recipient_pubkey: &str,
+        inbox: &InboxScheme,
+        unix_secs: u64,
+    ) -> String {
+        if inbox.is_plain() {
+            return self.task_topic(recipient_pubkey);
+        }
+        self.derived_inbox(recipient_pubkey, None, inbox.epoch(unix_secs))
+    }
+
+    /// Topics an agent listens on for its own inbox.
+    pub fn inbox_listen_topics(
+        &self,
+        pubkey: &str,
+        inbox: &InboxScheme,
+        unix_secs: u64,
+    ) -> Vec<String> {
+        self.inbox_topics_between(pubkey, inbox, unix_secs, unix_secs)
+    }
+
+    /// Topics an agent's inbox used at any time between `from_secs` and
+    /// `to_secs`, e.g. to fetch history across epochs.
+    pub fn inbox_topics_between(
+        &self,
+        pubkey: &str,
+        inbox: &InboxScheme,
+        from_secs: u64,
+        to_secs: u64,
+    ) -> Vec<String> {
+        if inbox.is_plain() {
+            return vec![self.task_topic(pubkey)];
+        }
+        inbox
+            .listen_epochs(from_secs, to_secs)
+            .into_iter()
+            .map(|epoch| self.derived_inbox(pubkey, None, epoch))
+            .collect()
+    }
+
+    /// Pairwise inbox of `recipient_pubkey` for messages from one peer,
+    /// derived from a secret only the two of them share.
+    pub fn pair_inbox_topic(
+        &self,
+        pair_secret: &[u8],
+        recipient_pubkey: &str,
+        inbox: &InboxScheme,
+        unix_secs: u64,
+    ) -> String {
+        self.derived_inbox(recipient_pubkey, Some(pair_secret), inbox.epoch(unix_secs))
+    }
+
+    /// Pairwise inbox topics the recipient listens on for one peer.
+    pub fn pair_inbox_listen_topics(
+        &self,
+        pair_secret: &[u8],
+        pubkey: &str,
+        inbox: &InboxScheme,
+        unix_secs: u64,
+    ) -> Vec<String> {
+        self.pair_inbox_topics_between(pair_secret, pubkey, inbox, unix_secs, unix_secs)
+    }
+
+    /// Pairwise inbox topics for one peer between `from_secs` and `to_secs`.
+    pub fn pair_inbox_topics_between(
+        &self,
+        pair_secret: &[u8],
+        pubkey: &str,
+        inbox: &InboxScheme,
+        from_secs: u64,
+        to_secs: u64,
+    ) -> Vec<String> {
+        inbox
+            .listen_epochs(from_secs, to_secs)
+            .into_iter()
+            .map(|epoch| self.derived_inbox(pubkey, Some(pair_secret), epoch))
+            .collect()
+    }
+
+    fn derived_inbox(&self, pubkey: &str, secret: Option<&[u8]>, epoch: Option<u64>) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(b"waku-a2a/inbox/");
+        if let Some(secret) = secret {
+            hasher.update(secret);
+        }
+        hasher.update(pubkey.as_bytes());
+        if let Some(epoch) = epoch {
+            hasher.update(epoch.to_be_bytes());
+        }
+        let id = hex::encode(&hasher.finalize()[..16]);
+        self.task_topic(&id)
+    }
+
+    /// SDS acknowledgements for a message.
+    pub fn ack_topic(&self, message_id: &str) -> String {
+        format!("{}/ack/{}/proto", self.prefix(), message_id)
+    }
+}
+
+fn validate_segment(what: &str, value: &str) -> Result<()> {
+    if value.is_empty() {
+        bail!("Topic {} must not be empty", what);
+    }
+    if !value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        bail!(
+            "Topic {} {:?} may only contain ASCII letters, digits, '-', '_' and '.'",
+            what,
+            value
+        );
+    }
+    Ok(())
+}
+
+/// Task inbox topic in the default scheme.
+pub fn task_topic(recipient_pubkey: &str) -> String {
+    TopicScheme::default().task_topic(recipient_pubkey)
+}
+
+/// ACK topic in the default scheme.
+pub fn ack_topic(message_id: &str) -> String {
+    TopicScheme::default().ack_topic(message_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_scheme() {
+        let scheme = TopicScheme::default();
+        assert_eq!(scheme.discovery(), DISCOVERY);
+        assert_eq!(scheme.task_topic("02ab"), task_topic("02ab"));
+        assert_eq!(scheme.ack_topic("m1"), ack_topic("m1"));
+    }
+
+    #[test]
+    fn test_namespaced_scheme() {
+        let staging = TopicScheme::namespaced("staging").unwrap();
+        assert_eq!(staging.discovery(), "/waku-a2a/1/staging/discovery/proto");
+        assert_eq!(
+            staging.task_topic("02ab"),
+            "/waku-a2a/1/staging/task/02ab/proto"
+        );
+        assert_eq!(staging.ack_topic("m1"), "/waku-a2a/1/staging/ack/m1/proto");
+
+        let custom = TopicScheme::new("lmao", 2)
+            .unwrap()
+            .with_namespace("tenant-a")
+            .unwrap();
+        assert_eq!(custom.discovery(), "/lmao/2/tenant-a/discovery/proto");
+        assert_ne!(custom.discovery(), staging.discovery());
+    }
+
+    #[test]
+    fn test_hashed_inbox() {
+        let scheme = TopicScheme::default();
+        let topic = scheme.inbox_topic("02ab", &InboxScheme::Hashed, 0);
+        assert!(!topic.contains("02ab"));
+        assert!(topic.starts_with("/waku-a2a/1/task/"));
+        assert_eq!(
+            topic,
+            scheme.inbox_topic("02ab", &InboxScheme::Hashed, 99_999)
+        );
+        assert_eq!(
+            scheme.inbox_listen_topics("02ab", &InboxScheme::Hashed, 0),
+            vec![topic]
+        );
+        assert_eq!(
+            scheme.inbox_topic("02ab", &InboxScheme::Plain, 0),
+            task_topic("02ab")
+        );
+    }
+
+    #[test]
+    fn test_rotating_inbox() {
+        let scheme = TopicScheme::default();
+        let inbox = InboxScheme::Rotating { epoch_secs: 100 };
+        let t0 = scheme.inbox_topic("02ab", &inbox, 150);
+        assert_eq!(t0, scheme.inbox_topic("02ab", &inbox, 199));
+        let t1 = scheme.inbox_topic("02ab", &inbox, 200);
+        assert_ne!(t0, t1);
+
+        // Recipient hears senders whose clock is one epoch off either way
+        let listen = scheme.inbox_listen_topics("02ab", &inbox, 150);
+        assert_eq!(listen.len(), 3);
+        assert!(listen.contains(&scheme.inbox_topic("02ab", &inbox, 50)));
+        assert!(listen.contains(&t0));
+        assert!(listen.contains(&t1));
+        assert!(!listen.contains(&scheme.inbox_topic("02ab", &inbox, 350)));
+
+        // History spans every epoch in between
+        let history = scheme.inbox_topics_between("02ab", &inbox, 150, 450);
+        assert_eq!(history.len(), 6);
+        for t in [50, 150, 250, 350, 450, 550] {
+            assert!(history.contains(&scheme.inbox_topic("02ab", &inbox, t)));
+        }
+    }
+
+    #[test]
+    fn test_listen_epochs_are_capped() {
+        let scheme = TopicScheme::default();
+        let inbox = InboxScheme::Rotating { epoch_secs: 1 };
+        let now = 1_700_000_000;
+        let history = scheme.inbox_topics_between("02ab", &inbox, 0, now);
+        assert_eq!(history.len() as u64, MAX_LISTEN_EPOCHS);
+        assert!(history.contains(&scheme.inbox_topic("02ab", &inbox, now + 1)));
+        assert!(!history.contains(&scheme.inbox_topic("02ab", &inbox, 0)));
+        let since = inbox.covered_since(0, now);
+        assert!(history.contains(&scheme.inbox_topic("02ab", &inbox, since)));
+        assert!(!history.contains(&scheme.inbox_topic("02ab", &inbox, since - 2)));
+
+        // Short ranges are covered in full
+        assert_eq!(inbox.covered_since(now - 10, now), now - 10);
+        assert_eq!(InboxScheme::Hashed.covered_since(0, now), 0);
+    }
+
+    #[test]
+    fn test_pair_inbox() {
+        let scheme = TopicScheme::default();
+        let inbox = InboxScheme::Rotating { epoch_secs: 100 };
+        let topic = scheme.pair_inbox_topic(b"secret", "02ab", &inbox, 150);
+        assert_ne!(topic, scheme.inbox_topic("02ab", &inbox, 150));
+        assert_ne!(
+            topic,
+            scheme.pair_inbox_topic(b"other", "02ab", &inbox, 150)
+        );
+        assert_ne!(
+            topic,
+            scheme.pair_inbox_topic(b"secret", "03cd", &inbox, 150)
+        );
+        assert!(scheme
+            .pair_inbox_listen_topics(b"secret", "02ab", &inbox, 150)
+            .contains(&topic));
+    }
+
+    #[test]
+    fn test_inbox_scheme_serialization() {
+        let json = serde_json::to_string(&InboxScheme::Rotating { epoch_secs: 60 }).unwrap();
+        assert_eq!(json, r#"{"mode":"rotating","epoch_secs":60}"#);
+        let future: InboxScheme = serde_json::from_str(r#"{"mode":"onion"}"#).unwrap();
+        assert_eq!(future, InboxScheme::Unsupported);
+        assert!(future.is_plain());
+    }
+
+    #[test]
+    fn test_rejects_invalid_segments() {
+        assert!(TopicScheme::new("", 1).is_err());
+        assert!(TopicScheme::new("a/b", 1).is_err());
+        assert!(TopicScheme::namespaced("prod/eu").is_err());
+        assert!(TopicScheme::namespaced("prod eu").is_err());
+    }
+}
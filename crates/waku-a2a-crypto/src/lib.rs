@@ -5,6 +5,7 @@ use chacha20poly1305::{
 };
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use x25519_dalek::{PublicKey, StaticSecret};
 
 /// Agent identity keypair (X25519 for ECDH key agreement).
@@ -57,6 +58,16 @@ impl AgentIdentity {
 pub struct SessionKey([u8; 32]);
 
 impl SessionKey {
+    /// Derive a 32-byte secret for another purpose (e.g. topic derivation)
+    /// without exposing the encryption key.
+    pub fn derive(&self, label: &str) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"waku-a2a/");
+        hasher.update(label.as_bytes());
+        hasher.update(self.0);
+        hasher.finalize().into()
+    }
+
     /// Encrypt plaintext, returns EncryptedPayload with random nonce.
     pub fn encrypt(&self, plaintext: &[u8]) -> Result<EncryptedPayload> {
         let cipher = ChaCha20Poly1305::new_from_slice(&self.0)
@@ -197,4 +208,15 @@ mod tests {
         let encrypted = key_ab.encrypt(b"secret").unwrap();
         assert!(key_ae.decrypt(&encrypted).is_err());
     }
+
+    #[test]
+    fn derived_secrets_are_shared_and_labelled() {
+        let alice = AgentIdentity::generate();
+        let bob = AgentIdentity::generate();
+
+        let ab = alice.shared_key(&bob.public);
+        let ba = bob.shared_key(&alice.public);
+        assert_eq!(ab.derive("inbox"), ba.derive("inbox"));
+        assert_ne!(ab.derive("inbox"), ab.derive("other"));
+    }
 }
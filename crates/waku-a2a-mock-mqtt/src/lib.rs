@@ -0,0 +1,464 @@
+//! In-process MQTT broker stand-in for hermetic tests.
+//!
+//! `MockBroker` accepts MQTT 3.1.1 clients on a local port, so
+//! `MqttTransport` and the agents above it can be tested without a real
+//! broker. Publishes are routed to every client with a matching
+//! subscription, `+` and `#` wildcards included, at the lower of the publish
+//! and subscription QoS, with the QoS 1 and 2 handshakes in both directions.
+//! Sessions are always clean; retained messages, wills and keep-alive
+//! enforcement are not implemented.
+//!
+//! ```no_run
+//! # async fn demo() -> anyhow::Result<()> {
+//! use waku_a2a_mock_mqtt::MockBroker;
+//! use waku_a2a_transport::mqtt::{MqttConfig, MqttTransport};
+//!
+//! let broker = MockBroker::start().await?;
+//! let alice = MqttTransport::connect(MqttConfig::new(broker.address())).await?;
+//! let bob = MqttTransport::connect(MqttConfig::new(broker.address())).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{Context, Result};
+use bytes::BytesMut;
+use rumqttc::mqttbytes::v4::{
+    ConnAck, ConnectReturnCode, Packet, PubAck, PubComp, PubRec, PubRel, Publish, SubAck,
+    SubscribeReasonCode, UnsubAck,
+};
+use rumqttc::mqttbytes::{self, matches, valid_filter};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+pub use rumqttc::mqttbytes::QoS;
+
+/// Largest packet accepted, as `MqttTransport` sends.
+const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+/// A message as the broker received it from a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Published {
+    pub client_id: String,
+    pub topic: String,
+    pub qos: QoS,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct BrokerState {
+    /// Username and password clients must present.
+    credentials: Option<(String, String)>,
+    next_client: u64,
+    clients: HashMap<u64, ClientState>,
+    /// Every message published, oldest first.
+    published: Vec<Published>,
+    /// Leave PINGREQs unanswered, like a broker that stopped responding.
+    ignore_pings: bool,
+}
+
+struct ClientState {
+    outgoing: mpsc::UnboundedSender<Packet>,
+    /// Subscription filters and the QoS granted for them.
+    filters: HashMap<String, QoS>,
+    next_pkid: u16,
+}
+
+/// A fake MQTT broker. Stops and disconnects its clients when dropped.
+pub struct MockBroker {
+    address: String,
+    state: Arc<Mutex<BrokerState>>,
+    /// Bumped to disconnect every client.
+    disconnect: watch::Sender<u64>,
+    server: JoinHandle<()>,
+}
+
+impl Drop for MockBroker {
+    fn drop(&mut self) {
+        self.server.abort();
+        self.disconnect_clients();
+    }
+}
+
+impl MockBroker {
+    /// Start a broker on a free local port.
+    pub async fn start() -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?.to_string();
+        let state = Arc::new(Mutex::new(BrokerState::default()));
+        let (disconnect, disconnected) = watch::channel(0);
+        let server = {
+            let state = state.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve_client(stream, state.clone(), disconnected.clone()));
+                }
+            })
+        };
+        Ok(Self {
+            address,
+            state,
+            disconnect,
+            server,
+        })
+    }
+
+    /// Refuse clients that don't log in with `username` and `password`.
+    pub fn with_credentials(self, username: &str, password: &str) -> Self {
+        self.state.lock().unwrap().credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    /// Address to connect to, e.g. `127.0.0.1:40123`.
+    pub fn address(&self) -> String {
+        self.address.clone()
+    }
+
+    /// Messages published so far, oldest first.
+    pub fn published(&self) -> Vec<Published> {
+        self.state.lock().unwrap().published.clone()
+    }
+
+    /// Subscription filters of connected clients, sorted.
+    pub fn subscriptions(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut filters: Vec<String> = state
+            .clients
+            .values()
+            .flat_map(|client| client.filters.keys().cloned())
+            .collect();
+        filters.sort();
+        filters.dedup();
+        filters
+    }
+
+    /// Clients currently connected.
+    pub fn client_count(&self) -> usize {
+        self.state.lock().unwrap().clients.len()
+    }
+
+    /// Stop answering pings, or start again.
+    pub fn ignore_pings(&self, ignore: bool) {
+        self.state.lock().unwrap().ignore_pings = ignore;
+    }
+
+    /// Drop every client connection, as a broker restart would.
+    pub fn disconnect_clients(&self) {
+        self.disconnect.send_modify(|generation| *generation += 1);
+    }
+}
+
+async fn serve_client(
+    mut stream: TcpStream,
+    state: Arc<Mutex<BrokerState>>,
+    mut disconnect: watch::Receiver<u64>,
+) {
+    let generation = *disconnect.borrow_and_update();
+    let mut buf = BytesMut::new();
+    let connect = match read_packet(&mut stream, &mut buf).await {
+        Ok(Some(Packet::Connect(connect))) => connect,
+        Ok(_) => return,
+        Err(e) => {
+            eprintln!("[mock-mqtt] Bad connect: {:#}", e);
+            return;
+        }
+    };
+    let refused = state
+        .lock()
+        .unwrap()
+        .credentials
+        .as_ref()
+        .is_some_and(|(username, password)| {
+            connect
+                .login
+                .as_ref()
+                .is_none_or(|login| &login.username != username || &login.password != password)
+        });
+    let code = match refused {
+        true => ConnectReturnCode::BadUserNamePassword,
+        false => ConnectReturnCode::Success,
+    };
+    let accepted = write_packet(&mut stream, &Packet::ConnAck(ConnAck::new(code, false))).await;
+    if refused || accepted.is_err() {
+        return;
+    }
+
+    let (mut read, mut write) = stream.into_split();
+    let (outgoing, mut packets) = mpsc::unbounded_channel();
+    let id = {
+        let mut state = state.lock().unwrap();
+        state.next_client += 1;
+        let id = state.next_client;
+        state.clients.insert(
+            id,
+            ClientState {
+                outgoing: outgoing.clone(),
+                filters: HashMap::new(),
+                next_pkid: 1,
+            },
+        );
+        id
+    };
+    let writer = tokio::spawn(async move {
+        while let Some(packet) = packets.recv().await {
+            if write_packet(&mut write, &packet).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    // QoS 2 publishes received but not yet released
+    let mut unreleased = HashSet::new();
+    loop {
+        let packet = tokio::select! {
+            packet = read_packet(&mut read, &mut buf) => match packet {
+                Ok(Some(packet)) => packet,
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("[mock-mqtt] Dropping {}: {:#}", connect.client_id, e);
+                    break;
+                }
+            },
+            _ = disconnect.wait_for(|current| *current != generation) => break,
+        };
+        let reply = match packet {
+            Packet::Publish(publish) => {
+                let reply = match publish.qos {
+                    QoS::AtMostOnce => None,
+                    QoS::AtLeastOnce => Some(Packet::PubAck(PubAck::new(publish.pkid))),
+                    QoS::ExactlyOnce => Some(Packet::PubRec(PubRec::new(publish.pkid))),
+                };
+                if publish.qos != QoS::ExactlyOnce || unreleased.insert(publish.pkid) {
+                    route(&state, &connect.client_id, publish);
+                }
+                reply
+            }
+            Packet::PubRel(PubRel { pkid, .. }) => {
+                unreleased.remove(&pkid);
+                Some(Packet::PubComp(PubComp::new(pkid)))
+            }
+            // A client received our QoS 2 delivery
+            Packet::PubRec(PubRec { pkid, .. }) => Some(Packet::PubRel(PubRel::new(pkid))),
+            Packet::Subscribe(subscribe) => {
+                let mut state = state.lock().unwrap();
+                let client = state.clients.get_mut(&id).expect("registered above");
+                let codes = subscribe
+                    .filters
+                    .into_iter()
+                    .map(|filter| {
+                        if !valid_filter(&filter.path) {
+                            return SubscribeReasonCode::Failure;
+                        }
+                        client.filters.insert(filter.path, filter.qos);
+                        SubscribeReasonCode::Success(filter.qos)
+                    })
+                    .collect();
+                Some(Packet::SubAck(SubAck::new(subscribe.pkid, codes)))
+            }
+            Packet::Unsubscribe(unsubscribe) => {
+                let mut state = state.lock().unwrap();
+                let client = state.clients.get_mut(&id).expect("registered above");
+                for topic in &unsubscribe.topics {
+                    client.filters.remove(topic);
+                }
+                Some(Packet::UnsubAck(UnsubAck::new(unsubscribe.pkid)))
+            }
+            Packet::PingReq => (!state.lock().unwrap().ignore_pings).then_some(Packet::PingResp),
+            Packet::Disconnect | Packet::Connect(_) => break,
+            // PUBACK and PUBCOMP of our deliveries
+            _ => None,
+        };
+        if let Some(reply) = reply {
+            let _ = outgoing.send(reply);
+        }
+    }
+
+    state.lock().unwrap().clients.remove(&id);
+    writer.abort();
+}
+
+/// Record `publish` and hand it to every client subscribed to its topic.
+fn route(state: &Mutex<BrokerState>, client_id: &str, publish: Publish) {
+    let mut state = state.lock().unwrap();
+    state.published.push(Published {
+        client_id: client_id.to_string(),
+        topic: publish.topic.clone(),
+        qos: publish.qos,
+        payload: publish.payload.to_vec(),
+    });
+    for client in state.clients.values_mut() {
+        let granted = client
+            .filters
+            .iter()
+            .filter(|(filter, _)| matches(&publish.topic, filter))
+            .map(|(_, qos)| *qos)
+            .reduce(|a, b| if b > a { b } else { a });
+        let Some(granted) = granted else {
+            continue;
+        };
+        let qos = if publish.qos < granted {
+            publish.qos
+        } else {
+            granted
+        };
+        let mut delivery = Publish::new(publish.topic.clone(), qos, publish.payload.to_vec());
+        if qos != QoS::AtMostOnce {
+            delivery.pkid = client.next_pkid;
+            client.next_pkid = client.next_pkid.checked_add(1).unwrap_or(1);
+        }
+        let _ = client.outgoing.send(Packet::Publish(delivery));
+    }
+}
+
+async fn write_packet(write: &mut (impl AsyncWrite + Unpin), packet: &Packet) -> Result<()> {
+    let mut buf = BytesMut::new();
+    packet
+        .write(&mut buf, MAX_PACKET_SIZE)
+        .context("Failed to encode MQTT packet")?;
+    write.write_all(&buf).await?;
+    Ok(())
+}
+
+/// The next packet, or None once the client closed the connection.
+async fn read_packet(
+    read: &mut (impl AsyncRead + Unpin),
+    buf: &mut BytesMut,
+) -> Result<Option<Packet>> {
+    loop {
+        match Packet::read(buf, MAX_PACKET_SIZE) {
+            Ok(packet) => return Ok(Some(packet)),
+            Err(mqttbytes::Error::InsufficientBytes(_)) => {}
+            Err(e) => return Err(e).context("Malformed MQTT packet"),
+        }
+        if read.read_buf(buf).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use rumqttc::mqttbytes::v4::{Connect, Subscribe};
+    use std::time::Duration;
+    use waku_a2a_core::Task;
+    use waku_a2a_node::WakuA2ANode;
+    use waku_a2a_transport::mqtt::{MqttConfig, MqttTransport};
+    use waku_a2a_transport::WakuTransport;
+
+    /// A bare client subscribed to `filter`.
+    async fn raw_subscriber(broker: &MockBroker, filter: &str, qos: QoS) -> (TcpStream, BytesMut) {
+        let mut stream = TcpStream::connect(broker.address()).await.unwrap();
+        let mut buf = BytesMut::new();
+        write_packet(&mut stream, &Packet::Connect(Connect::new("raw")))
+            .await
+            .unwrap();
+        let ack = read_packet(&mut stream, &mut buf).await.unwrap();
+        assert!(matches!(ack, Some(Packet::ConnAck(_))));
+        write_packet(&mut stream, &Packet::Subscribe(Subscribe::new(filter, qos)))
+            .await
+            .unwrap();
+        let ack = read_packet(&mut stream, &mut buf).await.unwrap();
+        assert!(matches!(ack, Some(Packet::SubAck(_))));
+        (stream, buf)
+    }
+
+    #[tokio::test]
+    async fn test_wildcards_and_qos_downgrade() {
+        let broker = MockBroker::start().await.unwrap();
+        let (mut raw, mut buf) = raw_subscriber(&broker, "waku/app/+/#", QoS::AtMostOnce).await;
+        assert_eq!(broker.subscriptions(), ["waku/app/+/#"]);
+
+        let publisher =
+            MqttTransport::connect(MqttConfig::new(broker.address()).with_qos(QoS::ExactlyOnce))
+                .await
+                .unwrap();
+        publisher
+            .publish("/other/1/x/proto", b"skip")
+            .await
+            .unwrap();
+        publisher.publish("/app/1/x/proto", b"hi").await.unwrap();
+
+        let packet = tokio::time::timeout(Duration::from_secs(5), read_packet(&mut raw, &mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let Some(Packet::Publish(publish)) = packet else {
+            panic!("expected a publish, got {:?}", packet);
+        };
+        assert_eq!(publish.topic, "waku/app/1/x/proto");
+        assert_eq!(publish.qos, QoS::AtMostOnce);
+        assert_eq!(&publish.payload[..], b"hi");
+        assert_eq!(broker.published().len(), 2);
+        assert_eq!(broker.published()[1].qos, QoS::ExactlyOnce);
+    }
+
+    #[tokio::test]
+    async fn test_credentials_checked() {
+        let broker = MockBroker::start()
+            .await
+            .unwrap()
+            .with_credentials("edge", "secret");
+        let config = MqttConfig::new(broker.address());
+        let err = MqttTransport::connect(config.clone().with_credentials("edge", "wrong"))
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("refused"), "{:#}", err);
+        assert!(MqttTransport::connect(config.clone()).await.is_err());
+        MqttTransport::connect(config.with_credentials("edge", "secret"))
+            .await
+            .unwrap();
+        assert_eq!(broker.client_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_agents_exchange_tasks() {
+        let broker = MockBroker::start().await.unwrap();
+        let connect = || MqttTransport::connect(MqttConfig::new(broker.address()));
+        let worker =
+            WakuA2ANode::new_encrypted("worker", "worker agent", vec![], connect().await.unwrap());
+        let requester = WakuA2ANode::new_encrypted(
+            "requester",
+            "requester agent",
+            vec![],
+            connect().await.unwrap(),
+        );
+
+        // No store behind a broker: the requester listens before the announcement
+        assert!(requester.discover().await.unwrap().is_empty());
+        worker.announce().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let cards = requester.discover().await.unwrap();
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].public_key, worker.pubkey());
+
+        worker.poll_tasks().await.unwrap();
+        requester.poll_tasks().await.unwrap();
+        let task = Task::new(requester.pubkey(), worker.pubkey(), "ping");
+        let serve = async {
+            let t = std::pin::pin!(worker.incoming_tasks())
+                .next()
+                .await
+                .unwrap();
+            assert_eq!(t.text(), Some("ping"));
+            worker
+                .respond_to(&t, "pong", Some(&requester.card))
+                .await
+                .unwrap();
+        };
+        let (acked, ()) = tokio::join!(requester.send_task_to(&task, Some(&cards[0])), serve);
+        assert!(acked.unwrap());
+
+        let response = std::pin::pin!(requester.incoming_tasks())
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(response.id, task.id);
+        assert_eq!(response.result_text(), Some("pong"));
+    }
+}
@@ -0,0 +1,530 @@
+//! In-process fake nwaku for hermetic tests.
+//!
+//! `MockNwaku` serves the parts of nwaku's REST API this workspace uses on a
+//! local port, so `NwakuRestTransport`, `WakuA2ANode`s and the CLI binary can
+//! be tested without docker or a network:
+//!
+//! - Relay: `/relay/v1/subscriptions`, `/relay/v1/messages/{pubsub}` and
+//!   their `/relay/v1/auto/` counterparts
+//! - Lightpush: `/lightpush/v1/message`
+//! - Filter: `/filter/v2/subscriptions[/{requestId}]`, `/filter/v2/messages/{content}`
+//! - Store: `/store/v3/messages`
+//! - `/health` and `/debug/v1/info`
+//!
+//! Nodes started from one `MockNetwork` relay to each other. Like nwaku, a
+//! node only keeps messages of topics it is subscribed to, in bounded caches
+//! that a GET drains; fetching an unsubscribed topic answers 404. Everything
+//! not ephemeral goes into a store shared by the network. `restart` makes a
+//! node forget its subscriptions and caches, as a real restart would.
+//!
+//! ```no_run
+//! # async fn demo() -> anyhow::Result<()> {
+//! use waku_a2a_mock_nwaku::MockNetwork;
+//! use waku_a2a_transport::nwaku_rest::NwakuRestTransport;
+//!
+//! let network = MockNetwork::new();
+//! let alice = network.start_node().await?;
+//! let bob = network.start_node().await?;
+//! let alice = NwakuRestTransport::new(&alice.url());
+//! let bob = NwakuRestTransport::new(&bob.url());
+//! # Ok(())
+//! # }
+//! ```
+
+mod http;
+mod routes;
+
+pub use http::Request;
+
+use anyhow::Result;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use waku_a2a_transport::autosharding::AutoSharding;
+use waku_a2a_transport::WakuMessage;
+
+/// Messages nwaku caches per subscribed topic for REST clients
+/// (`--rest-relay-cache-capacity`).
+pub const DEFAULT_CACHE_CAPACITY: usize = 30;
+
+/// Interval at which `wait_for_request` checks the request log.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A simulated Waku network that mock nodes relay on.
+#[derive(Clone)]
+pub struct MockNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+pub(crate) struct NetworkState {
+    pub sharding: AutoSharding,
+    pub cache_capacity: usize,
+    pub nodes: Vec<NodeState>,
+    /// Every non-ephemeral message relayed, oldest first.
+    pub store: Vec<StoredMessage>,
+    /// Hashes of all messages relayed, to drop duplicates.
+    pub seen: HashSet<String>,
+}
+
+/// A message kept by the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredMessage {
+    pub pubsub_topic: String,
+    pub message: WakuMessage,
+    /// Hex message hash, used as the store cursor.
+    pub hash: String,
+}
+
+/// What one nwaku knows; the index in `NetworkState::nodes` is its identity.
+pub(crate) struct NodeState {
+    /// Reported by `/health`, "Ready" unless set otherwise.
+    pub health: String,
+    /// Relay caches by pubsub topic.
+    pub relay: HashMap<String, VecDeque<WakuMessage>>,
+    /// Autosharded relay caches by content topic.
+    pub auto: HashMap<String, VecDeque<WakuMessage>>,
+    /// Filter subscriptions by content topic: pubsub topic and cache.
+    pub filter: HashMap<String, (String, VecDeque<WakuMessage>)>,
+    pub requests: Vec<Request>,
+}
+
+impl Default for NodeState {
+    fn default() -> Self {
+        Self {
+            health: "Ready".to_string(),
+            relay: HashMap::new(),
+            auto: HashMap::new(),
+            filter: HashMap::new(),
+            requests: Vec::new(),
+        }
+    }
+}
+
+impl Default for MockNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockNetwork {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(NetworkState {
+                sharding: AutoSharding::default(),
+                cache_capacity: DEFAULT_CACHE_CAPACITY,
+                nodes: Vec::new(),
+                store: Vec::new(),
+                seen: HashSet::new(),
+            })),
+        }
+    }
+
+    /// Shard content topics with `sharding` instead of The Waku Network's.
+    pub fn with_sharding(self, sharding: AutoSharding) -> Self {
+        self.state.lock().unwrap().sharding = sharding;
+        self
+    }
+
+    /// Keep at most `capacity` messages per topic cache.
+    pub fn with_cache_capacity(self, capacity: usize) -> Self {
+        self.state.lock().unwrap().cache_capacity = capacity.max(1);
+        self
+    }
+
+    /// Start another nwaku on this network, serving on a free local port.
+    pub async fn start_node(&self) -> Result<MockNwaku> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let index = {
+            let mut state = self.state.lock().unwrap();
+            state.nodes.push(NodeState::default());
+            state.nodes.len() - 1
+        };
+        let network = self.clone();
+        let server = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let network = network.clone();
+                tokio::spawn(async move {
+                    let request = match http::read_request(&mut stream).await {
+                        Ok(request) => request,
+                        Err(e) => {
+                            eprintln!("[mock-nwaku] Bad request: {:#}", e);
+                            return;
+                        }
+                    };
+                    let response = {
+                        let mut state = network.state.lock().unwrap();
+                        let response = routes::handle(&mut state, index, &request);
+                        state.nodes[index].requests.push(request);
+                        response
+                    };
+                    let _ = http::write_response(&mut stream, &response).await;
+                });
+            }
+        });
+        Ok(MockNwaku {
+            url,
+            index,
+            network: self.clone(),
+            server: Arc::new(server),
+        })
+    }
+
+    /// Messages in the store, oldest first.
+    pub fn stored(&self) -> Vec<StoredMessage> {
+        self.state.lock().unwrap().store.clone()
+    }
+}
+
+/// One fake nwaku node. The server stops when the last clone is dropped.
+#[derive(Clone)]
+pub struct MockNwaku {
+    url: String,
+    index: usize,
+    network: MockNetwork,
+    server: Arc<tokio::task::JoinHandle<()>>,
+}
+
+impl Drop for MockNwaku {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.server) == 1 {
+            self.server.abort();
+        }
+    }
+}
+
+impl MockNwaku {
+    /// Start a node on a network of its own.
+    pub async fn start() -> Result<Self> {
+        MockNetwork::new().start_node().await
+    }
+
+    /// Base URL of the REST API, e.g. `http://127.0.0.1:40123`.
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    pub fn network(&self) -> &MockNetwork {
+        &self.network
+    }
+
+    /// Requests served so far, oldest first.
+    pub fn requests(&self) -> Vec<Request> {
+        self.with_node(|node| node.requests.clone())
+    }
+
+    /// Wait up to `timeout` for a request matching `predicate`.
+    pub async fn wait_for_request(
+        &self,
+        predicate: impl Fn(&Request) -> bool,
+        timeout: Duration,
+    ) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if self.with_node(|node| node.requests.iter().any(&predicate)) {
+                return true;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Pubsub topics relayed, including shards of autosharded subscriptions.
+    pub fn relay_subscriptions(&self) -> Vec<String> {
+        let state = self.network.state.lock().unwrap();
+        let node = &state.nodes[self.index];
+        let mut topics: Vec<String> = node.relay.keys().cloned().collect();
+        topics.extend(
+            node.auto
+                .keys()
+                .filter_map(|topic| state.sharding.pubsub_topic(topic).ok()),
+        );
+        topics.sort();
+        topics.dedup();
+        topics
+    }
+
+    /// Content topics with a Filter subscription.
+    pub fn filter_subscriptions(&self) -> Vec<String> {
+        let mut topics: Vec<String> = self.with_node(|node| node.filter.keys().cloned().collect());
+        topics.sort();
+        topics
+    }
+
+    /// Report `health` (e.g. "Initializing") from `/health`; nwaku answers
+    /// 503 unless it is "Ready".
+    pub fn set_health(&self, health: &str) {
+        self.with_node(|node| node.health = health.to_string());
+    }
+
+    /// Forget all subscriptions and cached messages, as nwaku does when it
+    /// restarts. The request log and the network's store are kept.
+    pub fn restart(&self) {
+        self.with_node(|node| {
+            node.relay.clear();
+            node.auto.clear();
+            node.filter.clear();
+        });
+    }
+
+    fn with_node<R>(&self, f: impl FnOnce(&mut NodeState) -> R) -> R {
+        f(&mut self.network.state.lock().unwrap().nodes[self.index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use waku_a2a_core::Task;
+    use waku_a2a_node::WakuA2ANode;
+    use waku_a2a_transport::history::{fetch_history, HistoryQuery};
+    use waku_a2a_transport::nwaku_rest::{ClientMode, ConnectionState, NwakuRestTransport};
+    use waku_a2a_transport::WakuTransport;
+
+    const TOPIC: &str = "/waku-a2a/1/task/02ab/proto";
+    const SHARD: &str = "/waku/2/rs/1/5";
+
+    #[tokio::test]
+    async fn test_relay_between_nodes() {
+        let network = MockNetwork::new();
+        let (a, b, c) = (
+            network.start_node().await.unwrap(),
+            network.start_node().await.unwrap(),
+            network.start_node().await.unwrap(),
+        );
+        let alice = NwakuRestTransport::new(&a.url());
+        let bob = NwakuRestTransport::new(&b.url());
+        alice.subscribe(TOPIC).await.unwrap();
+        assert_eq!(a.relay_subscriptions(), vec![SHARD.to_string()]);
+        assert!(c.relay_subscriptions().is_empty());
+
+        bob.publish(TOPIC, b"hello").await.unwrap();
+        let received = alice.poll(TOPIC).await.unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].payload, b"hello");
+        assert_eq!(received[0].pubsub_topic.as_deref(), Some(SHARD));
+        assert!(received[0].timestamp.is_some());
+        // GET drains the cache
+        assert!(alice.poll(TOPIC).await.unwrap().is_empty());
+
+        // An unsubscribed topic is unknown to nwaku
+        let carol = NwakuRestTransport::new(&c.url());
+        let err = carol.poll(TOPIC).await.unwrap_err();
+        assert!(err.to_string().contains("404"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_relay_drops_duplicates() {
+        let node = MockNwaku::start().await.unwrap();
+        let t = NwakuRestTransport::new(&node.url());
+        t.subscribe(TOPIC).await.unwrap();
+        let message = WakuMessage::new(TOPIC, b"once".to_vec()).with_timestamp(42);
+        t.publish_message(&message).await.unwrap();
+        t.publish_message(&message).await.unwrap();
+        assert_eq!(t.poll(TOPIC).await.unwrap().len(), 1);
+        assert_eq!(node.network().stored().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cache_is_bounded() {
+        let network = MockNetwork::new().with_cache_capacity(2);
+        let node = network.start_node().await.unwrap();
+        let t = NwakuRestTransport::new(&node.url());
+        t.subscribe(TOPIC).await.unwrap();
+        for payload in [b"1", b"2", b"3"] {
+            t.publish(TOPIC, payload).await.unwrap();
+        }
+        let payloads: Vec<Vec<u8>> = t
+            .poll(TOPIC)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.payload)
+            .collect();
+        assert_eq!(payloads, vec![b"2".to_vec(), b"3".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_messages_rejected() {
+        let node = MockNwaku::start().await.unwrap();
+        let t = NwakuRestTransport::new(&node.url());
+        let oversized = WakuMessage::new(TOPIC, vec![0u8; 151 * 1024]);
+        let err = t.publish_message(&oversized).await.unwrap_err();
+        assert!(err.to_string().contains("400"), "{}", err);
+        assert!(node.network().stored().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_resubscribe_after_restart() {
+        let node = MockNwaku::start().await.unwrap();
+        let t = NwakuRestTransport::new(&node.url());
+        t.subscribe(TOPIC).await.unwrap();
+        node.restart();
+        assert!(node.relay_subscriptions().is_empty());
+
+        // The 404 makes the transport subscribe again
+        assert!(t.poll(TOPIC).await.unwrap().is_empty());
+        assert_eq!(node.relay_subscriptions(), vec![SHARD.to_string()]);
+        t.publish(TOPIC, b"back").await.unwrap();
+        assert_eq!(t.poll(TOPIC).await.unwrap()[0].payload, b"back");
+    }
+
+    #[tokio::test]
+    async fn test_auto_endpoints() {
+        let topic = "/toychat/2/huilong/proto";
+        let node = MockNwaku::start().await.unwrap();
+        let t = NwakuRestTransport::new(&node.url());
+        t.subscribe(topic).await.unwrap();
+        t.publish(topic, b"auto").await.unwrap();
+        let received = t.poll(topic).await.unwrap();
+        assert_eq!(received[0].payload, b"auto");
+        assert!(node
+            .requests()
+            .iter()
+            .any(|r| r.path == "/relay/v1/auto/messages"));
+    }
+
+    #[tokio::test]
+    async fn test_light_client_with_filter_and_lightpush() {
+        let network = MockNetwork::new();
+        let (service, relay) = (
+            network.start_node().await.unwrap(),
+            network.start_node().await.unwrap(),
+        );
+        let light = NwakuRestTransport::new(&service.url())
+            .with_mode(ClientMode::Light)
+            .with_ping_interval(Duration::ZERO);
+        let full = NwakuRestTransport::new(&relay.url());
+        light.subscribe(TOPIC).await.unwrap();
+        full.subscribe(TOPIC).await.unwrap();
+        assert_eq!(service.filter_subscriptions(), vec![TOPIC.to_string()]);
+        assert!(service.relay_subscriptions().is_empty());
+
+        full.publish(TOPIC, b"to light").await.unwrap();
+        assert_eq!(light.poll(TOPIC).await.unwrap()[0].payload, b"to light");
+        light.publish(TOPIC, b"from light").await.unwrap();
+        // nwaku hands its own messages back too
+        let payloads: Vec<Vec<u8>> = full
+            .poll(TOPIC)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.payload)
+            .collect();
+        assert_eq!(payloads, vec![b"to light".to_vec(), b"from light".to_vec()]);
+
+        // The failed ping after a restart renews the Filter subscription
+        service.restart();
+        assert!(light.poll(TOPIC).await.unwrap().is_empty());
+        assert_eq!(service.filter_subscriptions(), vec![TOPIC.to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_store_pages() {
+        let node = MockNwaku::start().await.unwrap();
+        let t = NwakuRestTransport::new(&node.url());
+        for i in 0..5i64 {
+            let message = WakuMessage::new(TOPIC, vec![i as u8]).with_timestamp(1000 + i);
+            t.publish_message(&message).await.unwrap();
+        }
+        t.publish_message(&WakuMessage::new(TOPIC, b"gone".to_vec()).with_ephemeral(true))
+            .await
+            .unwrap();
+        t.publish("/waku-a2a/1/task/03cd/proto", b"other")
+            .await
+            .unwrap();
+
+        let page = t
+            .query_history(&HistoryQuery::new(TOPIC).with_page_size(2))
+            .await
+            .unwrap();
+        assert_eq!(page.messages.len(), 2);
+        assert!(page.cursor.is_some());
+
+        let query = HistoryQuery::new(TOPIC).since(1001).with_page_size(2);
+        let messages = fetch_history(&t, &query).await.unwrap();
+        let payloads: Vec<Vec<u8>> = messages.into_iter().map(|m| m.payload).collect();
+        assert_eq!(payloads, vec![vec![1], vec![2], vec![3], vec![4]]);
+
+        let bad = HistoryQuery {
+            cursor: Some("0xdead".to_string()),
+            ..HistoryQuery::new(TOPIC)
+        };
+        assert!(t.query_history(&bad).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let node = MockNwaku::start().await.unwrap();
+        let health: serde_json::Value = reqwest::get(format!("{}/health", node.url()))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(health["nodeHealth"], "Ready");
+
+        let t = NwakuRestTransport::new(&node.url());
+        assert!(!t.health().await.unwrap().listen_addresses.is_empty());
+        node.set_health("Initializing");
+        let health = t.health().await.unwrap();
+        assert_eq!(health.node_health, "Initializing");
+        assert_eq!(t.connection_state(), ConnectionState::Degraded);
+    }
+
+    #[tokio::test]
+    async fn test_agents_exchange_tasks() {
+        let network = MockNetwork::new();
+        let (a, b) = (
+            network.start_node().await.unwrap(),
+            network.start_node().await.unwrap(),
+        );
+        let worker = WakuA2ANode::new_encrypted(
+            "worker",
+            "worker agent",
+            vec![],
+            NwakuRestTransport::new(&a.url()),
+        );
+        let requester = WakuA2ANode::new_encrypted(
+            "requester",
+            "requester agent",
+            vec![],
+            NwakuRestTransport::new(&b.url()),
+        );
+        worker.announce().await.unwrap();
+
+        // The worker's card comes from the store
+        requester.catch_up().await.unwrap();
+        let cards = requester.discover().await.unwrap();
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].public_key, worker.pubkey());
+
+        worker.poll_tasks().await.unwrap();
+        requester.poll_tasks().await.unwrap();
+        let task = Task::new(requester.pubkey(), worker.pubkey(), "ping");
+        let serve = async {
+            let t = std::pin::pin!(worker.incoming_tasks())
+                .next()
+                .await
+                .unwrap();
+            assert_eq!(t.text(), Some("ping"));
+            worker
+                .respond_to(&t, "pong", Some(&requester.card))
+                .await
+                .unwrap();
+        };
+        let (acked, ()) = tokio::join!(requester.send_task_to(&task, Some(&cards[0])), serve);
+        assert!(acked.unwrap());
+
+        let response = std::pin::pin!(requester.incoming_tasks())
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(response.id, task.id);
+        assert_eq!(response.result_text(), Some("pong"));
+    }
+}
@@ -0,0 +1,418 @@
+//! nwaku REST endpoints on top of the shared network state.
+
+use crate::http::{base64_decode, base64_encode, Request, Response};
+use crate::{NetworkState, NodeState, StoredMessage};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::VecDeque;
+use waku_a2a_transport::message::{now_nanos, MAX_META_SIZE};
+use waku_a2a_transport::WakuMessage;
+
+/// Largest message relay accepts (payload and meta).
+const MAX_MESSAGE_SIZE: usize = 150 * 1024;
+
+/// Store page size when the query doesn't set one.
+const DEFAULT_PAGE_SIZE: usize = 20;
+
+/// Largest store page nwaku returns.
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MessageJson {
+    payload: String,
+    content_topic: Option<String>,
+    #[serde(default)]
+    version: u32,
+    timestamp: Option<i64>,
+    #[serde(default)]
+    ephemeral: bool,
+    meta: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LightpushJson {
+    pubsub_topic: Option<String>,
+    message: MessageJson,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FilterJson {
+    request_id: String,
+    #[serde(default)]
+    content_filters: Vec<String>,
+    pubsub_topic: Option<String>,
+}
+
+pub(crate) fn handle(state: &mut NetworkState, node: usize, request: &Request) -> Response {
+    let method = request.method.as_str();
+    let path = request.path.as_str();
+    let result = match (method, path) {
+        ("GET", "/health") => Ok(health(&state.nodes[node])),
+        ("GET", "/debug/v1/info") => Ok(debug_info(node)),
+        ("POST" | "DELETE", "/relay/v1/subscriptions") => {
+            relay_subscriptions(state, node, request, false)
+        }
+        ("POST" | "DELETE", "/relay/v1/auto/subscriptions") => {
+            relay_subscriptions(state, node, request, true)
+        }
+        ("POST", "/relay/v1/auto/messages") => publish(state, None, &request.body),
+        ("POST", "/lightpush/v1/message") => lightpush(state, &request.body),
+        ("POST" | "DELETE", "/filter/v2/subscriptions") => {
+            filter_subscriptions(state, node, request)
+        }
+        ("GET", "/store/v3/messages") => store_query(state, request),
+        _ => {
+            if let Some(content_topic) = path.strip_prefix("/relay/v1/auto/messages/") {
+                match method {
+                    "GET" => Ok(drain(
+                        state.nodes[node].auto.get_mut(content_topic),
+                        content_topic,
+                    )),
+                    _ => Ok(method_not_allowed()),
+                }
+            } else if let Some(pubsub_topic) = path.strip_prefix("/relay/v1/messages/") {
+                match method {
+                    "GET" => Ok(drain(
+                        state.nodes[node].relay.get_mut(pubsub_topic),
+                        pubsub_topic,
+                    )),
+                    "POST" => publish(state, Some(pubsub_topic.to_string()), &request.body),
+                    _ => Ok(method_not_allowed()),
+                }
+            } else if let Some(content_topic) = path.strip_prefix("/filter/v2/messages/") {
+                match method {
+                    "GET" => Ok(drain(
+                        state.nodes[node]
+                            .filter
+                            .get_mut(content_topic)
+                            .map(|(_, cache)| cache),
+                        content_topic,
+                    )),
+                    _ => Ok(method_not_allowed()),
+                }
+            } else if let Some(request_id) = path.strip_prefix("/filter/v2/subscriptions/") {
+                match method {
+                    "GET" => Ok(filter_ping(state, node, request_id)),
+                    _ => Ok(method_not_allowed()),
+                }
+            } else {
+                Ok(Response::text(404, format!("Unknown endpoint {}", path)))
+            }
+        }
+    };
+    result.unwrap_or_else(|e| Response::text(400, format!("{:#}", e)))
+}
+
+fn health(node: &NodeState) -> Response {
+    let protocol_health = if node.health == "Ready" {
+        "Ready"
+    } else {
+        "Not Ready"
+    };
+    Response::json(
+        if node.health == "Ready" { 200 } else { 503 },
+        &json!({
+            "nodeHealth": node.health,
+            "protocolsHealth": [
+                {"Relay": protocol_health},
+                {"Store": protocol_health},
+                {"Lightpush": protocol_health},
+                {"Filter": protocol_health},
+            ],
+        }),
+    )
+}
+
+fn debug_info(node: usize) -> Response {
+    Response::json(
+        200,
+        &json!({
+            "listenAddresses": [format!("/ip4/127.0.0.1/tcp/{}", 60000 + node)],
+            "enrUri": format!("enr:-mock-{}", node),
+        }),
+    )
+}
+
+fn method_not_allowed() -> Response {
+    Response::text(405, "Method not allowed")
+}
+
+/// Add or remove pubsub topics, or content topics for `auto`.
+fn relay_subscriptions(
+    state: &mut NetworkState,
+    node: usize,
+    request: &Request,
+    auto: bool,
+) -> Result<Response> {
+    let topics: Vec<String> =
+        serde_json::from_str(&request.body).context("Expected a JSON array of topics")?;
+    if auto {
+        for topic in &topics {
+            state.sharding.pubsub_topic(topic)?;
+        }
+    }
+    let node = &mut state.nodes[node];
+    let caches = if auto {
+        &mut node.auto
+    } else {
+        &mut node.relay
+    };
+    for topic in topics {
+        if request.method == "DELETE" {
+            caches.remove(&topic);
+        } else {
+            caches.entry(topic).or_default();
+        }
+    }
+    Ok(Response::text(200, "OK"))
+}
+
+/// Relay a message on `pubsub_topic`, or on its content topic's shard.
+fn publish(state: &mut NetworkState, pubsub_topic: Option<String>, body: &str) -> Result<Response> {
+    let message: MessageJson = serde_json::from_str(body).context("Invalid message")?;
+    relay(state, pubsub_topic, message)?;
+    Ok(Response::text(200, "OK"))
+}
+
+fn lightpush(state: &mut NetworkState, body: &str) -> Result<Response> {
+    let request: LightpushJson = serde_json::from_str(body).context("Invalid lightpush request")?;
+    relay(state, request.pubsub_topic, request.message)?;
+    Ok(Response::text(200, "OK"))
+}
+
+/// Validate a message and deliver it to every node interested in it,
+/// publisher included, then store it unless it's ephemeral.
+fn relay(state: &mut NetworkState, pubsub_topic: Option<String>, json: MessageJson) -> Result<()> {
+    let content_topic = match json.content_topic {
+        Some(topic) if !topic.is_empty() => topic,
+        _ => bail!("Missing content topic"),
+    };
+    let pubsub_topic = match pubsub_topic {
+        Some(topic) => topic,
+        None => state.sharding.pubsub_topic(&content_topic)?,
+    };
+    let payload = base64_decode(&json.payload).context("Invalid payload")?;
+    let meta = match json.meta {
+        Some(meta) => base64_decode(&meta).context("Invalid meta")?,
+        None => Vec::new(),
+    };
+    if meta.len() > MAX_META_SIZE {
+        bail!(
+            "Meta is {} bytes, at most {} allowed",
+            meta.len(),
+            MAX_META_SIZE
+        );
+    }
+    if payload.len() + meta.len() > MAX_MESSAGE_SIZE {
+        bail!(
+            "Message size exceeded maximum of {} bytes",
+            MAX_MESSAGE_SIZE
+        );
+    }
+    let message = WakuMessage {
+        payload,
+        content_topic,
+        version: json.version,
+        timestamp: Some(json.timestamp.filter(|&t| t != 0).unwrap_or_else(now_nanos)),
+        ephemeral: json.ephemeral,
+        meta,
+        pubsub_topic: Some(pubsub_topic.clone()),
+    };
+    let hash = format!("0x{}", hex::encode(message.hash(&pubsub_topic)));
+    if !state.seen.insert(hash.clone()) {
+        // Relay drops messages it has already seen
+        return Ok(());
+    }
+
+    let capacity = state.cache_capacity;
+    let content_topic = message.content_topic.as_str();
+    let auto_shard = state.sharding.pubsub_topic(content_topic).ok();
+    for node in &mut state.nodes {
+        if let Some(cache) = node.relay.get_mut(&pubsub_topic) {
+            push_bounded(cache, message.clone(), capacity);
+        }
+        if auto_shard.as_ref() == Some(&pubsub_topic) {
+            if let Some(cache) = node.auto.get_mut(content_topic) {
+                push_bounded(cache, message.clone(), capacity);
+            }
+        }
+        if let Some((filter_pubsub, cache)) = node.filter.get_mut(content_topic) {
+            if *filter_pubsub == pubsub_topic {
+                push_bounded(cache, message.clone(), capacity);
+            }
+        }
+    }
+    if !message.ephemeral {
+        state.store.push(StoredMessage {
+            pubsub_topic,
+            message,
+            hash,
+        });
+    }
+    Ok(())
+}
+
+fn push_bounded(cache: &mut VecDeque<WakuMessage>, message: WakuMessage, capacity: usize) {
+    cache.push_back(message);
+    while cache.len() > capacity {
+        cache.pop_front();
+    }
+}
+
+/// Hand out and clear a subscription's cache; 404 if not subscribed.
+fn drain(cache: Option<&mut VecDeque<WakuMessage>>, topic: &str) -> Response {
+    match cache {
+        Some(cache) => {
+            let messages: Vec<serde_json::Value> =
+                cache.drain(..).map(|m| message_json(&m)).collect();
+            Response::json(200, &json!(messages))
+        }
+        None => Response::text(404, format!("Not subscribed to topic: {}", topic)),
+    }
+}
+
+fn message_json(message: &WakuMessage) -> serde_json::Value {
+    let mut json = json!({
+        "payload": base64_encode(&message.payload),
+        "contentTopic": message.content_topic,
+        "version": message.version,
+        "timestamp": message.timestamp.unwrap_or(0),
+        "ephemeral": message.ephemeral,
+    });
+    if !message.meta.is_empty() {
+        json["meta"] = json!(base64_encode(&message.meta));
+    }
+    json
+}
+
+fn filter_subscriptions(
+    state: &mut NetworkState,
+    node: usize,
+    request: &Request,
+) -> Result<Response> {
+    let filter: FilterJson =
+        serde_json::from_str(&request.body).context("Invalid filter request")?;
+    if filter.content_filters.is_empty() {
+        bail!("No content topics given");
+    }
+    let mut topics = Vec::new();
+    for content_topic in filter.content_filters {
+        let pubsub_topic = match &filter.pubsub_topic {
+            Some(topic) => topic.clone(),
+            None => state.sharding.pubsub_topic(&content_topic)?,
+        };
+        topics.push((content_topic, pubsub_topic));
+    }
+    let subscriptions = &mut state.nodes[node].filter;
+    for (content_topic, pubsub_topic) in topics {
+        if request.method == "DELETE" {
+            subscriptions.remove(&content_topic);
+        } else {
+            subscriptions
+                .entry(content_topic)
+                .or_insert_with(|| (pubsub_topic, VecDeque::new()));
+        }
+    }
+    Ok(Response::json(
+        200,
+        &json!({"requestId": filter.request_id, "statusDesc": "OK"}),
+    ))
+}
+
+/// The service peer only answers pings from clients it has subscriptions for.
+fn filter_ping(state: &NetworkState, node: usize, request_id: &str) -> Response {
+    if state.nodes[node].filter.is_empty() {
+        Response::json(
+            404,
+            &json!({"requestId": request_id, "statusDesc": "peer has no subscriptions"}),
+        )
+    } else {
+        Response::json(200, &json!({"requestId": request_id, "statusDesc": "OK"}))
+    }
+}
+
+fn store_query(state: &NetworkState, request: &Request) -> Result<Response> {
+    let pubsub_topic = request.param("pubsubTopic").filter(|t| !t.is_empty());
+    let content_topics: Vec<&str> = request
+        .param("contentTopics")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|t| !t.is_empty())
+        .collect();
+    if !content_topics.is_empty() && pubsub_topic.is_none() {
+        bail!("Content topics need a pubsub topic");
+    }
+    let include_data = request.param("includeData") == Some("true");
+    let ascending = request.param("ascending") != Some("false");
+    let start_time = parse_param::<i64>(request, "startTime")?;
+    let end_time = parse_param::<i64>(request, "endTime")?;
+    let page_size = parse_param::<usize>(request, "pageSize")?
+        .filter(|&size| size > 0)
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .min(MAX_PAGE_SIZE);
+
+    let mut matches: Vec<&StoredMessage> = state
+        .store
+        .iter()
+        .filter(|m| pubsub_topic.is_none_or(|t| m.pubsub_topic == t))
+        .filter(|m| {
+            content_topics.is_empty() || content_topics.contains(&m.message.content_topic.as_str())
+        })
+        .filter(|m| start_time.is_none_or(|t| m.message.timestamp >= Some(t)))
+        .filter(|m| end_time.is_none_or(|t| m.message.timestamp <= Some(t)))
+        .collect();
+    if !ascending {
+        matches.reverse();
+    }
+    let start = match request.param("cursor") {
+        Some(cursor) => match matches.iter().position(|m| m.hash == cursor) {
+            Some(index) => index + 1,
+            None => {
+                return Ok(Response::json(
+                    200,
+                    &json!({"statusCode": 400, "statusDesc": "cursor not found", "messages": []}),
+                ))
+            }
+        },
+        None => 0,
+    };
+    let end = (start + page_size).min(matches.len());
+    let page = &matches[start..end];
+
+    let messages: Vec<serde_json::Value> = page
+        .iter()
+        .map(|m| {
+            let mut entry = json!({"messageHash": m.hash, "pubsubTopic": m.pubsub_topic});
+            if include_data {
+                entry["message"] = message_json(&m.message);
+            }
+            entry
+        })
+        .collect();
+    let mut response = json!({
+        "requestId": "",
+        "statusCode": 200,
+        "statusDesc": "OK",
+        "messages": messages,
+    });
+    if end < matches.len() {
+        if let Some(last) = page.last() {
+            response["paginationCursor"] = json!(last.hash);
+        }
+    }
+    Ok(Response::json(200, &response))
+}
+
+fn parse_param<T: std::str::FromStr>(request: &Request, name: &str) -> Result<Option<T>> {
+    match request.param(name).filter(|v| !v.is_empty()) {
+        Some(value) => match value.parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => bail!("Invalid {}: {:?}", name, value),
+        },
+        None => Ok(None),
+    }
+}
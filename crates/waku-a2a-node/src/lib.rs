@@ -1,9 +1,85 @@
 use anyhow::{Context, Result};
+use futures::stream::{BoxStream, SelectAll};
+use futures::{FutureExt, Stream, StreamExt};
 use k256::ecdsa::SigningKey;
-use waku_a2a_core::{topics, A2AEnvelope, AgentCard, Task};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use waku_a2a_core::codec::{self, Codec, JsonCodec};
+use waku_a2a_core::compression::{self, Compression, MIN_COMPRESS_SIZE};
+use waku_a2a_core::fragment::{self, Fragment, FragmentOutcome, Reassembler};
+use waku_a2a_core::topics::{InboxScheme, TopicScheme, MAX_LISTEN_EPOCHS};
+use waku_a2a_core::{
+    supported_protocol_versions, A2AEnvelope, AgentCard, AgentSkill, EnvelopeError, SkillFilter,
+    Task, TaskState, TaskStatusUpdate, PROTOCOL_VERSION,
+};
 use waku_a2a_crypto::{AgentIdentity, IntroBundle};
+use waku_a2a_transport::history::fetch_history;
+use waku_a2a_transport::message::now_nanos;
+use waku_a2a_transport::nwaku_rest::ConnectionState;
 use waku_a2a_transport::sds::SdsTransport;
-use waku_a2a_transport::WakuTransport;
+use waku_a2a_transport::{HistoryQuery, Priority, WakuMessage, WakuTransport};
+
+pub mod outbox;
+pub mod updates;
+
+pub use outbox::{Outbox, OutboxEntry, OutboxStatus};
+pub use updates::{TaskUpdateEvent, UpdateBuffer, UpdateSequencer};
+pub use waku_a2a_transport::rate_limit::RateLimited;
+pub use waku_a2a_transport::Rejected;
+
+/// Interval between inbox polls while streaming task updates.
+const UPDATE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often `incoming_tasks` re-derives its inbox topics, which change with
+/// rotating inboxes and new pairwise sessions.
+const INBOX_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Sessions with no task exchanged for this long are forgotten, along with
+/// their pairwise inbox subscriptions.
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(24 * 3600);
+
+/// How often `run_outbox` retries pending tasks between reconnects.
+pub const OUTBOX_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How far back `catch_up` looks when no inbox message was seen yet.
+pub const CATCH_UP_WINDOW: Duration = Duration::from_secs(24 * 3600);
+
+/// Furthest back `catch_up` looks, however long ago `last_seen` is. Store
+/// nodes rarely keep messages longer.
+pub const MAX_CATCH_UP: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// What `WakuA2ANode::catch_up` fetched from the store.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CatchUpStats {
+    /// Inbox messages not seen before.
+    pub inbox_messages: usize,
+    /// Agent cards of other agents.
+    pub cards: usize,
+}
+
+/// Counters for incoming payloads that could not be used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecodeStats {
+    /// Envelopes of a type this build doesn't know (newer protocol).
+    pub unknown: u64,
+    /// Envelopes with a protocol version below `MIN_PROTOCOL_VERSION`.
+    pub unsupported_version: u64,
+    /// Payloads that aren't valid envelopes at all.
+    pub malformed: u64,
+}
+
+/// A peer whose X25519 key we know, so we can derive a pairwise inbox.
+struct PeerSession {
+    /// Their X25519 public key hex.
+    x25519: String,
+    /// True once they sent us an encrypted task, which proves they know our
+    /// key and listen on the pairwise inbox for us.
+    heard_from: bool,
+    /// Unix time of the last task exchanged with them.
+    last_active: u64,
+}
 
 /// A2A node: announce, discover, send/receive tasks over Waku.
 pub struct WakuA2ANode<T: WakuTransport> {
@@ -12,6 +88,29 @@ pub struct WakuA2ANode<T: WakuTransport> {
     signing_key: SigningKey,
     /// Optional X25519 identity for encrypted sessions.
     identity: Option<AgentIdentity>,
+    /// Codec for outgoing envelopes. Incoming ones are auto-detected.
+    codec: Box<dyn Codec>,
+    /// Compression for outgoing envelopes (None = uncompressed).
+    compression: Option<Compression>,
+    /// Tasks drained from the inbox but not yet returned by `poll_tasks`.
+    pending_tasks: Mutex<Vec<Task>>,
+    /// Cards fetched by `catch_up` but not yet returned by `discover`.
+    pending_cards: Mutex<Vec<AgentCard>>,
+    /// Timestamp (Unix ns) of the newest inbox message processed.
+    last_seen: Mutex<Option<i64>>,
+    /// Status updates drained from the inbox but not yet read.
+    pending_updates: Mutex<UpdateBuffer>,
+    /// Next outgoing status update sequence number, keyed by task ID.
+    status_seq: Mutex<HashMap<String, u64>>,
+    /// Fragments of oversized incoming envelopes.
+    reassembler: Mutex<Reassembler>,
+    /// Encryption sessions keyed by the peer's secp256k1 public key.
+    sessions: Mutex<HashMap<String, PeerSession>>,
+    /// Inbox topics `drain_inbox` is subscribed to.
+    inbox_subscriptions: Mutex<HashSet<String>>,
+    decode_stats: Mutex<DecodeStats>,
+    /// Durable record of outgoing tasks, if enabled.
+    outbox: Option<Outbox>,
 }
 
 impl<T: WakuTransport> WakuA2ANode<T> {
@@ -32,14 +131,14 @@ impl<T: WakuTransport> WakuA2ANode<T> {
             capabilities,
             public_key,
             intro_bundle: None,
+            skills: Vec::new(),
+            protocol_versions: supported_protocol_versions(),
+            codecs: codec::supported_codecs(),
+            compression: compression::supported_compression(),
+            inbox: InboxScheme::Plain,
         };
 
-        Self {
-            card,
-            transport: SdsTransport::new(transport),
-            signing_key,
-            identity: None,
-        }
+        Self::from_parts(card, transport, signing_key, None)
     }
 
     /// Create a new node with encryption enabled.
@@ -67,14 +166,14 @@ impl<T: WakuTransport> WakuA2ANode<T> {
             capabilities,
             public_key,
             intro_bundle: Some(intro_bundle),
+            skills: Vec::new(),
+            protocol_versions: supported_protocol_versions(),
+            codecs: codec::supported_codecs(),
+            compression: compression::supported_compression(),
+            inbox: InboxScheme::Plain,
         };
 
-        Self {
-            card,
-            transport: SdsTransport::new(transport),
-            signing_key,
-            identity: Some(identity),
-        }
+        Self::from_parts(card, transport, signing_key, Some(identity))
     }
 
     /// Create a node from an existing signing key (no encryption).
@@ -99,16 +198,113 @@ impl<T: WakuTransport> WakuA2ANode<T> {
             capabilities,
             public_key,
             intro_bundle: None,
+            skills: Vec::new(),
+            protocol_versions: supported_protocol_versions(),
+            codecs: codec::supported_codecs(),
+            compression: compression::supported_compression(),
+            inbox: InboxScheme::Plain,
         };
 
+        Self::from_parts(card, transport, signing_key, None)
+    }
+
+    fn from_parts(
+        card: AgentCard,
+        transport: T,
+        signing_key: SigningKey,
+        identity: Option<AgentIdentity>,
+    ) -> Self {
         Self {
             card,
             transport: SdsTransport::new(transport),
             signing_key,
-            identity: None,
+            identity,
+            codec: Box::new(JsonCodec),
+            compression: None,
+            pending_tasks: Mutex::new(Vec::new()),
+            pending_cards: Mutex::new(Vec::new()),
+            last_seen: Mutex::new(None),
+            pending_updates: Mutex::new(UpdateBuffer::new()),
+            status_seq: Mutex::new(HashMap::new()),
+            reassembler: Mutex::new(Reassembler::new()),
+            sessions: Mutex::new(HashMap::new()),
+            inbox_subscriptions: Mutex::new(HashSet::new()),
+            decode_stats: Mutex::new(DecodeStats::default()),
+            outbox: None,
         }
     }
 
+    /// Advertise typed skills on this agent's card.
+    pub fn with_skills(mut self, skills: Vec<AgentSkill>) -> Self {
+        self.card.skills = skills;
+        self
+    }
+
+    /// Encode outgoing tasks, responses and status updates with `codec`
+    /// (e.g. `CborCodec` or `JsonRpcCodec`). Peers whose card doesn't list the
+    /// codec still get native JSON.
+    pub fn with_codec(mut self, codec: impl Codec + 'static) -> Self {
+        self.codec = Box::new(codec);
+        self
+    }
+
+    /// Compress outgoing tasks, responses and status updates for peers whose
+    /// card accepts `compression`.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Use a custom topic scheme, e.g. to join a namespace shared only
+    /// with agents of the same fleet or tenant.
+    pub fn with_topic_scheme(mut self, topics: TopicScheme) -> Self {
+        self.transport.set_topic_scheme(topics);
+        self
+    }
+
+    /// Listen on an inbox topic that doesn't reveal our public key (see
+    /// `topics::InboxScheme`). Advertised on our card; peers must know the
+    /// card, or a task from us, to reach us.
+    pub fn with_inbox_scheme(mut self, inbox: InboxScheme) -> Self {
+        self.card.inbox = inbox;
+        self
+    }
+
+    /// Record outgoing tasks in `outbox` before publishing them. Tasks that
+    /// can't be published, e.g. because nwaku is down, are kept there and
+    /// `send_task_to` returns `Ok(false)` instead of failing; call
+    /// `drain_outbox` or `run_outbox` to deliver them.
+    pub fn with_outbox(mut self, outbox: Outbox) -> Self {
+        self.outbox = Some(outbox);
+        self
+    }
+
+    pub fn outbox(&self) -> Option<&Outbox> {
+        self.outbox.as_ref()
+    }
+
+    /// Delivery status of a task sent through the outbox.
+    pub fn outbox_status(&self, task_id: &str) -> Option<OutboxStatus> {
+        self.outbox.as_ref()?.get(task_id).map(|e| e.status)
+    }
+
+    /// Resume `catch_up` from a timestamp saved from `last_seen` before a
+    /// restart.
+    pub fn with_last_seen(self, unix_nanos: i64) -> Self {
+        *self.last_seen.lock().unwrap() = Some(unix_nanos);
+        self
+    }
+
+    /// Timestamp (Unix ns) of the newest inbox message processed so far.
+    pub fn last_seen(&self) -> Option<i64> {
+        *self.last_seen.lock().unwrap()
+    }
+
+    /// Content topic layout this node publishes and listens on.
+    pub fn topics(&self) -> &TopicScheme {
+        self.transport.topic_scheme()
+    }
+
     /// Get this agent's public key hex string.
     pub fn pubkey(&self) -> &str {
         &self.card.public_key
@@ -119,6 +315,12 @@ impl<T: WakuTransport> WakuA2ANode<T> {
         &self.signing_key
     }
 
+    /// Counters of incoming payloads dropped as unknown, incompatible or
+    /// malformed since this node was created.
+    pub fn decode_stats(&self) -> DecodeStats {
+        *self.decode_stats.lock().unwrap()
+    }
+
     /// Get the encryption identity (if encryption is enabled).
     pub fn identity(&self) -> Option<&AgentIdentity> {
         self.identity.as_ref()
@@ -126,11 +328,13 @@ impl<T: WakuTransport> WakuA2ANode<T> {
 
     /// Broadcast this agent's card on the discovery topic.
     pub async fn announce(&self) -> Result<()> {
+        // Cards stay in JSON so agents with any codec can discover us
         let envelope = A2AEnvelope::AgentCard(self.card.clone());
-        let payload = serde_json::to_vec(&envelope).context("Failed to serialize AgentCard")?;
+        let payload = JsonCodec.encode(&envelope, PROTOCOL_VERSION)?;
+        let message = WakuMessage::new(&self.topics().discovery(), payload);
         self.transport
             .inner()
-            .publish(topics::DISCOVERY, &payload)
+            .publish_prioritized(&message, Priority::Control)
             .await
             .context("Failed to announce AgentCard")?;
         eprintln!("[node] Announced: {} ({})", self.card.name, self.pubkey());
@@ -141,19 +345,95 @@ impl<T: WakuTransport> WakuA2ANode<T> {
     pub async fn discover(&self) -> Result<Vec<AgentCard>> {
         self.transport
             .inner()
-            .subscribe(topics::DISCOVERY)
+            .subscribe(&self.topics().discovery())
             .await?;
-        let messages = self.transport.inner().poll(topics::DISCOVERY).await?;
-        let mut cards = Vec::new();
-        for msg in messages {
-            if let Ok(A2AEnvelope::AgentCard(card)) = serde_json::from_slice(&msg) {
+        let messages = self
+            .transport
+            .inner()
+            .poll(&self.topics().discovery())
+            .await?;
+        let mut cards = std::mem::take(&mut *self.pending_cards.lock().unwrap());
+        cards.extend(self.decode_cards(&messages));
+        // Keep the latest card of each agent
+        let mut seen = HashSet::new();
+        cards.reverse();
+        cards.retain(|card| seen.insert(card.public_key.clone()));
+        cards.reverse();
+        Ok(cards)
+    }
+
+    /// Agent cards of other agents in `messages`.
+    fn decode_cards(&self, messages: &[WakuMessage]) -> Vec<AgentCard> {
+        messages
+            .iter()
+            .filter_map(|msg| match self.decode(&msg.payload) {
                 // Don't include self
-                if card.public_key != self.card.public_key {
-                    cards.push(card);
+                Some(A2AEnvelope::AgentCard(card)) if card.public_key != self.card.public_key => {
+                    Some(card)
                 }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Fetch what was published while this node was offline: inbox messages
+    /// and agent cards since `last_seen`, or since `CATCH_UP_WINDOW` ago.
+    /// Rotating inboxes are fetched for every epoch in between, up to
+    /// `MAX_CATCH_UP` and `topics::MAX_LISTEN_EPOCHS` back. Missed tasks
+    /// are returned by the next `poll_tasks` or `incoming_tasks`, missed
+    /// cards by the next `discover`.
+    pub async fn catch_up(&self) -> Result<CatchUpStats> {
+        let now = now_nanos();
+        let earliest = now - MAX_CATCH_UP.as_nanos() as i64;
+        let since = match self.last_seen() {
+            Some(seen) if seen < earliest => {
+                eprintln!(
+                    "[node] Last seen over {}h ago, catching up on that much only",
+                    MAX_CATCH_UP.as_secs() / 3600
+                );
+                earliest
             }
+            Some(seen) => seen.min(now) + 1,
+            None => now - CATCH_UP_WINDOW.as_nanos() as i64,
+        };
+
+        let mut messages = Vec::new();
+        let since_secs = u64::try_from(since / 1_000_000_000).unwrap_or_default();
+        let now_secs = unix_now();
+        let covered = self.card.inbox.covered_since(since_secs, now_secs);
+        if covered > since_secs {
+            eprintln!(
+                "[node] Catching up on the last {} inbox epochs only, since {}s ago",
+                MAX_LISTEN_EPOCHS,
+                now_secs.saturating_sub(covered)
+            );
         }
-        Ok(cards)
+        for topic in self.inbox_topics_between(since_secs, now_secs) {
+            let query = HistoryQuery::new(&topic).since(since);
+            messages.extend(fetch_history(self.transport.inner(), &query).await?);
+        }
+        let messages = self.transport.dedup(messages);
+        let inbox_messages = messages.len();
+        self.process_inbox(messages).await;
+
+        let query = HistoryQuery::new(&self.topics().discovery()).since(since);
+        let history = fetch_history(self.transport.inner(), &query).await?;
+        let cards = self.decode_cards(&history);
+        let stats = CatchUpStats {
+            inbox_messages,
+            cards: cards.len(),
+        };
+        self.pending_cards.lock().unwrap().extend(cards);
+        Ok(stats)
+    }
+
+    /// Discover agents that advertise a skill matching `filter`.
+    pub async fn discover_by_skill(&self, filter: &SkillFilter) -> Result<Vec<AgentCard>> {
+        let cards = self.discover().await?;
+        Ok(cards
+            .into_iter()
+            .filter(|c| c.matches_skill(filter))
+            .collect())
     }
 
     /// Send a task to another agent. Uses SDS for reliable delivery.
@@ -163,74 +443,516 @@ impl<T: WakuTransport> WakuA2ANode<T> {
     }
 
     /// Send a task, optionally encrypting if recipient has an intro bundle.
+    ///
+    /// Over a rate-limited transport (`rate_limit::RateLimitedTransport`) this
+    /// waits for publish budget, and fails with `RateLimited` in the error
+    /// chain when the wait would be too long; with an outbox the task is
+    /// kept for later instead.
     pub async fn send_task_to(
         &self,
         task: &Task,
         recipient_card: Option<&AgentCard>,
     ) -> Result<bool> {
-        let topic = topics::task_topic(&task.to);
+        let inbox = recipient_card.map(|c| c.inbox).unwrap_or_default();
+        let mut task = task.clone();
+        task.reply_inbox = self.card.inbox;
 
-        let envelope = self.maybe_encrypt_task(task, recipient_card)?;
-        let payload = serde_json::to_vec(&envelope).context("Failed to serialize envelope")?;
+        let version = Self::negotiate_version(recipient_card)?;
+        let envelope = self.maybe_encrypt_task(&task, recipient_card)?;
+        let frames = self.encode_frames(&envelope, recipient_card, version, &task.id)?;
 
-        self.transport
-            .inner()
-            .subscribe(&topic)
-            .await?;
+        let Some(ref outbox) = self.outbox else {
+            let topic = self.peer_inbox_topic(&task.to, inbox);
+            return self.publish_task(&topic, &frames, &task.id).await;
+        };
+        let entry = OutboxEntry::new(&task.id, &task.to, inbox, frames);
+        outbox.insert(entry.clone())?;
+        match self.deliver(outbox, entry).await {
+            Ok(acked) => Ok(acked),
+            Err(e) => {
+                eprintln!("[node] Task {} kept in the outbox: {:#}", task.id, e);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Publish a task's frames with SDS; true if the recipient ACKed them.
+    async fn publish_task(&self, topic: &str, frames: &[Vec<u8>], task_id: &str) -> Result<bool> {
+        self.transport.inner().subscribe(topic).await?;
 
         let acked = self
             .transport
-            .publish_reliable(&topic, &payload, &task.id)
+            .publish_fragments_reliable(topic, frames, task_id)
             .await
             .context("SDS publish failed")?;
 
         if acked {
-            eprintln!("[node] Task {} sent and ACKed", task.id);
+            eprintln!("[node] Task {} sent and ACKed", task_id);
         } else {
-            eprintln!("[node] Task {} sent but no ACK received", task.id);
+            eprintln!("[node] Task {} sent but no ACK received", task_id);
         }
         Ok(acked)
     }
 
+    /// Publish an outbox entry claimed by the caller and record the outcome.
+    async fn deliver(&self, outbox: &Outbox, entry: OutboxEntry) -> Result<bool> {
+        let topic = self.peer_inbox_topic(&entry.to, entry.inbox);
+        let result = self
+            .publish_task(&topic, &entry.frames, &entry.task_id)
+            .await;
+        outbox.finish(&entry.task_id, |e| {
+            let rate_limited =
+                matches!(result, Err(ref err) if err.downcast_ref::<RateLimited>().is_some());
+            if !rate_limited {
+                e.attempts += 1;
+            }
+            e.status = match result {
+                Ok(true) => OutboxStatus::Acked,
+                Err(ref err) if err.downcast_ref::<Rejected>().is_some() => OutboxStatus::Failed,
+                _ if e.attempts >= outbox::MAX_DELIVERY_ATTEMPTS => OutboxStatus::Failed,
+                Ok(false) => OutboxStatus::Sent,
+                Err(_) => e.status,
+            };
+            e.last_error = result.as_ref().err().map(|err| format!("{:#}", err));
+        })?;
+        if outbox.get(&entry.task_id).map(|e| e.status) == Some(OutboxStatus::Failed) {
+            match result {
+                Err(ref err) => {
+                    eprintln!("[node] Task {} failed, giving up: {:#}", entry.task_id, err)
+                }
+                Ok(_) => eprintln!(
+                    "[node] Task {} not ACKed after {} attempts, giving up",
+                    entry.task_id,
+                    outbox::MAX_DELIVERY_ATTEMPTS
+                ),
+            }
+        }
+        result
+    }
+
+    /// Publish the outbox's unacknowledged tasks again, oldest first, e.g.
+    /// after a restart or once nwaku is back. A task that can't be published
+    /// doesn't hold up the ones after it, but a rate limit ends the drain.
+    /// Returns how many tasks were ACKed, or an error if none could be
+    /// published.
+    pub async fn drain_outbox(&self) -> Result<usize> {
+        let Some(ref outbox) = self.outbox else {
+            return Ok(0);
+        };
+        let mut acked = 0;
+        let mut published = false;
+        let mut first_err = None;
+        let mut claimed = outbox.claim_pending().into_iter();
+        while let Some(entry) = claimed.next() {
+            let task_id = entry.task_id.clone();
+            match self.deliver(outbox, entry).await {
+                Ok(true) => {
+                    acked += 1;
+                    published = true;
+                }
+                Ok(false) => published = true,
+                Err(e) if e.downcast_ref::<RateLimited>().is_some() => {
+                    for entry in claimed {
+                        outbox.release(&entry.task_id);
+                    }
+                    return Err(e);
+                }
+                Err(e) => {
+                    eprintln!("[node] Task {} not delivered: {:#}", task_id, e);
+                    first_err.get_or_insert(e);
+                }
+            }
+        }
+        match first_err {
+            Some(e) if !published => Err(e),
+            _ => Ok(acked),
+        }
+    }
+
+    /// Deliver the outbox for as long as the returned future runs: right
+    /// away, whenever `connection` (see `NwakuRestTransport::watch_connection`)
+    /// reports nwaku connected again, and every `OUTBOX_RETRY_INTERVAL`, or
+    /// once a rate limit's `retry_after` is up.
+    pub async fn run_outbox(&self, mut connection: Option<watch::Receiver<ConnectionState>>) {
+        loop {
+            let mut wait = OUTBOX_RETRY_INTERVAL;
+            match self.drain_outbox().await {
+                Ok(0) => {}
+                Ok(acked) => eprintln!("[node] Delivered {} task(s) from the outbox", acked),
+                Err(e) => {
+                    eprintln!("[node] Outbox not drained: {:#}", e);
+                    if let Some(limited) = e.downcast_ref::<RateLimited>() {
+                        wait = limited.retry_after;
+                    }
+                }
+            }
+
+            let reconnected = async {
+                let Some(ref mut connection) = connection else {
+                    return futures::future::pending().await;
+                };
+                loop {
+                    if connection.changed().await.is_err() {
+                        return futures::future::pending().await;
+                    }
+                    if *connection.borrow_and_update() == ConnectionState::Connected {
+                        return;
+                    }
+                }
+            };
+            tokio::select! {
+                () = reconnected => {}
+                () = tokio::time::sleep(wait) => {}
+            }
+        }
+    }
+
     /// Poll for incoming tasks addressed to this agent.
     /// Automatically decrypts encrypted tasks if this node has an identity.
     pub async fn poll_tasks(&self) -> Result<Vec<Task>> {
-        let topic = topics::task_topic(&self.card.public_key);
-        self.transport.inner().subscribe(&topic).await?;
-        let messages = self.transport.poll_dedup(&topic).await?;
-        let mut tasks = Vec::new();
+        self.drain_inbox().await?;
+        Ok(std::mem::take(&mut *self.pending_tasks.lock().unwrap()))
+    }
+
+    /// Stream incoming tasks as they arrive, without polling loops in the
+    /// caller. Built on `WakuTransport::subscribe_stream`, so backends with
+    /// push delivery hand tasks over as soon as they are received.
+    pub fn incoming_tasks(&self) -> impl Stream<Item = Task> + '_ {
+        struct State<'a> {
+            topics: Vec<String>,
+            messages: Option<SelectAll<BoxStream<'a, WakuMessage>>>,
+            refreshed: tokio::time::Instant,
+            ready: VecDeque<Task>,
+        }
+
+        let state = State {
+            topics: Vec::new(),
+            messages: None,
+            refreshed: tokio::time::Instant::now(),
+            ready: VecDeque::new(),
+        };
+
+        futures::stream::unfold(state, move |mut st| async move {
+            loop {
+                if let Some(task) = st.ready.pop_front() {
+                    return Some((task, st));
+                }
+                st.ready
+                    .extend(std::mem::take(&mut *self.pending_tasks.lock().unwrap()));
+                if !st.ready.is_empty() {
+                    continue;
+                }
+
+                let topics = self.inbox_listen_topics();
+                if st.messages.is_none() || topics != st.topics {
+                    if let Err(e) = self.sync_inbox_subscriptions(&topics).await {
+                        eprintln!("[node] Failed to update inbox subscriptions: {:#}", e);
+                    }
+                    let streams = topics
+                        .iter()
+                        .map(|topic| self.transport.inner().subscribe_stream(topic));
+                    st.messages = Some(futures::stream::select_all(streams));
+                    st.topics = topics;
+                }
+                let messages = st.messages.as_mut().expect("set above");
+
+                let deadline = st.refreshed + INBOX_REFRESH_INTERVAL;
+                match tokio::time::timeout_at(deadline, messages.next()).await {
+                    Ok(Some(first)) => {
+                        // Take whatever else is already buffered so fragments
+                        // of one message are acknowledged together
+                        let mut batch = vec![first];
+                        while let Some(Some(msg)) = messages.next().now_or_never() {
+                            batch.push(msg);
+                        }
+                        let batch = self.transport.dedup(batch);
+                        self.process_inbox(batch).await;
+                    }
+                    Ok(None) => return None,
+                    Err(_) => {
+                        st.refreshed = tokio::time::Instant::now();
+                        self.process_inbox(Vec::new()).await;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Stream status updates for a task this node sent.
+    ///
+    /// Updates are yielded in `seq` order. If an update is still missing
+    /// after `updates::GAP_TIMEOUT` while later ones have arrived, a
+    /// `TaskUpdateEvent::Gap` is yielded and the stream moves on. The stream
+    /// ends after the final update.
+    pub fn task_updates<'a>(&'a self, task_id: &str) -> impl Stream<Item = TaskUpdateEvent> + 'a {
+        struct State {
+            task_id: String,
+            sequencer: UpdateSequencer,
+            ready: VecDeque<TaskUpdateEvent>,
+            gap_since: Option<tokio::time::Instant>,
+        }
+
+        let state = State {
+            task_id: task_id.to_string(),
+            sequencer: UpdateSequencer::new(),
+            ready: VecDeque::new(),
+            gap_since: None,
+        };
+
+        futures::stream::unfold(state, move |mut st| async move {
+            loop {
+                if let Some(event) = st.ready.pop_front() {
+                    return Some((event, st));
+                }
+                if st.sequencer.is_finished() {
+                    self.pending_updates.lock().unwrap().take(&st.task_id);
+                    return None;
+                }
+
+                if let Err(e) = self.drain_inbox().await {
+                    eprintln!("[node] Failed to poll task updates: {}", e);
+                }
+                let updates = self.pending_updates.lock().unwrap().take(&st.task_id);
+                for update in updates {
+                    st.sequencer.push(update);
+                }
+                for update in st.sequencer.drain_ready() {
+                    st.ready.push_back(TaskUpdateEvent::Status(update));
+                }
+
+                if st.sequencer.has_gap() {
+                    let since = *st.gap_since.get_or_insert_with(tokio::time::Instant::now);
+                    if since.elapsed() >= updates::GAP_TIMEOUT {
+                        st.ready.extend(st.sequencer.skip_gap());
+                        for update in st.sequencer.drain_ready() {
+                            st.ready.push_back(TaskUpdateEvent::Status(update));
+                        }
+                        st.gap_since = None;
+                    }
+                } else {
+                    st.gap_since = None;
+                }
+
+                if st.ready.is_empty() {
+                    tokio::time::sleep(UPDATE_POLL_INTERVAL).await;
+                }
+            }
+        })
+    }
+
+    /// Publish a status update for a task this node is working on.
+    /// Updates go to the requester's task topic with a per-task sequence number,
+    /// encrypted if the requester has sent us encrypted tasks.
+    pub async fn send_status_update(
+        &self,
+        task: &Task,
+        state: TaskState,
+        text: Option<&str>,
+        is_final: bool,
+    ) -> Result<()> {
+        let seq = {
+            let mut seqs = self.status_seq.lock().unwrap();
+            let next = seqs.entry(task.id.clone()).or_insert(0);
+            let seq = *next;
+            *next += 1;
+            if is_final {
+                seqs.remove(&task.id);
+            }
+            seq
+        };
+
+        let update = TaskStatusUpdate::new(&task.id, seq, state, text, is_final);
+        let envelope = self.maybe_encrypt_update(&task.from, update)?;
+        let message_id = format!("{}/{}", task.id, seq);
+        let frames = self.encode_frames(&envelope, None, PROTOCOL_VERSION, &message_id)?;
+
+        let topic = self.peer_inbox_topic(&task.from, task.reply_inbox);
+        for frame in frames {
+            self.transport
+                .inner()
+                .publish(&topic, &frame)
+                .await
+                .context("Failed to send status update")?;
+        }
+        Ok(())
+    }
+
+    /// Poll the inbox topics once and sort their contents into the pending
+    /// task and status update buffers. Fragments are reassembled; incomplete
+    /// messages get a partial ACK so the sender only retransmits what's missing.
+    async fn drain_inbox(&self) -> Result<()> {
+        let topics = self.inbox_listen_topics();
+        self.sync_inbox_subscriptions(&topics).await?;
+        let mut messages = Vec::new();
+        for topic in &topics {
+            messages.extend(self.transport.poll_dedup(topic).await?);
+        }
+        self.process_inbox(messages).await;
+        Ok(())
+    }
+
+    /// Subscribe to inbox topics we don't listen on yet, and unsubscribe from
+    /// ones we no longer need: past epochs of a rotating inbox and pairwise
+    /// inboxes of forgotten sessions.
+    async fn sync_inbox_subscriptions(&self, topics: &[String]) -> Result<()> {
+        let (new, stale): (Vec<String>, Vec<String>) = {
+            let subscribed = self.inbox_subscriptions.lock().unwrap();
+            (
+                topics
+                    .iter()
+                    .filter(|t| !subscribed.contains(*t))
+                    .cloned()
+                    .collect(),
+                subscribed
+                    .iter()
+                    .filter(|t| !topics.contains(t))
+                    .cloned()
+                    .collect(),
+            )
+        };
+        for topic in new {
+            self.transport.inner().subscribe(&topic).await?;
+            self.inbox_subscriptions.lock().unwrap().insert(topic);
+        }
+        for topic in stale {
+            match self.transport.inner().unsubscribe(&topic).await {
+                Ok(()) => {
+                    self.inbox_subscriptions.lock().unwrap().remove(&topic);
+                }
+                Err(e) => eprintln!("[node] Failed to unsubscribe from {}: {:#}", topic, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sort deduplicated inbox messages into the pending buffers.
+    async fn process_inbox(&self, messages: Vec<WakuMessage>) {
+        // Clamped so a sender's clock running ahead can't make `catch_up`
+        // skip messages
+        let newest = messages.iter().filter_map(|m| m.timestamp).max();
+        if let Some(newest) = newest.map(|t| t.min(now_nanos())) {
+            let mut last_seen = self.last_seen.lock().unwrap();
+            *last_seen = Some(last_seen.map_or(newest, |seen| seen.max(newest)));
+        }
+
+        let now = tokio::time::Instant::now().into_std();
+        for message_id in self.reassembler.lock().unwrap().expire(now) {
+            eprintln!("[node] Gave up reassembling {}", message_id);
+        }
+
+        let mut incomplete = BTreeMap::new();
         for msg in messages {
-            if let Ok(envelope) = serde_json::from_slice::<A2AEnvelope>(&msg) {
-                match envelope {
-                    A2AEnvelope::Task(task) => {
-                        let _ = self.transport.send_ack(&task.id).await;
-                        tasks.push(task);
+            let (envelope, resent) = match self.decode(&msg.payload) {
+                Some(A2AEnvelope::Fragment(fragment)) => {
+                    (self.reassemble(fragment, now, &mut incomplete), false)
+                }
+                other => (other, self.transport.is_retransmission(&msg)),
+            };
+            if let Some(envelope) = envelope {
+                self.handle_envelope(envelope, resent).await;
+            }
+        }
+
+        for (message_id, received) in incomplete {
+            let _ = self
+                .transport
+                .send_fragment_ack(&message_id, &received)
+                .await;
+        }
+    }
+
+    /// Feed a fragment to the reassembler; returns the envelope once complete.
+    /// Messages still missing fragments are recorded in `incomplete`.
+    fn reassemble(
+        &self,
+        fragment: Fragment,
+        now: std::time::Instant,
+        incomplete: &mut BTreeMap<String, Vec<u32>>,
+    ) -> Option<A2AEnvelope> {
+        let message_id = fragment.message_id.clone();
+        let outcome = self.reassembler.lock().unwrap().insert(fragment, now);
+        match outcome {
+            FragmentOutcome::Complete(payload) => {
+                incomplete.remove(&message_id);
+                match self.decode(&payload) {
+                    Some(A2AEnvelope::Fragment(_)) => {
+                        self.decode_stats.lock().unwrap().malformed += 1;
+                        eprintln!("[node] Dropping nested fragment in {}", message_id);
+                        None
                     }
-                    A2AEnvelope::EncryptedTask {
-                        encrypted,
-                        sender_pubkey,
-                    } => {
-                        if let Some(ref identity) = self.identity {
-                            match self.decrypt_task(identity, &sender_pubkey, &encrypted) {
-                                Ok(task) => {
-                                    let _ = self.transport.send_ack(&task.id).await;
-                                    tasks.push(task);
-                                }
-                                Err(e) => {
-                                    eprintln!("[node] Failed to decrypt task: {}", e);
-                                }
+                    other => other,
+                }
+            }
+            FragmentOutcome::Pending(received) => {
+                incomplete.insert(message_id, received);
+                None
+            }
+            FragmentOutcome::Duplicate => None,
+            FragmentOutcome::Rejected(reason) => {
+                incomplete.remove(&message_id);
+                self.decode_stats.lock().unwrap().malformed += 1;
+                eprintln!("[node] Dropping fragment of {}: {}", message_id, reason);
+                None
+            }
+        }
+    }
+
+    /// Route a decoded inbox envelope to the pending task or update buffers.
+    /// Retransmitted tasks are only ACKed again.
+    async fn handle_envelope(&self, envelope: A2AEnvelope, resent: bool) {
+        match envelope {
+            A2AEnvelope::Task(task) => {
+                let _ = self.transport.send_ack(&task.id).await;
+                if !resent {
+                    self.pending_tasks.lock().unwrap().push(task);
+                }
+            }
+            A2AEnvelope::EncryptedTask {
+                encrypted,
+                sender_pubkey,
+                compression,
+            } => {
+                if let Some(ref identity) = self.identity {
+                    match self.decrypt_task(identity, &sender_pubkey, &encrypted, compression) {
+                        Ok(task) => {
+                            self.record_session(&task.from, &sender_pubkey, true);
+                            let _ = self.transport.send_ack(&task.id).await;
+                            if !resent {
+                                self.pending_tasks.lock().unwrap().push(task);
                             }
-                        } else {
-                            eprintln!(
-                                "[node] Received encrypted task but no identity configured"
-                            );
                         }
+                        Err(e) => {
+                            eprintln!("[node] Failed to decrypt task: {}", e);
+                        }
+                    }
+                } else {
+                    eprintln!("[node] Received encrypted task but no identity configured");
+                }
+            }
+            A2AEnvelope::TaskStatus(update) if !resent => {
+                let now = tokio::time::Instant::now().into_std();
+                self.pending_updates.lock().unwrap().push(update, now);
+            }
+            A2AEnvelope::EncryptedStatus {
+                encrypted,
+                sender_pubkey,
+            } if !resent => {
+                let Some(ref identity) = self.identity else {
+                    eprintln!("[node] Received encrypted status update but no identity configured");
+                    return;
+                };
+                match self.decrypt_update(identity, &sender_pubkey, &encrypted) {
+                    Ok(update) => {
+                        let now = tokio::time::Instant::now().into_std();
+                        self.pending_updates.lock().unwrap().push(update, now);
+                    }
+                    Err(e) => {
+                        eprintln!("[node] Failed to decrypt status update: {}", e);
                     }
-                    _ => {}
                 }
             }
+            _ => {}
         }
-        Ok(tasks)
     }
 
     /// Respond to a task: send back a completed task with result.
@@ -240,24 +962,34 @@ impl<T: WakuTransport> WakuA2ANode<T> {
         self.respond_to(task, result_text, None).await
     }
 
-    /// Respond to a task, optionally encrypting to the sender.
+    /// Respond to a task, optionally encrypting to the sender. Like
+    /// `send_task_to`, fails with `RateLimited` when a rate-limited
+    /// transport has no publish budget left.
     pub async fn respond_to(
         &self,
         task: &Task,
         result_text: &str,
         sender_card: Option<&AgentCard>,
     ) -> Result<()> {
-        let response = task.respond(result_text);
-        let topic = topics::task_topic(&response.to);
+        let mut response = task.respond(result_text);
+        response.reply_inbox = self.card.inbox;
+        let inbox = sender_card
+            .map(|c| c.inbox)
+            .filter(|i| !i.is_plain())
+            .unwrap_or(task.reply_inbox);
+        let topic = self.peer_inbox_topic(&response.to, inbox);
 
+        let version = Self::negotiate_version(sender_card)?;
         let envelope = self.maybe_encrypt_task(&response, sender_card)?;
-        let payload = serde_json::to_vec(&envelope)?;
+        let frames = self.encode_frames(&envelope, sender_card, version, &response.id)?;
 
-        self.transport
-            .inner()
-            .publish(&topic, &payload)
-            .await
-            .context("Failed to send response")?;
+        for frame in frames {
+            self.transport
+                .inner()
+                .publish(&topic, &frame)
+                .await
+                .context("Failed to send response")?;
+        }
 
         eprintln!("[node] Responded to task {}", task.id);
         Ok(())
@@ -270,6 +1002,186 @@ impl<T: WakuTransport> WakuA2ANode<T> {
         Ok(task)
     }
 
+    /// Inbox topic of a peer listening under `inbox`. Uses the pairwise
+    /// inbox once the peer has proven it shares a session with us.
+    fn peer_inbox_topic(&self, peer: &str, inbox: InboxScheme) -> String {
+        if inbox.is_plain() {
+            return self.topics().task_topic(peer);
+        }
+        let now = unix_now();
+        match self.pair_secret(peer, true) {
+            Some(secret) => self.topics().pair_inbox_topic(&secret, peer, &inbox, now),
+            None => self.topics().inbox_topic(peer, &inbox, now),
+        }
+    }
+
+    /// Topics our own inbox is reachable on right now, including pairwise
+    /// inboxes for every peer we share a session with. Idle sessions are
+    /// forgotten first.
+    fn inbox_listen_topics(&self) -> Vec<String> {
+        let now = unix_now();
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|_, s| now.saturating_sub(s.last_active) < SESSION_IDLE_TIMEOUT.as_secs());
+        self.inbox_topics_between(now, now)
+    }
+
+    /// Topics our own inbox, including pairwise inboxes, used at any time
+    /// between two Unix times.
+    fn inbox_topics_between(&self, from_secs: u64, to_secs: u64) -> Vec<String> {
+        let inbox = self.card.inbox;
+        let mut topics =
+            self.topics()
+                .inbox_topics_between(self.pubkey(), &inbox, from_secs, to_secs);
+        if !inbox.is_plain() {
+            let peers: Vec<String> = self.sessions.lock().unwrap().keys().cloned().collect();
+            for peer in peers {
+                if let Some(secret) = self.pair_secret(&peer, false) {
+                    topics.extend(self.topics().pair_inbox_topics_between(
+                        &secret,
+                        self.pubkey(),
+                        &inbox,
+                        from_secs,
+                        to_secs,
+                    ));
+                }
+            }
+        }
+        topics
+    }
+
+    /// Remember a peer's X25519 key. `heard_from` sticks once set for a key.
+    fn record_session(&self, peer: &str, x25519: &str, heard_from: bool) {
+        let now = unix_now();
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .entry(peer.to_string())
+            .or_insert_with(|| PeerSession {
+                x25519: x25519.to_string(),
+                heard_from,
+                last_active: now,
+            });
+        session.last_active = now;
+        if session.x25519 != x25519 {
+            session.x25519 = x25519.to_string();
+            session.heard_from = heard_from;
+        } else {
+            session.heard_from |= heard_from;
+        }
+    }
+
+    /// Secret shared with a peer for pairwise inbox topics.
+    fn pair_secret(&self, peer: &str, require_heard_from: bool) -> Option<[u8; 32]> {
+        let identity = self.identity.as_ref()?;
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .get(peer)
+            .filter(|s| s.heard_from || !require_heard_from)?;
+        let their_pubkey = AgentIdentity::parse_public_key(&session.x25519).ok()?;
+        Some(identity.shared_key(&their_pubkey).derive("inbox"))
+    }
+
+    /// Encode an envelope for a peer into one or more Waku payloads:
+    /// compressed if both sides support it, then fragmented if it exceeds
+    /// `fragment::MAX_MESSAGE_SIZE`.
+    fn encode_frames(
+        &self,
+        envelope: &A2AEnvelope,
+        card: Option<&AgentCard>,
+        version: u32,
+        message_id: &str,
+    ) -> Result<Vec<Vec<u8>>> {
+        let codec = self.codec_for(card);
+        let mut payload = codec.encode(envelope, version)?;
+        // Encrypted payloads don't compress; tasks are compressed before encryption
+        if let (Some(compression), false) = (
+            self.compression_for(card),
+            matches!(
+                envelope,
+                A2AEnvelope::EncryptedTask { .. } | A2AEnvelope::EncryptedStatus { .. }
+            ),
+        ) {
+            if let Some(compressed) = codec::compress(codec, compression, &payload, version)? {
+                payload = compressed;
+            }
+        }
+        if !fragment::needs_fragmentation(&payload) {
+            return Ok(vec![payload]);
+        }
+        let fragments = fragment::split(&payload, message_id);
+        eprintln!(
+            "[node] Splitting {} byte envelope {} into {} fragments",
+            payload.len(),
+            message_id,
+            fragments.len()
+        );
+        fragments
+            .into_iter()
+            .map(|f| codec.encode(&A2AEnvelope::Fragment(f), version))
+            .collect()
+    }
+
+    /// Codec to use towards a peer: ours if their card lists it (or their
+    /// card is unknown), native JSON otherwise.
+    fn codec_for(&self, card: Option<&AgentCard>) -> &dyn Codec {
+        match card {
+            Some(card) if !card.supports_codec(self.codec.id()) => &JsonCodec,
+            _ => self.codec.as_ref(),
+        }
+    }
+
+    /// Compression to use towards a peer: ours if their card accepts it (or
+    /// their card is unknown), none otherwise.
+    fn compression_for(&self, card: Option<&AgentCard>) -> Option<Compression> {
+        self.compression
+            .filter(|c| card.is_none_or(|card| card.supports_compression(*c)))
+    }
+
+    /// Protocol version to use towards a peer: the highest one both sides
+    /// speak, or our current version if the peer's card is unknown.
+    fn negotiate_version(card: Option<&AgentCard>) -> Result<u32> {
+        match card {
+            Some(card) => card.negotiate_version().with_context(|| {
+                format!(
+                    "No common protocol version with {} (they speak {:?}, we speak {:?})",
+                    card.name,
+                    card.supported_versions(),
+                    supported_protocol_versions()
+                )
+            }),
+            None => Ok(PROTOCOL_VERSION),
+        }
+    }
+
+    /// Decode an incoming payload. Payloads that can't be used are logged and
+    /// counted in `decode_stats` instead of being dropped silently.
+    fn decode(&self, payload: &[u8]) -> Option<A2AEnvelope> {
+        match A2AEnvelope::decode(payload) {
+            Ok(A2AEnvelope::Unknown(raw)) => {
+                self.decode_stats.lock().unwrap().unknown += 1;
+                eprintln!(
+                    "[node] Ignoring unknown envelope type {:?} (protocol v{})",
+                    raw.get("type").and_then(|t| t.as_str()).unwrap_or("?"),
+                    A2AEnvelope::wire_version(&raw)
+                );
+                None
+            }
+            Ok(envelope) => Some(envelope),
+            Err(e) => {
+                {
+                    let mut stats = self.decode_stats.lock().unwrap();
+                    match e {
+                        EnvelopeError::UnsupportedVersion(_) => stats.unsupported_version += 1,
+                        EnvelopeError::Malformed(_) => stats.malformed += 1,
+                    }
+                }
+                eprintln!("[node] Dropping incoming payload: {}", e);
+                None
+            }
+        }
+    }
+
     /// Encrypt a task if both sides have encryption identities.
     fn maybe_encrypt_task(
         &self,
@@ -279,34 +1191,101 @@ impl<T: WakuTransport> WakuA2ANode<T> {
         if let (Some(ref identity), Some(card)) = (&self.identity, recipient_card) {
             if let Some(ref bundle) = card.intro_bundle {
                 let their_pubkey = AgentIdentity::parse_public_key(&bundle.agent_pubkey)?;
+                self.record_session(&card.public_key, &bundle.agent_pubkey, false);
                 let session_key = identity.shared_key(&their_pubkey);
                 let task_json = serde_json::to_vec(task)?;
-                let encrypted = session_key.encrypt(&task_json)?;
+                let (plaintext, compression) = match self.compression_for(Some(card)) {
+                    Some(c) if task_json.len() >= MIN_COMPRESS_SIZE => {
+                        let compressed = c.compress(&task_json)?;
+                        if compressed.len() < task_json.len() {
+                            (compressed, Some(c))
+                        } else {
+                            (task_json, None)
+                        }
+                    }
+                    _ => (task_json, None),
+                };
+                let encrypted = session_key.encrypt(&plaintext)?;
                 return Ok(A2AEnvelope::EncryptedTask {
                     encrypted,
                     sender_pubkey: identity.public_key_hex(),
+                    compression,
                 });
             }
         }
         Ok(A2AEnvelope::Task(task.clone()))
     }
 
+    /// Encrypt a status update to the requester if they have sent us
+    /// encrypted tasks, so progress is as private as the task itself.
+    fn maybe_encrypt_update(
+        &self,
+        requester: &str,
+        update: TaskStatusUpdate,
+    ) -> Result<A2AEnvelope> {
+        let Some(ref identity) = self.identity else {
+            return Ok(A2AEnvelope::TaskStatus(update));
+        };
+        let x25519 = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get(requester)
+            .filter(|s| s.heard_from)
+            .map(|s| s.x25519.clone());
+        let Some(x25519) = x25519 else {
+            return Ok(A2AEnvelope::TaskStatus(update));
+        };
+        let their_pubkey = AgentIdentity::parse_public_key(&x25519)?;
+        let encrypted = identity
+            .shared_key(&their_pubkey)
+            .encrypt(&serde_json::to_vec(&update)?)?;
+        Ok(A2AEnvelope::EncryptedStatus {
+            encrypted,
+            sender_pubkey: identity.public_key_hex(),
+        })
+    }
+
+    /// Decrypt an encrypted status update.
+    fn decrypt_update(
+        &self,
+        identity: &AgentIdentity,
+        sender_pubkey_hex: &str,
+        encrypted: &waku_a2a_crypto::EncryptedPayload,
+    ) -> Result<TaskStatusUpdate> {
+        let their_pubkey = AgentIdentity::parse_public_key(sender_pubkey_hex)?;
+        let plaintext = identity.shared_key(&their_pubkey).decrypt(encrypted)?;
+        serde_json::from_slice(&plaintext).context("Failed to deserialize decrypted status update")
+    }
+
     /// Decrypt an encrypted task payload.
     fn decrypt_task(
         &self,
         identity: &AgentIdentity,
         sender_pubkey_hex: &str,
         encrypted: &waku_a2a_crypto::EncryptedPayload,
+        compression: Option<Compression>,
     ) -> Result<Task> {
         let their_pubkey = AgentIdentity::parse_public_key(sender_pubkey_hex)?;
         let session_key = identity.shared_key(&their_pubkey);
-        let plaintext = session_key.decrypt(encrypted)?;
+        let mut plaintext = session_key.decrypt(encrypted)?;
+        if let Some(c) = compression {
+            plaintext = c.decompress(&plaintext)?;
+        }
         let task: Task =
             serde_json::from_slice(&plaintext).context("Failed to deserialize decrypted task")?;
         Ok(task)
     }
 }
 
+/// Seconds since the Unix epoch, for rotating inbox topics.
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
 /// Platform-appropriate RNG.
 fn rand_core() -> k256::elliptic_curve::rand_core::OsRng {
     k256::elliptic_curve::rand_core::OsRng
@@ -315,57 +1294,29 @@ fn rand_core() -> k256::elliptic_curve::rand_core::OsRng {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use async_trait::async_trait;
-    use std::sync::{Arc, Mutex};
-
-    struct MockTransport {
-        published: Arc<Mutex<Vec<(String, Vec<u8>)>>>,
-        poll_responses: Arc<Mutex<Vec<(String, Vec<u8>)>>>,
-    }
-
-    impl MockTransport {
-        fn new() -> Self {
-            Self {
-                published: Arc::new(Mutex::new(Vec::new())),
-                poll_responses: Arc::new(Mutex::new(Vec::new())),
-            }
-        }
+    use futures::StreamExt;
+    use waku_a2a_core::codec::CborCodec;
+    use waku_a2a_core::jsonrpc::JsonRpcCodec;
+    use waku_a2a_core::topics;
+    use waku_a2a_transport::sim::{LinkConfig, SimNetwork};
 
-        fn inject(&self, topic: &str, payload: Vec<u8>) {
-            let mut r = self.poll_responses.lock().unwrap();
-            r.push((topic.to_string(), payload));
-        }
+    /// Topic and payload of everything node `name` published.
+    fn published(network: &SimNetwork, name: &str) -> Vec<(String, Vec<u8>)> {
+        network
+            .published_by(name)
+            .into_iter()
+            .map(|m| (m.content_topic, m.payload))
+            .collect()
     }
 
-    #[async_trait]
-    impl WakuTransport for MockTransport {
-        async fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
-            let mut p = self.published.lock().unwrap();
-            p.push((topic.to_string(), payload.to_vec()));
-            Ok(())
-        }
-        async fn subscribe(&self, _topic: &str) -> Result<()> {
-            Ok(())
-        }
-        async fn poll(&self, topic: &str) -> Result<Vec<Vec<u8>>> {
-            let mut r = self.poll_responses.lock().unwrap();
-            let mut result = Vec::new();
-            let mut remaining = Vec::new();
-            for (t, p) in r.drain(..) {
-                if t == topic {
-                    result.push(p);
-                } else {
-                    remaining.push((t, p));
-                }
-            }
-            *r = remaining;
-            Ok(result)
-        }
+    /// Hand node `to` a message, whether or not it listens on `topic`.
+    fn deliver(network: &SimNetwork, to: &str, topic: &str, payload: Vec<u8>) {
+        network.deliver(to, WakuMessage::new(topic, payload));
     }
 
     #[test]
     fn test_node_creation() {
-        let transport = MockTransport::new();
+        let transport = SimNetwork::new(1).join("test");
         let node = WakuA2ANode::new("test", "test agent", vec!["text".into()], transport);
         assert_eq!(node.card.name, "test");
         assert!(!node.pubkey().is_empty());
@@ -373,11 +1324,15 @@ mod tests {
         assert_eq!(node.pubkey().len(), 66);
         assert!(node.identity().is_none());
         assert!(node.card.intro_bundle.is_none());
+        assert!(node.card.skills.is_empty());
+
+        let node = node.with_skills(vec![AgentSkill::new("echo", "Echo", "Echoes text")]);
+        assert!(node.card.skill("echo").is_some());
     }
 
     #[test]
     fn test_encrypted_node_creation() {
-        let transport = MockTransport::new();
+        let transport = SimNetwork::new(1).join("test");
         let node =
             WakuA2ANode::new_encrypted("test", "test agent", vec!["text".into()], transport);
         assert!(node.identity().is_some());
@@ -390,13 +1345,17 @@ mod tests {
 
     #[tokio::test]
     async fn test_announce() {
-        let transport = MockTransport::new();
-        let published = transport.published.clone();
-        let node = WakuA2ANode::new("echo", "echo agent", vec!["text".into()], transport);
+        let network = SimNetwork::new(1);
+        let node = WakuA2ANode::new(
+            "echo",
+            "echo agent",
+            vec!["text".into()],
+            network.join("echo"),
+        );
 
         node.announce().await.unwrap();
 
-        let msgs = published.lock().unwrap();
+        let msgs = published(&network, "echo");
         assert_eq!(msgs.len(), 1);
         assert_eq!(msgs[0].0, topics::DISCOVERY);
 
@@ -412,7 +1371,8 @@ mod tests {
 
     #[tokio::test]
     async fn test_discover() {
-        let transport = MockTransport::new();
+        let network = SimNetwork::new(1);
+        let transport = network.join("me");
         let other_card = AgentCard {
             name: "other".to_string(),
             description: "other agent".to_string(),
@@ -420,10 +1380,15 @@ mod tests {
             capabilities: vec!["code".to_string()],
             public_key: "02deadbeef".to_string(),
             intro_bundle: None,
+            skills: Vec::new(),
+            protocol_versions: supported_protocol_versions(),
+            codecs: codec::supported_codecs(),
+            compression: compression::supported_compression(),
+            inbox: InboxScheme::Plain,
         };
         let envelope = A2AEnvelope::AgentCard(other_card.clone());
         let payload = serde_json::to_vec(&envelope).unwrap();
-        transport.inject(topics::DISCOVERY, payload);
+        deliver(&network, "me", topics::DISCOVERY, payload);
 
         let node = WakuA2ANode::new("me", "my agent", vec![], transport);
         let cards = node.discover().await.unwrap();
@@ -432,14 +1397,1060 @@ mod tests {
         assert_eq!(cards[0].name, "other");
     }
 
+    #[tokio::test]
+    async fn test_catch_up_from_store() {
+        let network = SimNetwork::new(1);
+        let node = WakuA2ANode::new("me", "my agent", vec![], network.join("me"));
+        let now = now_nanos();
+
+        let task = Task::new("02aa", node.pubkey(), "sent while offline");
+        let mut other = node.card.clone();
+        other.name = "other".to_string();
+        other.public_key = "02deadbeef".to_string();
+        let stored = |topic: &str, envelope: &A2AEnvelope, timestamp: i64| {
+            WakuMessage::new(topic, serde_json::to_vec(envelope).unwrap()).with_timestamp(timestamp)
+        };
+        for message in [
+            stored(
+                &topics::task_topic(node.pubkey()),
+                &A2AEnvelope::Task(task.clone()),
+                now - 1_000,
+            ),
+            stored(
+                topics::DISCOVERY,
+                &A2AEnvelope::AgentCard(other),
+                now - 2_000,
+            ),
+            stored(
+                topics::DISCOVERY,
+                &A2AEnvelope::AgentCard(node.card.clone()),
+                now - 2_000,
+            ),
+            // Older than the catch-up window
+            stored(
+                &topics::task_topic(node.pubkey()),
+                &A2AEnvelope::Task(Task::new("02aa", node.pubkey(), "stale")),
+                now - CATCH_UP_WINDOW.as_nanos() as i64 - 1,
+            ),
+        ] {
+            network.store(message);
+        }
+
+        let stats = node.catch_up().await.unwrap();
+        assert_eq!(
+            stats,
+            CatchUpStats {
+                inbox_messages: 1,
+                cards: 1,
+            }
+        );
+        assert_eq!(node.last_seen(), Some(now - 1_000));
+        let tasks = node.poll_tasks().await.unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].id, task.id);
+        let cards = node.discover().await.unwrap();
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].name, "other");
+
+        // A second catch-up starts at the last message and skips what it saw
+        assert_eq!(node.catch_up().await.unwrap().inbox_messages, 0);
+        assert!(node.poll_tasks().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_catch_up_across_epochs() {
+        let network = SimNetwork::new(1);
+        let inbox = InboxScheme::Rotating { epoch_secs: 60 };
+        let now = now_nanos();
+        let seen = now - 600 * 1_000_000_000;
+        let node = WakuA2ANode::new("me", "my agent", vec![], network.join("me"))
+            .with_inbox_scheme(inbox)
+            .with_last_seen(seen);
+        // A task on the inbox topic of `sent_at`, stamped `timestamp`
+        let stored = |text: &str, sent_at: i64, timestamp: i64| {
+            let task = Task::new("02aa", node.pubkey(), text);
+            let secs = (sent_at / 1_000_000_000) as u64;
+            let topic = node.topics().inbox_topic(node.pubkey(), &inbox, secs);
+            let payload = serde_json::to_vec(&A2AEnvelope::Task(task)).unwrap();
+            WakuMessage::new(&topic, payload).with_timestamp(timestamp)
+        };
+        for message in [
+            // Already processed before going offline
+            stored("seen", seen, seen),
+            // Several epochs before the current listen window
+            stored("missed", seen + 1, seen + 1),
+            // From a sender whose clock runs an hour ahead
+            stored("future", now, now + 3600 * 1_000_000_000),
+        ] {
+            network.store(message);
+        }
+
+        assert_eq!(node.catch_up().await.unwrap().inbox_messages, 2);
+        let mut texts: Vec<String> = node
+            .poll_tasks()
+            .await
+            .unwrap()
+            .iter()
+            .filter_map(|t| t.text().map(str::to_string))
+            .collect();
+        texts.sort();
+        assert_eq!(texts, vec!["future", "missed"]);
+        assert!(node.last_seen().unwrap() <= now_nanos());
+
+        // The future timestamp doesn't hide what arrives next
+        let later = now_nanos();
+        network.store(stored("later", later, later));
+        assert_eq!(node.catch_up().await.unwrap().inbox_messages, 1);
+    }
+
+    #[tokio::test]
+    async fn test_catch_up_from_far_past_is_bounded() {
+        let network = SimNetwork::new(1);
+        let inbox = InboxScheme::Rotating { epoch_secs: 1 };
+        let now = now_nanos();
+        let node = WakuA2ANode::new("me", "my agent", vec![], network.join("me"))
+            .with_inbox_scheme(inbox)
+            .with_last_seen(now - 365 * 24 * 3600 * 1_000_000_000);
+        let stored = |text: &str, sent_at: i64| {
+            let task = Task::new("02aa", node.pubkey(), text);
+            let secs = (sent_at / 1_000_000_000) as u64;
+            let topic = node.topics().inbox_topic(node.pubkey(), &inbox, secs);
+            let payload = serde_json::to_vec(&A2AEnvelope::Task(task)).unwrap();
+            WakuMessage::new(&topic, payload).with_timestamp(sent_at)
+        };
+        let recent = now - 10 * 1_000_000_000;
+        network.store(stored("recent", recent));
+        // Beyond the newest `MAX_LISTEN_EPOCHS` epochs
+        network.store(stored(
+            "old",
+            now - (MAX_LISTEN_EPOCHS as i64 + 10) * 1_000_000_000,
+        ));
+
+        assert_eq!(node.catch_up().await.unwrap().inbox_messages, 1);
+        let tasks = node.poll_tasks().await.unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].text(), Some("recent"));
+    }
+
+    #[tokio::test]
+    async fn test_incompatible_messages_counted() {
+        let network = SimNetwork::new(1);
+        let transport = network.join("me");
+        deliver(
+            &network,
+            "me",
+            topics::DISCOVERY,
+            br#"{"v":9,"type":"agent_card_v2","name":"future"}"#.to_vec(),
+        );
+        deliver(
+            &network,
+            "me",
+            topics::DISCOVERY,
+            br#"{"v":0,"type":"agent_card"}"#.to_vec(),
+        );
+        deliver(&network, "me", topics::DISCOVERY, b"garbage".to_vec());
+
+        let node = WakuA2ANode::new("me", "my agent", vec![], transport);
+        assert!(node.discover().await.unwrap().is_empty());
+        assert_eq!(
+            node.decode_stats(),
+            DecodeStats {
+                unknown: 1,
+                unsupported_version: 1,
+                malformed: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_task_negotiates_version() {
+        let network = SimNetwork::new(1);
+        let node = WakuA2ANode::new("me", "my agent", vec![], network.join("me"));
+        assert_eq!(node.card.protocol_versions, supported_protocol_versions());
+
+        let mut card = node.card.clone();
+        card.public_key = "02peer".to_string();
+        card.protocol_versions = vec![PROTOCOL_VERSION + 1];
+        let task = Task::new(node.pubkey(), &card.public_key, "hi");
+        assert!(node.send_task_to(&task, Some(&card)).await.is_err());
+        assert!(network.published_by("me").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_codec_falls_back_to_json() {
+        let network = SimNetwork::new(1);
+        let node = WakuA2ANode::new("worker", "worker agent", vec![], network.join("worker"))
+            .with_codec(CborCodec);
+        assert!(node.card.supports_codec("cbor"));
+        let task = Task::new("02requester", node.pubkey(), "hello");
+
+        let mut requester = node.card.clone();
+        requester.public_key = "02requester".to_string();
+        node.respond_to(&task, "cbor", Some(&requester))
+            .await
+            .unwrap();
+
+        requester.codecs = vec![];
+        node.respond_to(&task, "json", Some(&requester))
+            .await
+            .unwrap();
+
+        let msgs = published(&network, "worker");
+        assert!(msgs[0].1.starts_with(&[0xd9, 0xd9, 0xf7]));
+        assert!(serde_json::from_slice::<serde_json::Value>(&msgs[1].1).is_ok());
+        for (expected, (_, payload)) in ["cbor", "json"].iter().zip(msgs.iter()) {
+            let Ok(A2AEnvelope::Task(response)) = A2AEnvelope::decode(payload) else {
+                panic!("expected task response");
+            };
+            assert_eq!(response.result_text(), Some(*expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retransmitted_task_delivered_once() {
+        let network = SimNetwork::new(1);
+        let node = WakuA2ANode::new("worker", "worker agent", vec![], network.join("worker"));
+        let task = Task::new("02requester", node.pubkey(), "hello");
+
+        // CBOR carries no JSON `id` for `poll_dedup`; the resent copy is
+        // only ACKed again
+        let payload = CborCodec
+            .encode(&A2AEnvelope::Task(task.clone()), PROTOCOL_VERSION)
+            .unwrap();
+        let message = WakuMessage::new(&topics::task_topic(node.pubkey()), payload);
+        network.deliver("worker", message.clone().with_timestamp(1));
+        assert_eq!(node.poll_tasks().await.unwrap(), vec![task.clone()]);
+        network.deliver("worker", message.with_timestamp(2));
+        assert!(node.poll_tasks().await.unwrap().is_empty());
+
+        let ack_topic = topics::ack_topic(&task.id);
+        let acks = published(&network, "worker")
+            .iter()
+            .filter(|(topic, _)| topic == &ack_topic)
+            .count();
+        assert_eq!(acks, 2);
+    }
+
+    #[tokio::test]
+    async fn test_large_response_is_fragmented() {
+        let network = SimNetwork::new(1);
+        let worker = WakuA2ANode::new("worker", "worker agent", vec![], network.join("worker"))
+            .with_codec(CborCodec);
+        let requester = WakuA2ANode::new(
+            "requester",
+            "requester agent",
+            vec![],
+            network.join("requester"),
+        );
+
+        let task = Task::new(requester.pubkey(), worker.pubkey(), "write a book");
+        let book = "All work and no play. ".repeat(20_000);
+        worker.respond(&task, &book).await.unwrap();
+
+        let mut frames = published(&network, "worker");
+        assert!(frames.len() > 1);
+        assert!(frames
+            .iter()
+            .all(|(_, f)| f.len() <= fragment::MAX_MESSAGE_SIZE));
+
+        // Out of order, first batch incomplete: partial ACK lists what arrived
+        let last = frames.remove(0);
+        frames.reverse();
+        for (topic, frame) in frames {
+            deliver(&network, "requester", &topic, frame);
+        }
+        assert!(requester.poll_tasks().await.unwrap().is_empty());
+        {
+            let acks = published(&network, "requester");
+            let (topic, ack) = acks.last().unwrap();
+            assert_eq!(topic, &topics::ack_topic(&task.id));
+            let ack: serde_json::Value = serde_json::from_slice(ack).unwrap();
+            assert!(!ack["fragments"].as_array().unwrap().contains(&0.into()));
+        }
+
+        deliver(&network, "requester", &last.0, last.1);
+        let tasks = requester.poll_tasks().await.unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].result_text(), Some(book.as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_compressed_responses() {
+        let network = SimNetwork::new(1);
+        let worker =
+            WakuA2ANode::new_encrypted("worker", "worker agent", vec![], network.join("worker"))
+                .with_compression(Compression::Zstd);
+        let requester = WakuA2ANode::new_encrypted(
+            "requester",
+            "requester agent",
+            vec![],
+            network.join("requester"),
+        );
+        assert_eq!(requester.card.compression, vec!["zstd"]);
+
+        let task = Task::new(requester.pubkey(), worker.pubkey(), "summarize");
+        let summary = "The quick brown fox jumps over the lazy dog. ".repeat(200);
+
+        // Encrypted: compressed before encryption
+        worker
+            .respond_to(&task, &summary, Some(&requester.card))
+            .await
+            .unwrap();
+        // Plaintext: wrapped in a compressed envelope
+        let mut plain_card = requester.card.clone();
+        plain_card.intro_bundle = None;
+        worker
+            .respond_to(&task, &summary, Some(&plain_card))
+            .await
+            .unwrap();
+        // Legacy peer: sent as is
+        plain_card.compression = vec![];
+        worker
+            .respond_to(&task, &summary, Some(&plain_card))
+            .await
+            .unwrap();
+
+        let frames = published(&network, "worker");
+        let raw: Vec<serde_json::Value> = frames
+            .iter()
+            .map(|(_, p)| serde_json::from_slice(p).unwrap())
+            .collect();
+        assert_eq!(raw[0]["type"], "encrypted_task");
+        assert_eq!(raw[0]["compression"], "zstd");
+        assert_eq!(raw[1]["type"], "compressed");
+        assert_eq!(raw[2]["type"], "task");
+        assert!(frames[0].1.len() < frames[2].1.len() / 4);
+        assert!(frames[1].1.len() < frames[2].1.len() / 4);
+
+        for (topic, frame) in frames {
+            deliver(&network, "requester", &topic, frame);
+        }
+        let tasks = requester.poll_tasks().await.unwrap();
+        assert_eq!(tasks.len(), 3);
+        for task in tasks {
+            assert_eq!(task.result_text(), Some(summary.as_str()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_topic_namespace_isolation() {
+        let staging = TopicScheme::namespaced("staging").unwrap();
+        let network = SimNetwork::new(1);
+        let node = WakuA2ANode::new("me", "my agent", vec![], network.join("me"))
+            .with_topic_scheme(staging.clone());
+        node.announce().await.unwrap();
+        assert_eq!(published(&network, "me")[0].0, staging.discovery());
+
+        let peer = WakuA2ANode::new("peer", "peer agent", vec![], network.join("peer"));
+        let card = serde_json::to_vec(&A2AEnvelope::AgentCard(peer.card.clone())).unwrap();
+        let task = Task::new(peer.pubkey(), node.pubkey(), "hi");
+        let task = serde_json::to_vec(&A2AEnvelope::Task(task)).unwrap();
+
+        // Traffic on the default topics is invisible to a namespaced node
+        deliver(&network, "me", topics::DISCOVERY, card.clone());
+        deliver(
+            &network,
+            "me",
+            &topics::task_topic(node.pubkey()),
+            task.clone(),
+        );
+        assert!(node.discover().await.unwrap().is_empty());
+        assert!(node.poll_tasks().await.unwrap().is_empty());
+
+        deliver(&network, "me", &staging.discovery(), card);
+        deliver(&network, "me", &staging.task_topic(node.pubkey()), task);
+        assert_eq!(node.discover().await.unwrap().len(), 1);
+        assert_eq!(node.poll_tasks().await.unwrap().len(), 1);
+        // ACKs go to the namespaced topic too
+        assert!(published(&network, "me")
+            .iter()
+            .any(|(t, _)| t.starts_with("/waku-a2a/1/staging/ack/")));
+    }
+
+    #[tokio::test]
+    async fn test_hashed_inbox() {
+        let network = SimNetwork::new(1);
+        let worker = WakuA2ANode::new("worker", "worker agent", vec![], network.join("worker"))
+            .with_inbox_scheme(InboxScheme::Hashed);
+        let requester = WakuA2ANode::new(
+            "requester",
+            "requester agent",
+            vec![],
+            network.join("requester"),
+        )
+        .with_inbox_scheme(InboxScheme::Rotating { epoch_secs: 3600 });
+
+        let mut task = Task::new(requester.pubkey(), worker.pubkey(), "hi");
+        task.reply_inbox = requester.card.inbox;
+        let payload = serde_json::to_vec(&A2AEnvelope::Task(task)).unwrap();
+
+        // Nothing is heard on the plain topic any more
+        deliver(
+            &network,
+            "worker",
+            &topics::task_topic(worker.pubkey()),
+            payload.clone(),
+        );
+        assert!(worker.poll_tasks().await.unwrap().is_empty());
+
+        let hashed = worker
+            .topics()
+            .inbox_topic(worker.pubkey(), &InboxScheme::Hashed, 0);
+        assert!(!hashed.contains(worker.pubkey()));
+        deliver(&network, "worker", &hashed, payload);
+        let tasks = worker.poll_tasks().await.unwrap();
+        assert_eq!(tasks.len(), 1);
+
+        // The reply goes to the inbox named in the task
+        worker.respond(&tasks[0], "hello").await.unwrap();
+        let (topic, response) = published(&network, "worker").pop().unwrap();
+        assert!(!topic.contains(requester.pubkey()));
+        assert!(requester.inbox_listen_topics().contains(&topic));
+        deliver(&network, "requester", &topic, response);
+        let responses = requester.poll_tasks().await.unwrap();
+        assert_eq!(responses[0].result_text(), Some("hello"));
+        assert_eq!(responses[0].reply_inbox, InboxScheme::Hashed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pair_inbox_after_session() {
+        let network = SimNetwork::new(1);
+        let worker =
+            WakuA2ANode::new_encrypted("worker", "worker agent", vec![], network.join("worker"))
+                .with_inbox_scheme(InboxScheme::Hashed);
+        let requester = WakuA2ANode::new_encrypted(
+            "requester",
+            "requester agent",
+            vec![],
+            network.join("requester"),
+        )
+        .with_inbox_scheme(InboxScheme::Rotating { epoch_secs: 3600 });
+
+        // First contact uses the worker's advertised inbox
+        let task = Task::new(requester.pubkey(), worker.pubkey(), "hi");
+        requester
+            .send_task_to(&task, Some(&worker.card))
+            .await
+            .unwrap();
+        let hashed = worker
+            .topics()
+            .inbox_topic(worker.pubkey(), &InboxScheme::Hashed, 0);
+        let sent = published(&network, "requester")[0].clone();
+        assert_eq!(sent.0, hashed);
+        deliver(&network, "worker", &sent.0, sent.1);
+        let tasks = worker.poll_tasks().await.unwrap();
+        assert_eq!(tasks.len(), 1);
+
+        // The worker has heard from the requester: replies use the pairwise inbox
+        worker.respond(&tasks[0], "done").await.unwrap();
+        let (topic, response) = published(&network, "worker").pop().unwrap();
+        let unpaired =
+            requester
+                .topics()
+                .inbox_topic(requester.pubkey(), &requester.card.inbox, unix_now());
+        assert_ne!(topic, unpaired);
+        assert!(requester.inbox_listen_topics().contains(&topic));
+        deliver(&network, "requester", &topic, response);
+        let responses = requester.poll_tasks().await.unwrap();
+        assert_eq!(responses[0].result_text(), Some("done"));
+    }
+
+    /// A transport that records subscribe (true) and unsubscribe (false) calls.
+    struct Recording {
+        inner: waku_a2a_transport::sim::SimTransport,
+        log: std::sync::Arc<Mutex<Vec<(bool, String)>>>,
+    }
+
+    impl Recording {
+        fn count(&self, subscribe: bool, topic: &str) -> usize {
+            self.log
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(s, t)| *s == subscribe && t == topic)
+                .count()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl WakuTransport for Recording {
+        async fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
+            self.inner.publish(topic, payload).await
+        }
+
+        async fn subscribe(&self, topic: &str) -> Result<()> {
+            self.log.lock().unwrap().push((true, topic.to_string()));
+            self.inner.subscribe(topic).await
+        }
+
+        async fn unsubscribe(&self, topic: &str) -> Result<()> {
+            self.log.lock().unwrap().push((false, topic.to_string()));
+            self.inner.unsubscribe(topic).await
+        }
+
+        async fn poll(&self, topic: &str) -> Result<Vec<WakuMessage>> {
+            self.inner.poll(topic).await
+        }
+    }
+
+    fn recording(network: &SimNetwork, name: &str) -> Recording {
+        Recording {
+            inner: network.join(name),
+            log: std::sync::Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rotating_inbox_unsubscribes_past_epochs() {
+        let network = SimNetwork::new(1);
+        let node = WakuA2ANode::new("me", "rotating", vec![], recording(&network, "me"))
+            .with_inbox_scheme(InboxScheme::Rotating { epoch_secs: 1 });
+        node.poll_tasks().await.unwrap();
+        let before = node.inbox_listen_topics();
+        node.poll_tasks().await.unwrap();
+        let transport = node.transport.inner();
+        for topic in &before {
+            assert_eq!(transport.count(true, topic), 1);
+        }
+
+        // Two epochs later the oldest topics have left the listen window
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        node.poll_tasks().await.unwrap();
+        let after = node.inbox_listen_topics();
+        let stale: Vec<&String> = before.iter().filter(|t| !after.contains(t)).collect();
+        assert!(!stale.is_empty());
+        for topic in stale {
+            assert_eq!(transport.count(false, topic), 1);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_session_forgotten() {
+        let network = SimNetwork::new(1);
+        let worker =
+            WakuA2ANode::new_encrypted("worker", "worker agent", vec![], network.join("worker"));
+        let requester = WakuA2ANode::new_encrypted(
+            "requester",
+            "requester agent",
+            vec![],
+            recording(&network, "requester"),
+        )
+        .with_inbox_scheme(InboxScheme::Hashed);
+
+        let task = Task::new(requester.pubkey(), worker.pubkey(), "hi");
+        requester
+            .send_task_to(&task, Some(&worker.card))
+            .await
+            .unwrap();
+        requester.poll_tasks().await.unwrap();
+        let secret = requester.pair_secret(worker.pubkey(), false).unwrap();
+        let pair_topics = requester.topics().pair_inbox_listen_topics(
+            &secret,
+            requester.pubkey(),
+            &requester.card.inbox,
+            unix_now(),
+        );
+        let transport = requester.transport.inner();
+        assert_eq!(transport.count(true, &pair_topics[0]), 1);
+
+        let idle = unix_now() - SESSION_IDLE_TIMEOUT.as_secs();
+        requester
+            .sessions
+            .lock()
+            .unwrap()
+            .get_mut(worker.pubkey())
+            .unwrap()
+            .last_active = idle;
+        requester.poll_tasks().await.unwrap();
+        assert!(requester.sessions.lock().unwrap().is_empty());
+        assert_eq!(transport.count(false, &pair_topics[0]), 1);
+    }
+
+    #[tokio::test]
+    async fn test_discover_by_skill() {
+        let network = SimNetwork::new(1);
+        let transport = network.join("me");
+        let mut translate = AgentSkill::new("translate", "Translate", "Translates text");
+        translate.tags = vec!["nlp".to_string()];
+        for (name, skills) in [
+            ("translator", vec![translate]),
+            ("legacy", vec![]),
+            (
+                "coder",
+                vec![AgentSkill::new("code", "Code", "Writes code")],
+            ),
+        ] {
+            let card = AgentCard {
+                name: name.to_string(),
+                description: String::new(),
+                version: "0.1.0".to_string(),
+                capabilities: vec!["text".to_string()],
+                public_key: format!("02{}", name),
+                intro_bundle: None,
+                skills,
+                protocol_versions: vec![],
+                codecs: vec![],
+                compression: vec![],
+                inbox: InboxScheme::Plain,
+            };
+            let payload = serde_json::to_vec(&A2AEnvelope::AgentCard(card)).unwrap();
+            deliver(&network, "me", topics::DISCOVERY, payload);
+        }
+
+        let node = WakuA2ANode::new("me", "my agent", vec![], transport);
+        let cards = node
+            .discover_by_skill(&SkillFilter::tag("nlp"))
+            .await
+            .unwrap();
+
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].name, "translator");
+    }
+
     #[tokio::test]
     async fn test_poll_tasks() {
-        let transport = MockTransport::new();
-        let node = WakuA2ANode::new("echo", "echo agent", vec!["text".into()], transport);
+        let network = SimNetwork::new(1);
+        let node = WakuA2ANode::new(
+            "echo",
+            "echo agent",
+            vec!["text".into()],
+            network.join("echo"),
+        );
 
-        // SdsTransport wraps the mock, so we can't inject after construction.
-        // This test validates the API works with an empty inbox.
         let tasks = node.poll_tasks().await.unwrap();
         assert!(tasks.is_empty());
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_incoming_tasks_stream() {
+        let network = SimNetwork::new(1);
+        let node = WakuA2ANode::new("echo", "echo agent", vec![], network.join("echo"));
+        let topic = topics::task_topic(node.pubkey());
+
+        let task = Task::new("02aa", node.pubkey(), "first");
+        let payload = serde_json::to_vec(&A2AEnvelope::Task(task.clone())).unwrap();
+        deliver(&network, "echo", &topic, payload.clone());
+
+        let mut incoming = std::pin::pin!(node.incoming_tasks());
+        assert_eq!(incoming.next().await.unwrap().id, task.id);
+
+        // A duplicate is dropped; a task arriving later is delivered
+        let later = Task::new("02aa", node.pubkey(), "second");
+        let delayed = network.clone();
+        let delayed_payload = serde_json::to_vec(&A2AEnvelope::Task(later.clone())).unwrap();
+        tokio::spawn(async move {
+            deliver(&delayed, "echo", &topic, payload);
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            deliver(&delayed, "echo", &topic, delayed_payload);
+        });
+        assert_eq!(incoming.next().await.unwrap().id, later.id);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_encrypted_task_over_faulty_network() {
+        let link = LinkConfig::default()
+            .with_latency(Duration::from_millis(300))
+            .with_jitter(Duration::from_millis(200))
+            .with_loss(0.3)
+            .with_duplicate(0.5);
+        let network = SimNetwork::new(11).with_default_link(link);
+        let worker =
+            WakuA2ANode::new_encrypted("worker", "worker agent", vec![], network.join("worker"));
+        let requester = WakuA2ANode::new_encrypted(
+            "requester",
+            "requester agent",
+            vec![],
+            network.join("requester"),
+        );
+        // Subscribes the worker's inbox before anything is sent
+        assert!(worker.poll_tasks().await.unwrap().is_empty());
+
+        let task = Task::new(requester.pubkey(), worker.pubkey(), "hi");
+        let receive = async {
+            let mut received = Vec::new();
+            let mut incoming = std::pin::pin!(worker.incoming_tasks());
+            while let Ok(Some(task)) =
+                tokio::time::timeout(Duration::from_secs(60), incoming.next()).await
+            {
+                received.push(task);
+            }
+            received
+        };
+        let (acked, received) =
+            tokio::join!(requester.send_task_to(&task, Some(&worker.card)), receive);
+        assert!(acked.unwrap());
+        // Lost copies were retransmitted, duplicates dropped
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].text(), Some("hi"));
+        let stats = network.stats();
+        assert!(stats.dropped > 0);
+        assert!(stats.duplicated > 0);
+    }
+
+    /// A transport whose publishes and subscriptions fail while `down` is set,
+    /// like nwaku being unreachable, and that refuses publishes to `rejects`.
+    struct Outage {
+        inner: waku_a2a_transport::sim::SimTransport,
+        down: std::sync::Arc<std::sync::atomic::AtomicBool>,
+        rejects: Option<String>,
+    }
+
+    impl Outage {
+        fn check(&self) -> Result<()> {
+            if self.down.load(std::sync::atomic::Ordering::SeqCst) {
+                anyhow::bail!("connection refused");
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl WakuTransport for Outage {
+        async fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
+            self.check()?;
+            if self.rejects.as_deref() == Some(topic) {
+                return Err(Rejected::new("invalid content topic").into());
+            }
+            self.inner.publish(topic, payload).await
+        }
+
+        async fn subscribe(&self, topic: &str) -> Result<()> {
+            self.check()?;
+            self.inner.subscribe(topic).await
+        }
+
+        async fn poll(&self, topic: &str) -> Result<Vec<WakuMessage>> {
+            self.check()?;
+            self.inner.poll(topic).await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_outbox_survives_outage_and_restart() {
+        let path = std::env::temp_dir().join(format!(
+            "waku-a2a-node-outbox-{}.jsonl",
+            uuid::Uuid::new_v4()
+        ));
+        let network = SimNetwork::new(5);
+        let worker = WakuA2ANode::new("worker", "worker agent", vec![], network.join("worker"));
+        assert!(worker.poll_tasks().await.unwrap().is_empty());
+        let down = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let requester = WakuA2ANode::new(
+            "requester",
+            "requester agent",
+            vec![],
+            Outage {
+                inner: network.join("requester"),
+                down: down.clone(),
+                rejects: None,
+            },
+        )
+        .with_outbox(Outbox::open(&path).unwrap());
+
+        // nwaku is down: the task is kept instead of failing
+        let task = Task::new(requester.pubkey(), worker.pubkey(), "hi");
+        assert!(!requester.send_task(&task).await.unwrap());
+        assert_eq!(
+            requester.outbox_status(&task.id),
+            Some(OutboxStatus::Queued)
+        );
+        assert!(requester.drain_outbox().await.is_err());
+        let entry = requester.outbox().unwrap().get(&task.id).unwrap();
+        assert_eq!(entry.attempts, 2);
+        assert!(entry.last_error.unwrap().contains("connection refused"));
+        drop(requester);
+
+        // After a restart it's delivered once nwaku reports being connected
+        let requester = WakuA2ANode::new(
+            "requester",
+            "requester agent",
+            vec![],
+            Outage {
+                inner: network.join("restarted"),
+                down: down.clone(),
+                rejects: None,
+            },
+        )
+        .with_outbox(Outbox::open(&path).unwrap());
+        let (connection, watch) = watch::channel(ConnectionState::Disconnected);
+        let recover = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            down.store(false, std::sync::atomic::Ordering::SeqCst);
+            connection.send_replace(ConnectionState::Connected);
+            let mut incoming = std::pin::pin!(worker.incoming_tasks());
+            let received = incoming.next().await.unwrap();
+            while requester.outbox_status(&task.id) != Some(OutboxStatus::Acked) {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            received
+        };
+        // Well before the periodic retry
+        let received = tokio::time::timeout(OUTBOX_RETRY_INTERVAL / 2, async {
+            tokio::select! {
+                () = requester.run_outbox(Some(watch)) => unreachable!(),
+                received = recover => received,
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(received.id, task.id);
+        assert_eq!(received.text(), Some("hi"));
+        let entry = requester.outbox().unwrap().get(&task.id).unwrap();
+        // Including the drain run_outbox starts with, while still down
+        assert_eq!(entry.attempts, 4);
+        assert!(entry.last_error.is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_outbox_gives_up_without_holding_up_others() {
+        let path = std::env::temp_dir().join(format!(
+            "waku-a2a-node-outbox-{}.jsonl",
+            uuid::Uuid::new_v4()
+        ));
+        let network = SimNetwork::new(1);
+        let worker = WakuA2ANode::new("worker", "worker agent", vec![], network.join("worker"));
+        let unreachable = WakuA2ANode::new("gone", "gone agent", vec![], network.join("gone"));
+        let flaky = WakuA2ANode::new("flaky", "flaky agent", vec![], network.join("flaky"));
+        assert!(worker.poll_tasks().await.unwrap().is_empty());
+        let down = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let requester = WakuA2ANode::new(
+            "requester",
+            "requester agent",
+            vec![],
+            Outage {
+                inner: network.join("requester"),
+                down: down.clone(),
+                rejects: Some(worker.topics().task_topic(unreachable.pubkey())),
+            },
+        )
+        .with_outbox(Outbox::open(&path).unwrap());
+
+        // Queued while down, oldest (by ID) first
+        let mut rejected = Task::new(requester.pubkey(), unreachable.pubkey(), "bad topic");
+        rejected.id = "t1".into();
+        let mut failing = Task::new(requester.pubkey(), flaky.pubkey(), "never acked");
+        failing.id = "t2".into();
+        let mut task = Task::new(requester.pubkey(), worker.pubkey(), "hi");
+        task.id = "t3".into();
+        for task in [&rejected, &failing, &task] {
+            assert!(!requester.send_task(task).await.unwrap());
+        }
+
+        // A refused task fails at once and the rest are still published
+        down.store(false, std::sync::atomic::Ordering::SeqCst);
+        let receive = async {
+            let mut incoming = std::pin::pin!(worker.incoming_tasks());
+            incoming.next().await.unwrap()
+        };
+        let (acked, received) = tokio::join!(requester.drain_outbox(), receive);
+        assert_eq!(acked.unwrap(), 1);
+        assert_eq!(received.id, task.id);
+        let entry = requester.outbox().unwrap().get(&rejected.id).unwrap();
+        assert_eq!(entry.status, OutboxStatus::Failed);
+        assert_eq!(entry.attempts, 2);
+        assert!(entry.last_error.unwrap().contains("invalid content topic"));
+        assert_eq!(requester.outbox_status(&task.id), Some(OutboxStatus::Acked));
+
+        // Failed attempts count towards the limit too
+        down.store(true, std::sync::atomic::Ordering::SeqCst);
+        while requester.outbox_status(&failing.id) != Some(OutboxStatus::Failed) {
+            assert!(requester.drain_outbox().await.is_err());
+        }
+        let entry = requester.outbox().unwrap().get(&failing.id).unwrap();
+        assert_eq!(entry.attempts, outbox::MAX_DELIVERY_ATTEMPTS);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit_surfaces_to_caller() {
+        use waku_a2a_transport::rate_limit::{RateLimit, RateLimitedTransport};
+
+        let network = SimNetwork::new(1);
+        let limit = RateLimit::new(2, Duration::from_secs(60))
+            .with_control_reserve(1)
+            .with_max_wait(Duration::ZERO);
+        let worker = WakuA2ANode::new(
+            "worker",
+            "worker agent",
+            vec![],
+            RateLimitedTransport::new(network.join("worker"), limit),
+        );
+        let task = Task::new("02aa", worker.pubkey(), "hi");
+
+        // The reserved token is left for control messages like the card
+        worker.respond(&task, "first").await.unwrap();
+        let err = worker.respond(&task, "second").await.unwrap_err();
+        let limited = err.downcast_ref::<RateLimited>().expect("rate limited");
+        assert!(limited.retry_after <= Duration::from_secs(60));
+        worker.announce().await.unwrap();
+        assert_eq!(published(&network, "worker").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_jsonrpc_wire_format() {
+        let network = SimNetwork::new(1);
+        let node = WakuA2ANode::new("worker", "worker agent", vec![], network.join("worker"))
+            .with_codec(JsonRpcCodec);
+        let task = Task::new("02requester", node.pubkey(), "hello");
+
+        // Incoming JSON-RPC requests are understood regardless of our own format
+        let request = JsonRpcCodec
+            .encode(&A2AEnvelope::Task(task.clone()), PROTOCOL_VERSION)
+            .unwrap();
+        deliver(
+            &network,
+            "worker",
+            &topics::task_topic(node.pubkey()),
+            request,
+        );
+        let tasks = node.poll_tasks().await.unwrap();
+        assert_eq!(tasks, vec![task.clone()]);
+
+        node.respond(&tasks[0], "hi").await.unwrap();
+        let msgs = published(&network, "worker");
+        let (topic, payload) = msgs.last().unwrap();
+        assert_eq!(topic, &topics::task_topic("02requester"));
+        let value: serde_json::Value = serde_json::from_slice(payload).unwrap();
+        assert_eq!(value["jsonrpc"], "2.0");
+        assert_eq!(value["result"]["kind"], "task");
+        assert_eq!(value["result"]["contextId"], task.id.as_str());
+    }
+
+    fn status_payload(task_id: &str, seq: u64, is_final: bool) -> Vec<u8> {
+        let state = if is_final {
+            TaskState::Completed
+        } else {
+            TaskState::Working
+        };
+        let update = TaskStatusUpdate::new(task_id, seq, state, Some("progress"), is_final);
+        serde_json::to_vec(&A2AEnvelope::TaskStatus(update)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_send_status_update() {
+        let network = SimNetwork::new(1);
+        let node = WakuA2ANode::new("worker", "worker agent", vec![], network.join("worker"));
+        let task = Task::new("02requester", node.pubkey(), "long job");
+
+        node.send_status_update(&task, TaskState::Working, Some("step 1"), false)
+            .await
+            .unwrap();
+        node.send_status_update(&task, TaskState::Completed, None, true)
+            .await
+            .unwrap();
+
+        let msgs = published(&network, "worker");
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0].0, topics::task_topic("02requester"));
+        let seqs: Vec<(u64, bool)> = msgs
+            .iter()
+            .map(|(_, p)| match serde_json::from_slice(p).unwrap() {
+                A2AEnvelope::TaskStatus(u) => (u.seq, u.is_final),
+                _ => panic!("Expected TaskStatus envelope"),
+            })
+            .collect();
+        assert_eq!(seqs, vec![(0, false), (1, true)]);
+        assert!(node.status_seq.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_status_updates_encrypted_for_encrypted_task() {
+        let network = SimNetwork::new(1);
+        let worker =
+            WakuA2ANode::new_encrypted("worker", "worker agent", vec![], network.join("worker"));
+        let requester = WakuA2ANode::new_encrypted(
+            "requester",
+            "requester agent",
+            vec![],
+            network.join("requester"),
+        );
+        // Subscribes both inboxes before anything is sent
+        assert!(worker.poll_tasks().await.unwrap().is_empty());
+        assert!(requester.poll_tasks().await.unwrap().is_empty());
+
+        let task = Task::new(requester.pubkey(), worker.pubkey(), "long job");
+        let receive = async {
+            loop {
+                let tasks = worker.poll_tasks().await.unwrap();
+                if !tasks.is_empty() {
+                    return tasks;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        let (acked, received) =
+            tokio::join!(requester.send_task_to(&task, Some(&worker.card)), receive);
+        assert!(acked.unwrap());
+
+        worker
+            .send_status_update(&received[0], TaskState::Working, Some("step 1"), false)
+            .await
+            .unwrap();
+        worker
+            .send_status_update(&received[0], TaskState::Completed, None, true)
+            .await
+            .unwrap();
+        let msgs = published(&network, "worker");
+        for (_, payload) in &msgs[msgs.len() - 2..] {
+            let value: serde_json::Value = serde_json::from_slice(payload).unwrap();
+            assert_eq!(value["type"], "encrypted_status");
+        }
+
+        let events: Vec<TaskUpdateEvent> = requester.task_updates(&task.id).collect().await;
+        let states: Vec<TaskState> = events
+            .iter()
+            .map(|e| match e {
+                TaskUpdateEvent::Status(u) => u.state.clone(),
+                TaskUpdateEvent::Gap { .. } => panic!("unexpected gap"),
+            })
+            .collect();
+        assert_eq!(states, vec![TaskState::Working, TaskState::Completed]);
+    }
+
+    #[tokio::test]
+    async fn test_task_updates_reordered() {
+        let network = SimNetwork::new(1);
+        let node = WakuA2ANode::new("me", "requester", vec![], network.join("me"));
+        let inbox = topics::task_topic(node.pubkey());
+        let other = Task::new("02peer", node.pubkey(), "unrelated");
+        for payload in [
+            status_payload("task-1", 1, false),
+            serde_json::to_vec(&A2AEnvelope::Task(other)).unwrap(),
+            status_payload("task-1", 2, true),
+            status_payload("task-1", 0, false),
+        ] {
+            deliver(&network, "me", &inbox, payload);
+        }
+
+        let events: Vec<TaskUpdateEvent> = node.task_updates("task-1").collect().await;
+        let seqs: Vec<u64> = events
+            .iter()
+            .map(|e| match e {
+                TaskUpdateEvent::Status(u) => u.seq,
+                TaskUpdateEvent::Gap { .. } => panic!("unexpected gap"),
+            })
+            .collect();
+        assert_eq!(seqs, vec![0, 1, 2]);
+
+        // The task drained while streaming is still delivered by poll_tasks
+        let tasks = node.poll_tasks().await.unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].text(), Some("unrelated"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_task_updates_gap() {
+        let network = SimNetwork::new(1);
+        let node = WakuA2ANode::new("me", "requester", vec![], network.join("me"));
+        let inbox = topics::task_topic(node.pubkey());
+        deliver(&network, "me", &inbox, status_payload("task-1", 0, false));
+        deliver(&network, "me", &inbox, status_payload("task-1", 2, true));
+
+        let events: Vec<TaskUpdateEvent> = node.task_updates("task-1").collect().await;
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], TaskUpdateEvent::Status(u) if u.seq == 0));
+        assert_eq!(events[1], TaskUpdateEvent::Gap { from: 1, to: 2 });
+        assert!(matches!(&events[2], TaskUpdateEvent::Status(u) if u.is_final));
+    }
 }
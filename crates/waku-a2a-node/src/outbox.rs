@@ -0,0 +1,575 @@
+//! Durable outbox for outgoing tasks.
+//!
+//! With an outbox, `WakuA2ANode::send_task_to` records a task's encoded
+//! frames before publishing them, so the task survives nwaku being down and
+//! the process exiting. Tasks the recipient hasn't ACKed yet are published
+//! again by `WakuA2ANode::drain_outbox`.
+//!
+//! The store is a JSON-lines file: a new task appends its entry, later
+//! changes only its new status fields. Finished tasks drop their frames.
+//! Opening the file, and appending once `COMPACT_AFTER_LINES` lines were
+//! added, keeps the latest state of each task, drops tasks finished more
+//! than `FINISHED_RETENTION` ago and rewrites it compacted.
+//! An exclusive lock on a `.lock` file next to it keeps a second `Outbox`
+//! from opening the same store while this one is open.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use waku_a2a_core::topics::InboxScheme;
+
+/// Reliable publishes (each with its own SDS retransmissions) of a task
+/// before the outbox gives up on it, whether they went unACKed or failed.
+/// Publishes put off by a rate limit don't count.
+pub const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+
+/// How long ACKed and failed tasks stay queryable.
+pub const FINISHED_RETENTION: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// Lines appended since the last compaction after which the file is
+/// compacted again.
+const COMPACT_AFTER_LINES: usize = 1024;
+
+/// Where a task in the outbox stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutboxStatus {
+    /// Recorded but not published yet, e.g. because nwaku was down.
+    Queued,
+    /// Published, but the recipient didn't ACK it.
+    Sent,
+    /// ACKed by the recipient.
+    Acked,
+    /// Not ACKed after `MAX_DELIVERY_ATTEMPTS`, or refused by the transport
+    /// (see `waku_a2a_transport::Rejected`).
+    Failed,
+}
+
+impl OutboxStatus {
+    /// Whether the task is still to be (re)published.
+    pub fn is_pending(self) -> bool {
+        matches!(self, OutboxStatus::Queued | OutboxStatus::Sent)
+    }
+}
+
+/// An outgoing task and its delivery state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub task_id: String,
+    /// Recipient's public key.
+    pub to: String,
+    /// Recipient's inbox scheme. The topic is derived from it on every
+    /// attempt, as rotating and pairwise inbox topics change over time.
+    #[serde(default)]
+    pub inbox: InboxScheme,
+    /// The encoded (and possibly encrypted or fragmented) envelope. Empty
+    /// once the task is finished.
+    #[serde(with = "hex_frames")]
+    pub frames: Vec<Vec<u8>>,
+    pub status: OutboxStatus,
+    /// Reliable publishes tried, successful or not.
+    pub attempts: u32,
+    /// Unix seconds.
+    pub created_at: u64,
+    /// Unix seconds.
+    pub updated_at: u64,
+    /// Why the last attempt failed, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+impl OutboxEntry {
+    pub fn new(task_id: &str, to: &str, inbox: InboxScheme, frames: Vec<Vec<u8>>) -> Self {
+        let now = unix_secs();
+        Self {
+            task_id: task_id.to_string(),
+            to: to.to_string(),
+            inbox,
+            frames,
+            status: OutboxStatus::Queued,
+            attempts: 0,
+            created_at: now,
+            updated_at: now,
+            last_error: None,
+        }
+    }
+}
+
+/// Status fields of an entry, appended when they change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StatusUpdate {
+    task_id: String,
+    status: OutboxStatus,
+    attempts: u32,
+    updated_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+}
+
+impl StatusUpdate {
+    fn new(entry: &OutboxEntry) -> Self {
+        Self {
+            task_id: entry.task_id.clone(),
+            status: entry.status,
+            attempts: entry.attempts,
+            updated_at: entry.updated_at,
+            last_error: entry.last_error.clone(),
+        }
+    }
+
+    fn apply(self, entry: &mut OutboxEntry) {
+        entry.status = self.status;
+        entry.attempts = self.attempts;
+        entry.updated_at = self.updated_at;
+        entry.last_error = self.last_error;
+        if !entry.status.is_pending() {
+            entry.frames.clear();
+        }
+    }
+}
+
+/// A line of the outbox file.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Record {
+    Entry(OutboxEntry),
+    Update(StatusUpdate),
+}
+
+/// On-disk record of outgoing tasks.
+pub struct Outbox {
+    path: PathBuf,
+    inner: Mutex<OutboxInner>,
+}
+
+struct OutboxInner {
+    /// Held for the lock on it.
+    _lock: File,
+    file: File,
+    /// Lines appended since the file was last compacted.
+    appended: usize,
+    entries: BTreeMap<String, OutboxEntry>,
+    /// Tasks being published right now, so a drain doesn't send them twice.
+    in_flight: HashSet<String>,
+}
+
+impl Outbox {
+    /// Open the outbox at `path`, creating it if it doesn't exist. Fails if
+    /// it is already open, in this process or another.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let lock = lock(&path)?;
+
+        let mut entries = BTreeMap::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for (i, line) in BufReader::new(file).lines().enumerate() {
+                    let line =
+                        line.with_context(|| format!("Failed to read {}", path.display()))?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    // A crash mid-write leaves a torn last line
+                    match serde_json::from_str::<Record>(&line) {
+                        Ok(Record::Entry(entry)) => {
+                            entries.insert(entry.task_id.clone(), entry);
+                        }
+                        Ok(Record::Update(update)) => {
+                            if let Some(entry) = entries.get_mut(&update.task_id) {
+                                update.apply(entry);
+                            }
+                        }
+                        Err(e) => eprintln!(
+                            "[node] Skipping unreadable outbox line {} in {}: {}",
+                            i + 1,
+                            path.display(),
+                            e
+                        ),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to open {}", path.display())),
+        }
+
+        let file = compact(&path, &mut entries)?;
+        Ok(Self {
+            path,
+            inner: Mutex::new(OutboxInner {
+                _lock: lock,
+                file,
+                appended: 0,
+                entries,
+                in_flight: HashSet::new(),
+            }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The entry for `task_id`, if the task went through this outbox.
+    pub fn get(&self, task_id: &str) -> Option<OutboxEntry> {
+        self.inner.lock().unwrap().entries.get(task_id).cloned()
+    }
+
+    /// All entries, oldest first.
+    pub fn entries(&self) -> Vec<OutboxEntry> {
+        let mut entries: Vec<_> = self
+            .inner
+            .lock()
+            .unwrap()
+            .entries
+            .values()
+            .cloned()
+            .collect();
+        entries.sort_by_key(|e| e.created_at);
+        entries
+    }
+
+    /// Entries still to be (re)published, oldest first.
+    pub fn pending(&self) -> Vec<OutboxEntry> {
+        self.entries()
+            .into_iter()
+            .filter(|e| e.status.is_pending())
+            .collect()
+    }
+
+    /// Record a new entry, claimed for publishing by the caller.
+    pub(crate) fn insert(&self, entry: OutboxEntry) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.append(&entry)?;
+        inner.in_flight.insert(entry.task_id.clone());
+        inner.entries.insert(entry.task_id.clone(), entry);
+        inner.compact_if_due(&self.path)
+    }
+
+    /// Claim the pending entries nobody is publishing yet, oldest first.
+    pub(crate) fn claim_pending(&self) -> Vec<OutboxEntry> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut claimed: Vec<OutboxEntry> = inner
+            .entries
+            .values()
+            .filter(|e| e.status.is_pending() && !inner.in_flight.contains(&e.task_id))
+            .cloned()
+            .collect();
+        claimed.sort_by_key(|e| e.created_at);
+        for entry in &claimed {
+            inner.in_flight.insert(entry.task_id.clone());
+        }
+        claimed
+    }
+
+    /// Give up a claim without changing the entry.
+    pub(crate) fn release(&self, task_id: &str) {
+        self.inner.lock().unwrap().in_flight.remove(task_id);
+    }
+
+    /// Record the outcome of publishing a claimed entry and release it.
+    pub(crate) fn finish(
+        &self,
+        task_id: &str,
+        update: impl FnOnce(&mut OutboxEntry),
+    ) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.in_flight.remove(task_id);
+        let Some(mut entry) = inner.entries.get(task_id).cloned() else {
+            return Ok(());
+        };
+        update(&mut entry);
+        entry.updated_at = unix_secs();
+        let update = StatusUpdate::new(&entry);
+        inner.append(&update)?;
+        update.apply(&mut entry);
+        inner.entries.insert(entry.task_id.clone(), entry);
+        inner.compact_if_due(&self.path)
+    }
+}
+
+impl OutboxInner {
+    /// Append a record and flush it to disk.
+    fn append(&mut self, record: &impl Serialize) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file
+            .write_all(&line)
+            .and_then(|()| self.file.sync_data())
+            .context("Failed to write to the outbox")?;
+        self.appended += 1;
+        Ok(())
+    }
+
+    /// Compact the file once enough lines were appended to it.
+    fn compact_if_due(&mut self, path: &Path) -> Result<()> {
+        if self.appended < COMPACT_AFTER_LINES {
+            return Ok(());
+        }
+        self.file = compact(path, &mut self.entries)?;
+        self.appended = 0;
+        Ok(())
+    }
+}
+
+/// Lock the outbox at `path` for as long as the returned file is open.
+fn lock(path: &Path) -> Result<File> {
+    let lock_path = path.with_extension("lock");
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("Failed to open {}", lock_path.display()))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => bail!("Outbox {} is already open", path.display()),
+        Err(TryLockError::Error(e)) => {
+            Err(e).with_context(|| format!("Failed to lock {}", lock_path.display()))
+        }
+    }
+}
+
+/// Drop entries finished more than `FINISHED_RETENTION` ago, replace the
+/// file at `path` with the rest, one per line, and open it for appending.
+/// Written to a temporary file first so a crash can't lose it.
+fn compact(path: &Path, entries: &mut BTreeMap<String, OutboxEntry>) -> Result<File> {
+    let cutoff = unix_secs().saturating_sub(FINISHED_RETENTION.as_secs());
+    entries.retain(|_, e| e.status.is_pending() || e.updated_at >= cutoff);
+    let tmp = path.with_extension("tmp");
+    let mut file =
+        File::create(&tmp).with_context(|| format!("Failed to create {}", tmp.display()))?;
+    for entry in entries.values() {
+        serde_json::to_writer(&mut file, entry)?;
+        file.write_all(b"\n")?;
+    }
+    file.sync_all()?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    OpenOptions::new()
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Frames as a list of hex strings.
+mod hex_frames {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(frames: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(frames.iter().map(hex::encode))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Vec<u8>>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|frame| hex::decode(frame).map_err(serde::de::Error::custom))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "waku-a2a-outbox-{}-{}.jsonl",
+            name,
+            uuid::Uuid::new_v4()
+        ))
+    }
+
+    #[test]
+    fn test_outbox_survives_reopen() {
+        let path = temp_path("reopen");
+        let outbox = Outbox::open(&path).unwrap();
+        outbox
+            .insert(OutboxEntry::new(
+                "t1",
+                "02aa",
+                InboxScheme::Plain,
+                vec![vec![1, 2], vec![3]],
+            ))
+            .unwrap();
+        outbox
+            .insert(OutboxEntry::new(
+                "t2",
+                "02bb",
+                InboxScheme::Plain,
+                vec![vec![4]],
+            ))
+            .unwrap();
+        outbox
+            .finish("t1", |e| {
+                e.attempts += 1;
+                e.status = OutboxStatus::Sent;
+            })
+            .unwrap();
+        outbox
+            .finish("t2", |e| {
+                e.attempts += 1;
+                e.status = OutboxStatus::Acked;
+            })
+            .unwrap();
+        // Claims don't outlive the process
+        assert!(outbox.claim_pending().iter().any(|e| e.task_id == "t1"));
+        drop(outbox);
+
+        // A torn line from a crash is skipped
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"task_id\":\"t3\",\"to").unwrap();
+        drop(file);
+
+        let outbox = Outbox::open(&path).unwrap();
+        let t1 = outbox.get("t1").unwrap();
+        assert_eq!(t1.status, OutboxStatus::Sent);
+        assert_eq!(t1.attempts, 1);
+        assert_eq!(t1.frames, vec![vec![1, 2], vec![3]]);
+        assert_eq!(outbox.get("t2").unwrap().status, OutboxStatus::Acked);
+        assert!(outbox.get("t3").is_none());
+        let pending: Vec<_> = outbox.pending().into_iter().map(|e| e.task_id).collect();
+        assert_eq!(pending, ["t1"]);
+
+        // Compacted to one line per task
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(lines, 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_outbox_file_stays_bounded() {
+        let path = temp_path("bounded");
+        let outbox = Outbox::open(&path).unwrap();
+        let frame = vec![7u8; 4096];
+        outbox
+            .insert(OutboxEntry::new(
+                "t1",
+                "02aa",
+                InboxScheme::Plain,
+                vec![frame.clone()],
+            ))
+            .unwrap();
+        // Every failed attempt is recorded without repeating the frames
+        for _ in 0..3 * COMPACT_AFTER_LINES {
+            outbox
+                .finish("t1", |e| {
+                    e.attempts += 1;
+                    e.status = OutboxStatus::Sent;
+                    e.last_error = Some("down".into());
+                })
+                .unwrap();
+            let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+            assert!(lines <= COMPACT_AFTER_LINES + 1, "{} lines", lines);
+        }
+        let size = std::fs::metadata(&path).unwrap().len();
+        assert!(size < 2 * frame.len() as u64 + 100 * COMPACT_AFTER_LINES as u64);
+        assert_eq!(outbox.get("t1").unwrap().frames, vec![frame]);
+
+        // Finished tasks drop their frames, in memory and on disk
+        outbox
+            .finish("t1", |e| e.status = OutboxStatus::Acked)
+            .unwrap();
+        assert!(outbox.get("t1").unwrap().frames.is_empty());
+        drop(outbox);
+        let outbox = Outbox::open(&path).unwrap();
+        let t1 = outbox.get("t1").unwrap();
+        assert_eq!(t1.status, OutboxStatus::Acked);
+        assert_eq!(t1.attempts, 3 * COMPACT_AFTER_LINES as u32);
+        assert!(t1.frames.is_empty());
+        assert!(std::fs::metadata(&path).unwrap().len() < 1024);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_outbox_claims() {
+        let path = temp_path("claims");
+        let outbox = Outbox::open(&path).unwrap();
+        outbox
+            .insert(OutboxEntry::new(
+                "t1",
+                "02aa",
+                InboxScheme::Plain,
+                vec![vec![1]],
+            ))
+            .unwrap();
+        // Claimed by the inserting caller
+        assert!(outbox.claim_pending().is_empty());
+        outbox.release("t1");
+        assert_eq!(outbox.claim_pending().len(), 1);
+        assert!(outbox.claim_pending().is_empty());
+        outbox
+            .finish("t1", |e| e.last_error = Some("down".into()))
+            .unwrap();
+        let claimed = outbox.claim_pending();
+        assert_eq!(claimed[0].last_error.as_deref(), Some("down"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_outbox_opened_once() {
+        let path = temp_path("lock");
+        let outbox = Outbox::open(&path).unwrap();
+        let err = Outbox::open(&path).err().expect("already open");
+        assert!(err.to_string().contains("already open"), "{}", err);
+        drop(outbox);
+        Outbox::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("lock")).unwrap();
+    }
+
+    #[test]
+    fn test_old_finished_entries_dropped() {
+        let path = temp_path("retention");
+        let outbox = Outbox::open(&path).unwrap();
+        for id in ["old-acked", "old-queued"] {
+            outbox
+                .insert(OutboxEntry::new(
+                    id,
+                    "02aa",
+                    InboxScheme::Plain,
+                    vec![vec![1]],
+                ))
+                .unwrap();
+        }
+        let old = unix_secs() - FINISHED_RETENTION.as_secs() - 1;
+        outbox
+            .finish("old-acked", |e| {
+                e.status = OutboxStatus::Acked;
+            })
+            .unwrap();
+        drop(outbox);
+        // Backdate by rewriting the file
+        let content = std::fs::read_to_string(&path).unwrap();
+        let backdated: String = content
+            .lines()
+            .map(|line| {
+                let mut record: serde_json::Value = serde_json::from_str(line).unwrap();
+                record["updated_at"] = old.into();
+                record.to_string() + "\n"
+            })
+            .collect();
+        std::fs::write(&path, backdated).unwrap();
+
+        let outbox = Outbox::open(&path).unwrap();
+        assert!(outbox.get("old-acked").is_none());
+        assert!(outbox.get("old-queued").is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+//! Client-side handling of streamed task status updates.
+//!
+//! Waku gives no ordering guarantees, so updates for a task may arrive out of
+//! order, duplicated, or not at all. `UpdateSequencer` restores `seq` order and
+//! reports gaps that never fill in. `UpdateBuffer` holds updates until they are
+//! read, within fixed bounds since any peer can send updates for any task ID.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use waku_a2a_core::TaskStatusUpdate;
+
+/// How long to wait for a missing update before skipping past it.
+pub const GAP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most updates buffered per task; the oldest are dropped first.
+pub const MAX_BUFFERED_UPDATES_PER_TASK: usize = 256;
+
+/// Most tasks with buffered updates; the least recently updated is evicted.
+pub const MAX_BUFFERED_TASKS: usize = 1024;
+
+/// How long unread updates are kept after the task's last update.
+pub const BUFFERED_UPDATE_TTL: Duration = Duration::from_secs(600);
+
+/// Event yielded by `WakuA2ANode::task_updates`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TaskUpdateEvent {
+    /// Next in-order status update.
+    Status(TaskStatusUpdate),
+    /// Updates `from..to` (exclusive) never arrived within `GAP_TIMEOUT`.
+    Gap { from: u64, to: u64 },
+}
+
+/// Reorders status updates of a single task by sequence number.
+#[derive(Debug, Default)]
+pub struct UpdateSequencer {
+    next_seq: u64,
+    pending: BTreeMap<u64, TaskStatusUpdate>,
+    finished: bool,
+}
+
+impl UpdateSequencer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sequence number of the next update to be released.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// True once the final update has been released.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// True if out-of-order updates are waiting on a missing one.
+    pub fn has_gap(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Buffer an update. Duplicates and already-released updates are ignored.
+    pub fn push(&mut self, update: TaskStatusUpdate) {
+        if self.finished || update.seq < self.next_seq {
+            return;
+        }
+        self.pending.entry(update.seq).or_insert(update);
+    }
+
+    /// Release all updates that are now contiguous.
+    pub fn drain_ready(&mut self) -> Vec<TaskStatusUpdate> {
+        let mut ready = Vec::new();
+        while !self.finished {
+            let Some(update) = self.pending.remove(&self.next_seq) else {
+                break;
+            };
+            self.next_seq += 1;
+            self.finished = update.is_final;
+            ready.push(update);
+        }
+        if self.finished {
+            self.pending.clear();
+        }
+        ready
+    }
+
+    /// Give up on the missing updates: advance to the lowest buffered `seq`
+    /// and return the skipped range as a gap event.
+    pub fn skip_gap(&mut self) -> Option<TaskUpdateEvent> {
+        let (&lowest, _) = self.pending.iter().next()?;
+        let gap = TaskUpdateEvent::Gap {
+            from: self.next_seq,
+            to: lowest,
+        };
+        self.next_seq = lowest;
+        Some(gap)
+    }
+}
+
+/// Updates of one task in `UpdateBuffer`.
+#[derive(Debug)]
+struct BufferedUpdates {
+    updates: VecDeque<TaskStatusUpdate>,
+    last_update: Instant,
+}
+
+/// Status updates received but not yet read by `task_updates`, keyed by task ID.
+#[derive(Debug, Default)]
+pub struct UpdateBuffer {
+    tasks: HashMap<String, BufferedUpdates>,
+}
+
+impl UpdateBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of tasks with buffered updates.
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Buffer an update. Updates past a buffered final update are ignored.
+    pub fn push(&mut self, update: TaskStatusUpdate, now: Instant) {
+        self.tasks
+            .retain(|_, b| now.duration_since(b.last_update) < BUFFERED_UPDATE_TTL);
+        if !self.tasks.contains_key(&update.task_id) && self.tasks.len() >= MAX_BUFFERED_TASKS {
+            let oldest = self
+                .tasks
+                .iter()
+                .min_by_key(|(_, b)| b.last_update)
+                .map(|(id, _)| id.clone());
+            if let Some(id) = oldest {
+                self.tasks.remove(&id);
+            }
+        }
+
+        let buffered =
+            self.tasks
+                .entry(update.task_id.clone())
+                .or_insert_with(|| BufferedUpdates {
+                    updates: VecDeque::new(),
+                    last_update: now,
+                });
+        if buffered
+            .updates
+            .iter()
+            .any(|u| u.is_final && u.seq < update.seq)
+        {
+            return;
+        }
+        if buffered.updates.len() >= MAX_BUFFERED_UPDATES_PER_TASK {
+            buffered.updates.pop_front();
+        }
+        buffered.updates.push_back(update);
+        buffered.last_update = now;
+    }
+
+    /// Remove and return the buffered updates of a task.
+    pub fn take(&mut self, task_id: &str) -> Vec<TaskStatusUpdate> {
+        self.tasks
+            .remove(task_id)
+            .map(|b| b.updates.into())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use waku_a2a_core::TaskState;
+
+    fn update(seq: u64, is_final: bool) -> TaskStatusUpdate {
+        TaskStatusUpdate::new("t", seq, TaskState::Working, None, is_final)
+    }
+
+    #[test]
+    fn test_reorders_out_of_order_updates() {
+        let mut seq = UpdateSequencer::new();
+        seq.push(update(1, false));
+        assert!(seq.drain_ready().is_empty());
+        assert!(seq.has_gap());
+
+        seq.push(update(0, false));
+        let ready: Vec<u64> = seq.drain_ready().iter().map(|u| u.seq).collect();
+        assert_eq!(ready, vec![0, 1]);
+        assert!(!seq.has_gap());
+        assert_eq!(seq.next_seq(), 2);
+    }
+
+    #[test]
+    fn test_ignores_duplicates() {
+        let mut seq = UpdateSequencer::new();
+        seq.push(update(0, false));
+        seq.push(update(0, false));
+        assert_eq!(seq.drain_ready().len(), 1);
+        seq.push(update(0, false));
+        assert!(seq.drain_ready().is_empty());
+    }
+
+    #[test]
+    fn test_skip_gap() {
+        let mut seq = UpdateSequencer::new();
+        seq.push(update(3, false));
+        assert_eq!(
+            seq.skip_gap(),
+            Some(TaskUpdateEvent::Gap { from: 0, to: 3 })
+        );
+        assert_eq!(seq.drain_ready().len(), 1);
+        assert_eq!(seq.skip_gap(), None);
+    }
+
+    #[test]
+    fn test_final_update_ends_sequence() {
+        let mut seq = UpdateSequencer::new();
+        seq.push(update(0, true));
+        seq.push(update(1, false));
+        assert_eq!(seq.drain_ready().len(), 1);
+        assert!(seq.is_finished());
+        seq.push(update(2, false));
+        assert!(seq.drain_ready().is_empty());
+    }
+
+    #[test]
+    fn test_buffer_drops_oldest_update() {
+        let mut buffer = UpdateBuffer::new();
+        let now = Instant::now();
+        for seq in 0..=MAX_BUFFERED_UPDATES_PER_TASK as u64 {
+            buffer.push(update(seq, false), now);
+        }
+        let updates = buffer.take("t");
+        assert_eq!(updates.len(), MAX_BUFFERED_UPDATES_PER_TASK);
+        assert_eq!(updates[0].seq, 1);
+        assert!(buffer.take("t").is_empty());
+    }
+
+    #[test]
+    fn test_buffer_evicts_least_recent_task() {
+        let mut buffer = UpdateBuffer::new();
+        let start = Instant::now();
+        for i in 0..=MAX_BUFFERED_TASKS {
+            let update =
+                TaskStatusUpdate::new(&format!("t{}", i), 0, TaskState::Working, None, false);
+            buffer.push(update, start + Duration::from_millis(i as u64));
+        }
+        assert_eq!(buffer.len(), MAX_BUFFERED_TASKS);
+        assert!(buffer.take("t0").is_empty());
+        assert_eq!(buffer.take("t1").len(), 1);
+    }
+
+    #[test]
+    fn test_buffer_expires_unread_updates() {
+        let mut buffer = UpdateBuffer::new();
+        let now = Instant::now();
+        buffer.push(update(0, false), now);
+        let other = TaskStatusUpdate::new("u", 0, TaskState::Working, None, false);
+        buffer.push(other, now + BUFFERED_UPDATE_TTL);
+        assert!(buffer.take("t").is_empty());
+        assert_eq!(buffer.len(), 1);
+    }
+
+    #[test]
+    fn test_buffer_ignores_updates_after_final() {
+        let mut buffer = UpdateBuffer::new();
+        let now = Instant::now();
+        buffer.push(update(1, true), now);
+        buffer.push(update(2, false), now);
+        buffer.push(update(0, false), now);
+        let seqs: Vec<u64> = buffer.take("t").iter().map(|u| u.seq).collect();
+        assert_eq!(seqs, vec![1, 0]);
+    }
+}
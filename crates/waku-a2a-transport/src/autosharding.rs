@@ -0,0 +1,225 @@
+//! Waku autosharding (RFC 51/WAKU2-RELAY-SHARDING).
+//!
+//! Relay traffic is split into shards, each a pubsub topic
+//! `/waku/2/rs/{cluster}/{shard}`. With autosharding the shard of a content
+//! topic is derived from its application and version: the last 8 bytes of
+//! `sha256(application || version)`, as a big-endian integer, modulo the
+//! number of shards in the cluster. All topics of one application version
+//! therefore share a shard.
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+
+/// Cluster of The Waku Network.
+pub const TWN_CLUSTER_ID: u16 = 1;
+
+/// Number of autosharded shards in The Waku Network.
+pub const TWN_SHARD_COUNT: u32 = 8;
+
+/// Pubsub topic used before sharding. Kept for nodes still configured with it.
+pub const LEGACY_PUBSUB_TOPIC: &str = "/waku/2/default-waku/proto";
+
+/// Static shard pubsub topic.
+pub fn shard_topic(cluster_id: u16, shard: u16) -> String {
+    format!("/waku/2/rs/{}/{}", cluster_id, shard)
+}
+
+/// Parsed content topic `[/{generation}]/{application}/{version}/{name}/{encoding}`.
+///
+/// The RFC names are a single segment; this crate's topics use several
+/// (`task/{pubkey}`), so everything between version and encoding is taken as
+/// the name. A leading numeric segment is read as the generation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentTopic {
+    pub generation: u32,
+    pub application: String,
+    pub version: String,
+    pub name: String,
+    pub encoding: String,
+}
+
+impl ContentTopic {
+    pub fn parse(topic: &str) -> Result<Self> {
+        let Some(rest) = topic.strip_prefix('/') else {
+            bail!("Content topic {:?} must start with '/'", topic);
+        };
+        let mut segments: Vec<&str> = rest.split('/').collect();
+        let generation = match segments.first().map(|s| s.parse::<u32>()) {
+            Some(Ok(generation)) if segments.len() >= 5 => {
+                segments.remove(0);
+                generation
+            }
+            _ => 0,
+        };
+        if segments.len() < 4 || segments.iter().any(|s| s.is_empty()) {
+            bail!(
+                "Content topic {:?} is not /{{application}}/{{version}}/{{name}}/{{encoding}}",
+                topic
+            );
+        }
+        if generation != 0 {
+            bail!("Content topic generation {} is not supported", generation);
+        }
+        let encoding = segments.pop().expect("at least four segments");
+        Ok(Self {
+            generation,
+            application: segments[0].to_string(),
+            version: segments[1].to_string(),
+            name: segments[2..].join("/"),
+            encoding: encoding.to_string(),
+        })
+    }
+
+    /// True for the RFC's single-segment names, the only form nwaku's
+    /// `/relay/v1/auto/` endpoints accept.
+    pub fn is_standard(&self) -> bool {
+        !self.name.contains('/')
+    }
+}
+
+/// Autosharding parameters of a cluster.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AutoSharding {
+    pub cluster_id: u16,
+    pub shard_count: u32,
+}
+
+impl Default for AutoSharding {
+    /// The Waku Network: cluster 1, 8 shards.
+    fn default() -> Self {
+        Self {
+            cluster_id: TWN_CLUSTER_ID,
+            shard_count: TWN_SHARD_COUNT,
+        }
+    }
+}
+
+impl AutoSharding {
+    pub fn new(cluster_id: u16, shard_count: u32) -> Result<Self> {
+        if shard_count == 0 || shard_count > u16::MAX as u32 + 1 {
+            bail!(
+                "Shard count must be between 1 and 65536, got {}",
+                shard_count
+            );
+        }
+        Ok(Self {
+            cluster_id,
+            shard_count,
+        })
+    }
+
+    /// Shard index of a parsed content topic.
+    pub fn shard(&self, topic: &ContentTopic) -> u16 {
+        let mut hasher = Sha256::new();
+        hasher.update(topic.application.as_bytes());
+        hasher.update(topic.version.as_bytes());
+        let hash = hasher.finalize();
+        let value = u64::from_be_bytes(hash[24..32].try_into().expect("8 bytes"));
+        (value % self.shard_count.max(1) as u64) as u16
+    }
+
+    /// Pubsub topic carrying `content_topic`.
+    pub fn pubsub_topic(&self, content_topic: &str) -> Result<String> {
+        let topic = ContentTopic::parse(content_topic)
+            .with_context(|| format!("Cannot autoshard {:?}", content_topic))?;
+        Ok(shard_topic(self.cluster_id, self.shard(&topic)))
+    }
+}
+
+/// How content topics are mapped to relay pubsub topics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PubsubRouting {
+    /// Derive the shard from each content topic.
+    Auto(AutoSharding),
+    /// Publish everything on one pubsub topic (static sharding or the legacy
+    /// default topic).
+    Static(String),
+}
+
+impl Default for PubsubRouting {
+    fn default() -> Self {
+        PubsubRouting::Auto(AutoSharding::default())
+    }
+}
+
+impl PubsubRouting {
+    pub fn pubsub_topic(&self, content_topic: &str) -> Result<String> {
+        match self {
+            PubsubRouting::Auto(sharding) => sharding.pubsub_topic(content_topic),
+            PubsubRouting::Static(topic) => Ok(topic.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_content_topic() {
+        let topic = ContentTopic::parse("/toychat/2/huilong/proto").unwrap();
+        assert_eq!(topic.application, "toychat");
+        assert_eq!(topic.version, "2");
+        assert_eq!(topic.name, "huilong");
+        assert_eq!(topic.encoding, "proto");
+
+        let topic = ContentTopic::parse("/0/toychat/2/huilong/proto").unwrap();
+        assert_eq!(topic.application, "toychat");
+
+        assert!(topic.is_standard());
+
+        let topic = ContentTopic::parse("/waku-a2a/1/staging/task/02ab/proto").unwrap();
+        assert_eq!(topic.application, "waku-a2a");
+        assert_eq!(topic.name, "staging/task/02ab");
+        assert!(!topic.is_standard());
+
+        assert!(ContentTopic::parse("toychat/2/huilong/proto").is_err());
+        assert!(ContentTopic::parse("/toychat/2/proto").is_err());
+        assert!(ContentTopic::parse("/toychat//huilong/proto").is_err());
+        assert!(ContentTopic::parse("/1/toychat/2/huilong/proto").is_err());
+    }
+
+    #[test]
+    fn test_shard_vectors() {
+        // Vectors shared with nwaku and js-waku for 8 shards
+        let sharding = AutoSharding::new(1, 8).unwrap();
+        for (topic, shard) in [
+            ("/toychat/2/huilong/proto", 3),
+            ("/myapp/1/latest/proto", 0),
+            ("/waku/2/content/test.js", 1),
+            ("/0/toychat/2/huilong/proto", 3),
+            ("/app/22/sometopic/someencoding", 2),
+            ("/app/27/sometopic/someencoding", 5),
+            ("/app/20/sometopic/someencoding", 7),
+            ("/app/29/sometopic/someencoding", 6),
+        ] {
+            let parsed = ContentTopic::parse(topic).unwrap();
+            assert_eq!(sharding.shard(&parsed), shard, "{}", topic);
+        }
+        assert_eq!(
+            sharding.pubsub_topic("/toychat/2/huilong/proto").unwrap(),
+            "/waku/2/rs/1/3"
+        );
+    }
+
+    #[test]
+    fn test_topics_of_one_app_share_a_shard() {
+        let routing = PubsubRouting::default();
+        let discovery = routing.pubsub_topic("/waku-a2a/1/discovery/proto").unwrap();
+        assert_eq!(
+            routing.pubsub_topic("/waku-a2a/1/task/02ab/proto").unwrap(),
+            discovery
+        );
+        assert_eq!(
+            routing.pubsub_topic("/waku-a2a/1/ack/m1/proto").unwrap(),
+            discovery
+        );
+        assert!(AutoSharding::new(1, 0).is_err());
+
+        let legacy = PubsubRouting::Static(LEGACY_PUBSUB_TOPIC.to_string());
+        assert_eq!(
+            legacy.pubsub_topic("anything").unwrap(),
+            LEGACY_PUBSUB_TOPIC
+        );
+    }
+}
@@ -0,0 +1,792 @@
+//! Transport combinators.
+//!
+//! Wrappers that make several `WakuTransport`s look like one:
+//!
+//! - `FallbackTransport` uses the first backend that works, e.g. a primary
+//!   nwaku with a backup. A backend that fails is skipped and probed with
+//!   `WakuTransport::health_check` every recovery interval, so traffic
+//!   returns to the primary once it is healthy.
+//! - `FanoutTransport` publishes to and receives from all backends at once,
+//!   e.g. to mirror traffic onto a second network during a migration. Copies
+//!   of a message arriving through several backends are delivered once.
+//! - `TopicRouterTransport` hands each content topic to the backend registered
+//!   for its longest matching prefix.
+//!
+//! Backends are boxed, so they can be of different types and combinators can
+//! be nested.
+
+use crate::message::now_nanos;
+use crate::message::SeenHashes;
+pub use crate::message::DEDUP_CAPACITY;
+use crate::rate_limit::RateLimited;
+use crate::{
+    HistoryPage, HistoryQuery, Priority, WakuMessage, WakuTransport, STREAM_POLL_INTERVAL,
+};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures::future::{join_all, BoxFuture};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// How often `FallbackTransport` probes a failed backend by default.
+pub const DEFAULT_RECOVERY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Uses the first of its backends that works.
+///
+/// Every call goes to the preferred backend that hasn't failed; on error the
+/// next one is tried. Any error counts as a failure of the backend, except
+/// `RateLimited`, which is returned as is. A failed backend is used again
+/// once its `health_check` succeeds, which is probed at most once per
+/// recovery interval. Subscriptions are tracked and registered again with a
+/// backend before it is used after a failure or a switch, since it may have
+/// missed them or lost them in a restart.
+pub struct FallbackTransport {
+    backends: Vec<Box<dyn WakuTransport>>,
+    recovery_interval: Duration,
+    state: Mutex<FallbackState>,
+    /// Signalled whenever the active backend changes.
+    switched: Notify,
+}
+
+struct FallbackState {
+    /// Index of the backend that served the last successful call.
+    active: usize,
+    /// When each backend last failed or failed its health check, if it
+    /// hasn't succeeded since.
+    failed_at: Vec<Option<Instant>>,
+    /// Whether each backend has all of `subscriptions`.
+    synced: Vec<bool>,
+    subscriptions: BTreeSet<String>,
+}
+
+impl FallbackTransport {
+    /// Use `backends` in order of preference. Panics if there are none.
+    pub fn new(backends: Vec<Box<dyn WakuTransport>>) -> Self {
+        assert!(!backends.is_empty(), "FallbackTransport needs a backend");
+        let count = backends.len();
+        Self {
+            backends,
+            recovery_interval: DEFAULT_RECOVERY_INTERVAL,
+            state: Mutex::new(FallbackState {
+                active: 0,
+                failed_at: vec![None; count],
+                synced: vec![true; count],
+                subscriptions: BTreeSet::new(),
+            }),
+            switched: Notify::new(),
+        }
+    }
+
+    /// Probe a failed backend every `interval` until it is healthy again.
+    pub fn with_recovery_interval(mut self, interval: Duration) -> Self {
+        self.recovery_interval = interval;
+        self
+    }
+
+    /// Index of the backend that served the last successful call.
+    pub fn active(&self) -> usize {
+        self.state.lock().unwrap().active
+    }
+
+    /// Backends to try in order: those that haven't failed and failed ones
+    /// whose health check succeeds, or all of them if none qualify.
+    async fn candidates(&self) -> Vec<usize> {
+        let now = Instant::now();
+        let failed_at = self.state.lock().unwrap().failed_at.clone();
+        let mut available = Vec::new();
+        for (index, failed_at) in failed_at.into_iter().enumerate() {
+            match failed_at {
+                None => available.push(index),
+                Some(t) if now.duration_since(t) >= self.recovery_interval => {
+                    match self.backends[index].health_check().await {
+                        Ok(()) => available.push(index),
+                        Err(e) => {
+                            eprintln!("[transport] Backend {} still unhealthy: {:#}", index, e);
+                            self.state.lock().unwrap().failed_at[index] = Some(Instant::now());
+                        }
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+        if available.is_empty() {
+            (0..self.backends.len()).collect()
+        } else {
+            available
+        }
+    }
+
+    /// Run `op` on the first candidate backend that succeeds.
+    async fn run<'a, R>(
+        &'a self,
+        op: impl Fn(&'a dyn WakuTransport) -> BoxFuture<'a, Result<R>>,
+    ) -> Result<R> {
+        let mut last_err = None;
+        for index in self.candidates().await {
+            let result = match self.sync(index).await {
+                Ok(()) => op(self.backends[index].as_ref()).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(value) => {
+                    let mut state = self.state.lock().unwrap();
+                    state.failed_at[index] = None;
+                    if state.active != index {
+                        eprintln!("[transport] Switched to backend {}", index);
+                        state.active = index;
+                        self.switched.notify_waiters();
+                    }
+                    return Ok(value);
+                }
+                // The backend works but wants us to slow down; another one
+                // wouldn't take the same traffic
+                Err(e) if e.downcast_ref::<RateLimited>().is_some() => return Err(e),
+                Err(e) => {
+                    eprintln!("[transport] Backend {} failed: {:#}", index, e);
+                    let mut state = self.state.lock().unwrap();
+                    state.failed_at[index] = Some(Instant::now());
+                    state.synced[index] = false;
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.expect("FallbackTransport has a backend")).context("All backends failed")
+    }
+
+    /// Register the tracked subscriptions with a backend that may lack them.
+    async fn sync(&self, index: usize) -> Result<()> {
+        let topics = {
+            let state = self.state.lock().unwrap();
+            if state.synced[index] {
+                return Ok(());
+            }
+            state.subscriptions.clone()
+        };
+        for topic in &topics {
+            self.backends[index].subscribe(topic).await?;
+        }
+        self.state.lock().unwrap().synced[index] = true;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl WakuTransport for FallbackTransport {
+    async fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
+        self.run(|t| t.publish(topic, payload)).await
+    }
+
+    async fn publish_message(&self, message: &WakuMessage) -> Result<()> {
+        self.run(|t| t.publish_message(message)).await
+    }
+
+    async fn publish_prioritized(&self, message: &WakuMessage, priority: Priority) -> Result<()> {
+        self.run(|t| t.publish_prioritized(message, priority)).await
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<()> {
+        self.run(|t| t.subscribe(topic)).await?;
+        let mut state = self.state.lock().unwrap();
+        if state.subscriptions.insert(topic.to_string()) {
+            // Only the active backend has it
+            let active = state.active;
+            for (i, synced) in state.synced.iter_mut().enumerate() {
+                *synced &= i == active;
+            }
+        }
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, topic: &str) -> Result<()> {
+        if !self.state.lock().unwrap().subscriptions.remove(topic) {
+            return Ok(());
+        }
+        self.run(|t| t.unsubscribe(topic)).await
+    }
+
+    async fn poll(&self, topic: &str) -> Result<Vec<WakuMessage>> {
+        self.run(|t| t.poll(topic)).await
+    }
+
+    /// Cursors are only valid on the backend that returned them.
+    async fn query_history(&self, query: &HistoryQuery) -> Result<HistoryPage> {
+        self.run(|t| t.query_history(query)).await
+    }
+
+    /// Healthy if any backend is.
+    async fn health_check(&self) -> Result<()> {
+        self.run(|t| t.health_check()).await
+    }
+
+    /// Streams from the active backend, moving to another one when calls
+    /// switch backends. Health is checked every recovery interval, so a
+    /// stream fails over and back even when nothing else uses the transport.
+    fn subscribe_stream<'a>(&'a self, topic: &str) -> BoxStream<'a, WakuMessage> {
+        struct State<'a> {
+            topic: String,
+            subscribed: bool,
+            backend: Option<usize>,
+            messages: Option<BoxStream<'a, WakuMessage>>,
+            next_check: Instant,
+            /// A backend switched back to may still hold messages already
+            /// received through another one.
+            seen: SeenHashes,
+        }
+
+        let state = State {
+            topic: topic.to_string(),
+            subscribed: false,
+            backend: None,
+            messages: None,
+            next_check: Instant::now() + self.recovery_interval,
+            seen: SeenHashes::new(DEDUP_CAPACITY),
+        };
+        Box::pin(futures::stream::unfold(state, move |mut st| async move {
+            loop {
+                if !st.subscribed {
+                    match self.subscribe(&st.topic).await {
+                        Ok(()) => st.subscribed = true,
+                        Err(e) => {
+                            eprintln!("[transport] Subscribe to {} failed: {:#}", st.topic, e);
+                            tokio::time::sleep(STREAM_POLL_INTERVAL).await;
+                            continue;
+                        }
+                    }
+                }
+                let active = self.active();
+                if st.backend != Some(active) {
+                    st.messages = Some(self.backends[active].subscribe_stream(&st.topic));
+                    st.backend = Some(active);
+                }
+                let messages = st.messages.as_mut().expect("set above");
+
+                let switched = self.switched.notified();
+                tokio::select! {
+                    message = messages.next() => match message {
+                        Some(message) => {
+                            if st.seen.insert(message.hash("")) {
+                                return Some((message, st));
+                            }
+                        }
+                        None => {
+                            st.backend = None;
+                            tokio::time::sleep(STREAM_POLL_INTERVAL).await;
+                        }
+                    },
+                    () = switched => {}
+                    () = tokio::time::sleep_until(st.next_check) => {
+                        st.next_check = Instant::now() + self.recovery_interval;
+                        if let Err(e) = self.health_check().await {
+                            eprintln!("[transport] {:#}", e);
+                        }
+                    }
+                }
+            }
+        }))
+    }
+}
+
+/// Publishes to and receives from all of its backends.
+///
+/// Calls succeed if at least one backend succeeds; failures of the others are
+/// logged. Received messages are deduplicated by their hash without the
+/// pubsub topic, so networks sharded differently still match, which is why
+/// `publish` stamps the timestamp once for all backends.
+pub struct FanoutTransport {
+    backends: Vec<Box<dyn WakuTransport>>,
+    seen: Mutex<SeenHashes>,
+}
+
+impl FanoutTransport {
+    /// Panics if there are no backends.
+    pub fn new(backends: Vec<Box<dyn WakuTransport>>) -> Self {
+        assert!(!backends.is_empty(), "FanoutTransport needs a backend");
+        Self {
+            backends,
+            seen: Mutex::new(SeenHashes::new(DEDUP_CAPACITY)),
+        }
+    }
+
+    fn dedup(&self, messages: Vec<WakuMessage>) -> Vec<WakuMessage> {
+        let mut seen = self.seen.lock().unwrap();
+        messages
+            .into_iter()
+            .filter(|m| seen.insert(m.hash("")))
+            .collect()
+    }
+}
+
+/// Results of the backends that succeeded, or the first error if none did.
+fn any_ok<T>(results: Vec<Result<T>>, what: &str) -> Result<Vec<T>> {
+    let mut values = Vec::new();
+    let mut first_err = None;
+    for (index, result) in results.into_iter().enumerate() {
+        match result {
+            Ok(value) => values.push(value),
+            Err(e) => {
+                eprintln!("[transport] {} on backend {} failed: {:#}", what, index, e);
+                first_err.get_or_insert(e);
+            }
+        }
+    }
+    match first_err {
+        Some(e) if values.is_empty() => {
+            Err(e).with_context(|| format!("{} failed on all backends", what))
+        }
+        _ => Ok(values),
+    }
+}
+
+#[async_trait]
+impl WakuTransport for FanoutTransport {
+    async fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
+        self.publish_message(&WakuMessage::new(topic, payload))
+            .await
+    }
+
+    async fn publish_message(&self, message: &WakuMessage) -> Result<()> {
+        self.publish_prioritized(message, Priority::of(message))
+            .await
+    }
+
+    async fn publish_prioritized(&self, message: &WakuMessage, priority: Priority) -> Result<()> {
+        let mut message = message.clone();
+        message.timestamp.get_or_insert_with(now_nanos);
+        let results = join_all(
+            self.backends
+                .iter()
+                .map(|b| b.publish_prioritized(&message, priority)),
+        )
+        .await;
+        any_ok(results, "Publish")?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<()> {
+        let results = join_all(self.backends.iter().map(|b| b.subscribe(topic))).await;
+        any_ok(results, "Subscribe")?;
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, topic: &str) -> Result<()> {
+        let results = join_all(self.backends.iter().map(|b| b.unsubscribe(topic))).await;
+        any_ok(results, "Unsubscribe")?;
+        Ok(())
+    }
+
+    async fn poll(&self, topic: &str) -> Result<Vec<WakuMessage>> {
+        let results = join_all(self.backends.iter().map(|b| b.poll(topic))).await;
+        let messages = any_ok(results, "Poll")?.into_iter().flatten().collect();
+        Ok(self.dedup(messages))
+    }
+
+    /// Served by the first backend that answers; pages of different stores
+    /// can't be merged.
+    async fn query_history(&self, query: &HistoryQuery) -> Result<HistoryPage> {
+        let mut last_err = None;
+        for (index, backend) in self.backends.iter().enumerate() {
+            match backend.query_history(query).await {
+                Ok(page) => return Ok(page),
+                Err(e) => {
+                    eprintln!(
+                        "[transport] History query on backend {} failed: {:#}",
+                        index, e
+                    );
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.expect("FanoutTransport has a backend"))
+            .context("History query failed on all backends")
+    }
+
+    /// Healthy if any backend is.
+    async fn health_check(&self) -> Result<()> {
+        let results = join_all(self.backends.iter().map(|b| b.health_check())).await;
+        any_ok(results, "Health check")?;
+        Ok(())
+    }
+
+    /// Merges the backends' streams.
+    fn subscribe_stream<'a>(&'a self, topic: &str) -> BoxStream<'a, WakuMessage> {
+        let streams = self.backends.iter().map(|b| b.subscribe_stream(topic));
+        Box::pin(futures::stream::select_all(streams).filter(move |message| {
+            let new = self.seen.lock().unwrap().insert(message.hash(""));
+            async move { new }
+        }))
+    }
+}
+
+/// Sends each content topic to the backend of its longest matching prefix,
+/// or to the default backend.
+pub struct TopicRouterTransport {
+    default: Box<dyn WakuTransport>,
+    /// Longest prefix first.
+    routes: Vec<(String, Box<dyn WakuTransport>)>,
+}
+
+impl TopicRouterTransport {
+    pub fn new(default: Box<dyn WakuTransport>) -> Self {
+        Self {
+            default,
+            routes: Vec::new(),
+        }
+    }
+
+    /// Send topics starting with `prefix` to `backend`.
+    pub fn with_route(mut self, prefix: &str, backend: Box<dyn WakuTransport>) -> Self {
+        self.routes.push((prefix.to_string(), backend));
+        self.routes
+            .sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        self
+    }
+
+    fn backend(&self, topic: &str) -> &dyn WakuTransport {
+        self.routes
+            .iter()
+            .find(|(prefix, _)| topic.starts_with(prefix.as_str()))
+            .map(|(_, backend)| backend.as_ref())
+            .unwrap_or(self.default.as_ref())
+    }
+}
+
+#[async_trait]
+impl WakuTransport for TopicRouterTransport {
+    async fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
+        self.backend(topic).publish(topic, payload).await
+    }
+
+    async fn publish_message(&self, message: &WakuMessage) -> Result<()> {
+        self.backend(&message.content_topic)
+            .publish_message(message)
+            .await
+    }
+
+    async fn publish_prioritized(&self, message: &WakuMessage, priority: Priority) -> Result<()> {
+        self.backend(&message.content_topic)
+            .publish_prioritized(message, priority)
+            .await
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<()> {
+        self.backend(topic).subscribe(topic).await
+    }
+
+    async fn unsubscribe(&self, topic: &str) -> Result<()> {
+        self.backend(topic).unsubscribe(topic).await
+    }
+
+    async fn poll(&self, topic: &str) -> Result<Vec<WakuMessage>> {
+        self.backend(topic).poll(topic).await
+    }
+
+    async fn query_history(&self, query: &HistoryQuery) -> Result<HistoryPage> {
+        self.backend(&query.content_topic)
+            .query_history(query)
+            .await
+    }
+
+    /// Healthy if every backend is, since each serves its own topics.
+    async fn health_check(&self) -> Result<()> {
+        self.default.health_check().await?;
+        for (prefix, backend) in &self.routes {
+            backend
+                .health_check()
+                .await
+                .with_context(|| format!("Backend for {} is unhealthy", prefix))?;
+        }
+        Ok(())
+    }
+
+    fn subscribe_stream<'a>(&'a self, topic: &str) -> BoxStream<'a, WakuMessage> {
+        self.backend(topic).subscribe_stream(topic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SimNetwork, SimTransport};
+    use anyhow::bail;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    const TOPIC: &str = "/waku-a2a/1/task/02ab/proto";
+
+    /// A backend that can be switched off, or reported unhealthy while its
+    /// calls still work.
+    #[derive(Clone)]
+    struct Flaky {
+        inner: SimTransport,
+        down: Arc<AtomicBool>,
+        unhealthy: Arc<AtomicBool>,
+    }
+
+    impl Flaky {
+        fn new(inner: SimTransport) -> Self {
+            Self {
+                inner,
+                down: Arc::new(AtomicBool::new(false)),
+                unhealthy: Arc::new(AtomicBool::new(false)),
+            }
+        }
+
+        fn set_down(&self, down: bool) {
+            self.down.store(down, Ordering::SeqCst);
+        }
+
+        fn set_unhealthy(&self, unhealthy: bool) {
+            self.unhealthy.store(unhealthy, Ordering::SeqCst);
+        }
+
+        fn check(&self) -> Result<()> {
+            if self.down.load(Ordering::SeqCst) {
+                bail!("backend down");
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl WakuTransport for Flaky {
+        async fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
+            self.check()?;
+            self.inner.publish(topic, payload).await
+        }
+
+        async fn publish_message(&self, message: &WakuMessage) -> Result<()> {
+            self.check()?;
+            self.inner.publish_message(message).await
+        }
+
+        async fn subscribe(&self, topic: &str) -> Result<()> {
+            self.check()?;
+            self.inner.subscribe(topic).await
+        }
+
+        async fn poll(&self, topic: &str) -> Result<Vec<WakuMessage>> {
+            self.check()?;
+            self.inner.poll(topic).await
+        }
+
+        async fn health_check(&self) -> Result<()> {
+            self.check()?;
+            if self.unhealthy.load(Ordering::SeqCst) {
+                bail!("backend unhealthy");
+            }
+            Ok(())
+        }
+    }
+
+    fn payloads(messages: Vec<WakuMessage>) -> Vec<Vec<u8>> {
+        messages.into_iter().map(|m| m.payload).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fallback_fails_over_and_recovers() {
+        let network = SimNetwork::new(1);
+        let primary = Flaky::new(network.join("primary"));
+        let peer = network.join("peer");
+        let t = FallbackTransport::new(vec![
+            Box::new(primary.clone()),
+            Box::new(network.join("backup")),
+        ]);
+        t.subscribe(TOPIC).await.unwrap();
+        peer.publish(TOPIC, b"1").await.unwrap();
+        assert_eq!(payloads(t.poll(TOPIC).await.unwrap()), vec![b"1"]);
+        assert_eq!(t.active(), 0);
+
+        // The backup takes over with our subscriptions
+        primary.set_down(true);
+        assert!(t.poll(TOPIC).await.unwrap().is_empty());
+        assert_eq!(t.active(), 1);
+        peer.publish(TOPIC, b"2").await.unwrap();
+        assert_eq!(payloads(t.poll(TOPIC).await.unwrap()), vec![b"2"]);
+        t.publish(TOPIC, b"3").await.unwrap();
+        assert_eq!(network.published_by("backup").len(), 1);
+
+        // The primary is left alone until the recovery interval is over
+        primary.set_down(false);
+        t.poll(TOPIC).await.unwrap();
+        assert_eq!(t.active(), 1);
+        tokio::time::advance(DEFAULT_RECOVERY_INTERVAL).await;
+        t.poll(TOPIC).await.unwrap();
+        assert_eq!(t.active(), 0);
+        peer.publish(TOPIC, b"4").await.unwrap();
+        assert_eq!(payloads(t.poll(TOPIC).await.unwrap()), vec![b"4"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fallback_waits_for_health_check() {
+        let network = SimNetwork::new(1);
+        let primary = Flaky::new(network.join("primary"));
+        let t = FallbackTransport::new(vec![
+            Box::new(primary.clone()),
+            Box::new(network.join("backup")),
+        ]);
+        primary.set_down(true);
+        t.publish(TOPIC, b"1").await.unwrap();
+        assert_eq!(t.active(), 1);
+
+        // Reachable again but not ready: stays on the backup
+        primary.set_down(false);
+        primary.set_unhealthy(true);
+        tokio::time::advance(DEFAULT_RECOVERY_INTERVAL).await;
+        t.publish(TOPIC, b"2").await.unwrap();
+        assert_eq!(t.active(), 1);
+        assert!(network.published_by("primary").is_empty());
+
+        primary.set_unhealthy(false);
+        tokio::time::advance(DEFAULT_RECOVERY_INTERVAL).await;
+        t.publish(TOPIC, b"3").await.unwrap();
+        assert_eq!(t.active(), 0);
+        assert_eq!(network.published_by("primary").len(), 1);
+    }
+
+    /// Publish `payload` from `peer` after `delay` while reading the next
+    /// message from `stream`, which only makes progress while polled.
+    async fn publish_and_receive(
+        stream: &mut BoxStream<'_, WakuMessage>,
+        peer: &SimTransport,
+        payload: &[u8],
+        delay: Duration,
+    ) -> WakuMessage {
+        let publish = async {
+            tokio::time::sleep(delay).await;
+            peer.publish(TOPIC, payload).await.unwrap();
+        };
+        let receive = tokio::time::timeout(delay + Duration::from_secs(60), stream.next());
+        let ((), message) = tokio::join!(publish, receive);
+        message.expect("stream timed out").expect("stream ended")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fallback_stream_fails_over_and_back() {
+        let network = SimNetwork::new(1);
+        let primary = Flaky::new(network.join("primary"));
+        let peer = network.join("peer");
+        let t = FallbackTransport::new(vec![
+            Box::new(primary.clone()),
+            Box::new(network.join("backup")),
+        ]);
+        let mut stream = t.subscribe_stream(TOPIC);
+        let delay = Duration::from_secs(1);
+
+        let message = publish_and_receive(&mut stream, &peer, b"1", delay).await;
+        assert_eq!(message.payload, b"1");
+
+        // The periodic health check moves the stream to the backup
+        primary.set_down(true);
+        let delay = DEFAULT_RECOVERY_INTERVAL * 2;
+        let message = publish_and_receive(&mut stream, &peer, b"2", delay).await;
+        assert_eq!(message.payload, b"2");
+        assert_eq!(t.active(), 1);
+
+        // ...and back once the primary is healthy
+        primary.set_down(false);
+        let message = publish_and_receive(&mut stream, &peer, b"3", delay).await;
+        assert_eq!(message.payload, b"3");
+        assert_eq!(t.active(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fallback_passes_rate_limits_through() {
+        use crate::rate_limit::{RateLimit, RateLimitedTransport};
+
+        let network = SimNetwork::new(1);
+        let limit = RateLimit::new(1, Duration::from_secs(60)).with_max_wait(Duration::ZERO);
+        let t = FallbackTransport::new(vec![
+            Box::new(RateLimitedTransport::new(network.join("primary"), limit)),
+            Box::new(network.join("backup")),
+        ]);
+        t.publish(TOPIC, b"1").await.unwrap();
+        let err = t.publish(TOPIC, b"2").await.unwrap_err();
+        assert!(err.downcast_ref::<RateLimited>().is_some(), "{:#}", err);
+        // Neither failed over nor sent through the backup
+        assert_eq!(t.active(), 0);
+        assert!(network.published_by("backup").is_empty());
+        t.subscribe(TOPIC).await.unwrap();
+        assert!(network.subscriptions("backup").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fallback_fails_when_all_backends_fail() {
+        let network = SimNetwork::new(1);
+        let a = Flaky::new(network.join("a"));
+        let b = Flaky::new(network.join("b"));
+        a.set_down(true);
+        b.set_down(true);
+        let t = FallbackTransport::new(vec![Box::new(a.clone()), Box::new(b)]);
+        assert!(t.publish(TOPIC, b"x").await.is_err());
+
+        // Failed backends are retried when nothing else is left
+        a.set_down(false);
+        t.publish(TOPIC, b"x").await.unwrap();
+        assert_eq!(t.active(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_fanout_bridges_networks() {
+        let (old, new) = (SimNetwork::new(1), SimNetwork::new(2));
+        let t = FanoutTransport::new(vec![Box::new(old.join("me")), Box::new(new.join("me"))]);
+        let (old_peer, new_peer) = (old.join("peer"), new.join("peer"));
+        t.subscribe(TOPIC).await.unwrap();
+        old_peer.subscribe(TOPIC).await.unwrap();
+        new_peer.subscribe(TOPIC).await.unwrap();
+
+        t.publish(TOPIC, b"both").await.unwrap();
+        assert_eq!(payloads(old_peer.poll(TOPIC).await.unwrap()), vec![b"both"]);
+        assert_eq!(payloads(new_peer.poll(TOPIC).await.unwrap()), vec![b"both"]);
+        // Our own message came back on both networks but is delivered once
+        assert_eq!(payloads(t.poll(TOPIC).await.unwrap()), vec![b"both"]);
+
+        old_peer.publish(TOPIC, b"old").await.unwrap();
+        new_peer.publish(TOPIC, b"new").await.unwrap();
+        let mut received = payloads(t.poll(TOPIC).await.unwrap());
+        received.sort();
+        assert_eq!(received, vec![b"new".to_vec(), b"old".to_vec()]);
+
+        let copy = WakuMessage::new(TOPIC, b"copy".to_vec()).with_timestamp(7);
+        old.deliver("me", copy.clone());
+        new.deliver("me", copy);
+        let mut stream = t.subscribe_stream(TOPIC);
+        assert_eq!(stream.next().await.unwrap().payload, b"copy");
+        let next = tokio::time::timeout(Duration::from_millis(50), stream.next()).await;
+        assert!(next.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fanout_tolerates_failed_backend() {
+        let network = SimNetwork::new(1);
+        let a = Flaky::new(network.join("a"));
+        let b = Flaky::new(network.join("b"));
+        let t = FanoutTransport::new(vec![Box::new(a.clone()), Box::new(b.clone())]);
+        a.set_down(true);
+        t.publish(TOPIC, b"x").await.unwrap();
+        assert_eq!(network.published_by("b").len(), 1);
+        b.set_down(true);
+        assert!(t.publish(TOPIC, b"y").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_topic_router() {
+        let (main, discovery) = (SimNetwork::new(1), SimNetwork::new(2));
+        let t = TopicRouterTransport::new(Box::new(main.join("me")))
+            .with_route("/waku-a2a/1/", Box::new(main.join("other")))
+            .with_route("/waku-a2a/1/discovery/", Box::new(discovery.join("me")));
+
+        t.publish("/waku-a2a/1/discovery/proto", b"card")
+            .await
+            .unwrap();
+        t.publish(TOPIC, b"task").await.unwrap();
+        t.publish("/other/1/x/proto", b"misc").await.unwrap();
+        assert_eq!(payloads(discovery.published_by("me")), vec![b"card"]);
+        assert_eq!(payloads(main.published_by("other")), vec![b"task"]);
+        assert_eq!(payloads(main.published_by("me")), vec![b"misc"]);
+    }
+}
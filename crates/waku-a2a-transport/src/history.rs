@@ -0,0 +1,79 @@
+//! Message history queries (Waku Store).
+//!
+//! Relay only delivers messages to peers online at the time. Store nodes keep
+//! a history that can be queried page by page, so an agent coming back online
+//! can fetch what it missed.
+
+use crate::{WakuMessage, WakuTransport};
+use anyhow::Result;
+
+/// Pages fetched by `fetch_history` before it stops.
+pub const MAX_HISTORY_PAGES: usize = 50;
+
+/// A history query on one content topic.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistoryQuery {
+    pub content_topic: String,
+    /// Oldest message timestamp to return, in Unix nanoseconds.
+    pub start_time: Option<i64>,
+    /// Newest message timestamp to return, in Unix nanoseconds.
+    pub end_time: Option<i64>,
+    /// Messages per page (None = the backend's default).
+    pub page_size: Option<u32>,
+    /// Cursor of the previous page, to continue after it.
+    pub cursor: Option<String>,
+}
+
+impl HistoryQuery {
+    pub fn new(content_topic: &str) -> Self {
+        Self {
+            content_topic: content_topic.to_string(),
+            ..Self::default()
+        }
+    }
+
+    pub fn since(mut self, unix_nanos: i64) -> Self {
+        self.start_time = Some(unix_nanos);
+        self
+    }
+
+    pub fn until(mut self, unix_nanos: i64) -> Self {
+        self.end_time = Some(unix_nanos);
+        self
+    }
+
+    pub fn with_page_size(mut self, page_size: u32) -> Self {
+        self.page_size = Some(page_size);
+        self
+    }
+}
+
+/// One page of history, oldest message first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistoryPage {
+    pub messages: Vec<WakuMessage>,
+    /// Set when more messages follow; pass it as the next query's cursor.
+    pub cursor: Option<String>,
+}
+
+/// Fetch every page of `query`, up to `MAX_HISTORY_PAGES`.
+pub async fn fetch_history<T: WakuTransport + ?Sized>(
+    transport: &T,
+    query: &HistoryQuery,
+) -> Result<Vec<WakuMessage>> {
+    let mut query = query.clone();
+    let mut messages = Vec::new();
+    for _ in 0..MAX_HISTORY_PAGES {
+        let page = transport.query_history(&query).await?;
+        messages.extend(page.messages);
+        match page.cursor {
+            Some(cursor) => query.cursor = Some(cursor),
+            None => return Ok(messages),
+        }
+    }
+    eprintln!(
+        "[transport] History of {} truncated after {} pages",
+        query.content_topic, MAX_HISTORY_PAGES
+    );
+    Ok(messages)
+}
@@ -0,0 +1,1077 @@
+//! Local IPC bus for agents on the same host.
+//!
+//! An `IpcHub` listens on a Unix domain socket; agent processes connect to it
+//! with `IpcTransport` and publish and subscribe with the same content topic
+//! semantics as Waku Relay: every subscriber of a topic, the publisher
+//! included, gets each message, stamped with a timestamp if it had none.
+//! The hub runs as its own process (`waku-a2a hub`) or embedded in one of
+//! the agents.
+//!
+//! With an upstream transport (e.g. `NwakuRestTransport`), the hub bridges to
+//! the Waku network: local publishes are forwarded upstream, upstream
+//! messages on topics local clients subscribe to are delivered to them, and
+//! history queries are answered by the upstream store. Upstream copies of
+//! messages published locally are recognized by their hash and dropped.
+//!
+//! The protocol is newline-delimited JSON. Clients send requests
+//! (`publish`, `subscribe`, `unsubscribe`, `history`) with an `id` and get a
+//! `reply` with the same `id`; the hub pushes `message` events for
+//! subscribed topics in between. A client whose hub goes away reconnects on
+//! its next request, or from a `subscribe_stream` that notices, and
+//! subscribes again. The hub buffers at most `IpcHubConfig::event_capacity`
+//! events for a client and disconnects one that falls further behind.
+
+use crate::message::{now_nanos, SeenHashes};
+use crate::nwaku_rest::{base64_decode, base64_encode, DEFAULT_QUEUE_CAPACITY};
+use crate::{HistoryPage, HistoryQuery, WakuMessage, WakuTransport, STREAM_POLL_INTERVAL};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot, watch, Notify};
+use tokio::task::JoinHandle;
+
+/// Hashes of recently delivered messages the hub remembers, to drop upstream
+/// echoes of local publishes.
+const SEEN_CAPACITY: usize = 4096;
+
+/// Default for `IpcHubConfig::event_capacity`.
+pub const DEFAULT_EVENT_CAPACITY: usize = 4096;
+
+/// A message on the wire, with binary fields in base64.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WireMessage {
+    content_topic: String,
+    payload: String,
+    #[serde(default)]
+    version: u32,
+    #[serde(default)]
+    timestamp: Option<i64>,
+    #[serde(default)]
+    ephemeral: bool,
+    #[serde(default)]
+    meta: String,
+    #[serde(default)]
+    pubsub_topic: Option<String>,
+}
+
+impl WireMessage {
+    fn from_message(message: &WakuMessage) -> Self {
+        Self {
+            content_topic: message.content_topic.clone(),
+            payload: base64_encode(&message.payload),
+            version: message.version,
+            timestamp: message.timestamp,
+            ephemeral: message.ephemeral,
+            meta: base64_encode(&message.meta),
+            pubsub_topic: message.pubsub_topic.clone(),
+        }
+    }
+
+    fn into_message(self) -> Result<WakuMessage> {
+        Ok(WakuMessage {
+            payload: base64_decode(&self.payload)?,
+            content_topic: self.content_topic,
+            version: self.version,
+            timestamp: self.timestamp,
+            ephemeral: self.ephemeral,
+            meta: base64_decode(&self.meta)?,
+            pubsub_topic: self.pubsub_topic,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WireQuery {
+    content_topic: String,
+    start_time: Option<i64>,
+    end_time: Option<i64>,
+    page_size: Option<u32>,
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct WirePage {
+    messages: Vec<WireMessage>,
+    cursor: Option<String>,
+}
+
+/// Client to hub.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    Publish { id: u64, message: WireMessage },
+    Subscribe { id: u64, topic: String },
+    Unsubscribe { id: u64, topic: String },
+    History { id: u64, query: WireQuery },
+}
+
+/// Hub to client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Event {
+    Reply {
+        id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        page: Option<WirePage>,
+    },
+    Message {
+        message: WireMessage,
+    },
+}
+
+fn encode_line<T: Serialize>(value: &T) -> String {
+    let mut line = serde_json::to_string(value).expect("wire types serialize");
+    line.push('\n');
+    line
+}
+
+/// Settings for `IpcHub::start`.
+pub struct IpcHubConfig {
+    pub socket: PathBuf,
+    /// Transport that off-host traffic goes through.
+    pub upstream: Option<Box<dyn WakuTransport>>,
+    /// Events buffered for a client that isn't reading them before it is
+    /// disconnected.
+    pub event_capacity: usize,
+}
+
+impl IpcHubConfig {
+    pub fn new(socket: impl AsRef<Path>) -> Self {
+        Self {
+            socket: socket.as_ref().to_path_buf(),
+            upstream: None,
+            event_capacity: DEFAULT_EVENT_CAPACITY,
+        }
+    }
+
+    pub fn with_upstream(mut self, upstream: impl WakuTransport + 'static) -> Self {
+        self.upstream = Some(Box::new(upstream));
+        self
+    }
+
+    pub fn with_event_capacity(mut self, capacity: usize) -> Self {
+        self.event_capacity = capacity.max(1);
+        self
+    }
+}
+
+/// A running hub. Stops and removes its socket when dropped.
+pub struct IpcHub {
+    socket: PathBuf,
+    state: Arc<HubState>,
+    accept: JoinHandle<()>,
+}
+
+struct HubState {
+    upstream: Option<Box<dyn WakuTransport>>,
+    event_capacity: usize,
+    clients: Mutex<HashMap<u64, HubClient>>,
+    /// Tasks relaying upstream messages of each locally subscribed topic.
+    bridges: Mutex<HashMap<String, JoinHandle<()>>>,
+    seen: Mutex<SeenHashes>,
+    /// Set when the hub stops, to disconnect its clients.
+    closing: watch::Sender<bool>,
+}
+
+struct HubClient {
+    events: mpsc::Sender<String>,
+    topics: HashSet<String>,
+    /// Signalled to disconnect a client that stopped reading.
+    kick: Arc<Notify>,
+}
+
+impl IpcHub {
+    /// Listen on the configured socket, replacing a stale socket file left
+    /// by a hub that didn't shut down cleanly. Fails if another hub is
+    /// listening there.
+    pub async fn start(config: IpcHubConfig) -> Result<Self> {
+        let socket = config.socket;
+        if UnixStream::connect(&socket).await.is_ok() {
+            bail!("An IPC hub is already listening on {}", socket.display());
+        }
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket)
+            .with_context(|| format!("Failed to listen on {}", socket.display()))?;
+
+        let state = Arc::new(HubState {
+            upstream: config.upstream,
+            event_capacity: config.event_capacity,
+            clients: Mutex::new(HashMap::new()),
+            bridges: Mutex::new(HashMap::new()),
+            seen: Mutex::new(SeenHashes::new(SEEN_CAPACITY)),
+            closing: watch::channel(false).0,
+        });
+        let accept = tokio::spawn(accept_clients(listener, state.clone()));
+        Ok(Self {
+            socket,
+            state,
+            accept,
+        })
+    }
+
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    /// Clients currently connected.
+    pub fn client_count(&self) -> usize {
+        self.state.clients.lock().unwrap().len()
+    }
+}
+
+impl Drop for IpcHub {
+    fn drop(&mut self) {
+        self.accept.abort();
+        for (_, bridge) in self.state.bridges.lock().unwrap().drain() {
+            bridge.abort();
+        }
+        self.state.closing.send_replace(true);
+        let _ = std::fs::remove_file(&self.socket);
+    }
+}
+
+async fn accept_clients(listener: UnixListener, state: Arc<HubState>) {
+    let mut next_id = 0u64;
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("[ipc] Accept failed: {}", e);
+                continue;
+            }
+        };
+        next_id += 1;
+        tokio::spawn(serve_client(next_id, stream, state.clone()));
+    }
+}
+
+async fn serve_client(client_id: u64, stream: UnixStream, state: Arc<HubState>) {
+    let (read, mut write) = stream.into_split();
+    let (events, mut outgoing) = mpsc::channel::<String>(state.event_capacity);
+    let kick = Arc::new(Notify::new());
+    state.clients.lock().unwrap().insert(
+        client_id,
+        HubClient {
+            events: events.clone(),
+            topics: HashSet::new(),
+            kick: kick.clone(),
+        },
+    );
+    let writer = tokio::spawn(async move {
+        while let Some(line) = outgoing.recv().await {
+            if write.write_all(line.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    let mut closing = state.closing.subscribe();
+    let mut lines = BufReader::new(read).lines();
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => match line {
+                Ok(Some(line)) => line,
+                _ => break,
+            },
+            _ = closing.wait_for(|closing| *closing) => break,
+            () = kick.notified() => {
+                eprintln!("[ipc] Client {} isn't keeping up, disconnecting", client_id);
+                break;
+            }
+        };
+        let request = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                eprintln!("[ipc] Dropping malformed request: {}", e);
+                continue;
+            }
+        };
+        let (state, events) = (state.clone(), events.clone());
+        tokio::spawn(async move {
+            let reply = state.handle(client_id, request).await;
+            let _ = events.send(encode_line(&reply)).await;
+        });
+    }
+
+    // Disconnected: drop its subscriptions
+    let topics = state
+        .clients
+        .lock()
+        .unwrap()
+        .remove(&client_id)
+        .map(|c| c.topics)
+        .unwrap_or_default();
+    for topic in topics {
+        state.release_topic(&topic).await;
+    }
+    writer.abort();
+}
+
+impl HubState {
+    async fn handle(self: &Arc<Self>, client_id: u64, request: Request) -> Event {
+        let (id, result) = match request {
+            Request::Publish { id, message } => (id, self.publish(message).await.map(|()| None)),
+            Request::Subscribe { id, topic } => {
+                (id, self.subscribe(client_id, &topic).await.map(|()| None))
+            }
+            Request::Unsubscribe { id, topic } => {
+                (id, self.unsubscribe(client_id, &topic).await.map(|()| None))
+            }
+            Request::History { id, query } => (id, self.history(query).await.map(Some)),
+        };
+        match result {
+            Ok(page) => Event::Reply {
+                id,
+                error: None,
+                page,
+            },
+            Err(e) => Event::Reply {
+                id,
+                error: Some(format!("{:#}", e)),
+                page: None,
+            },
+        }
+    }
+
+    /// Deliver to local subscribers, then forward upstream.
+    async fn publish(&self, message: WireMessage) -> Result<()> {
+        let mut message = message.into_message()?;
+        message.timestamp.get_or_insert_with(now_nanos);
+        self.deliver(&message);
+        if let Some(ref upstream) = self.upstream {
+            upstream
+                .publish_message(&message)
+                .await
+                .context("Delivered on this host, but not upstream")?;
+        }
+        Ok(())
+    }
+
+    /// Hand a message to every client subscribed to its topic, unless it
+    /// was delivered before. Clients whose buffer is full are disconnected.
+    fn deliver(&self, message: &WakuMessage) {
+        if !self.seen.lock().unwrap().insert(message.hash("")) {
+            return;
+        }
+        let line = encode_line(&Event::Message {
+            message: WireMessage::from_message(message),
+        });
+        for client in self.clients.lock().unwrap().values() {
+            if client.topics.contains(&message.content_topic)
+                && matches!(
+                    client.events.try_send(line.clone()),
+                    Err(mpsc::error::TrySendError::Full(_))
+                )
+            {
+                client.kick.notify_one();
+            }
+        }
+    }
+
+    async fn subscribe(self: &Arc<Self>, client_id: u64, topic: &str) -> Result<()> {
+        {
+            let mut clients = self.clients.lock().unwrap();
+            let Some(client) = clients.get_mut(&client_id) else {
+                return Ok(());
+            };
+            client.topics.insert(topic.to_string());
+        }
+        let Some(ref upstream) = self.upstream else {
+            return Ok(());
+        };
+        if self.bridges.lock().unwrap().contains_key(topic) {
+            return Ok(());
+        }
+        upstream
+            .subscribe(topic)
+            .await
+            .context("Subscribed on this host, but not upstream")?;
+        let state = self.clone();
+        let bridged = topic.to_string();
+        let bridge = tokio::spawn(async move {
+            let Some(ref upstream) = state.upstream else {
+                return;
+            };
+            let mut messages = upstream.subscribe_stream(&bridged);
+            while let Some(message) = messages.next().await {
+                state.deliver(&message);
+            }
+        });
+        let mut bridges = self.bridges.lock().unwrap();
+        if bridges.contains_key(topic) {
+            // Another client's subscribe got there first
+            bridge.abort();
+        } else {
+            bridges.insert(topic.to_string(), bridge);
+        }
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, client_id: u64, topic: &str) -> Result<()> {
+        let removed = self
+            .clients
+            .lock()
+            .unwrap()
+            .get_mut(&client_id)
+            .is_some_and(|c| c.topics.remove(topic));
+        if removed {
+            self.release_topic(topic).await;
+        }
+        Ok(())
+    }
+
+    /// Stop bridging `topic` once no client subscribes to it.
+    async fn release_topic(&self, topic: &str) {
+        let in_use = self
+            .clients
+            .lock()
+            .unwrap()
+            .values()
+            .any(|c| c.topics.contains(topic));
+        if in_use {
+            return;
+        }
+        let bridge = self.bridges.lock().unwrap().remove(topic);
+        if let Some(bridge) = bridge {
+            bridge.abort();
+            if let Some(ref upstream) = self.upstream {
+                if let Err(e) = upstream.unsubscribe(topic).await {
+                    eprintln!("[ipc] Upstream unsubscribe from {} failed: {:#}", topic, e);
+                }
+            }
+        }
+    }
+
+    async fn history(&self, query: WireQuery) -> Result<WirePage> {
+        let Some(ref upstream) = self.upstream else {
+            return Ok(WirePage {
+                messages: Vec::new(),
+                cursor: None,
+            });
+        };
+        let query = HistoryQuery {
+            content_topic: query.content_topic,
+            start_time: query.start_time,
+            end_time: query.end_time,
+            page_size: query.page_size,
+            cursor: query.cursor,
+        };
+        let page = upstream.query_history(&query).await?;
+        Ok(WirePage {
+            messages: page
+                .messages
+                .iter()
+                .map(WireMessage::from_message)
+                .collect(),
+            cursor: page.cursor,
+        })
+    }
+}
+
+/// `WakuTransport` over a connection to an `IpcHub`.
+pub struct IpcTransport {
+    socket: PathBuf,
+    shared: Arc<ClientShared>,
+    connection: tokio::sync::Mutex<Option<Connection>>,
+    next_id: AtomicU64,
+    /// Subscribed content topics, renewed after reconnecting.
+    subscriptions: Mutex<HashSet<String>>,
+}
+
+struct ClientShared {
+    /// Received messages of subscribed content topics.
+    queues: Mutex<HashMap<String, VecDeque<WakuMessage>>>,
+    /// Messages kept per content topic; the oldest are dropped beyond it.
+    queue_capacity: AtomicUsize,
+    /// Signalled whenever a message is queued or the connection closes.
+    arrived: Notify,
+    /// Requests waiting for their reply.
+    pending: Mutex<HashMap<u64, oneshot::Sender<Event>>>,
+}
+
+struct Connection {
+    write: OwnedWriteHalf,
+    reader: JoinHandle<()>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl IpcTransport {
+    /// Connect to the hub listening on `socket`.
+    pub async fn connect(socket: impl AsRef<Path>) -> Result<Self> {
+        let transport = Self {
+            socket: socket.as_ref().to_path_buf(),
+            shared: Arc::new(ClientShared {
+                queues: Mutex::new(HashMap::new()),
+                queue_capacity: AtomicUsize::new(DEFAULT_QUEUE_CAPACITY),
+                arrived: Notify::new(),
+                pending: Mutex::new(HashMap::new()),
+            }),
+            connection: tokio::sync::Mutex::new(None),
+            next_id: AtomicU64::new(1),
+            subscriptions: Mutex::new(HashSet::new()),
+        };
+        *transport.connection.lock().await = Some(transport.open().await?);
+        Ok(transport)
+    }
+
+    /// Keep at most `capacity` unpolled messages per content topic.
+    pub fn with_queue_capacity(self, capacity: usize) -> Self {
+        self.shared
+            .queue_capacity
+            .store(capacity.max(1), Ordering::SeqCst);
+        self
+    }
+
+    async fn open(&self) -> Result<Connection> {
+        let stream = UnixStream::connect(&self.socket).await.with_context(|| {
+            format!(
+                "Failed to connect to the IPC hub at {} (is it running?)",
+                self.socket.display()
+            )
+        })?;
+        let (read, write) = stream.into_split();
+        let closed = Arc::new(AtomicBool::new(false));
+        let reader = tokio::spawn(read_events(read, self.shared.clone(), closed.clone()));
+        Ok(Connection {
+            write,
+            reader,
+            closed,
+        })
+    }
+
+    /// Send a request and wait for its reply, reconnecting first if the hub
+    /// went away.
+    async fn call(&self, request: impl FnOnce(u64) -> Request) -> Result<Option<WirePage>> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (reply, response) = oneshot::channel();
+        self.shared.pending.lock().unwrap().insert(id, reply);
+        let line = encode_line(&request(id));
+
+        let sent = async {
+            let mut connection = self.connection.lock().await;
+            self.reconnect_if_closed(&mut connection).await?;
+            let conn = connection.as_mut().expect("connected above");
+            if let Err(e) = conn.write.write_all(line.as_bytes()).await {
+                conn.closed.store(true, Ordering::SeqCst);
+                return Err(e).context("Lost the connection to the IPC hub");
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = sent {
+            self.shared.pending.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+
+        match response.await {
+            Ok(Event::Reply {
+                error: Some(error), ..
+            }) => bail!("IPC hub: {}", error),
+            Ok(Event::Reply { page, .. }) => Ok(page),
+            Ok(Event::Message { .. }) => unreachable!("only replies are routed to requests"),
+            Err(_) => bail!("Lost the connection to the IPC hub"),
+        }
+    }
+
+    /// Connect again, and subscribe again, if the hub went away.
+    async fn reconnect_if_closed(&self, connection: &mut Option<Connection>) -> Result<()> {
+        if connection
+            .as_ref()
+            .is_some_and(|c| !c.closed.load(Ordering::SeqCst))
+        {
+            return Ok(());
+        }
+        *connection = None;
+        let mut fresh = self.open().await?;
+        self.resubscribe(&mut fresh).await?;
+        eprintln!("[ipc] Reconnected to {}", self.socket.display());
+        *connection = Some(fresh);
+        Ok(())
+    }
+
+    /// Whether the connection to the hub was lost.
+    async fn is_closed(&self) -> bool {
+        self.connection
+            .lock()
+            .await
+            .as_ref()
+            .is_none_or(|c| c.closed.load(Ordering::SeqCst))
+    }
+
+    /// Renew our subscriptions on a new connection. Replies use ID 0 and
+    /// are ignored.
+    async fn resubscribe(&self, connection: &mut Connection) -> Result<()> {
+        let topics: Vec<String> = self.subscriptions.lock().unwrap().iter().cloned().collect();
+        for topic in topics {
+            let line = encode_line(&Request::Subscribe { id: 0, topic });
+            connection
+                .write
+                .write_all(line.as_bytes())
+                .await
+                .context("Lost the connection to the IPC hub")?;
+        }
+        Ok(())
+    }
+}
+
+/// Route replies to their requests and queue pushed messages, dropping the
+/// oldest beyond the queue capacity. Pending requests fail and streams wake
+/// up once the hub disconnects.
+async fn read_events(
+    read: tokio::net::unix::OwnedReadHalf,
+    shared: Arc<ClientShared>,
+    closed: Arc<AtomicBool>,
+) {
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        match serde_json::from_str::<Event>(&line) {
+            Ok(reply @ Event::Reply { id, .. }) => {
+                if let Some(waiting) = shared.pending.lock().unwrap().remove(&id) {
+                    let _ = waiting.send(reply);
+                }
+            }
+            Ok(Event::Message { message }) => {
+                let message = match message.into_message() {
+                    Ok(message) => message,
+                    Err(e) => {
+                        eprintln!("[ipc] Dropping undecodable message: {:#}", e);
+                        continue;
+                    }
+                };
+                let capacity = shared.queue_capacity.load(Ordering::SeqCst);
+                let mut queues = shared.queues.lock().unwrap();
+                if let Some(queue) = queues.get_mut(&message.content_topic) {
+                    if queue.len() >= capacity {
+                        queue.pop_front();
+                    }
+                    queue.push_back(message);
+                    drop(queues);
+                    shared.arrived.notify_waiters();
+                }
+            }
+            Err(e) => eprintln!("[ipc] Dropping malformed event: {}", e),
+        }
+    }
+    closed.store(true, Ordering::SeqCst);
+    shared.pending.lock().unwrap().clear();
+    shared.arrived.notify_waiters();
+}
+
+#[async_trait]
+impl WakuTransport for IpcTransport {
+    async fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
+        self.publish_message(&WakuMessage::new(topic, payload))
+            .await
+    }
+
+    /// Messages without a timestamp are stamped by the hub.
+    async fn publish_message(&self, message: &WakuMessage) -> Result<()> {
+        let message = WireMessage::from_message(message);
+        self.call(|id| Request::Publish { id, message }).await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<()> {
+        if self.subscriptions.lock().unwrap().contains(topic) {
+            return Ok(());
+        }
+        self.shared
+            .queues
+            .lock()
+            .unwrap()
+            .entry(topic.to_string())
+            .or_default();
+        let topic_owned = topic.to_string();
+        self.call(|id| Request::Subscribe {
+            id,
+            topic: topic_owned,
+        })
+        .await?;
+        self.subscriptions.lock().unwrap().insert(topic.to_string());
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, topic: &str) -> Result<()> {
+        self.shared.queues.lock().unwrap().remove(topic);
+        if !self.subscriptions.lock().unwrap().remove(topic) {
+            return Ok(());
+        }
+        let topic = topic.to_string();
+        self.call(|id| Request::Unsubscribe { id, topic }).await?;
+        Ok(())
+    }
+
+    async fn poll(&self, topic: &str) -> Result<Vec<WakuMessage>> {
+        let mut queues = self.shared.queues.lock().unwrap();
+        let Some(queue) = queues.get_mut(topic) else {
+            return Ok(Vec::new());
+        };
+        Ok(queue.drain(..).collect())
+    }
+
+    /// Healthy if connected to the hub, reconnecting first if needed.
+    async fn health_check(&self) -> Result<()> {
+        self.reconnect_if_closed(&mut *self.connection.lock().await)
+            .await
+    }
+
+    /// Answered by the hub's upstream store; empty without an upstream.
+    async fn query_history(&self, query: &HistoryQuery) -> Result<HistoryPage> {
+        let query = WireQuery {
+            content_topic: query.content_topic.clone(),
+            start_time: query.start_time,
+            end_time: query.end_time,
+            page_size: query.page_size,
+            cursor: query.cursor.clone(),
+        };
+        let page = self
+            .call(|id| Request::History { id, query })
+            .await?
+            .context("IPC hub sent no history page")?;
+        Ok(HistoryPage {
+            messages: page
+                .messages
+                .into_iter()
+                .map(WireMessage::into_message)
+                .collect::<Result<_>>()?,
+            cursor: page.cursor,
+        })
+    }
+
+    /// Push delivery: messages are handed over as the hub sends them. If the
+    /// hub goes away the stream reconnects and subscribes again.
+    fn subscribe_stream<'a>(&'a self, topic: &str) -> BoxStream<'a, WakuMessage> {
+        let topic = topic.to_string();
+        Box::pin(futures::stream::unfold(
+            (VecDeque::new(), false),
+            move |(mut ready, mut subscribed)| {
+                let topic = topic.clone();
+                async move {
+                    loop {
+                        if let Some(message) = ready.pop_front() {
+                            return Some((message, (ready, subscribed)));
+                        }
+                        if !subscribed {
+                            match self.subscribe(&topic).await {
+                                Ok(()) => subscribed = true,
+                                Err(e) => {
+                                    eprintln!("[ipc] Subscribe to {} failed: {:#}", topic, e);
+                                    tokio::time::sleep(STREAM_POLL_INTERVAL).await;
+                                    continue;
+                                }
+                            }
+                        }
+                        // Register before polling so an arrival in between isn't missed
+                        let arrived = self.shared.arrived.notified();
+                        if self.is_closed().await {
+                            if let Err(e) = self.health_check().await {
+                                eprintln!("[ipc] Reconnect for {} failed: {:#}", topic, e);
+                                tokio::time::sleep(STREAM_POLL_INTERVAL).await;
+                                continue;
+                            }
+                        }
+                        ready.extend(self.poll(&topic).await.unwrap_or_default());
+                        if ready.is_empty() {
+                            arrived.await;
+                        }
+                    }
+                }
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sds::SdsTransport;
+    use crate::sim::SimNetwork;
+    use std::time::Duration;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "waku-a2a-{}-{}.sock",
+            name,
+            &uuid::Uuid::new_v4().to_string()[..8]
+        ))
+    }
+
+    async fn next(stream: &mut BoxStream<'_, WakuMessage>) -> WakuMessage {
+        tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("message within 5s")
+            .expect("stream open")
+    }
+
+    #[tokio::test]
+    async fn test_hub_routes_between_clients() {
+        let hub = IpcHub::start(IpcHubConfig::new(socket_path("routes")))
+            .await
+            .unwrap();
+        assert!(IpcHub::start(IpcHubConfig::new(hub.socket()))
+            .await
+            .is_err());
+        let a = IpcTransport::connect(hub.socket()).await.unwrap();
+        let b = IpcTransport::connect(hub.socket()).await.unwrap();
+
+        let mut stream = a.subscribe_stream("/app/1/chat/proto");
+        // Subscribed once the stream is polled
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), stream.next())
+                .await
+                .is_err()
+        );
+        b.subscribe("/app/1/other/proto").await.unwrap();
+
+        let message = WakuMessage::new("/app/1/chat/proto", b"hi".to_vec())
+            .with_ephemeral(true)
+            .with_meta(b"m".to_vec());
+        b.publish_message(&message).await.unwrap();
+        let received = next(&mut stream).await;
+        assert_eq!(received.payload, b"hi");
+        assert!(received.ephemeral);
+        assert_eq!(received.meta, b"m");
+        assert!(received.timestamp.is_some());
+        assert!(b.poll("/app/1/other/proto").await.unwrap().is_empty());
+
+        // The publisher hears its own messages, like on relay
+        b.subscribe("/app/1/chat/proto").await.unwrap();
+        b.publish("/app/1/chat/proto", b"again").await.unwrap();
+        assert_eq!(next(&mut stream).await.payload, b"again");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(b.poll("/app/1/chat/proto").await.unwrap().len(), 1);
+
+        // Without an upstream there is no history
+        let page = a
+            .query_history(&HistoryQuery::new("/app/1/chat/proto"))
+            .await
+            .unwrap();
+        assert!(page.messages.is_empty());
+        assert_eq!(hub.client_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_hub_bridges_upstream() {
+        let network = SimNetwork::new(3);
+        let remote = network.join("remote");
+        let hub = IpcHub::start(
+            IpcHubConfig::new(socket_path("bridge")).with_upstream(network.join("hub")),
+        )
+        .await
+        .unwrap();
+        let local = IpcTransport::connect(hub.socket()).await.unwrap();
+        let mut stream = local.subscribe_stream("/app/1/in/proto");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), stream.next())
+                .await
+                .is_err()
+        );
+
+        // Remote to local
+        remote
+            .publish("/app/1/in/proto", b"from afar")
+            .await
+            .unwrap();
+        assert_eq!(next(&mut stream).await.payload, b"from afar");
+
+        // Local to remote; the upstream echo isn't delivered again
+        remote.subscribe("/app/1/in/proto").await.unwrap();
+        local
+            .publish("/app/1/in/proto", b"from here")
+            .await
+            .unwrap();
+        assert_eq!(next(&mut stream).await.payload, b"from here");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(1500), stream.next())
+                .await
+                .is_err()
+        );
+        let payloads: Vec<_> = remote
+            .poll("/app/1/in/proto")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.payload)
+            .collect();
+        assert_eq!(payloads, [b"from here".to_vec()]);
+
+        // History comes from the upstream store
+        let page = local
+            .query_history(&HistoryQuery::new("/app/1/in/proto"))
+            .await
+            .unwrap();
+        assert_eq!(page.messages.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_client_reconnects_after_hub_restart() {
+        let socket = socket_path("restart");
+        let hub = IpcHub::start(IpcHubConfig::new(&socket)).await.unwrap();
+        let listener = IpcTransport::connect(&socket).await.unwrap();
+        listener.subscribe("/app/1/t/proto").await.unwrap();
+        drop(hub);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(listener.publish("/app/1/t/proto", b"lost").await.is_err());
+
+        let _hub = IpcHub::start(IpcHubConfig::new(&socket)).await.unwrap();
+        let sender = IpcTransport::connect(&socket).await.unwrap();
+        // Reconnects and subscribes again on its next request
+        listener.publish("/app/1/x/proto", b"wake").await.unwrap();
+        sender.publish("/app/1/t/proto", b"back").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let payloads: Vec<_> = listener
+            .poll("/app/1/t/proto")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.payload)
+            .collect();
+        assert_eq!(payloads, [b"back".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_reconnects_after_hub_restart() {
+        let socket = socket_path("stream-restart");
+        let hub = IpcHub::start(IpcHubConfig::new(&socket)).await.unwrap();
+        let listener = IpcTransport::connect(&socket).await.unwrap();
+        let mut stream = listener.subscribe_stream("/app/1/t/proto");
+        let sender = IpcTransport::connect(&socket).await.unwrap();
+
+        // Only ever reads the stream, never makes a request of its own
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), stream.next())
+                .await
+                .is_err()
+        );
+        sender.publish("/app/1/t/proto", b"before").await.unwrap();
+        assert_eq!(next(&mut stream).await.payload, b"before");
+
+        drop(hub);
+        drop(sender);
+        let _hub = IpcHub::start(IpcHubConfig::new(&socket)).await.unwrap();
+        let sender = IpcTransport::connect(&socket).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                tokio::select! {
+                    message = stream.next() => break message.expect("stream open"),
+                    () = tokio::time::sleep(Duration::from_millis(100)) => {
+                        sender.publish("/app/1/t/proto", b"after").await.unwrap();
+                    }
+                }
+            }
+        })
+        .await
+        .expect("message within 5s");
+        assert_eq!(received.payload, b"after");
+    }
+
+    #[tokio::test]
+    async fn test_client_queue_capped_on_arrival() {
+        let hub = IpcHub::start(IpcHubConfig::new(socket_path("cap")))
+            .await
+            .unwrap();
+        let listener = IpcTransport::connect(hub.socket())
+            .await
+            .unwrap()
+            .with_queue_capacity(2);
+        listener.subscribe("/app/1/t/proto").await.unwrap();
+        let sender = IpcTransport::connect(hub.socket()).await.unwrap();
+        for i in 0..5u8 {
+            sender.publish("/app/1/t/proto", &[i]).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            listener.shared.queues.lock().unwrap()["/app/1/t/proto"].len(),
+            2
+        );
+        let payloads: Vec<_> = listener
+            .poll("/app/1/t/proto")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.payload)
+            .collect();
+        assert_eq!(payloads, [vec![3], vec![4]]);
+    }
+
+    #[tokio::test]
+    async fn test_hub_disconnects_slow_client() {
+        let hub = IpcHub::start(IpcHubConfig::new(socket_path("slow")).with_event_capacity(4))
+            .await
+            .unwrap();
+        // Subscribes, then never reads
+        let mut stuck = UnixStream::connect(hub.socket()).await.unwrap();
+        let subscribe = Request::Subscribe {
+            id: 1,
+            topic: "/app/1/t/proto".to_string(),
+        };
+        stuck
+            .write_all(encode_line(&subscribe).as_bytes())
+            .await
+            .unwrap();
+        let sender = IpcTransport::connect(hub.socket()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(hub.client_count(), 2);
+
+        let payload = vec![0u8; 64 * 1024];
+        for _ in 0..100 {
+            sender.publish("/app/1/t/proto", &payload).await.unwrap();
+            if hub.client_count() == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(hub.client_count(), 1);
+        // Others are unaffected
+        sender
+            .publish("/app/1/t/proto", b"still here")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reliable_delivery_over_hub() {
+        let hub = IpcHub::start(IpcHubConfig::new(socket_path("sds")))
+            .await
+            .unwrap();
+        let sender = SdsTransport::new(IpcTransport::connect(hub.socket()).await.unwrap());
+        let receiver = SdsTransport::new(IpcTransport::connect(hub.socket()).await.unwrap());
+        receiver
+            .inner()
+            .subscribe("/app/1/task/proto")
+            .await
+            .unwrap();
+
+        let receive = async {
+            let mut messages = receiver.inner().subscribe_stream("/app/1/task/proto");
+            let message = next(&mut messages).await;
+            receiver.send_ack("task-1").await.unwrap();
+            message
+        };
+        let (acked, message) = tokio::join!(
+            sender.publish_reliable("/app/1/task/proto", b"do it", "task-1"),
+            receive
+        );
+        assert!(acked.unwrap());
+        assert_eq!(message.payload, b"do it");
+    }
+}
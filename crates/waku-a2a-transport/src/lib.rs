@@ -1,8 +1,55 @@
 use anyhow::Result;
 use async_trait::async_trait;
+use futures::stream::BoxStream;
+use std::collections::VecDeque;
+use std::time::Duration;
 
+pub mod autosharding;
+pub mod combinators;
+pub mod history;
+#[cfg(unix)]
+pub mod ipc;
+pub mod message;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
 pub mod nwaku_rest;
+#[cfg(feature = "p2p")]
+pub mod p2p;
+pub mod rate_limit;
+pub mod retry;
 pub mod sds;
+pub mod sim;
+
+pub use history::{HistoryPage, HistoryQuery};
+pub use message::WakuMessage;
+pub use rate_limit::Priority;
+
+/// Interval between polls in the default `WakuTransport::subscribe_stream`.
+pub const STREAM_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A call the backend refused as invalid, e.g. nwaku answering 400 to a
+/// malformed content topic. Unlike an unreachable backend, retrying won't
+/// help.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejected {
+    pub reason: String,
+}
+
+impl Rejected {
+    pub fn new(reason: impl Into<String>) -> Self {
+        Self {
+            reason: reason.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rejected: {}", self.reason)
+    }
+}
+
+impl std::error::Error for Rejected {}
 
 /// Swappable Waku transport trait.
 ///
@@ -10,16 +57,145 @@ pub mod sds;
 /// - `LogosDeliveryTransport`: uses logos-delivery-rust-bindings (waku-bindings FFI)
 ///   TODO (Issue #1): implement once libwaku build is resolved
 /// - `NwakuRestTransport`: uses nwaku REST API as fallback (current default)
+///
+/// With the `p2p` feature, `p2p::P2pTransport` runs an embedded relay node
+/// instead.
 #[async_trait]
 pub trait WakuTransport: Send + Sync {
     /// Publish a payload to a Waku content topic.
     async fn publish(&self, topic: &str, payload: &[u8]) -> Result<()>;
 
+    /// Publish a message with its metadata. The default publishes only the
+    /// payload, for backends that can't carry `ephemeral`, `meta` or
+    /// `timestamp`.
+    async fn publish_message(&self, message: &WakuMessage) -> Result<()> {
+        self.publish(&message.content_topic, &message.payload).await
+    }
+
+    /// Publish a message with a priority for rate-limited backends (see
+    /// `rate_limit`). The default ignores `priority`.
+    async fn publish_prioritized(&self, message: &WakuMessage, _priority: Priority) -> Result<()> {
+        self.publish_message(message).await
+    }
+
     /// Subscribe to a Waku content topic.
     async fn subscribe(&self, topic: &str) -> Result<()>;
 
-    /// Poll for messages on a content topic. Returns raw payloads.
-    async fn poll(&self, topic: &str) -> Result<Vec<Vec<u8>>>;
+    /// Stop receiving a content topic. Transports without server-side
+    /// subscriptions have nothing to undo.
+    async fn unsubscribe(&self, _topic: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Poll for messages on a content topic.
+    async fn poll(&self, topic: &str) -> Result<Vec<WakuMessage>>;
+
+    /// Fetch one page of stored messages. Transports without a message
+    /// history return an empty page.
+    async fn query_history(&self, _query: &HistoryQuery) -> Result<HistoryPage> {
+        Ok(HistoryPage::default())
+    }
+
+    /// Check that the backend can serve calls, e.g. for `FallbackTransport`
+    /// to tell when a failed backend is back. The default reports healthy.
+    async fn health_check(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Subscribe to a content topic and receive its messages as they arrive.
+    ///
+    /// The default implementation polls every `STREAM_POLL_INTERVAL`;
+    /// backends that can push messages override it. Errors are logged and
+    /// retried, so the stream only ends when the backend shuts down.
+    fn subscribe_stream<'a>(&'a self, topic: &str) -> BoxStream<'a, WakuMessage> {
+        poll_stream(self, topic, STREAM_POLL_INTERVAL)
+    }
+}
+
+/// Stream a content topic by polling `transport` every `interval`.
+pub fn poll_stream<'a, T: WakuTransport + ?Sized>(
+    transport: &'a T,
+    topic: &str,
+    interval: Duration,
+) -> BoxStream<'a, WakuMessage> {
+    struct State<'a, T: ?Sized> {
+        transport: &'a T,
+        topic: String,
+        subscribed: bool,
+        ready: VecDeque<WakuMessage>,
+    }
+
+    let state = State {
+        transport,
+        topic: topic.to_string(),
+        subscribed: false,
+        ready: VecDeque::new(),
+    };
+    Box::pin(futures::stream::unfold(state, move |mut st| async move {
+        loop {
+            if let Some(message) = st.ready.pop_front() {
+                return Some((message, st));
+            }
+            if !st.subscribed {
+                match st.transport.subscribe(&st.topic).await {
+                    Ok(()) => st.subscribed = true,
+                    Err(e) => {
+                        eprintln!("[transport] Subscribe to {} failed: {:#}", st.topic, e);
+                        tokio::time::sleep(interval).await;
+                        continue;
+                    }
+                }
+            }
+            match st.transport.poll(&st.topic).await {
+                Ok(messages) if !messages.is_empty() => st.ready.extend(messages),
+                Ok(_) => tokio::time::sleep(interval).await,
+                Err(e) => {
+                    eprintln!("[transport] Poll of {} failed: {:#}", st.topic, e);
+                    tokio::time::sleep(interval).await;
+                }
+            }
+        }
+    }))
+}
+
+/// Lets callers pick a backend at runtime with `Box<dyn WakuTransport>`.
+#[async_trait]
+impl<T: WakuTransport + ?Sized> WakuTransport for Box<T> {
+    async fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
+        (**self).publish(topic, payload).await
+    }
+
+    async fn publish_message(&self, message: &WakuMessage) -> Result<()> {
+        (**self).publish_message(message).await
+    }
+
+    async fn publish_prioritized(&self, message: &WakuMessage, priority: Priority) -> Result<()> {
+        (**self).publish_prioritized(message, priority).await
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<()> {
+        (**self).subscribe(topic).await
+    }
+
+    async fn unsubscribe(&self, topic: &str) -> Result<()> {
+        (**self).unsubscribe(topic).await
+    }
+
+    async fn poll(&self, topic: &str) -> Result<Vec<WakuMessage>> {
+        (**self).poll(topic).await
+    }
+
+    async fn query_history(&self, query: &HistoryQuery) -> Result<HistoryPage> {
+        (**self).query_history(query).await
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        (**self).health_check().await
+    }
+
+    fn subscribe_stream<'a>(&'a self, topic: &str) -> BoxStream<'a, WakuMessage> {
+        (**self).subscribe_stream(topic)
+    }
 }
 
 // TODO (Issue #1): Implement LogosDeliveryTransport using waku-bindings FFI
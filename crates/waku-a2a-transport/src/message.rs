@@ -0,0 +1,187 @@
+//! Waku messages (RFC 14/WAKU2-MESSAGE).
+//!
+//! Besides the payload, a message carries the metadata nwaku hands out: the
+//! sender's timestamp, the `ephemeral` flag that keeps it out of store nodes
+//! and an application `meta` field. Its deterministic hash is
+//!
+//! `sha256(pubsub_topic || payload || content_topic || meta || timestamp)`
+//!
+//! with the timestamp as a big-endian 64-bit integer (0 when unset). The same
+//! message therefore hashes the same on every node that relays it, which makes
+//! the hash a natural deduplication key.
+
+use sha2::{Digest, Sha256};
+use std::collections::{HashSet, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Maximum size of the `meta` field.
+pub const MAX_META_SIZE: usize = 64;
+
+/// Message hashes transports remember to drop copies.
+pub const DEDUP_CAPACITY: usize = 4096;
+
+/// A message on a content topic.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WakuMessage {
+    pub payload: Vec<u8>,
+    pub content_topic: String,
+    pub version: u32,
+    /// Sender time in Unix nanoseconds.
+    pub timestamp: Option<i64>,
+    /// Relay only; store nodes don't keep ephemeral messages.
+    pub ephemeral: bool,
+    /// Application metadata, at most `MAX_META_SIZE` bytes.
+    pub meta: Vec<u8>,
+    /// Pubsub topic the message was received on, if the transport knows it.
+    pub pubsub_topic: Option<String>,
+}
+
+impl WakuMessage {
+    pub fn new(content_topic: &str, payload: impl Into<Vec<u8>>) -> Self {
+        Self {
+            payload: payload.into(),
+            content_topic: content_topic.to_string(),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_ephemeral(mut self, ephemeral: bool) -> Self {
+        self.ephemeral = ephemeral;
+        self
+    }
+
+    pub fn with_meta(mut self, meta: impl Into<Vec<u8>>) -> Self {
+        self.meta = meta.into();
+        self
+    }
+
+    pub fn with_timestamp(mut self, unix_nanos: i64) -> Self {
+        self.timestamp = Some(unix_nanos);
+        self
+    }
+
+    /// Deterministic message hash on `pubsub_topic`.
+    pub fn hash(&self, pubsub_topic: &str) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(pubsub_topic.as_bytes());
+        hasher.update(&self.payload);
+        hasher.update(self.content_topic.as_bytes());
+        hasher.update(&self.meta);
+        hasher.update(self.timestamp.unwrap_or(0).to_be_bytes());
+        hasher.finalize().into()
+    }
+
+    /// Hash on the pubsub topic it was received on, or on an empty pubsub
+    /// topic if that's unknown. Only comparable between messages of the same
+    /// transport.
+    pub fn received_hash(&self) -> [u8; 32] {
+        self.hash(self.pubsub_topic.as_deref().unwrap_or_default())
+    }
+}
+
+/// Bounded set of recently seen hashes; the oldest is forgotten once it
+/// holds `capacity` of them.
+#[derive(Debug, Clone)]
+pub struct SeenHashes {
+    capacity: usize,
+    set: HashSet<[u8; 32]>,
+    order: VecDeque<[u8; 32]>,
+}
+
+impl SeenHashes {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            set: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Remember `hash`; false if it was already known.
+    pub fn insert(&mut self, hash: [u8; 32]) -> bool {
+        if !self.set.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.set.remove(&old);
+            }
+        }
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+/// Current time in Unix nanoseconds, for message timestamps.
+pub fn now_nanos() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seen_hashes_forget_oldest() {
+        let mut seen = SeenHashes::new(2);
+        assert!(seen.insert([1; 32]));
+        assert!(!seen.insert([1; 32]));
+        assert!(seen.insert([2; 32]));
+        assert!(seen.insert([3; 32]));
+        assert_eq!(seen.len(), 2);
+        // [1] was evicted, [3] is still known
+        assert!(!seen.insert([3; 32]));
+        assert!(seen.insert([1; 32]));
+    }
+
+    #[test]
+    fn test_rfc_hash_vector() {
+        let message = WakuMessage::new(
+            "/waku/2/default-content/proto",
+            [
+                0x01, 0x02, 0x03, 0x04, b'T', b'E', b'S', b'T', 0x05, 0x06, 0x07, 0x08,
+            ],
+        )
+        .with_meta(b"super-secret".to_vec())
+        .with_timestamp(0x175789bfa23f8400);
+        assert_eq!(
+            message.hash("/waku/2/default-waku/proto"),
+            [
+                0x64, 0xcc, 0xe7, 0x33, 0xfe, 0xd1, 0x34, 0xe8, 0x3d, 0xa0, 0x2b, 0x02, 0xc6, 0xf6,
+                0x89, 0x81, 0x48, 0x72, 0xb1, 0xa0, 0xac, 0x97, 0xea, 0x56, 0xb7, 0x60, 0x95, 0xc3,
+                0xc7, 0x2b, 0xfe, 0x05,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hash_fields() {
+        let base = WakuMessage::new("/app/1/topic/proto", b"hi".to_vec()).with_timestamp(1);
+        let hash = base.hash("/waku/2/rs/1/5");
+        assert_ne!(hash, base.hash("/waku/2/rs/1/6"));
+        assert_ne!(
+            hash,
+            base.clone().with_meta(b"m".to_vec()).hash("/waku/2/rs/1/5")
+        );
+        assert_ne!(hash, base.clone().with_timestamp(2).hash("/waku/2/rs/1/5"));
+        // Not part of the hash: relaying flags and local bookkeeping
+        assert_eq!(
+            hash,
+            base.clone().with_ephemeral(true).hash("/waku/2/rs/1/5")
+        );
+        let mut received = base.clone();
+        received.pubsub_topic = Some("/waku/2/rs/1/5".to_string());
+        assert_eq!(received.received_hash(), hash);
+    }
+}
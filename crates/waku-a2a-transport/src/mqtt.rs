@@ -0,0 +1,884 @@
+//! MQTT backend for deployments that run an MQTT broker instead of nwaku.
+//!
+//! Content topics map onto MQTT topics below a prefix
+//! (`/waku-a2a/1/discovery/proto` becomes `waku/waku-a2a/1/discovery/proto`)
+//! and a message's payload is the MQTT message, published and subscribed at
+//! the configured QoS. MQTT 3.1.1 has no room for the other `WakuMessage`
+//! fields: `meta` and `ephemeral` are dropped and receivers stamp the
+//! timestamp on arrival. Brokers keep no history, so `query_history` returns
+//! empty pages.
+//!
+//! As with relay, the broker hands a client its own messages when it is
+//! subscribed to their topic. A publish returns once the broker has
+//! acknowledged it (PUBACK at QoS 1, PUBCOMP at QoS 2, sent at QoS 0), so
+//! `SdsTransport` and the node's outbox see an unreachable broker as a failed
+//! publish. A connection is considered lost when the broker closes it or
+//! leaves a ping unanswered for the keep-alive interval; the transport then
+//! reconnects on the next request, or from a `subscribe_stream`, and
+//! subscribes again.
+//!
+//! Speaks MQTT 3.1.1 over TCP with clean sessions, using rumqttc's packet
+//! codec. Enabled by the `mqtt` feature.
+
+use crate::message::now_nanos;
+use crate::nwaku_rest::DEFAULT_QUEUE_CAPACITY;
+use crate::{WakuMessage, WakuTransport, STREAM_POLL_INTERVAL};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use bytes::BytesMut;
+use futures::stream::BoxStream;
+use rumqttc::mqttbytes::v4::{
+    ConnectReturnCode, Login, Packet, PubAck, PubComp, PubRec, PubRel, Publish, SubAck, Subscribe,
+    SubscribeFilter, SubscribeReasonCode, UnsubAck, Unsubscribe,
+};
+use rumqttc::mqttbytes::{self, valid_topic};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+pub use rumqttc::mqttbytes::QoS;
+
+/// MQTT topic level content topics are placed under.
+pub const DEFAULT_TOPIC_PREFIX: &str = "waku";
+
+/// Interval after which an idle connection is pinged, and within which the
+/// broker must answer.
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// How long to wait for the broker to connect or acknowledge a request.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest packet sent or accepted, with room for Waku's 150 KiB messages.
+pub const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+/// Settings for `MqttTransport::connect`.
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    /// Broker address as `host:port`.
+    pub broker: String,
+    pub client_id: String,
+    /// QoS of publishes and subscriptions.
+    pub qos: QoS,
+    /// MQTT topic levels in front of content topics; empty to use content
+    /// topics as they are.
+    pub topic_prefix: String,
+    pub keep_alive: Duration,
+    /// Username and password.
+    pub credentials: Option<(String, String)>,
+    pub request_timeout: Duration,
+    /// Messages buffered per content topic.
+    pub queue_capacity: usize,
+}
+
+impl MqttConfig {
+    /// Connect to `broker` (`host:port`) at QoS 1 with a random client ID.
+    pub fn new(broker: impl Into<String>) -> Self {
+        Self {
+            broker: broker.into(),
+            client_id: format!(
+                "waku-a2a-{}",
+                &uuid::Uuid::new_v4().simple().to_string()[..12]
+            ),
+            qos: QoS::AtLeastOnce,
+            topic_prefix: DEFAULT_TOPIC_PREFIX.to_string(),
+            keep_alive: DEFAULT_KEEP_ALIVE,
+            credentials: None,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+        }
+    }
+
+    pub fn with_client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.client_id = client_id.into();
+        self
+    }
+
+    pub fn with_qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    pub fn with_topic_prefix(mut self, prefix: &str) -> Self {
+        self.topic_prefix = prefix.trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn with_keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Keep at most `capacity` unpolled messages per content topic.
+    pub fn with_queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity.max(1);
+        self
+    }
+
+    /// MQTT topic carrying `content_topic`. Content topics start with '/'
+    /// and mustn't contain MQTT wildcards.
+    pub fn mqtt_topic(&self, content_topic: &str) -> Result<String> {
+        if !content_topic.starts_with('/') || !valid_topic(content_topic) {
+            bail!("Content topic {} can't be mapped to MQTT", content_topic);
+        }
+        Ok(format!("{}{}", self.topic_prefix, content_topic))
+    }
+
+    /// Content topic carried by `mqtt_topic`; None outside the prefix.
+    pub fn content_topic<'a>(&self, mqtt_topic: &'a str) -> Option<&'a str> {
+        mqtt_topic
+            .strip_prefix(self.topic_prefix.as_str())
+            .filter(|topic| topic.starts_with('/'))
+    }
+}
+
+/// `WakuTransport` over an MQTT broker.
+pub struct MqttTransport {
+    config: MqttConfig,
+    shared: Arc<Shared>,
+    connection: tokio::sync::Mutex<Option<Connection>>,
+    next_pkid: Mutex<u16>,
+    /// Subscribed content topics, renewed after reconnecting.
+    subscriptions: Mutex<HashSet<String>>,
+}
+
+#[derive(Default)]
+struct Shared {
+    /// Received messages of subscribed content topics.
+    queues: Mutex<HashMap<String, VecDeque<WakuMessage>>>,
+    /// Signalled whenever a message is queued or the connection is lost.
+    arrived: Notify,
+    /// Requests waiting for the broker's acknowledgement, by packet ID.
+    pending: Mutex<HashMap<u16, oneshot::Sender<Packet>>>,
+    /// Signalled when the broker answers a ping.
+    pong: Notify,
+}
+
+impl Shared {
+    /// Queue a received message, dropping the oldest of its topic beyond the
+    /// queue capacity.
+    fn deliver(&self, config: &MqttConfig, publish: Publish) {
+        let Some(content_topic) = config.content_topic(&publish.topic) else {
+            return;
+        };
+        let mut queues = self.queues.lock().unwrap();
+        if let Some(queue) = queues.get_mut(content_topic) {
+            if queue.len() >= config.queue_capacity {
+                queue.pop_front();
+            }
+            queue.push_back(
+                WakuMessage::new(content_topic, publish.payload.to_vec())
+                    .with_timestamp(now_nanos()),
+            );
+            drop(queues);
+            self.arrived.notify_waiters();
+        }
+    }
+
+    /// Mark a connection lost and wake streams so they reconnect.
+    fn close(&self, closed: &AtomicBool) {
+        closed.store(true, Ordering::SeqCst);
+        self.pending.lock().unwrap().clear();
+        self.arrived.notify_waiters();
+    }
+}
+
+struct Connection {
+    outgoing: mpsc::UnboundedSender<Packet>,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Connection {
+    fn is_open(&self) -> bool {
+        !self.closed.load(Ordering::SeqCst) && !self.outgoing.is_closed()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.writer.abort();
+    }
+}
+
+impl MqttTransport {
+    /// Connect to the broker; fails if it can't be reached or refuses the
+    /// client.
+    pub async fn connect(config: MqttConfig) -> Result<Self> {
+        let transport = Self {
+            config,
+            shared: Arc::new(Shared::default()),
+            connection: tokio::sync::Mutex::new(None),
+            next_pkid: Mutex::new(1),
+            subscriptions: Mutex::new(HashSet::new()),
+        };
+        *transport.connection.lock().await = Some(transport.open().await?);
+        Ok(transport)
+    }
+
+    pub fn config(&self) -> &MqttConfig {
+        &self.config
+    }
+
+    async fn open(&self) -> Result<Connection> {
+        let broker = &self.config.broker;
+        let timeout = self.config.request_timeout;
+        let mut stream = tokio::time::timeout(timeout, TcpStream::connect(broker))
+            .await
+            .map_err(|_| anyhow!("Timed out connecting to the MQTT broker at {}", broker))?
+            .with_context(|| format!("Failed to connect to the MQTT broker at {}", broker))?;
+
+        let mut connect = rumqttc::mqttbytes::v4::Connect::new(self.config.client_id.clone());
+        connect.keep_alive = self.config.keep_alive.as_secs().clamp(1, u16::MAX as u64) as u16;
+        if let Some((ref username, ref password)) = self.config.credentials {
+            connect.login = Some(Login::new(username, password));
+        }
+        write_packet(&mut stream, &Packet::Connect(connect)).await?;
+        let mut buf = BytesMut::new();
+        let connack = tokio::time::timeout(timeout, read_packet(&mut stream, &mut buf))
+            .await
+            .map_err(|_| anyhow!("MQTT broker at {} didn't accept the connection", broker))??;
+        match connack {
+            Some(Packet::ConnAck(ack)) if ack.code == ConnectReturnCode::Success => {}
+            Some(Packet::ConnAck(ack)) => {
+                bail!(
+                    "MQTT broker at {} refused the connection: {:?}",
+                    broker,
+                    ack.code
+                )
+            }
+            _ => bail!("MQTT broker at {} didn't accept the connection", broker),
+        }
+
+        let (read, write) = stream.into_split();
+        let (outgoing, packets) = mpsc::unbounded_channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let writer = tokio::spawn(write_packets(
+            write,
+            packets,
+            self.config.keep_alive,
+            self.shared.clone(),
+            closed.clone(),
+        ));
+        let reader = tokio::spawn(read_packets(
+            read,
+            buf,
+            self.config.clone(),
+            self.shared.clone(),
+            outgoing.clone(),
+            closed.clone(),
+        ));
+        Ok(Connection {
+            outgoing,
+            reader,
+            writer,
+            closed,
+        })
+    }
+
+    /// Sender of the current connection, reconnecting and subscribing again
+    /// if it was lost.
+    async fn outgoing(&self) -> Result<mpsc::UnboundedSender<Packet>> {
+        let mut connection = self.connection.lock().await;
+        if let Some(conn) = connection.as_ref().filter(|c| c.is_open()) {
+            return Ok(conn.outgoing.clone());
+        }
+        *connection = None;
+        let fresh = self.open().await?;
+        self.resubscribe(&fresh.outgoing).await?;
+        eprintln!("[mqtt] Reconnected to {}", self.config.broker);
+        let outgoing = fresh.outgoing.clone();
+        *connection = Some(fresh);
+        Ok(outgoing)
+    }
+
+    /// Whether the connection to the broker was lost.
+    async fn is_closed(&self) -> bool {
+        self.connection
+            .lock()
+            .await
+            .as_ref()
+            .is_none_or(|c| !c.is_open())
+    }
+
+    /// Renew our subscriptions on a new connection.
+    async fn resubscribe(&self, outgoing: &mpsc::UnboundedSender<Packet>) -> Result<()> {
+        let topics: Vec<String> = self.subscriptions.lock().unwrap().iter().cloned().collect();
+        if topics.is_empty() {
+            return Ok(());
+        }
+        let filters = topics
+            .iter()
+            .map(|topic| {
+                Ok(SubscribeFilter::new(
+                    self.config.mqtt_topic(topic)?,
+                    self.config.qos,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let ack = self
+            .request(outgoing, |pkid| {
+                Packet::Subscribe(Subscribe { pkid, filters })
+            })
+            .await?;
+        check_suback(&ack, &topics.join(", "))
+    }
+
+    /// Send a packet the broker acknowledges with the same packet ID and
+    /// wait for the acknowledgement. A connection that fails to answer is
+    /// replaced on the next request.
+    async fn call(&self, packet: impl FnOnce(u16) -> Packet) -> Result<Packet> {
+        let outgoing = self.outgoing().await?;
+        let result = self.request(&outgoing, packet).await;
+        if result.is_err() {
+            if let Some(conn) = self.connection.lock().await.as_ref() {
+                if conn.outgoing.same_channel(&outgoing) {
+                    conn.closed.store(true, Ordering::SeqCst);
+                }
+            }
+        }
+        result
+    }
+
+    async fn request(
+        &self,
+        outgoing: &mpsc::UnboundedSender<Packet>,
+        packet: impl FnOnce(u16) -> Packet,
+    ) -> Result<Packet> {
+        let pkid = self.next_pkid();
+        let (ack, acked) = oneshot::channel();
+        self.shared.pending.lock().unwrap().insert(pkid, ack);
+        if outgoing.send(packet(pkid)).is_err() {
+            self.shared.pending.lock().unwrap().remove(&pkid);
+            bail!("Lost the connection to the MQTT broker");
+        }
+        match tokio::time::timeout(self.config.request_timeout, acked).await {
+            Ok(Ok(ack)) => Ok(ack),
+            Ok(Err(_)) => bail!("Lost the connection to the MQTT broker"),
+            Err(_) => {
+                self.shared.pending.lock().unwrap().remove(&pkid);
+                bail!(
+                    "MQTT broker didn't answer within {:?}",
+                    self.config.request_timeout
+                )
+            }
+        }
+    }
+
+    /// Packet IDs run from 1 to 65535.
+    fn next_pkid(&self) -> u16 {
+        let mut next = self.next_pkid.lock().unwrap();
+        let pkid = *next;
+        *next = next.checked_add(1).unwrap_or(1);
+        pkid
+    }
+}
+
+fn check_suback(ack: &Packet, topics: &str) -> Result<()> {
+    match ack {
+        Packet::SubAck(SubAck { return_codes, .. })
+            if return_codes
+                .iter()
+                .all(|code| matches!(code, SubscribeReasonCode::Success(_))) =>
+        {
+            Ok(())
+        }
+        _ => bail!("MQTT broker refused the subscription to {}", topics),
+    }
+}
+
+/// Packet ID of an acknowledgement that answers one of our requests.
+fn ack_pkid(packet: &Packet) -> Option<u16> {
+    match packet {
+        Packet::PubAck(PubAck { pkid, .. })
+        | Packet::PubComp(PubComp { pkid, .. })
+        | Packet::SubAck(SubAck { pkid, .. })
+        | Packet::UnsubAck(UnsubAck { pkid, .. }) => Some(*pkid),
+        _ => None,
+    }
+}
+
+/// Acknowledge and queue incoming messages and route acknowledgements to
+/// their requests. Pending requests fail and streams wake up once the broker
+/// disconnects.
+async fn read_packets(
+    mut read: OwnedReadHalf,
+    mut buf: BytesMut,
+    config: MqttConfig,
+    shared: Arc<Shared>,
+    outgoing: mpsc::UnboundedSender<Packet>,
+    closed: Arc<AtomicBool>,
+) {
+    // QoS 2 messages received but not yet released by the broker
+    let mut unreleased = HashSet::new();
+    loop {
+        let packet = match read_packet(&mut read, &mut buf).await {
+            Ok(Some(packet)) => packet,
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("[mqtt] Dropping the connection to the broker: {:#}", e);
+                break;
+            }
+        };
+        match packet {
+            Packet::Publish(publish) => {
+                let fresh = match publish.qos {
+                    QoS::AtMostOnce => true,
+                    QoS::AtLeastOnce => {
+                        let _ = outgoing.send(Packet::PubAck(PubAck::new(publish.pkid)));
+                        true
+                    }
+                    QoS::ExactlyOnce => {
+                        let _ = outgoing.send(Packet::PubRec(PubRec::new(publish.pkid)));
+                        unreleased.insert(publish.pkid)
+                    }
+                };
+                if fresh {
+                    shared.deliver(&config, publish);
+                }
+            }
+            Packet::PubRel(PubRel { pkid, .. }) => {
+                unreleased.remove(&pkid);
+                let _ = outgoing.send(Packet::PubComp(PubComp::new(pkid)));
+            }
+            // Our QoS 2 publish arrived; PUBCOMP completes it
+            Packet::PubRec(PubRec { pkid, .. }) => {
+                let _ = outgoing.send(Packet::PubRel(PubRel::new(pkid)));
+            }
+            Packet::PingResp => shared.pong.notify_one(),
+            packet => {
+                if let Some(pkid) = ack_pkid(&packet) {
+                    if let Some(waiting) = shared.pending.lock().unwrap().remove(&pkid) {
+                        let _ = waiting.send(packet);
+                    }
+                }
+            }
+        }
+    }
+    shared.close(&closed);
+}
+
+/// Send queued packets, and a PINGREQ whenever the connection has been idle
+/// for the keep-alive interval. Gives up on the connection if the broker
+/// doesn't answer a ping within another interval.
+async fn write_packets(
+    mut write: OwnedWriteHalf,
+    mut packets: mpsc::UnboundedReceiver<Packet>,
+    keep_alive: Duration,
+    shared: Arc<Shared>,
+    closed: Arc<AtomicBool>,
+) {
+    let mut deadline = Instant::now() + keep_alive;
+    let mut awaiting_pong = false;
+    loop {
+        let packet = tokio::select! {
+            packet = packets.recv() => match packet {
+                Some(packet) => packet,
+                None => break,
+            },
+            () = shared.pong.notified() => {
+                awaiting_pong = false;
+                deadline = Instant::now() + keep_alive;
+                continue;
+            }
+            () = tokio::time::sleep_until(deadline) => {
+                if awaiting_pong {
+                    eprintln!(
+                        "[mqtt] Broker didn't answer a ping within {:?}, dropping the connection",
+                        keep_alive
+                    );
+                    break;
+                }
+                awaiting_pong = true;
+                Packet::PingReq
+            }
+        };
+        if let Err(e) = write_packet(&mut write, &packet).await {
+            eprintln!("[mqtt] Dropping the connection to the broker: {:#}", e);
+            break;
+        }
+        if !awaiting_pong || matches!(packet, Packet::PingReq) {
+            deadline = Instant::now() + keep_alive;
+        }
+    }
+    shared.close(&closed);
+}
+
+async fn write_packet(write: &mut (impl AsyncWrite + Unpin), packet: &Packet) -> Result<()> {
+    let mut buf = BytesMut::new();
+    packet
+        .write(&mut buf, MAX_PACKET_SIZE)
+        .context("Failed to encode MQTT packet")?;
+    write.write_all(&buf).await?;
+    Ok(())
+}
+
+/// The next packet, or None once the broker closed the connection.
+async fn read_packet(
+    read: &mut (impl AsyncRead + Unpin),
+    buf: &mut BytesMut,
+) -> Result<Option<Packet>> {
+    loop {
+        match Packet::read(buf, MAX_PACKET_SIZE) {
+            Ok(packet) => return Ok(Some(packet)),
+            Err(mqttbytes::Error::InsufficientBytes(_)) => {}
+            Err(e) => return Err(e).context("Malformed MQTT packet"),
+        }
+        if read.read_buf(buf).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+#[async_trait]
+impl WakuTransport for MqttTransport {
+    /// Returns once the broker acknowledged the message at the configured
+    /// QoS; at QoS 0, once it is sent.
+    async fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
+        let qos = self.config.qos;
+        let mut publish = Publish::new(self.config.mqtt_topic(topic)?, qos, payload);
+        if publish.size() > MAX_PACKET_SIZE {
+            bail!(
+                "Message of {} bytes is too large for MQTT (at most {})",
+                payload.len(),
+                MAX_PACKET_SIZE
+            );
+        }
+        if qos == QoS::AtMostOnce {
+            return self
+                .outgoing()
+                .await?
+                .send(Packet::Publish(publish))
+                .map_err(|_| anyhow!("Lost the connection to the MQTT broker"));
+        }
+        self.call(|pkid| {
+            publish.pkid = pkid;
+            Packet::Publish(publish)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<()> {
+        if self.subscriptions.lock().unwrap().contains(topic) {
+            return Ok(());
+        }
+        let filter = SubscribeFilter::new(self.config.mqtt_topic(topic)?, self.config.qos);
+        self.shared
+            .queues
+            .lock()
+            .unwrap()
+            .entry(topic.to_string())
+            .or_default();
+        let ack = self
+            .call(|pkid| {
+                Packet::Subscribe(Subscribe {
+                    pkid,
+                    filters: vec![filter],
+                })
+            })
+            .await?;
+        check_suback(&ack, topic)?;
+        self.subscriptions.lock().unwrap().insert(topic.to_string());
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, topic: &str) -> Result<()> {
+        self.shared.queues.lock().unwrap().remove(topic);
+        if !self.subscriptions.lock().unwrap().remove(topic) {
+            return Ok(());
+        }
+        let mqtt_topic = self.config.mqtt_topic(topic)?;
+        self.call(|pkid| {
+            Packet::Unsubscribe(Unsubscribe {
+                pkid,
+                topics: vec![mqtt_topic],
+            })
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn poll(&self, topic: &str) -> Result<Vec<WakuMessage>> {
+        let mut queues = self.shared.queues.lock().unwrap();
+        let Some(queue) = queues.get_mut(topic) else {
+            return Ok(Vec::new());
+        };
+        Ok(queue.drain(..).collect())
+    }
+
+    /// Healthy if connected to the broker, reconnecting first if needed.
+    async fn health_check(&self) -> Result<()> {
+        self.outgoing().await?;
+        Ok(())
+    }
+
+    /// Push delivery: messages are handed over as the broker sends them. If
+    /// the connection is lost the stream reconnects and subscribes again.
+    fn subscribe_stream<'a>(&'a self, topic: &str) -> BoxStream<'a, WakuMessage> {
+        let topic = topic.to_string();
+        Box::pin(futures::stream::unfold(
+            (VecDeque::new(), false),
+            move |(mut ready, mut subscribed)| {
+                let topic = topic.clone();
+                async move {
+                    loop {
+                        if let Some(message) = ready.pop_front() {
+                            return Some((message, (ready, subscribed)));
+                        }
+                        if !subscribed {
+                            match self.subscribe(&topic).await {
+                                Ok(()) => subscribed = true,
+                                Err(e) => {
+                                    eprintln!("[mqtt] Subscribe to {} failed: {:#}", topic, e);
+                                    tokio::time::sleep(STREAM_POLL_INTERVAL).await;
+                                    continue;
+                                }
+                            }
+                        }
+                        // Register before polling so an arrival in between isn't missed
+                        let arrived = self.shared.arrived.notified();
+                        if self.is_closed().await {
+                            if let Err(e) = self.health_check().await {
+                                eprintln!("[mqtt] Reconnect for {} failed: {:#}", topic, e);
+                                tokio::time::sleep(STREAM_POLL_INTERVAL).await;
+                                continue;
+                            }
+                        }
+                        ready.extend(self.poll(&topic).await.unwrap_or_default());
+                        if ready.is_empty() {
+                            arrived.await;
+                        }
+                    }
+                }
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sds::SdsTransport;
+    use futures::StreamExt;
+    use waku_a2a_mock_mqtt::MockBroker;
+
+    async fn next(stream: &mut BoxStream<'_, WakuMessage>) -> WakuMessage {
+        tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("message within 5s")
+            .expect("stream open")
+    }
+
+    #[test]
+    fn test_topic_mapping() {
+        let config = MqttConfig::new("localhost:1883");
+        let topic = config.mqtt_topic("/waku-a2a/1/discovery/proto").unwrap();
+        assert_eq!(topic, "waku/waku-a2a/1/discovery/proto");
+        assert_eq!(
+            config.content_topic(&topic),
+            Some("/waku-a2a/1/discovery/proto")
+        );
+        assert_eq!(config.content_topic("other/waku-a2a/1/x/proto"), None);
+        assert!(config.mqtt_topic("/app/1/+/proto").is_err());
+        assert!(config.mqtt_topic("app/1/x/proto").is_err());
+
+        let config = config.with_topic_prefix("site/7/");
+        assert_eq!(
+            config.mqtt_topic("/app/1/x/proto").unwrap(),
+            "site/7/app/1/x/proto"
+        );
+        let config = config.with_topic_prefix("");
+        assert_eq!(
+            config.mqtt_topic("/app/1/x/proto").unwrap(),
+            "/app/1/x/proto"
+        );
+        assert_eq!(
+            config.content_topic("/app/1/x/proto"),
+            Some("/app/1/x/proto")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exchange_at_each_qos() {
+        let broker = MockBroker::start().await.unwrap();
+        for qos in [QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce] {
+            let config = MqttConfig::new(broker.address()).with_qos(qos);
+            let a = MqttTransport::connect(config.clone()).await.unwrap();
+            let b = MqttTransport::connect(config).await.unwrap();
+            let topic = format!("/app/1/qos{}/proto", qos as u8);
+            let mut stream = b.subscribe_stream(&topic);
+            b.subscribe(&topic).await.unwrap();
+            a.publish(&topic, b"hello").await.unwrap();
+
+            let message = next(&mut stream).await;
+            assert_eq!(message.payload, b"hello");
+            assert_eq!(message.content_topic, topic);
+            assert!(message.timestamp.is_some());
+            let published = broker.published().pop().unwrap();
+            assert_eq!(published.topic, format!("waku{}", topic));
+            assert_eq!(published.qos, qos);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_after_connection_loss() {
+        let broker = MockBroker::start().await.unwrap();
+        let a = MqttTransport::connect(MqttConfig::new(broker.address()))
+            .await
+            .unwrap();
+        let b = MqttTransport::connect(MqttConfig::new(broker.address()))
+            .await
+            .unwrap();
+        b.subscribe("/app/1/t/proto").await.unwrap();
+
+        broker.disconnect_clients();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(broker.client_count(), 0);
+
+        // Reconnects on the next request and subscribes again
+        b.publish("/app/1/other/proto", b"wake").await.unwrap();
+        a.publish("/app/1/t/proto", b"back").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let payloads: Vec<_> = b
+            .poll("/app/1/t/proto")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.payload)
+            .collect();
+        assert_eq!(payloads, [b"back".to_vec()]);
+
+        // Without a broker, publishes fail
+        drop(broker);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(a.publish("/app/1/t/proto", b"lost").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_reconnects_after_connection_loss() {
+        let broker = MockBroker::start().await.unwrap();
+        let connect = || MqttTransport::connect(MqttConfig::new(broker.address()));
+        let listener = connect().await.unwrap();
+        let mut stream = listener.subscribe_stream("/app/1/t/proto");
+        let sender = connect().await.unwrap();
+
+        // Only ever reads the stream, never makes a request of its own
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), stream.next())
+                .await
+                .is_err()
+        );
+        sender.publish("/app/1/t/proto", b"before").await.unwrap();
+        assert_eq!(next(&mut stream).await.payload, b"before");
+
+        broker.disconnect_clients();
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                tokio::select! {
+                    message = stream.next() => break message.expect("stream open"),
+                    () = tokio::time::sleep(Duration::from_millis(100)) => {
+                        sender.publish("/app/1/t/proto", b"after").await.unwrap();
+                    }
+                }
+            }
+        })
+        .await
+        .expect("message within 5s");
+        assert_eq!(received.payload, b"after");
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_ping_drops_connection() {
+        let broker = MockBroker::start().await.unwrap();
+        let t = MqttTransport::connect(
+            MqttConfig::new(broker.address()).with_keep_alive(Duration::from_secs(1)),
+        )
+        .await
+        .unwrap();
+        t.subscribe("/app/1/t/proto").await.unwrap();
+
+        // Answered pings keep the connection
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert!(!t.is_closed().await);
+
+        broker.ignore_pings(true);
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert!(t.is_closed().await);
+
+        broker.ignore_pings(false);
+        t.publish("/app/1/t/proto", b"back").await.unwrap();
+        assert_eq!(broker.subscriptions(), ["waku/app/1/t/proto"]);
+    }
+
+    #[tokio::test]
+    async fn test_queue_capped_on_arrival() {
+        let broker = MockBroker::start().await.unwrap();
+        let config = MqttConfig::new(broker.address());
+        let listener = MqttTransport::connect(config.clone().with_queue_capacity(2))
+            .await
+            .unwrap();
+        listener.subscribe("/app/1/t/proto").await.unwrap();
+        let sender = MqttTransport::connect(config).await.unwrap();
+        for i in 0..5u8 {
+            sender.publish("/app/1/t/proto", &[i]).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            listener.shared.queues.lock().unwrap()["/app/1/t/proto"].len(),
+            2
+        );
+        let payloads: Vec<_> = listener
+            .poll("/app/1/t/proto")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.payload)
+            .collect();
+        assert_eq!(payloads, [vec![3], vec![4]]);
+    }
+
+    #[tokio::test]
+    async fn test_reliable_delivery_over_mqtt() {
+        let broker = MockBroker::start().await.unwrap();
+        let connect = || MqttTransport::connect(MqttConfig::new(broker.address()));
+        let sender = SdsTransport::new(connect().await.unwrap());
+        let receiver = SdsTransport::new(connect().await.unwrap());
+        receiver
+            .inner()
+            .subscribe("/app/1/task/proto")
+            .await
+            .unwrap();
+
+        let receive = async {
+            let mut messages = receiver.inner().subscribe_stream("/app/1/task/proto");
+            let message = next(&mut messages).await;
+            receiver.send_ack("task-1").await.unwrap();
+            message
+        };
+        let (acked, message) = tokio::join!(
+            sender.publish_reliable("/app/1/task/proto", b"do it", "task-1"),
+            receive
+        );
+        assert!(acked.unwrap());
+        assert_eq!(message.payload, b"do it");
+    }
+}
@@ -3,25 +3,184 @@
 //! Talks to a running nwaku node via its REST API (default: http://localhost:8645).
 //! This is the v0.1 transport while we work on the logos-delivery-rust-bindings FFI
 //! integration (Issue #1).
+//!
+//! Messages are routed to relay shards by autosharding (see `autosharding`);
+//! use `with_routing` for a different cluster or a static pubsub topic.
+//! Content topics in the standard four-segment form are handed to nwaku's own
+//! `/relay/v1/auto/` endpoints; longer ones, like this crate's, go through the
+//! pubsub endpoints on the shard computed here.
+//!
+//! `subscribe` registers the relay subscription with nwaku. Subscriptions are
+//! re-established when nwaku reports an unknown topic or after it was
+//! unreachable, e.g. because it restarted.
+//!
+//! nwaku hands out every cached message of a pubsub topic at once, whatever
+//! its content topic. `poll` therefore sorts fetched messages into bounded
+//! per-content-topic queues for all subscribed topics, so polling one topic
+//! never discards messages meant for another.
+//!
+//! Messages keep the metadata nwaku reports (timestamp, `ephemeral`, `meta`)
+//! and are tagged with their pubsub topic, so their hash can be computed.
+//!
+//! History is queried through nwaku's Store endpoint (`/store/v3/messages`),
+//! served by nwaku itself or by the store peer set with `with_store_peer`.
+//!
+//! In `ClientMode::Light` nwaku doesn't relay our shards. Messages are
+//! published with Lightpush and received through Filter subscriptions at
+//! nwaku's service peers. Those expire when the peer restarts or stops
+//! hearing from us, so the subscriptions are pinged every
+//! `FILTER_PING_INTERVAL` and renewed when a ping fails.
+//!
+//! Requests time out (`with_request_timeout`, `with_connect_timeout`) and are
+//! retried with backoff when nwaku is unreachable or answers 5xx
+//! (`with_retry_policy`). Rate limiting (429, or an error naming a rate or
+//! message limit, as RLN rejections do) isn't retried: it fails with
+//! `rate_limit::RateLimited`, carrying nwaku's `Retry-After` if it sent one.
+//! Other 4xx answers, and messages nwaku can't take (an invalid content topic,
+//! oversized meta), fail with `Rejected`. After repeated failures a circuit
+//! breaker stops sending for a while; then nwaku's `/health` is checked before
+//! traffic resumes. `connection_state` and `watch_connection` report whether
+//! nwaku is reachable and healthy.
 
-use crate::WakuTransport;
-use anyhow::{Context, Result};
+use crate::autosharding::{shard_topic, ContentTopic, PubsubRouting};
+use crate::message::{now_nanos, MAX_META_SIZE};
+use crate::rate_limit::RateLimited;
+use crate::retry::{BreakerState, CircuitBreaker, RetryPolicy};
+use crate::Rejected;
+use crate::{HistoryPage, HistoryQuery, WakuMessage, WakuTransport};
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
 
 /// Transport implementation backed by the nwaku REST API.
 ///
-/// Requires a running nwaku node. Start one with:
+/// Requires a running nwaku node relaying the shard of our topics (shard 5 of
+/// The Waku Network for `/waku-a2a/1/...`). Start one with:
 /// ```bash
 /// docker run -p 8645:8645 statusteam/nim-waku:v0.31.0 \
-///   --rest --rest-address=0.0.0.0 --rest-port=8645
+///   --rest --rest-address=0.0.0.0 --rest-port=8645 \
+///   --cluster-id=1 --num-shards-in-network=8 --shard=5
 /// ```
+/// In light mode nwaku needs Filter and Lightpush service peers instead
+/// (`--filternode=<multiaddr> --lightpushnode=<multiaddr>`, no `--shard`).
 pub struct NwakuRestTransport {
     pub waku_url: String,
     client: reqwest::Client,
-    subscribed_topics: Mutex<HashSet<String>>,
+    routing: PubsubRouting,
+    /// Relay or Filter subscriptions registered with nwaku, keyed by content
+    /// topic.
+    subscriptions: Mutex<HashMap<String, RelayRoute>>,
+    /// Set when nwaku may have lost our subscriptions.
+    resubscribe_needed: AtomicBool,
+    /// Fetched messages not yet returned by `poll`.
+    demux: Mutex<Demux>,
+    queue_capacity: usize,
+    /// Multiaddr of the store node to query; nwaku's own store if None.
+    store_peer: Option<String>,
+    mode: ClientMode,
+    ping_interval: Duration,
+    last_ping: Mutex<Instant>,
+    connect_timeout: Duration,
+    request_timeout: Duration,
+    retry: RetryPolicy,
+    breaker: CircuitBreaker,
+    connection: watch::Sender<ConnectionState>,
+}
+
+/// Whether nwaku can be used, as far as the transport knows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Nothing sent yet.
+    #[default]
+    Unknown,
+    /// nwaku answered the last request and isn't reporting problems.
+    Connected,
+    /// nwaku answers but is failing requests or not ready.
+    Degraded,
+    /// nwaku is unreachable, or the circuit breaker is open.
+    Disconnected,
+}
+
+/// nwaku's view of its own health (`/health` and `/debug/v1/info`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeHealth {
+    /// Overall status, e.g. "Ready" or "Initializing".
+    pub node_health: String,
+    /// Status per protocol, e.g. ("Relay", "Ready").
+    pub protocols: Vec<(String, String)>,
+    /// Listen multiaddrs, if nwaku serves `/debug/v1/info`.
+    pub listen_addresses: Vec<String>,
+    pub enr_uri: Option<String>,
+}
+
+impl NodeHealth {
+    pub fn is_ready(&self) -> bool {
+        self.node_health == "Ready"
+    }
+}
+
+/// How the transport sends and receives through nwaku.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientMode {
+    /// nwaku relays our shards.
+    #[default]
+    Relay,
+    /// Publish with Lightpush and receive through Filter subscriptions, for
+    /// nodes that can't afford full relay.
+    Light,
+}
+
+/// Interval between Filter subscription pings in light mode.
+pub const FILTER_PING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Default time allowed to connect to nwaku.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default time allowed for a whole request.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Content topics per Filter subscribe request allowed by the protocol.
+const MAX_CONTENT_FILTERS: usize = 30;
+
+/// Messages buffered per content topic by default.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+/// Counters for messages `poll` fetched but could not deliver.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DemuxStats {
+    /// Oldest messages dropped because their topic's queue was full.
+    pub overflowed: u64,
+    /// Messages for content topics that aren't subscribed.
+    pub unrouted: u64,
+}
+
+#[derive(Default)]
+struct Demux {
+    queues: HashMap<String, VecDeque<WakuMessage>>,
+    stats: DemuxStats,
+}
+
+/// nwaku endpoint family serving a content topic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RelayRoute {
+    /// `/relay/v1/messages/{pubsub}` and `/relay/v1/subscriptions`.
+    Pubsub(String),
+    /// `/relay/v1/auto/...`: nwaku derives the shard itself.
+    Auto,
+    /// `/filter/v2/...` and `/lightpush/v1/message` on this pubsub topic.
+    Filter(String),
+}
+
+impl RelayRoute {
+    /// Whether content topics on this route share one nwaku subscription.
+    fn is_shared(&self) -> bool {
+        matches!(self, RelayRoute::Pubsub(_))
+    }
 }
 
 #[derive(Serialize)]
@@ -29,28 +188,640 @@ struct RelayMessage {
     payload: String,
     #[serde(rename = "contentTopic")]
     content_topic: String,
-    timestamp: u64,
+    version: u32,
+    timestamp: i64,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    ephemeral: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LightpushRequest {
+    pubsub_topic: String,
+    message: RelayMessage,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FilterRequest<'a> {
+    request_id: String,
+    content_filters: &'a [String],
+    pubsub_topic: &'a str,
 }
 
 #[derive(Deserialize, Debug)]
 struct WakuMessageResponse {
     payload: String,
     #[serde(rename = "contentTopic")]
-    #[allow(dead_code)]
     content_topic: Option<String>,
+    #[serde(default)]
+    version: u32,
+    timestamp: Option<i64>,
+    #[serde(default)]
+    ephemeral: bool,
+    meta: Option<String>,
+}
+
+impl WakuMessageResponse {
+    fn decode(self, content_topic: &str, pubsub_topic: Option<String>) -> Option<WakuMessage> {
+        Some(WakuMessage {
+            payload: base64_decode(&self.payload).ok()?,
+            content_topic: content_topic.to_string(),
+            version: self.version,
+            timestamp: self.timestamp,
+            ephemeral: self.ephemeral,
+            meta: self
+                .meta
+                .as_deref()
+                .and_then(|meta| base64_decode(meta).ok())
+                .unwrap_or_default(),
+            pubsub_topic,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct StoreResponse {
+    status_code: u32,
+    #[serde(default)]
+    status_desc: String,
+    #[serde(default)]
+    messages: Vec<StoredMessage>,
+    pagination_cursor: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct HealthResponse {
+    node_health: String,
+    #[serde(default)]
+    protocols_health: Vec<serde_json::Map<String, serde_json::Value>>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct DebugInfo {
+    #[serde(default)]
+    listen_addresses: Vec<String>,
+    enr_uri: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct StoredMessage {
+    message: Option<WakuMessageResponse>,
+    pubsub_topic: Option<String>,
 }
 
 impl NwakuRestTransport {
     pub fn new(waku_url: &str) -> Self {
         Self {
             waku_url: waku_url.trim_end_matches('/').to_string(),
-            client: reqwest::Client::new(),
-            subscribed_topics: Mutex::new(HashSet::new()),
+            client: build_client(DEFAULT_CONNECT_TIMEOUT, DEFAULT_REQUEST_TIMEOUT),
+            routing: PubsubRouting::default(),
+            subscriptions: Mutex::new(HashMap::new()),
+            resubscribe_needed: AtomicBool::new(false),
+            demux: Mutex::new(Demux::default()),
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            store_peer: None,
+            mode: ClientMode::Relay,
+            ping_interval: FILTER_PING_INTERVAL,
+            last_ping: Mutex::new(Instant::now()),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            retry: RetryPolicy::default(),
+            breaker: CircuitBreaker::default(),
+            connection: watch::Sender::new(ConnectionState::Unknown),
         }
     }
+
+    /// Give up connecting to nwaku after `timeout`.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self.client = build_client(self.connect_timeout, self.request_timeout);
+        self
+    }
+
+    /// Give up on a request, including its response, after `timeout`.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self.client = build_client(self.connect_timeout, self.request_timeout);
+        self
+    }
+
+    /// Retry transient failures with `policy` (`RetryPolicy::none()` to
+    /// disable).
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Stop sending to nwaku as `breaker` decides.
+    pub fn with_circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.breaker = breaker;
+        self
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        *self.connection.borrow()
+    }
+
+    /// Receiver notified whenever the connection state changes.
+    pub fn watch_connection(&self) -> watch::Receiver<ConnectionState> {
+        self.connection.subscribe()
+    }
+
+    /// Ask nwaku for its health. Updates the connection state, and closes
+    /// the circuit breaker if nwaku is ready or opens it further if not.
+    pub async fn health(&self) -> Result<NodeHealth> {
+        let result = self.fetch_health().await;
+        match &result {
+            Ok(health) if health.is_ready() => {
+                self.breaker.record_success();
+                self.set_connection(ConnectionState::Connected);
+            }
+            Ok(_) => {
+                self.breaker.record_failure();
+                self.set_connection(ConnectionState::Degraded);
+            }
+            Err(_) => {
+                self.breaker.record_failure();
+                self.set_connection(ConnectionState::Disconnected);
+            }
+        }
+        result
+    }
+
+    async fn fetch_health(&self) -> Result<NodeHealth> {
+        let resp = self
+            .client
+            .get(format!("{}/health", self.waku_url))
+            .send()
+            .await
+            .context("Failed to check health (is nwaku running?)")?;
+        let status = resp.status();
+        let body = resp.text().await.unwrap_or_default();
+        let report: HealthResponse = match serde_json::from_str(&body) {
+            Ok(report) => report,
+            Err(_) => bail!("nwaku health check failed ({}): {}", status, body),
+        };
+        let protocols = report
+            .protocols_health
+            .into_iter()
+            .flat_map(|entry| entry.into_iter())
+            .map(|(protocol, health)| {
+                let health = health.as_str().map(str::to_string);
+                (protocol, health.unwrap_or_default())
+            })
+            .collect();
+
+        // Older nwaku versions don't have the debug endpoint
+        let info = match self
+            .client
+            .get(format!("{}/debug/v1/info", self.waku_url))
+            .send()
+            .await
+        {
+            Ok(resp) if resp.status().is_success() => resp.json().await.unwrap_or_default(),
+            _ => DebugInfo::default(),
+        };
+        Ok(NodeHealth {
+            node_health: report.node_health,
+            protocols,
+            listen_addresses: info.listen_addresses,
+            enr_uri: info.enr_uri,
+        })
+    }
+
+    fn set_connection(&self, state: ConnectionState) {
+        self.connection.send_if_modified(|current| {
+            if *current == state {
+                return false;
+            }
+            eprintln!("[nwaku] Connection {:?} -> {:?}", current, state);
+            *current = state;
+            true
+        });
+    }
+
+    /// Send and receive in `mode` (relay by default).
+    pub fn with_mode(mut self, mode: ClientMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn mode(&self) -> ClientMode {
+        self.mode
+    }
+
+    /// Ping Filter subscriptions every `interval` in light mode.
+    pub fn with_ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    /// Query history from the store node at `multiaddr` instead of nwaku's
+    /// own store.
+    pub fn with_store_peer(mut self, multiaddr: &str) -> Self {
+        self.store_peer = Some(multiaddr.to_string());
+        self
+    }
+
+    /// Buffer at most `capacity` undelivered messages per content topic.
+    pub fn with_queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity.max(1);
+        self
+    }
+
+    /// Counters of fetched messages dropped since creation.
+    pub fn demux_stats(&self) -> DemuxStats {
+        self.demux.lock().unwrap().stats
+    }
+
+    /// Map content topics to pubsub topics with `routing` instead of The
+    /// Waku Network's autosharding.
+    pub fn with_routing(mut self, routing: PubsubRouting) -> Self {
+        self.routing = routing;
+        self
+    }
+
+    pub fn routing(&self) -> &PubsubRouting {
+        &self.routing
+    }
+
+    /// Content topics currently subscribed.
+    pub fn subscribed_topics(&self) -> Vec<String> {
+        let mut topics: Vec<String> = self.subscriptions.lock().unwrap().keys().cloned().collect();
+        topics.sort();
+        topics
+    }
+
+    /// Register all tracked subscriptions with nwaku again.
+    pub async fn resubscribe(&self) -> Result<()> {
+        self.resubscribe_needed.store(false, Ordering::SeqCst);
+        let (pubsub, auto, filter) = {
+            let subscriptions = self.subscriptions.lock().unwrap();
+            let mut pubsub = BTreeSet::new();
+            let mut auto = BTreeSet::new();
+            let mut filter: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            for (topic, route) in subscriptions.iter() {
+                match route {
+                    RelayRoute::Pubsub(p) => {
+                        pubsub.insert(p.clone());
+                    }
+                    RelayRoute::Auto => {
+                        auto.insert(topic.clone());
+                    }
+                    RelayRoute::Filter(p) => {
+                        filter.entry(p.clone()).or_default().push(topic.clone())
+                    }
+                }
+            }
+            (pubsub, auto, filter)
+        };
+        let result = async {
+            if !pubsub.is_empty() {
+                self.relay_subscriptions(reqwest::Method::POST, false, &pubsub)
+                    .await?;
+            }
+            if !auto.is_empty() {
+                self.relay_subscriptions(reqwest::Method::POST, true, &auto)
+                    .await?;
+            }
+            for (pubsub, mut topics) in filter {
+                topics.sort();
+                for chunk in topics.chunks(MAX_CONTENT_FILTERS) {
+                    self.filter_subscriptions(reqwest::Method::POST, &pubsub, chunk)
+                        .await?;
+                }
+            }
+            Ok(())
+        }
+        .await;
+        if result.is_err() {
+            self.resubscribe_needed.store(true, Ordering::SeqCst);
+        }
+        result
+    }
+
+    fn route(&self, content_topic: &str) -> Result<RelayRoute> {
+        if self.mode == ClientMode::Light {
+            return Ok(RelayRoute::Filter(
+                self.routing.pubsub_topic(content_topic)?,
+            ));
+        }
+        match &self.routing {
+            PubsubRouting::Static(pubsub) => Ok(RelayRoute::Pubsub(pubsub.clone())),
+            PubsubRouting::Auto(sharding) => {
+                let topic = ContentTopic::parse(content_topic)
+                    .with_context(|| format!("Cannot autoshard {:?}", content_topic))?;
+                if topic.is_standard() {
+                    Ok(RelayRoute::Auto)
+                } else {
+                    Ok(RelayRoute::Pubsub(shard_topic(
+                        sharding.cluster_id,
+                        sharding.shard(&topic),
+                    )))
+                }
+            }
+        }
+    }
+
+    fn publish_url(&self, content_topic: &str) -> Result<String> {
+        Ok(match self.route(content_topic)? {
+            RelayRoute::Pubsub(pubsub) => format!(
+                "{}/relay/v1/messages/{}",
+                self.waku_url,
+                encode_topic(&pubsub)
+            ),
+            RelayRoute::Auto => format!("{}/relay/v1/auto/messages", self.waku_url),
+            RelayRoute::Filter(_) => format!("{}/lightpush/v1/message", self.waku_url),
+        })
+    }
+
+    fn poll_url(&self, content_topic: &str) -> Result<String> {
+        Ok(match self.route(content_topic)? {
+            RelayRoute::Pubsub(pubsub) => format!(
+                "{}/relay/v1/messages/{}",
+                self.waku_url,
+                encode_topic(&pubsub)
+            ),
+            RelayRoute::Auto => format!(
+                "{}/relay/v1/auto/messages/{}",
+                self.waku_url,
+                encode_topic(content_topic)
+            ),
+            RelayRoute::Filter(_) => format!(
+                "{}/filter/v2/messages/{}",
+                self.waku_url,
+                encode_topic(content_topic)
+            ),
+        })
+    }
+
+    /// Add or remove Filter subscriptions for content topics on one pubsub
+    /// topic.
+    async fn filter_subscriptions(
+        &self,
+        method: reqwest::Method,
+        pubsub_topic: &str,
+        content_topics: &[String],
+    ) -> Result<()> {
+        let what = if method == reqwest::Method::DELETE {
+            "filter unsubscribe"
+        } else {
+            "filter subscribe"
+        };
+        let body = FilterRequest {
+            request_id: uuid::Uuid::new_v4().to_string(),
+            content_filters: content_topics,
+            pubsub_topic,
+        };
+        let request = self
+            .client
+            .request(method, format!("{}/filter/v2/subscriptions", self.waku_url))
+            .json(&body);
+        let resp = self.send(request, what).await?;
+        ensure_success(resp, what).await?;
+        Ok(())
+    }
+
+    /// In light mode, ping the Filter service peer once per ping interval
+    /// and renew the subscriptions if it no longer has them.
+    async fn ping_filter_if_due(&self) -> Result<()> {
+        if self.mode != ClientMode::Light {
+            return Ok(());
+        }
+        {
+            let mut last_ping = self.last_ping.lock().unwrap();
+            if last_ping.elapsed() < self.ping_interval {
+                return Ok(());
+            }
+            *last_ping = Instant::now();
+        }
+        if self.subscriptions.lock().unwrap().is_empty() {
+            return Ok(());
+        }
+        let url = format!(
+            "{}/filter/v2/subscriptions/{}",
+            self.waku_url,
+            uuid::Uuid::new_v4()
+        );
+        let resp = self.send(self.client.get(&url), "ping filter").await?;
+        if !resp.status().is_success() {
+            eprintln!(
+                "[nwaku] Filter ping failed ({}), renewing subscriptions",
+                resp.status()
+            );
+            self.resubscribe().await?;
+        }
+        Ok(())
+    }
+
+    /// Add or remove relay subscriptions: pubsub topics, or content topics
+    /// for the `auto` endpoint.
+    async fn relay_subscriptions<S: AsRef<str>>(
+        &self,
+        method: reqwest::Method,
+        auto: bool,
+        topics: impl IntoIterator<Item = S>,
+    ) -> Result<()> {
+        let topics: Vec<String> = topics.into_iter().map(|t| t.as_ref().to_string()).collect();
+        let path = if auto {
+            "relay/v1/auto/subscriptions"
+        } else {
+            "relay/v1/subscriptions"
+        };
+        let what = if method == reqwest::Method::DELETE {
+            "unsubscribe"
+        } else {
+            "subscribe"
+        };
+        let request = self
+            .client
+            .request(method, format!("{}/{}", self.waku_url, path))
+            .json(&topics);
+        let resp = self.send(request, what).await?;
+        ensure_success(resp, what).await?;
+        Ok(())
+    }
+
+    /// Send a request, retrying transient failures, and flag subscriptions
+    /// for renewal if nwaku is unreachable.
+    async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+        what: &str,
+    ) -> Result<reqwest::Response> {
+        if !self.breaker.allow() {
+            self.set_connection(ConnectionState::Disconnected);
+            bail!(
+                "Failed to {}: nwaku kept failing, not retrying yet (is nwaku running?)",
+                what
+            );
+        }
+        if self.breaker.state() == BreakerState::HalfOpen {
+            // Only resume traffic once nwaku is back and ready
+            match self.health().await {
+                Ok(health) if health.is_ready() => {}
+                Ok(health) => bail!("Failed to {}: nwaku is {}", what, health.node_health),
+                Err(e) => {
+                    self.resubscribe_needed.store(true, Ordering::SeqCst);
+                    return Err(e).with_context(|| format!("Failed to {}", what));
+                }
+            }
+        }
+
+        let mut retry = 0;
+        loop {
+            let can_retry = retry < self.retry.max_retries;
+            let attempt = match request.try_clone() {
+                Some(attempt) if can_retry => attempt,
+                _ => return self.send_last(request, what).await,
+            };
+            match attempt.send().await {
+                Ok(resp) if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                    return Err(self.rate_limited(resp, what).await);
+                }
+                Ok(resp) if is_transient_status(resp.status()) => {
+                    let status = resp.status();
+                    let body = resp.text().await.unwrap_or_default();
+                    if is_rate_limit_message(&body) {
+                        self.breaker.record_success();
+                        self.set_connection(ConnectionState::Connected);
+                        return Err(rate_limit_error(what, status, None, &body));
+                    }
+                    eprintln!("[nwaku] {} failed ({}), retrying", what, status);
+                }
+                Ok(resp) => {
+                    self.breaker.record_success();
+                    self.set_connection(ConnectionState::Connected);
+                    return Ok(resp);
+                }
+                Err(e) if is_transient_error(&e) => {
+                    self.resubscribe_needed.store(true, Ordering::SeqCst);
+                    eprintln!("[nwaku] {} failed ({}), retrying", what, e);
+                }
+                Err(e) => return self.failed(e, what),
+            }
+            tokio::time::sleep(self.retry.backoff(retry)).await;
+            retry += 1;
+        }
+    }
+
+    /// Send the final attempt of a request.
+    async fn send_last(
+        &self,
+        request: reqwest::RequestBuilder,
+        what: &str,
+    ) -> Result<reqwest::Response> {
+        match request.send().await {
+            Ok(resp) if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                Err(self.rate_limited(resp, what).await)
+            }
+            Ok(resp) => {
+                if resp.status().is_server_error() {
+                    self.breaker.record_failure();
+                    self.set_connection(ConnectionState::Degraded);
+                } else {
+                    self.breaker.record_success();
+                    self.set_connection(ConnectionState::Connected);
+                }
+                Ok(resp)
+            }
+            Err(e) => self.failed(e, what),
+        }
+    }
+
+    /// nwaku is fine but asks us to slow down.
+    async fn rate_limited(&self, resp: reqwest::Response, what: &str) -> anyhow::Error {
+        self.breaker.record_success();
+        self.set_connection(ConnectionState::Connected);
+        let status = resp.status();
+        let retry_after = resp
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
+        let body = resp.text().await.unwrap_or_default();
+        rate_limit_error(what, status, retry_after, &body)
+    }
+
+    fn failed(&self, e: reqwest::Error, what: &str) -> Result<reqwest::Response> {
+        self.resubscribe_needed.store(true, Ordering::SeqCst);
+        self.breaker.record_failure();
+        self.set_connection(ConnectionState::Disconnected);
+        Err(e).with_context(|| format!("Failed to {} (is nwaku running?)", what))
+    }
+}
+
+fn build_client(connect_timeout: Duration, request_timeout: Duration) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(connect_timeout)
+        .timeout(request_timeout)
+        .build()
+        .expect("HTTP client configuration is valid")
+}
+
+/// Responses worth retrying: server-side failures, which nwaku also reports
+/// when it has no peers to publish to yet.
+fn is_transient_status(status: reqwest::StatusCode) -> bool {
+    status.is_server_error()
+}
+
+/// How long to hold off after rate limiting without a `Retry-After`.
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Whether an nwaku error message is about rate limiting, e.g. a store or
+/// lightpush service peer's "too many requests" or an RLN message limit.
+fn is_rate_limit_message(body: &str) -> bool {
+    let body = body.to_ascii_lowercase();
+    ["rate limit", "too many requests", "message limit"]
+        .iter()
+        .any(|marker| body.contains(marker))
+}
+
+fn rate_limit_error(
+    what: &str,
+    status: reqwest::StatusCode,
+    retry_after: Option<Duration>,
+    body: &str,
+) -> anyhow::Error {
+    RateLimited {
+        retry_after: retry_after.unwrap_or(RATE_LIMIT_BACKOFF),
+        reason: format!("nwaku {} failed ({}): {}", what, status, body.trim()),
+    }
+    .into()
+}
+
+/// Errors worth retrying: nwaku unreachable or too slow. A connection lost
+/// after the request went out isn't: nwaku may have processed it.
+fn is_transient_error(e: &reqwest::Error) -> bool {
+    e.is_connect() || e.is_timeout()
+}
+
+/// Fail with nwaku's status and error body unless the request succeeded.
+async fn ensure_success(resp: reqwest::Response, what: &str) -> Result<reqwest::Response> {
+    if resp.status().is_success() {
+        return Ok(resp);
+    }
+    let status = resp.status();
+    let body = resp.text().await.unwrap_or_default();
+    if is_rate_limit_message(&body) {
+        return Err(rate_limit_error(what, status, None, &body));
+    }
+    let reason = format!("nwaku {} failed ({}): {}", what, status, body);
+    if status.is_client_error() && status != reqwest::StatusCode::REQUEST_TIMEOUT {
+        return Err(Rejected::new(reason).into());
+    }
+    bail!(reason)
 }
 
-fn base64_encode(data: &[u8]) -> String {
+pub(crate) fn base64_encode(data: &[u8]) -> String {
     // Simple base64 encoding without external dependency
     // nwaku REST API expects base64-encoded payloads
     let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
@@ -76,7 +847,7 @@ fn base64_encode(data: &[u8]) -> String {
     result
 }
 
-fn base64_decode(input: &str) -> Result<Vec<u8>> {
+pub(crate) fn base64_decode(input: &str) -> Result<Vec<u8>> {
     let input = input.trim_end_matches('=');
     let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
     let mut buf = Vec::new();
@@ -116,93 +887,242 @@ fn encode_topic(topic: &str) -> String {
     topic.replace('/', "%2F")
 }
 
-const DEFAULT_PUBSUB_TOPIC: &str = "/waku/2/default-waku/proto";
-
 #[async_trait]
 impl WakuTransport for NwakuRestTransport {
     async fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
-        let url = format!(
-            "{}/relay/v1/messages/{}",
-            self.waku_url,
-            encode_topic(DEFAULT_PUBSUB_TOPIC)
-        );
+        self.publish_message(&WakuMessage::new(topic, payload))
+            .await
+    }
 
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_nanos() as u64;
+    /// Messages without a timestamp are stamped with the current time.
+    async fn publish_message(&self, message: &WakuMessage) -> Result<()> {
+        if message.meta.len() > MAX_META_SIZE {
+            return Err(Rejected::new(format!(
+                "Message meta is {} bytes, at most {} allowed",
+                message.meta.len(),
+                MAX_META_SIZE
+            ))
+            .into());
+        }
+        let invalid = |e: anyhow::Error| Rejected::new(format!("{:#}", e));
+        let route = self.route(&message.content_topic).map_err(invalid)?;
+        let url = self.publish_url(&message.content_topic).map_err(invalid)?;
 
         let msg = RelayMessage {
-            payload: base64_encode(payload),
-            content_topic: topic.to_string(),
-            timestamp: now,
+            payload: base64_encode(&message.payload),
+            content_topic: message.content_topic.clone(),
+            version: message.version,
+            timestamp: message.timestamp.unwrap_or_else(now_nanos),
+            ephemeral: message.ephemeral,
+            meta: (!message.meta.is_empty()).then(|| base64_encode(&message.meta)),
         };
 
-        let resp = self
-            .client
-            .post(&url)
-            .json(&msg)
-            .send()
-            .await
-            .context("Failed to publish to nwaku")?;
-
-        if !resp.status().is_success() {
-            let status = resp.status();
-            let body = resp.text().await.unwrap_or_default();
-            anyhow::bail!("nwaku publish failed ({}): {}", status, body);
-        }
+        let request = match route {
+            RelayRoute::Filter(pubsub_topic) => self.client.post(&url).json(&LightpushRequest {
+                pubsub_topic,
+                message: msg,
+            }),
+            _ => self.client.post(&url).json(&msg),
+        };
+        let resp = self.send(request, "publish").await?;
+        ensure_success(resp, "publish").await?;
         Ok(())
     }
 
     async fn subscribe(&self, topic: &str) -> Result<()> {
-        // nwaku auto-relay doesn't require explicit content topic subscriptions
-        // for the default pubsub topic. Track it locally for poll filtering.
-        let mut topics = self.subscribed_topics.lock().unwrap();
-        topics.insert(topic.to_string());
+        if self.resubscribe_needed.load(Ordering::SeqCst) {
+            self.resubscribe().await?;
+        }
+        let route = self.route(topic)?;
+        let already_relayed = {
+            let subscriptions = self.subscriptions.lock().unwrap();
+            if subscriptions.contains_key(topic) {
+                return Ok(());
+            }
+            route.is_shared() && subscriptions.values().any(|r| *r == route)
+        };
+        if !already_relayed {
+            match &route {
+                RelayRoute::Pubsub(pubsub) => {
+                    self.relay_subscriptions(reqwest::Method::POST, false, [pubsub])
+                        .await?
+                }
+                RelayRoute::Auto => {
+                    self.relay_subscriptions(reqwest::Method::POST, true, [topic])
+                        .await?
+                }
+                RelayRoute::Filter(pubsub) => {
+                    self.filter_subscriptions(reqwest::Method::POST, pubsub, &[topic.to_string()])
+                        .await?
+                }
+            }
+        }
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .insert(topic.to_string(), route);
         Ok(())
     }
 
-    async fn poll(&self, topic: &str) -> Result<Vec<Vec<u8>>> {
-        let url = format!(
-            "{}/relay/v1/messages/{}",
-            self.waku_url,
-            encode_topic(DEFAULT_PUBSUB_TOPIC)
-        );
+    /// The pubsub subscription is dropped once no subscribed content topic
+    /// uses it.
+    async fn unsubscribe(&self, topic: &str) -> Result<()> {
+        self.demux.lock().unwrap().queues.remove(topic);
+        let route = {
+            let mut subscriptions = self.subscriptions.lock().unwrap();
+            let Some(route) = subscriptions.remove(topic) else {
+                return Ok(());
+            };
+            if route.is_shared() && subscriptions.values().any(|r| *r == route) {
+                return Ok(());
+            }
+            route
+        };
+        let result = match &route {
+            RelayRoute::Pubsub(pubsub) => {
+                self.relay_subscriptions(reqwest::Method::DELETE, false, [pubsub])
+                    .await
+            }
+            RelayRoute::Auto => {
+                self.relay_subscriptions(reqwest::Method::DELETE, true, [topic])
+                    .await
+            }
+            RelayRoute::Filter(pubsub) => {
+                self.filter_subscriptions(reqwest::Method::DELETE, pubsub, &[topic.to_string()])
+                    .await
+            }
+        };
+        if result.is_err() {
+            // Still subscribed on nwaku's side; keep tracking it
+            self.subscriptions
+                .lock()
+                .unwrap()
+                .insert(topic.to_string(), route);
+        }
+        result
+    }
 
-        let resp = self
-            .client
-            .get(&url)
-            .send()
-            .await
-            .context("Failed to poll nwaku")?;
+    async fn poll(&self, topic: &str) -> Result<Vec<WakuMessage>> {
+        if self.resubscribe_needed.load(Ordering::SeqCst) {
+            self.resubscribe().await?;
+        }
+        self.ping_filter_if_due().await?;
+        let url = self.poll_url(topic)?;
 
-        if !resp.status().is_success() {
-            let status = resp.status();
-            let body = resp.text().await.unwrap_or_default();
-            anyhow::bail!("nwaku poll failed ({}): {}", status, body);
+        let mut resp = self.send(self.client.get(&url), "poll").await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND
+            && self.subscriptions.lock().unwrap().contains_key(topic)
+        {
+            // nwaku forgot our subscription, most likely after a restart
+            eprintln!("[nwaku] Subscription for {} lost, resubscribing", topic);
+            self.resubscribe().await?;
+            resp = self.send(self.client.get(&url), "poll").await?;
         }
+        let resp = ensure_success(resp, "poll").await?;
 
         let messages: Vec<WakuMessageResponse> = resp.json().await.unwrap_or_default();
 
-        let mut payloads = Vec::new();
+        let subscribed: HashSet<String> =
+            self.subscriptions.lock().unwrap().keys().cloned().collect();
+        let mut demux = self.demux.lock().unwrap();
         for msg in messages {
-            // Filter by content topic
-            if let Some(ct) = &msg.content_topic {
-                if ct != topic {
-                    continue;
-                }
+            let target = msg.content_topic.as_deref().unwrap_or(topic);
+            if target != topic && !subscribed.contains(target) {
+                demux.stats.unrouted += 1;
+                continue;
             }
-            if let Ok(decoded) = base64_decode(&msg.payload) {
-                payloads.push(decoded);
+            let target = target.to_string();
+            let pubsub_topic = self.routing.pubsub_topic(&target).ok();
+            let Some(message) = msg.decode(&target, pubsub_topic) else {
+                continue;
+            };
+            let queue = demux.queues.entry(target).or_default();
+            queue.push_back(message);
+            if queue.len() > self.queue_capacity {
+                queue.pop_front();
+                demux.stats.overflowed += 1;
             }
         }
-        Ok(payloads)
+        Ok(demux
+            .queues
+            .remove(topic)
+            .map(Vec::from)
+            .unwrap_or_default())
+    }
+
+    /// Fails unless nwaku reports itself ready.
+    async fn health_check(&self) -> Result<()> {
+        let health = self.health().await?;
+        if !health.is_ready() {
+            bail!("nwaku is not ready ({})", health.node_health);
+        }
+        Ok(())
+    }
+
+    async fn query_history(&self, query: &HistoryQuery) -> Result<HistoryPage> {
+        let pubsub_topic = self.routing.pubsub_topic(&query.content_topic)?;
+        let mut params = vec![
+            ("includeData", "true".to_string()),
+            ("ascending", "true".to_string()),
+            ("pubsubTopic", pubsub_topic.clone()),
+            ("contentTopics", query.content_topic.clone()),
+        ];
+        if let Some(peer) = &self.store_peer {
+            params.push(("peerAddr", peer.clone()));
+        }
+        if let Some(start) = query.start_time {
+            params.push(("startTime", start.to_string()));
+        }
+        if let Some(end) = query.end_time {
+            params.push(("endTime", end.to_string()));
+        }
+        if let Some(size) = query.page_size {
+            params.push(("pageSize", size.to_string()));
+        }
+        if let Some(cursor) = &query.cursor {
+            params.push(("cursor", cursor.clone()));
+        }
+
+        let request = self
+            .client
+            .get(format!("{}/store/v3/messages", self.waku_url))
+            .query(&params);
+        let resp = self.send(request, "store query").await?;
+        let resp = ensure_success(resp, "store query").await?;
+        let page: StoreResponse = resp.json().await.context("Invalid nwaku store response")?;
+        if page.status_code != 200 {
+            bail!(
+                "nwaku store query failed ({}): {}",
+                page.status_code,
+                page.status_desc
+            );
+        }
+
+        let messages = page
+            .messages
+            .into_iter()
+            .filter_map(|stored| {
+                let message = stored.message?;
+                let content_topic = message
+                    .content_topic
+                    .clone()
+                    .unwrap_or_else(|| query.content_topic.clone());
+                let pubsub = stored.pubsub_topic.or_else(|| Some(pubsub_topic.clone()));
+                message.decode(&content_topic, pubsub)
+            })
+            .collect();
+        Ok(HistoryPage {
+            messages,
+            cursor: page.pagination_cursor,
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     #[test]
     fn test_base64_roundtrip() {
@@ -245,5 +1165,718 @@ mod tests {
     fn test_transport_creation() {
         let t = NwakuRestTransport::new("http://localhost:8645");
         assert_eq!(t.waku_url, "http://localhost:8645");
+        assert_eq!(t.routing(), &PubsubRouting::default());
+    }
+
+    #[test]
+    fn test_relay_urls() {
+        let t = NwakuRestTransport::new("http://localhost:8645/");
+        // Our multi-segment topics are sharded locally
+        let shard = "http://localhost:8645/relay/v1/messages/%2Fwaku%2F2%2Frs%2F1%2F5";
+        assert_eq!(t.publish_url("/waku-a2a/1/ack/m1/proto").unwrap(), shard);
+        assert_eq!(t.poll_url("/waku-a2a/1/task/02ab/proto").unwrap(), shard);
+        // Standard topics use nwaku's autosharding endpoints
+        assert_eq!(
+            t.publish_url("/waku-a2a/1/discovery/proto").unwrap(),
+            "http://localhost:8645/relay/v1/auto/messages"
+        );
+        assert_eq!(
+            t.poll_url("/toychat/2/huilong/proto").unwrap(),
+            "http://localhost:8645/relay/v1/auto/messages/%2Ftoychat%2F2%2Fhuilong%2Fproto"
+        );
+        assert!(t.publish_url("not-a-topic").is_err());
+
+        let t = t.with_routing(PubsubRouting::Static(
+            crate::autosharding::LEGACY_PUBSUB_TOPIC.to_string(),
+        ));
+        assert_eq!(
+            t.publish_url("not-a-topic").unwrap(),
+            "http://localhost:8645/relay/v1/messages/%2Fwaku%2F2%2Fdefault-waku%2Fproto"
+        );
+    }
+
+    type RequestLog = Arc<Mutex<Vec<(String, String, String)>>>;
+
+    /// Minimal HTTP server standing in for nwaku. `handler` maps
+    /// (method, path, body) to (status, body); every request is logged.
+    async fn mock_nwaku<F>(handler: F) -> (String, RequestLog)
+    where
+        F: Fn(&str, &str, &str) -> (u16, String) + Send + Sync + 'static,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let log: RequestLog = Arc::new(Mutex::new(Vec::new()));
+        let handler = Arc::new(handler);
+        let requests = log.clone();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    return;
+                };
+                let handler = handler.clone();
+                let requests = requests.clone();
+                tokio::spawn(async move {
+                    let Some((method, path, body)) = read_request(&mut stream).await else {
+                        return;
+                    };
+                    let (status, response) = handler(&method, &path, &body);
+                    requests.lock().unwrap().push((method, path, body));
+                    let reply = format!(
+                        "HTTP/1.1 {} MOCK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        response.len(),
+                        response
+                    );
+                    let _ = stream.write_all(reply.as_bytes()).await;
+                });
+            }
+        });
+        (url, log)
+    }
+
+    async fn read_request(stream: &mut tokio::net::TcpStream) -> Option<(String, String, String)> {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 4096];
+        let header_end = loop {
+            let n = stream.read(&mut chunk).await.ok()?;
+            if n == 0 {
+                return None;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+        };
+        let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+        let mut request_line = head.lines().next()?.split_whitespace();
+        let method = request_line.next()?.to_string();
+        let path = request_line.next()?.to_string();
+        let content_length = head
+            .lines()
+            .filter_map(|l| l.split_once(':'))
+            .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, v)| v.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+        while buf.len() < header_end + content_length {
+            let n = stream.read(&mut chunk).await.ok()?;
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        let body = String::from_utf8_lossy(&buf[header_end..]).to_string();
+        Some((method, path, body))
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_registers_relay_subscriptions() {
+        let (url, log) = mock_nwaku(|_, _, _| (200, "OK".to_string())).await;
+        let t = NwakuRestTransport::new(&url);
+
+        t.subscribe("/waku-a2a/1/task/02ab/proto").await.unwrap();
+        t.subscribe("/waku-a2a/1/task/03cd/proto").await.unwrap();
+        t.subscribe("/waku-a2a/1/task/03cd/proto").await.unwrap();
+        t.subscribe("/waku-a2a/1/discovery/proto").await.unwrap();
+        // Both task inboxes share one shard: a single pubsub subscription
+        assert_eq!(
+            log.lock().unwrap().clone(),
+            vec![
+                (
+                    "POST".to_string(),
+                    "/relay/v1/subscriptions".to_string(),
+                    r#"["/waku/2/rs/1/5"]"#.to_string()
+                ),
+                (
+                    "POST".to_string(),
+                    "/relay/v1/auto/subscriptions".to_string(),
+                    r#"["/waku-a2a/1/discovery/proto"]"#.to_string()
+                ),
+            ]
+        );
+        assert_eq!(t.subscribed_topics().len(), 3);
+
+        // The shard stays subscribed until its last content topic goes
+        log.lock().unwrap().clear();
+        t.unsubscribe("/waku-a2a/1/task/02ab/proto").await.unwrap();
+        assert!(log.lock().unwrap().is_empty());
+        t.unsubscribe("/waku-a2a/1/task/03cd/proto").await.unwrap();
+        t.unsubscribe("/waku-a2a/1/discovery/proto").await.unwrap();
+        let log = log.lock().unwrap();
+        assert_eq!(log[0].0, "DELETE");
+        assert_eq!(log[0].1, "/relay/v1/subscriptions");
+        assert_eq!(log[0].2, r#"["/waku/2/rs/1/5"]"#);
+        assert_eq!(log[1].1, "/relay/v1/auto/subscriptions");
+        assert!(t.subscribed_topics().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_errors_are_reported() {
+        let (url, log) = mock_nwaku(|_, _, _| (400, "Invalid pubsub topic".to_string())).await;
+        let t = NwakuRestTransport::new(&url);
+
+        let err = t
+            .subscribe("/waku-a2a/1/discovery/proto")
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("400"), "{}", err);
+        assert!(err.contains("Invalid pubsub topic"), "{}", err);
+        assert!(t.subscribed_topics().is_empty());
+
+        // Not tracked, so the next attempt asks nwaku again
+        let _ = t.subscribe("/waku-a2a/1/discovery/proto").await;
+        assert_eq!(log.lock().unwrap().len(), 2);
+
+        let unreachable = NwakuRestTransport::new("http://127.0.0.1:9");
+        let err = unreachable
+            .subscribe("/waku-a2a/1/discovery/proto")
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("is nwaku running?"));
+    }
+
+    fn payloads(messages: Vec<WakuMessage>) -> Vec<Vec<u8>> {
+        messages.into_iter().map(|m| m.payload).collect()
+    }
+
+    fn messages_json(messages: &[(&str, &[u8])]) -> String {
+        let messages: Vec<serde_json::Value> = messages
+            .iter()
+            .map(|(topic, payload)| {
+                serde_json::json!({"payload": base64_encode(payload), "contentTopic": topic})
+            })
+            .collect();
+        serde_json::Value::Array(messages).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_poll_demultiplexes_content_topics() {
+        let inbox = "/waku-a2a/1/task/02ab/proto";
+        let acks = "/waku-a2a/1/ack/m1/proto";
+        // nwaku returns everything cached on the shard, once
+        let batch = Arc::new(Mutex::new(Some(messages_json(&[
+            (inbox, b"task-1"),
+            (acks, b"ack-1"),
+            ("/waku-a2a/1/task/99ff/proto", b"someone else"),
+            (inbox, b"task-2"),
+        ]))));
+        let (url, _) = mock_nwaku(move |method, _, _| match method {
+            "GET" => (200, batch.lock().unwrap().take().unwrap_or("[]".into())),
+            _ => (200, "OK".to_string()),
+        })
+        .await;
+        let t = NwakuRestTransport::new(&url);
+        t.subscribe(inbox).await.unwrap();
+        t.subscribe(acks).await.unwrap();
+
+        // Polling the ACK topic keeps the tasks for the inbox poller
+        assert_eq!(
+            payloads(t.poll(acks).await.unwrap()),
+            vec![b"ack-1".to_vec()]
+        );
+        assert_eq!(
+            payloads(t.poll(inbox).await.unwrap()),
+            vec![b"task-1".to_vec(), b"task-2".to_vec()]
+        );
+        assert!(t.poll(inbox).await.unwrap().is_empty());
+        assert_eq!(
+            t.demux_stats(),
+            DemuxStats {
+                overflowed: 0,
+                unrouted: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_demux_queue_is_bounded() {
+        let inbox = "/waku-a2a/1/task/02ab/proto";
+        let other = "/waku-a2a/1/task/03cd/proto";
+        let batch = messages_json(&[
+            (inbox, b"1"),
+            (inbox, b"2"),
+            (inbox, b"3"),
+            (other, b"mine"),
+        ]);
+        let (url, _) = mock_nwaku(move |method, _, _| match method {
+            "GET" => (200, batch.clone()),
+            _ => (200, "OK".to_string()),
+        })
+        .await;
+        let t = NwakuRestTransport::new(&url).with_queue_capacity(2);
+        t.subscribe(inbox).await.unwrap();
+        t.subscribe(other).await.unwrap();
+
+        // Two fetches without the inbox being polled: only the newest survive
+        assert_eq!(
+            payloads(t.poll(other).await.unwrap()),
+            vec![b"mine".to_vec()]
+        );
+        assert_eq!(
+            payloads(t.poll(other).await.unwrap()),
+            vec![b"mine".to_vec()]
+        );
+        assert_eq!(t.demux_stats().overflowed, 4);
+
+        t.unsubscribe(inbox).await.unwrap();
+        assert!(!t.demux.lock().unwrap().queues.contains_key(inbox));
+    }
+
+    #[tokio::test]
+    async fn test_resubscribes_after_restart() {
+        let subscribed = Arc::new(AtomicBool::new(false));
+        let state = subscribed.clone();
+        let topic = "/waku-a2a/1/task/02ab/proto";
+        let message = format!(
+            r#"[{{"payload":"{}","contentTopic":"{}"}}]"#,
+            base64_encode(b"card"),
+            topic
+        );
+        let (url, log) = mock_nwaku(move |method, path, _| {
+            if path == "/relay/v1/subscriptions" && method == "POST" {
+                state.store(true, Ordering::SeqCst);
+                (200, "OK".to_string())
+            } else if state.load(Ordering::SeqCst) {
+                (200, message.clone())
+            } else {
+                (404, "Not subscribed to topic".to_string())
+            }
+        })
+        .await;
+        let t = NwakuRestTransport::new(&url);
+        t.subscribe(topic).await.unwrap();
+        assert_eq!(
+            payloads(t.poll(topic).await.unwrap()),
+            vec![b"card".to_vec()]
+        );
+
+        // nwaku restarts and forgets the subscription
+        subscribed.store(false, Ordering::SeqCst);
+        log.lock().unwrap().clear();
+        assert_eq!(
+            payloads(t.poll(topic).await.unwrap()),
+            vec![b"card".to_vec()]
+        );
+        let paths: Vec<String> = log.lock().unwrap().iter().map(|r| r.1.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "/relay/v1/messages/%2Fwaku%2F2%2Frs%2F1%2F5",
+                "/relay/v1/subscriptions",
+                "/relay/v1/messages/%2Fwaku%2F2%2Frs%2F1%2F5",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_message_metadata() {
+        let topic = "/waku-a2a/1/ack/m1/proto";
+        let fetched = format!(
+            r#"[{{"payload":"{}","contentTopic":"{}","version":0,"timestamp":42,"ephemeral":true,"meta":"{}"}}]"#,
+            base64_encode(b"ack"),
+            topic,
+            base64_encode(b"m")
+        );
+        let (url, log) = mock_nwaku(move |method, _, _| match method {
+            "GET" => (200, fetched.clone()),
+            _ => (200, "OK".to_string()),
+        })
+        .await;
+        let t = NwakuRestTransport::new(&url);
+
+        let message = WakuMessage::new(topic, b"ack".to_vec())
+            .with_ephemeral(true)
+            .with_meta(b"m".to_vec())
+            .with_timestamp(42);
+        t.publish_message(&message).await.unwrap();
+        let body: serde_json::Value = serde_json::from_str(&log.lock().unwrap()[0].2).unwrap();
+        assert_eq!(body["ephemeral"], true);
+        assert_eq!(body["meta"], base64_encode(b"m"));
+        assert_eq!(body["timestamp"], 42);
+
+        // What comes back is the same message, on our shard
+        t.subscribe(topic).await.unwrap();
+        let received = t.poll(topic).await.unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].pubsub_topic.as_deref(), Some("/waku/2/rs/1/5"));
+        assert_eq!(received[0].received_hash(), message.hash("/waku/2/rs/1/5"));
+
+        let oversized =
+            WakuMessage::new(topic, b"x".to_vec()).with_meta(vec![0; MAX_META_SIZE + 1]);
+        assert!(t.publish_message(&oversized).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_store_query_pages() {
+        let topic = "/waku-a2a/1/task/02ab/proto";
+        let stored = |payload: &[u8], timestamp: i64| {
+            serde_json::json!({
+                "messageHash": "0x00",
+                "pubsubTopic": "/waku/2/rs/1/5",
+                "message": {
+                    "payload": base64_encode(payload),
+                    "contentTopic": topic,
+                    "timestamp": timestamp,
+                },
+            })
+        };
+        let first = serde_json::json!({
+            "requestId": "r",
+            "statusCode": 200,
+            "statusDesc": "OK",
+            "messages": [stored(b"one", 10), stored(b"two", 20)],
+            "paginationCursor": "0xabc",
+        })
+        .to_string();
+        let last = serde_json::json!({
+            "requestId": "r",
+            "statusCode": 200,
+            "statusDesc": "OK",
+            "messages": [stored(b"three", 30)],
+        })
+        .to_string();
+        let (url, log) = mock_nwaku(move |_, path, _| {
+            if path.contains("cursor=0xabc") {
+                (200, last.clone())
+            } else {
+                (200, first.clone())
+            }
+        })
+        .await;
+        let t = NwakuRestTransport::new(&url).with_store_peer("/ip4/10.0.0.1/tcp/60000/p2p/peer");
+
+        let query = HistoryQuery::new(topic).since(5).with_page_size(2);
+        let messages = crate::history::fetch_history(&t, &query).await.unwrap();
+        assert_eq!(
+            payloads(messages.clone()),
+            vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]
+        );
+        assert_eq!(messages[2].timestamp, Some(30));
+        assert_eq!(messages[2].pubsub_topic.as_deref(), Some("/waku/2/rs/1/5"));
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 2);
+        let path = &log[0].1;
+        assert!(path.starts_with("/store/v3/messages?"), "{}", path);
+        for param in [
+            "pubsubTopic=%2Fwaku%2F2%2Frs%2F1%2F5",
+            "contentTopics=%2Fwaku-a2a%2F1%2Ftask%2F02ab%2Fproto",
+            "startTime=5",
+            "pageSize=2",
+            "peerAddr=%2Fip4%2F10.0.0.1",
+        ] {
+            assert!(path.contains(param), "{} missing from {}", param, path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_store_query_errors() {
+        let (url, _) = mock_nwaku(|_, _, _| {
+            (
+                200,
+                r#"{"statusCode":503,"statusDesc":"no suitable remote peers"}"#.to_string(),
+            )
+        })
+        .await;
+        let t = NwakuRestTransport::new(&url);
+        let err = t
+            .query_history(&HistoryQuery::new("/waku-a2a/1/discovery/proto"))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("no suitable remote peers"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_light_client_endpoints() {
+        let inbox = "/waku-a2a/1/task/02ab/proto";
+        let fetched = messages_json(&[(inbox, b"task")]);
+        let (url, log) = mock_nwaku(move |method, _, _| match method {
+            "GET" => (200, fetched.clone()),
+            _ => (200, "OK".to_string()),
+        })
+        .await;
+        let t = NwakuRestTransport::new(&url).with_mode(ClientMode::Light);
+
+        t.subscribe(inbox).await.unwrap();
+        t.publish(inbox, b"hello").await.unwrap();
+        assert_eq!(
+            payloads(t.poll(inbox).await.unwrap()),
+            vec![b"task".to_vec()]
+        );
+        t.unsubscribe(inbox).await.unwrap();
+
+        let log = log.lock().unwrap();
+        let requests: Vec<(&str, &str)> = log
+            .iter()
+            .map(|(method, path, _)| (method.as_str(), path.as_str()))
+            .collect();
+        assert_eq!(
+            requests,
+            vec![
+                ("POST", "/filter/v2/subscriptions"),
+                ("POST", "/lightpush/v1/message"),
+                (
+                    "GET",
+                    "/filter/v2/messages/%2Fwaku-a2a%2F1%2Ftask%2F02ab%2Fproto"
+                ),
+                ("DELETE", "/filter/v2/subscriptions"),
+            ]
+        );
+        let subscribe: serde_json::Value = serde_json::from_str(&log[0].2).unwrap();
+        assert_eq!(subscribe["contentFilters"], serde_json::json!([inbox]));
+        assert_eq!(subscribe["pubsubTopic"], "/waku/2/rs/1/5");
+        assert!(subscribe["requestId"].is_string());
+        let push: serde_json::Value = serde_json::from_str(&log[1].2).unwrap();
+        assert_eq!(push["pubsubTopic"], "/waku/2/rs/1/5");
+        assert_eq!(push["message"]["contentTopic"], inbox);
+        assert_eq!(push["message"]["payload"], base64_encode(b"hello"));
+    }
+
+    #[tokio::test]
+    async fn test_filter_ping_renews_subscriptions() {
+        let alive = Arc::new(AtomicBool::new(true));
+        let state = alive.clone();
+        let (url, log) = mock_nwaku(move |method, path, _| {
+            if path.starts_with("/filter/v2/subscriptions/") {
+                if state.load(Ordering::SeqCst) {
+                    (200, r#"{"statusDesc":"OK"}"#.to_string())
+                } else {
+                    (404, r#"{"statusDesc":"NOT_FOUND"}"#.to_string())
+                }
+            } else if method == "POST" {
+                state.store(true, Ordering::SeqCst);
+                (200, "OK".to_string())
+            } else {
+                (200, "[]".to_string())
+            }
+        })
+        .await;
+        let t = NwakuRestTransport::new(&url)
+            .with_mode(ClientMode::Light)
+            .with_ping_interval(Duration::ZERO);
+        let inbox = "/waku-a2a/1/task/02ab/proto";
+        let acks = "/waku-a2a/1/ack/m1/proto";
+        t.subscribe(inbox).await.unwrap();
+        t.subscribe(acks).await.unwrap();
+
+        // A healthy subscription is only pinged
+        log.lock().unwrap().clear();
+        t.poll(inbox).await.unwrap();
+        assert_eq!(log.lock().unwrap().len(), 2);
+
+        // The service peer lost it: both topics are renewed in one request
+        alive.store(false, Ordering::SeqCst);
+        log.lock().unwrap().clear();
+        t.poll(inbox).await.unwrap();
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 3);
+        assert_eq!(log[1].0, "POST");
+        assert_eq!(log[1].1, "/filter/v2/subscriptions");
+        let renewed: serde_json::Value = serde_json::from_str(&log[1].2).unwrap();
+        assert_eq!(renewed["contentFilters"], serde_json::json!([acks, inbox]));
+    }
+
+    fn fast_retries() -> RetryPolicy {
+        RetryPolicy::default().with_backoff(Duration::from_millis(1), Duration::from_millis(5))
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_failures() {
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let count = requests.clone();
+        let (url, log) = mock_nwaku(move |_, _, _| match count.fetch_add(1, Ordering::SeqCst) {
+            0 => (503, "busy".to_string()),
+            1 => (500, "Failed to publish: NoPeersToPublish".to_string()),
+            _ => (200, "OK".to_string()),
+        })
+        .await;
+        let t = NwakuRestTransport::new(&url).with_retry_policy(fast_retries());
+        t.publish("/waku-a2a/1/discovery/proto", b"card")
+            .await
+            .unwrap();
+        let log = log.lock().unwrap().clone();
+        assert_eq!(log.len(), 3);
+        // Retries send the same message, timestamp included
+        assert!(log.iter().all(|r| r.2 == log[0].2));
+        assert_eq!(t.connection_state(), ConnectionState::Connected);
+
+        // Client errors aren't retried
+        let (url, log) = mock_nwaku(|_, _, _| (400, "bad".to_string())).await;
+        let t = NwakuRestTransport::new(&url).with_retry_policy(fast_retries());
+        let err = t
+            .publish("/waku-a2a/1/discovery/proto", b"x")
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<Rejected>().is_some(), "{}", err);
+        assert_eq!(log.lock().unwrap().len(), 1);
+
+        // Neither are server errors once retries are used up
+        let (url, log) = mock_nwaku(|_, _, _| (503, "busy".to_string())).await;
+        let t = NwakuRestTransport::new(&url).with_retry_policy(fast_retries().with_max_retries(2));
+        let err = t
+            .publish("/waku-a2a/1/discovery/proto", b"x")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("503"), "{}", err);
+        assert_eq!(log.lock().unwrap().len(), 3);
+        assert_eq!(t.connection_state(), ConnectionState::Degraded);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_connection_not_retried() {
+        // Reads the request, then hangs up without answering
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let count = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                count.fetch_add(1, Ordering::SeqCst);
+                let mut buf = [0u8; 4096];
+                let _ = tokio::io::AsyncReadExt::read(&mut stream, &mut buf).await;
+            }
+        });
+        let t = NwakuRestTransport::new(&url).with_retry_policy(fast_retries());
+        let err = t
+            .publish("/waku-a2a/1/discovery/proto", b"x")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("publish"), "{:#}", err);
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiting_not_retried() {
+        for (status, body) in [
+            (429, "Request rejected due to too many requests"),
+            (
+                500,
+                "Failed to publish: RLN message limit exceeded for epoch",
+            ),
+        ] {
+            let (url, log) = mock_nwaku(move |_, _, _| (status, body.to_string())).await;
+            let t = NwakuRestTransport::new(&url).with_retry_policy(fast_retries());
+            let err = t
+                .publish("/waku-a2a/1/discovery/proto", b"x")
+                .await
+                .unwrap_err();
+            let limited = err.downcast_ref::<RateLimited>().expect("rate limited");
+            assert_eq!(limited.retry_after, RATE_LIMIT_BACKOFF);
+            assert!(limited.reason.contains(body), "{}", limited.reason);
+            assert_eq!(log.lock().unwrap().len(), 1);
+            // nwaku itself is fine
+            assert_eq!(t.connection_state(), ConnectionState::Connected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        // Accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+        let t = NwakuRestTransport::new(&url)
+            .with_request_timeout(Duration::from_millis(100))
+            .with_retry_policy(fast_retries().with_max_retries(1));
+        let started = Instant::now();
+        let err = t.poll("/waku-a2a/1/discovery/proto").await.unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("is nwaku running?"),
+            "{:#}",
+            err
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(t.connection_state(), ConnectionState::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn test_health_report() {
+        let (url, _) = mock_nwaku(|_, path, _| match path {
+            "/health" => (
+                200,
+                r#"{"nodeHealth":"Ready","protocolsHealth":[{"Relay":"Ready"},{"Rln Relay":"Not Mounted"}]}"#
+                    .to_string(),
+            ),
+            "/debug/v1/info" => (
+                200,
+                r#"{"listenAddresses":["/ip4/127.0.0.1/tcp/60000"],"enrUri":"enr:-abc"}"#
+                    .to_string(),
+            ),
+            _ => (404, String::new()),
+        })
+        .await;
+        let t = NwakuRestTransport::new(&url);
+        assert_eq!(t.connection_state(), ConnectionState::Unknown);
+        let health = t.health().await.unwrap();
+        assert!(health.is_ready());
+        assert_eq!(
+            health.protocols,
+            vec![
+                ("Relay".to_string(), "Ready".to_string()),
+                ("Rln Relay".to_string(), "Not Mounted".to_string()),
+            ]
+        );
+        assert_eq!(health.listen_addresses, vec!["/ip4/127.0.0.1/tcp/60000"]);
+        assert_eq!(health.enr_uri.as_deref(), Some("enr:-abc"));
+        assert_eq!(t.connection_state(), ConnectionState::Connected);
+
+        let (url, _) = mock_nwaku(|_, _, _| {
+            (
+                503,
+                r#"{"nodeHealth":"Initializing","protocolsHealth":[]}"#.to_string(),
+            )
+        })
+        .await;
+        let t = NwakuRestTransport::new(&url);
+        let health = t.health().await.unwrap();
+        assert!(!health.is_ready());
+        assert!(health.listen_addresses.is_empty());
+        assert_eq!(t.connection_state(), ConnectionState::Degraded);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_waits_for_health() {
+        let up = Arc::new(AtomicBool::new(false));
+        let state = up.clone();
+        let (url, log) = mock_nwaku(move |_, path, _| {
+            if !state.load(Ordering::SeqCst) {
+                (503, "down".to_string())
+            } else if path == "/health" {
+                (200, r#"{"nodeHealth":"Ready"}"#.to_string())
+            } else {
+                (200, "OK".to_string())
+            }
+        })
+        .await;
+        let t = NwakuRestTransport::new(&url)
+            .with_retry_policy(RetryPolicy::none())
+            .with_circuit_breaker(CircuitBreaker::new(2, Duration::from_millis(50)));
+        let mut states = t.watch_connection();
+        let topic = "/waku-a2a/1/discovery/proto";
+        assert!(t.publish(topic, b"1").await.is_err());
+        assert!(t.publish(topic, b"2").await.is_err());
+
+        // Open: rejected without asking nwaku
+        log.lock().unwrap().clear();
+        let err = t.publish(topic, b"3").await.unwrap_err();
+        assert!(err.to_string().contains("not retrying yet"), "{}", err);
+        assert!(log.lock().unwrap().is_empty());
+        assert_eq!(*states.borrow_and_update(), ConnectionState::Disconnected);
+
+        // Half-open: traffic resumes after a successful health check
+        up.store(true, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        t.publish(topic, b"4").await.unwrap();
+        let paths: Vec<String> = log.lock().unwrap().iter().map(|r| r.1.clone()).collect();
+        assert_eq!(paths[0], "/health");
+        assert_eq!(paths.last().unwrap(), "/relay/v1/auto/messages");
+        assert!(states.has_changed().unwrap());
+        assert_eq!(*states.borrow_and_update(), ConnectionState::Connected);
     }
 }
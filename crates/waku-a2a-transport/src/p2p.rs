@@ -0,0 +1,722 @@
+//! Embedded Waku Relay node on rust-libp2p — no nwaku process needed.
+//!
+//! Speaks the Waku Relay wire protocol: gossipsub negotiated as
+//! `/vac/waku/relay/2.0.0`, pubsub topics chosen by `PubsubRouting` and
+//! messages encoded as the `WakuMessage` protobuf. Messages are unsigned and
+//! identified by their deterministic hash, as in nwaku. Connections use TCP
+//! with Noise and Yamux.
+//!
+//! Peers are found by dialing the configured multiaddrs and, on local
+//! networks, by mDNS. Several agents on one host therefore form a mesh on
+//! their own.
+//!
+//! Not implemented: the Waku metadata and peer exchange protocols, which
+//! nwaku nodes of The Waku Network expect from peers in their cluster, and
+//! RLN. Meshes of these embedded nodes don't need them.
+//!
+//! Enabled by the `p2p` feature.
+
+use crate::autosharding::PubsubRouting;
+use crate::message::now_nanos;
+use crate::nwaku_rest::DEFAULT_QUEUE_CAPACITY;
+use crate::{WakuMessage, WakuTransport, STREAM_POLL_INTERVAL};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use libp2p::gossipsub::{self, IdentTopic, MessageAuthenticity, ValidationMode};
+use libp2p::swarm::behaviour::toggle::Toggle;
+use libp2p::swarm::{NetworkBehaviour, SwarmEvent};
+use libp2p::{identity, mdns, noise, tcp, yamux, Multiaddr, PeerId, Swarm};
+use prost::Message as _;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Notify};
+
+/// Gossipsub protocol ID of Waku Relay.
+pub const RELAY_PROTOCOL_ID: &str = "/vac/waku/relay/2.0.0";
+
+/// Largest message Waku Relay accepts.
+pub const MAX_MESSAGE_SIZE: usize = 150 * 1024;
+
+/// How long `start` waits for the listeners to come up.
+const LISTEN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the first publish on a pubsub topic waits for connected peers to
+/// announce their relay subscriptions, which they do right after connecting.
+const PUBLISH_PEER_WAIT: Duration = Duration::from_secs(2);
+
+/// `WakuMessage` protobuf (RFC 14). The RLN proof field is not used.
+#[derive(Clone, PartialEq, prost::Message)]
+struct WakuMessageProto {
+    #[prost(bytes = "vec", tag = "1")]
+    payload: Vec<u8>,
+    #[prost(string, tag = "2")]
+    content_topic: String,
+    #[prost(uint32, optional, tag = "3")]
+    version: Option<u32>,
+    #[prost(sint64, optional, tag = "10")]
+    timestamp: Option<i64>,
+    #[prost(bytes = "vec", optional, tag = "11")]
+    meta: Option<Vec<u8>>,
+    #[prost(bool, optional, tag = "31")]
+    ephemeral: Option<bool>,
+}
+
+impl WakuMessageProto {
+    fn from_message(message: &WakuMessage) -> Self {
+        Self {
+            payload: message.payload.clone(),
+            content_topic: message.content_topic.clone(),
+            version: Some(message.version),
+            timestamp: message.timestamp,
+            meta: (!message.meta.is_empty()).then(|| message.meta.clone()),
+            ephemeral: message.ephemeral.then_some(true),
+        }
+    }
+
+    fn into_message(self, pubsub_topic: &str) -> WakuMessage {
+        WakuMessage {
+            payload: self.payload,
+            content_topic: self.content_topic,
+            version: self.version.unwrap_or(0),
+            timestamp: self.timestamp,
+            ephemeral: self.ephemeral.unwrap_or(false),
+            meta: self.meta.unwrap_or_default(),
+            pubsub_topic: Some(pubsub_topic.to_string()),
+        }
+    }
+}
+
+/// Decode a relayed message; None if it isn't a `WakuMessage`.
+fn decode_message(data: &[u8], pubsub_topic: &str) -> Option<WakuMessage> {
+    WakuMessageProto::decode(data)
+        .ok()
+        .map(|proto| proto.into_message(pubsub_topic))
+}
+
+/// Settings of the embedded node.
+#[derive(Debug, Clone)]
+pub struct P2pConfig {
+    /// Addresses to listen on.
+    pub listen: Vec<Multiaddr>,
+    /// Peers to dial at startup.
+    pub peers: Vec<Multiaddr>,
+    /// Find peers on the local network with mDNS.
+    pub mdns: bool,
+    pub routing: PubsubRouting,
+    /// Messages buffered per content topic.
+    pub queue_capacity: usize,
+}
+
+impl Default for P2pConfig {
+    /// Listen on a random TCP port of all interfaces, with mDNS.
+    fn default() -> Self {
+        Self {
+            listen: vec!["/ip4/0.0.0.0/tcp/0".parse().expect("valid multiaddr")],
+            peers: Vec::new(),
+            mdns: true,
+            routing: PubsubRouting::default(),
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+        }
+    }
+}
+
+impl P2pConfig {
+    pub fn with_listen(mut self, addrs: Vec<Multiaddr>) -> Self {
+        self.listen = addrs;
+        self
+    }
+
+    pub fn with_peers(mut self, peers: Vec<Multiaddr>) -> Self {
+        self.peers = peers;
+        self
+    }
+
+    pub fn with_mdns(mut self, mdns: bool) -> Self {
+        self.mdns = mdns;
+        self
+    }
+
+    pub fn with_routing(mut self, routing: PubsubRouting) -> Self {
+        self.routing = routing;
+        self
+    }
+}
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    gossipsub: gossipsub::Behaviour,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+}
+
+enum Command {
+    Publish {
+        pubsub_topic: String,
+        data: Vec<u8>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Subscribe {
+        pubsub_topic: String,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Unsubscribe {
+        pubsub_topic: String,
+    },
+    Dial(Multiaddr),
+}
+
+/// State shared with the swarm task.
+#[derive(Default)]
+struct Shared {
+    /// Received messages of subscribed content topics, oldest dropped first
+    /// beyond `queue_capacity`.
+    queues: Mutex<HashMap<String, VecDeque<WakuMessage>>>,
+    queue_capacity: usize,
+    /// Signalled whenever a message is queued.
+    arrived: Notify,
+    /// Currently connected peers.
+    peers: Mutex<HashSet<PeerId>>,
+    /// Connected peers relaying each pubsub topic.
+    topic_peers: Mutex<HashMap<String, HashSet<PeerId>>>,
+    /// Signalled whenever a peer connects or subscribes to a pubsub topic.
+    peers_changed: Notify,
+    listen_addrs: Mutex<Vec<Multiaddr>>,
+}
+
+/// `WakuTransport` backed by an embedded Waku Relay node.
+pub struct P2pTransport {
+    peer_id: PeerId,
+    routing: PubsubRouting,
+    commands: mpsc::UnboundedSender<Command>,
+    shared: Arc<Shared>,
+    /// Subscribed content topics and their pubsub topics.
+    subscriptions: Mutex<HashMap<String, String>>,
+    /// Pubsub topics a publish already waited for relay peers on.
+    peer_waits: Mutex<HashSet<String>>,
+}
+
+impl P2pTransport {
+    /// Start the node with a fresh identity. Must be called within a Tokio
+    /// runtime; the node runs until the transport is dropped.
+    pub async fn start(config: P2pConfig) -> Result<Self> {
+        Self::start_with_key(config, identity::Keypair::generate_ed25519()).await
+    }
+
+    /// Start the node with the libp2p identity `keypair`.
+    pub async fn start_with_key(config: P2pConfig, keypair: identity::Keypair) -> Result<Self> {
+        let mut swarm = build_swarm(keypair, config.mdns)?;
+        let peer_id = *swarm.local_peer_id();
+        for addr in &config.listen {
+            swarm
+                .listen_on(addr.clone())
+                .with_context(|| format!("Failed to listen on {}", addr))?;
+        }
+
+        // Wait for the listeners so `listen_addrs` is useful right away
+        let shared = Arc::new(Shared {
+            queue_capacity: config.queue_capacity.max(1),
+            ..Shared::default()
+        });
+        let mut pending = config.listen.len();
+        let deadline = tokio::time::Instant::now() + LISTEN_TIMEOUT;
+        while pending > 0 {
+            match tokio::time::timeout_at(deadline, swarm.select_next_some()).await {
+                Ok(SwarmEvent::NewListenAddr { address, .. }) => {
+                    shared.listen_addrs.lock().unwrap().push(address);
+                    pending -= 1;
+                }
+                Ok(SwarmEvent::ListenerError { error, .. }) => {
+                    bail!("Listener failed: {}", error)
+                }
+                Ok(_) => {}
+                Err(_) => bail!("Timed out waiting for listeners to start"),
+            }
+        }
+
+        for addr in &config.peers {
+            if let Err(e) = swarm.dial(addr.clone()) {
+                eprintln!("[p2p] Failed to dial {}: {}", addr, e);
+            }
+        }
+
+        let (commands, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run_swarm(swarm, receiver, shared.clone()));
+        Ok(Self {
+            peer_id,
+            routing: config.routing,
+            commands,
+            shared,
+            subscriptions: Mutex::new(HashMap::new()),
+            peer_waits: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Addresses the node listens on, for other nodes to dial.
+    pub fn listen_addrs(&self) -> Vec<Multiaddr> {
+        self.shared.listen_addrs.lock().unwrap().clone()
+    }
+
+    /// Number of connected peers.
+    pub fn peer_count(&self) -> usize {
+        self.shared.peers.lock().unwrap().len()
+    }
+
+    /// Dial another node.
+    pub fn dial(&self, addr: Multiaddr) -> Result<()> {
+        self.commands
+            .send(Command::Dial(addr))
+            .map_err(|_| anyhow::anyhow!("p2p node stopped"))
+    }
+
+    /// Wait until at least one peer is connected. Returns false on timeout.
+    pub async fn wait_for_peers(&self, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let changed = self.shared.peers_changed.notified();
+            if self.peer_count() > 0 {
+                return true;
+            }
+            if tokio::time::timeout_at(deadline, changed).await.is_err() {
+                return false;
+            }
+        }
+    }
+
+    /// Wait until a connected peer relays `pubsub_topic`. Returns false on
+    /// timeout.
+    async fn wait_for_relay_peer(&self, pubsub_topic: &str, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let changed = self.shared.peers_changed.notified();
+            let relayed = self
+                .shared
+                .topic_peers
+                .lock()
+                .unwrap()
+                .get(pubsub_topic)
+                .is_some_and(|peers| !peers.is_empty());
+            if relayed {
+                return true;
+            }
+            if tokio::time::timeout_at(deadline, changed).await.is_err() {
+                return false;
+            }
+        }
+    }
+
+    async fn request(&self, command: Command, reply: oneshot::Receiver<Result<()>>) -> Result<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| anyhow::anyhow!("p2p node stopped"))?;
+        reply.await.context("p2p node stopped")?
+    }
+}
+
+fn build_swarm(keypair: identity::Keypair, enable_mdns: bool) -> Result<Swarm<Behaviour>> {
+    let swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default().nodelay(true),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_behaviour(|key| {
+            let config = gossipsub::ConfigBuilder::default()
+                .protocol_id(RELAY_PROTOCOL_ID, gossipsub::Version::V1_1)
+                .validation_mode(ValidationMode::Anonymous)
+                .max_transmit_size(MAX_MESSAGE_SIZE + 1024)
+                .message_id_fn(|message: &gossipsub::Message| {
+                    let hash = match decode_message(&message.data, message.topic.as_str()) {
+                        Some(waku) => waku.hash(message.topic.as_str()),
+                        None => Sha256::digest(&message.data).into(),
+                    };
+                    gossipsub::MessageId::from(hash.to_vec())
+                })
+                .build()?;
+            let gossipsub = gossipsub::Behaviour::new(MessageAuthenticity::Anonymous, config)?;
+            let mdns = if enable_mdns {
+                Some(mdns::tokio::Behaviour::new(
+                    mdns::Config::default(),
+                    key.public().to_peer_id(),
+                )?)
+            } else {
+                None
+            };
+            Ok(Behaviour {
+                gossipsub,
+                mdns: mdns.into(),
+            })
+        })
+        .map_err(|e| anyhow::anyhow!("Failed to set up relay: {}", e))?
+        .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+        .build();
+    Ok(swarm)
+}
+
+/// Drive the swarm until the transport is dropped.
+async fn run_swarm(
+    mut swarm: Swarm<Behaviour>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    shared: Arc<Shared>,
+) {
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(command) => handle_command(&mut swarm, command),
+                None => return,
+            },
+            event = swarm.select_next_some() => handle_event(&mut swarm, event, &shared),
+        }
+    }
+}
+
+fn handle_command(swarm: &mut Swarm<Behaviour>, command: Command) {
+    let gossipsub = &mut swarm.behaviour_mut().gossipsub;
+    match command {
+        Command::Publish {
+            pubsub_topic,
+            data,
+            reply,
+        } => {
+            let result = match gossipsub.publish(IdentTopic::new(&pubsub_topic), data) {
+                // Same hash: this exact message is already out
+                Ok(_) | Err(gossipsub::PublishError::Duplicate) => Ok(()),
+                Err(gossipsub::PublishError::InsufficientPeers) => {
+                    Err(anyhow::anyhow!("No relay peers on {} yet", pubsub_topic))
+                }
+                Err(e) => Err(anyhow::anyhow!("Relay publish failed: {}", e)),
+            };
+            let _ = reply.send(result);
+        }
+        Command::Subscribe {
+            pubsub_topic,
+            reply,
+        } => {
+            let result = gossipsub
+                .subscribe(&IdentTopic::new(&pubsub_topic))
+                .map(|_| ())
+                .map_err(|e| anyhow::anyhow!("Relay subscribe failed: {}", e));
+            let _ = reply.send(result);
+        }
+        Command::Unsubscribe { pubsub_topic } => {
+            let _ = gossipsub.unsubscribe(&IdentTopic::new(&pubsub_topic));
+        }
+        Command::Dial(addr) => {
+            if let Err(e) = swarm.dial(addr.clone()) {
+                eprintln!("[p2p] Failed to dial {}: {}", addr, e);
+            }
+        }
+    }
+}
+
+fn handle_event(swarm: &mut Swarm<Behaviour>, event: SwarmEvent<BehaviourEvent>, shared: &Shared) {
+    match event {
+        SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message {
+            message,
+            ..
+        })) => {
+            let Some(waku) = decode_message(&message.data, message.topic.as_str()) else {
+                return;
+            };
+            let mut queues = shared.queues.lock().unwrap();
+            if let Some(queue) = queues.get_mut(&waku.content_topic) {
+                if queue.len() >= shared.queue_capacity {
+                    queue.pop_front();
+                }
+                queue.push_back(waku);
+                drop(queues);
+                shared.arrived.notify_waiters();
+            }
+        }
+        SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Subscribed {
+            peer_id,
+            topic,
+        })) => {
+            shared
+                .topic_peers
+                .lock()
+                .unwrap()
+                .entry(topic.into_string())
+                .or_default()
+                .insert(peer_id);
+            shared.peers_changed.notify_waiters();
+        }
+        SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed {
+            peer_id,
+            topic,
+        })) => {
+            if let Some(peers) = shared.topic_peers.lock().unwrap().get_mut(topic.as_str()) {
+                peers.remove(&peer_id);
+            }
+        }
+        SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(found))) => {
+            for (peer, addr) in found {
+                if !swarm.is_connected(&peer) {
+                    let _ = swarm.dial(addr);
+                }
+            }
+        }
+        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+            shared.peers.lock().unwrap().insert(peer_id);
+            shared.peers_changed.notify_waiters();
+        }
+        SwarmEvent::ConnectionClosed {
+            peer_id,
+            num_established: 0,
+            ..
+        } => {
+            shared.peers.lock().unwrap().remove(&peer_id);
+            for peers in shared.topic_peers.lock().unwrap().values_mut() {
+                peers.remove(&peer_id);
+            }
+        }
+        SwarmEvent::NewListenAddr { address, .. } => {
+            shared.listen_addrs.lock().unwrap().push(address);
+        }
+        SwarmEvent::OutgoingConnectionError {
+            peer_id: Some(peer),
+            error,
+            ..
+        } => {
+            eprintln!("[p2p] Connection to {} failed: {}", peer, error);
+        }
+        _ => {}
+    }
+}
+
+#[async_trait]
+impl WakuTransport for P2pTransport {
+    async fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
+        self.publish_message(&WakuMessage::new(topic, payload))
+            .await
+    }
+
+    /// Messages without a timestamp are stamped with the current time. Fails
+    /// if no peer relays the message's pubsub topic.
+    async fn publish_message(&self, message: &WakuMessage) -> Result<()> {
+        let pubsub_topic = self.routing.pubsub_topic(&message.content_topic)?;
+        let mut proto = WakuMessageProto::from_message(message);
+        proto.timestamp = Some(message.timestamp.unwrap_or_else(now_nanos));
+        let data = proto.encode_to_vec();
+        if data.len() > MAX_MESSAGE_SIZE {
+            bail!(
+                "Message is {} bytes, relay allows at most {}",
+                data.len(),
+                MAX_MESSAGE_SIZE
+            );
+        }
+        if self.peer_count() > 0 && self.peer_waits.lock().unwrap().insert(pubsub_topic.clone()) {
+            self.wait_for_relay_peer(&pubsub_topic, PUBLISH_PEER_WAIT)
+                .await;
+        }
+        let (reply, response) = oneshot::channel();
+        self.request(
+            Command::Publish {
+                pubsub_topic,
+                data,
+                reply,
+            },
+            response,
+        )
+        .await
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<()> {
+        let pubsub_topic = self.routing.pubsub_topic(topic)?;
+        let relayed = {
+            let subscriptions = self.subscriptions.lock().unwrap();
+            if subscriptions.contains_key(topic) {
+                return Ok(());
+            }
+            subscriptions.values().any(|p| *p == pubsub_topic)
+        };
+        if !relayed {
+            let (reply, response) = oneshot::channel();
+            self.request(
+                Command::Subscribe {
+                    pubsub_topic: pubsub_topic.clone(),
+                    reply,
+                },
+                response,
+            )
+            .await?;
+        }
+        self.shared
+            .queues
+            .lock()
+            .unwrap()
+            .entry(topic.to_string())
+            .or_default();
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .insert(topic.to_string(), pubsub_topic);
+        Ok(())
+    }
+
+    /// The pubsub topic is left once no subscribed content topic uses it.
+    async fn unsubscribe(&self, topic: &str) -> Result<()> {
+        self.shared.queues.lock().unwrap().remove(topic);
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let Some(pubsub_topic) = subscriptions.remove(topic) else {
+            return Ok(());
+        };
+        if !subscriptions.values().any(|p| *p == pubsub_topic) {
+            let _ = self.commands.send(Command::Unsubscribe { pubsub_topic });
+        }
+        Ok(())
+    }
+
+    async fn poll(&self, topic: &str) -> Result<Vec<WakuMessage>> {
+        let mut queues = self.shared.queues.lock().unwrap();
+        let Some(queue) = queues.get_mut(topic) else {
+            return Ok(Vec::new());
+        };
+        Ok(queue.drain(..).collect())
+    }
+
+    /// Push delivery: messages are handed over as the swarm receives them.
+    fn subscribe_stream<'a>(&'a self, topic: &str) -> BoxStream<'a, WakuMessage> {
+        let topic = topic.to_string();
+        Box::pin(futures::stream::unfold(
+            (VecDeque::new(), false),
+            move |(mut ready, mut subscribed)| {
+                let topic = topic.clone();
+                async move {
+                    loop {
+                        if let Some(message) = ready.pop_front() {
+                            return Some((message, (ready, subscribed)));
+                        }
+                        if !subscribed {
+                            match self.subscribe(&topic).await {
+                                Ok(()) => subscribed = true,
+                                Err(e) => {
+                                    eprintln!("[p2p] Subscribe to {} failed: {:#}", topic, e);
+                                    tokio::time::sleep(STREAM_POLL_INTERVAL).await;
+                                    continue;
+                                }
+                            }
+                        }
+                        // Register before polling so an arrival in between isn't missed
+                        let arrived = self.shared.arrived.notified();
+                        ready.extend(self.poll(&topic).await.unwrap_or_default());
+                        if ready.is_empty() {
+                            arrived.await;
+                        }
+                    }
+                }
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_config() -> P2pConfig {
+        P2pConfig::default()
+            .with_listen(vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()])
+            .with_mdns(false)
+    }
+
+    #[test]
+    fn test_protobuf_roundtrip() {
+        let message = WakuMessage::new("/waku-a2a/1/task/02ab/proto", b"hello".to_vec())
+            .with_timestamp(-5)
+            .with_meta(b"m".to_vec())
+            .with_ephemeral(true);
+        let bytes = WakuMessageProto::from_message(&message).encode_to_vec();
+        let decoded = decode_message(&bytes, "/waku/2/rs/1/5").unwrap();
+        assert_eq!(decoded.payload, message.payload);
+        assert_eq!(decoded.content_topic, message.content_topic);
+        assert_eq!(decoded.timestamp, Some(-5));
+        assert_eq!(decoded.meta, b"m");
+        assert!(decoded.ephemeral);
+        assert_eq!(decoded.pubsub_topic.as_deref(), Some("/waku/2/rs/1/5"));
+
+        // Field numbers of RFC 14: content topic is field 2, length-delimited
+        let minimal = WakuMessageProto::from_message(&WakuMessage::new("/a/1/b/c", Vec::new()));
+        let bytes = minimal.encode_to_vec();
+        assert_eq!(&bytes[..2], &[0x12, 8]);
+    }
+
+    #[tokio::test]
+    async fn test_two_nodes_form_a_mesh() {
+        let a = P2pTransport::start(local_config()).await.unwrap();
+        let topic = "/waku-a2a/1/task/02ab/proto";
+        assert!(a.publish(topic, b"alone").await.is_err());
+
+        let b = P2pTransport::start(local_config().with_peers(a.listen_addrs()))
+            .await
+            .unwrap();
+        a.subscribe(topic).await.unwrap();
+        b.subscribe(topic).await.unwrap();
+        let mut incoming = b.subscribe_stream(topic);
+        assert!(a.wait_for_peers(Duration::from_secs(5)).await);
+
+        // Waits for b's subscription to reach a
+        a.publish(topic, b"hello").await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(10), incoming.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.payload, b"hello");
+        assert_eq!(received.pubsub_topic.as_deref(), Some("/waku/2/rs/1/5"));
+        assert!(received.timestamp.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_queue_capped_on_arrival() {
+        let a = P2pTransport::start(local_config()).await.unwrap();
+        let b = P2pTransport::start(P2pConfig {
+            queue_capacity: 2,
+            ..local_config().with_peers(a.listen_addrs())
+        })
+        .await
+        .unwrap();
+        let topic = "/waku-a2a/1/task/02ab/proto";
+        a.subscribe(topic).await.unwrap();
+        b.subscribe(topic).await.unwrap();
+        assert!(a.wait_for_peers(Duration::from_secs(5)).await);
+
+        for i in 0..3u8 {
+            a.publish(topic, &[i]).await.unwrap();
+        }
+        let last_arrived = async {
+            loop {
+                let arrived = b.shared.arrived.notified();
+                let queued = {
+                    let queues = b.shared.queues.lock().unwrap();
+                    queues[topic]
+                        .back()
+                        .is_some_and(|m| m.payload == [2])
+                        .then(|| queues[topic].len())
+                };
+                if let Some(len) = queued {
+                    assert_eq!(len, 2);
+                    return;
+                }
+                arrived.await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), last_arrived)
+            .await
+            .unwrap();
+        let payloads: Vec<Vec<u8>> = b
+            .poll(topic)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.payload)
+            .collect();
+        assert_eq!(payloads, vec![vec![1], vec![2]]);
+    }
+}
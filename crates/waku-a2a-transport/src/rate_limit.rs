@@ -0,0 +1,481 @@
+//! Outbound publish rate limiting.
+//!
+//! Waku networks with RLN allow each member a fixed number of messages per
+//! epoch; messages over the limit are dropped and their sender may be
+//! penalized. `RateLimitedTransport` keeps publishes within such a budget:
+//! `RateLimiter` hands out `messages_per_epoch` tokens per epoch, with epochs
+//! aligned to Unix time like RLN's, and makes publishers wait for the next
+//! epoch when the budget is used up.
+//!
+//! Publishes have a `Priority`. Waiting control messages (ACKs, agent cards)
+//! go before normal ones, and those before bulk ones (retransmissions), and
+//! `control_reserve` tokens of every epoch are kept for control messages.
+//!
+//! A publish that would have to wait longer than `max_wait` fails right away
+//! with `RateLimited`, so callers can back off or queue the message. The
+//! error survives `anyhow` context: check for it with
+//! `err.downcast_ref::<RateLimited>()`. Transports report rate limiting by
+//! the network (e.g. nwaku answering 429) the same way, and
+//! `RateLimitedTransport` then pauses until the network's `retry_after`.
+
+use crate::{HistoryPage, HistoryQuery, WakuMessage, WakuTransport};
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// How urgent a publish is, most urgent first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// ACKs and other protocol messages that keep peers working.
+    Control,
+    /// Tasks, responses and status updates.
+    #[default]
+    Normal,
+    /// Retransmissions and anything else that can wait.
+    Bulk,
+}
+
+impl Priority {
+    /// Default class of a message published without one: ephemeral
+    /// messages, like SDS ACKs, are control traffic.
+    pub fn of(message: &WakuMessage) -> Self {
+        if message.ephemeral {
+            Priority::Control
+        } else {
+            Priority::Normal
+        }
+    }
+}
+
+/// A publish was refused because of a rate limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimited {
+    /// When trying again may succeed.
+    pub retry_after: Duration,
+    pub reason: String,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Rate limited: {} (retry in {:.1}s)",
+            self.reason,
+            self.retry_after.as_secs_f64()
+        )
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// A publish budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub messages_per_epoch: u32,
+    pub epoch: Duration,
+    /// Tokens per epoch only control messages may use.
+    pub control_reserve: u32,
+    /// Longest a publish waits for a token before failing with `RateLimited`.
+    pub max_wait: Duration,
+}
+
+impl RateLimit {
+    /// `messages_per_epoch` messages per `epoch`, a tenth of them (at least
+    /// one, if there are two or more) reserved for control messages, waiting
+    /// at most one epoch.
+    pub fn new(messages_per_epoch: u32, epoch: Duration) -> Self {
+        let control_reserve = if messages_per_epoch >= 2 {
+            (messages_per_epoch / 10).max(1)
+        } else {
+            0
+        };
+        Self {
+            messages_per_epoch,
+            epoch: epoch.max(Duration::from_millis(1)),
+            control_reserve,
+            max_wait: epoch,
+        }
+    }
+
+    pub fn with_control_reserve(mut self, reserve: u32) -> Self {
+        self.control_reserve = reserve.min(self.messages_per_epoch);
+        self
+    }
+
+    pub fn with_max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = max_wait;
+        self
+    }
+
+    /// Tokens per epoch a publish of `priority` may use.
+    fn allowance(&self, priority: Priority) -> u32 {
+        match priority {
+            Priority::Control => self.messages_per_epoch,
+            Priority::Normal | Priority::Bulk => {
+                self.messages_per_epoch.saturating_sub(self.control_reserve)
+            }
+        }
+    }
+}
+
+/// Hands out publish tokens within a `RateLimit`.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    /// Start of epoch 0 on the Tokio clock.
+    origin: Instant,
+    state: Mutex<LimiterState>,
+    /// Wakes waiters when a higher-priority waiter is done.
+    released: Notify,
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    epoch: u64,
+    used: u32,
+    /// Waiting publishers per priority.
+    waiting: [u32; 3],
+    /// Set when the network reported rate limiting.
+    paused_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        // Align epochs to Unix time, as RLN does
+        let since_unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let into_epoch = since_unix.as_nanos() % limit.epoch.as_nanos();
+        let now = Instant::now();
+        let origin = now
+            .checked_sub(Duration::from_nanos(into_epoch as u64))
+            .unwrap_or(now);
+        Self {
+            limit,
+            origin,
+            state: Mutex::new(LimiterState {
+                epoch: 0,
+                used: 0,
+                waiting: [0; 3],
+                paused_until: None,
+            }),
+            released: Notify::new(),
+        }
+    }
+
+    pub fn limit(&self) -> &RateLimit {
+        &self.limit
+    }
+
+    /// Tokens left in the current epoch.
+    pub fn remaining(&self) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        self.roll(&mut state, Instant::now());
+        self.limit.messages_per_epoch.saturating_sub(state.used)
+    }
+
+    /// Take a token if one is available right now.
+    pub fn try_acquire(&self, priority: Priority) -> Result<(), RateLimited> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        self.take(&mut state, priority, now)
+            .map_err(|at| self.refusal(at - now))
+    }
+
+    /// Take a token, waiting for one up to `max_wait`.
+    pub async fn acquire(&self, priority: Priority) -> Result<(), RateLimited> {
+        let deadline = Instant::now() + self.limit.max_wait;
+        let mut waiting = Waiting {
+            limiter: self,
+            priority,
+            registered: false,
+        };
+        loop {
+            let released = self.released.notified();
+            let now = Instant::now();
+            let retry_at = {
+                let mut state = self.state.lock().unwrap();
+                match self.take(&mut state, priority, now) {
+                    Ok(()) => return Ok(()),
+                    Err(at) if at > deadline => return Err(self.refusal(at - now)),
+                    Err(at) => {
+                        if !waiting.registered {
+                            state.waiting[priority as usize] += 1;
+                            waiting.registered = true;
+                        }
+                        at
+                    }
+                }
+            };
+            tokio::select! {
+                () = tokio::time::sleep_until(retry_at) => {}
+                () = released => {}
+            }
+        }
+    }
+
+    /// Stop handing out tokens for `duration`, e.g. because the network
+    /// reported rate limiting.
+    pub fn pause(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut state = self.state.lock().unwrap();
+        state.paused_until = Some(state.paused_until.map_or(until, |p| p.max(until)));
+    }
+
+    /// Take a token, or return when to try again.
+    fn take(
+        &self,
+        state: &mut LimiterState,
+        priority: Priority,
+        now: Instant,
+    ) -> Result<(), Instant> {
+        self.roll(state, now);
+        if let Some(until) = state.paused_until {
+            if now < until {
+                return Err(until);
+            }
+            state.paused_until = None;
+        }
+        let more_urgent_waiting = state.waiting[..priority as usize].iter().any(|&n| n > 0);
+        if more_urgent_waiting || state.used >= self.limit.allowance(priority) {
+            return Err(self.epoch_start(state.epoch + 1));
+        }
+        state.used += 1;
+        Ok(())
+    }
+
+    /// Start a new budget if a new epoch began.
+    fn roll(&self, state: &mut LimiterState, now: Instant) {
+        let since = now.saturating_duration_since(self.origin).as_nanos();
+        let epoch = (since / self.limit.epoch.as_nanos()) as u64;
+        if epoch != state.epoch {
+            state.epoch = epoch;
+            state.used = 0;
+        }
+    }
+
+    fn epoch_start(&self, epoch: u64) -> Instant {
+        let nanos = self.limit.epoch.as_nanos() * epoch as u128;
+        self.origin + Duration::from_nanos(nanos as u64)
+    }
+
+    fn refusal(&self, retry_after: Duration) -> RateLimited {
+        RateLimited {
+            retry_after,
+            reason: format!(
+                "publish budget of {} message(s) per {:?} used up",
+                self.limit.messages_per_epoch, self.limit.epoch
+            ),
+        }
+    }
+}
+
+/// A publisher's place in the queue, given up when it gets a token, fails
+/// or is cancelled.
+struct Waiting<'a> {
+    limiter: &'a RateLimiter,
+    priority: Priority,
+    registered: bool,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if self.registered {
+            self.limiter.state.lock().unwrap().waiting[self.priority as usize] -= 1;
+            self.limiter.released.notify_waiters();
+        }
+    }
+}
+
+/// Keeps publishes to `inner` within a `RateLimit`. Everything else is
+/// passed through.
+pub struct RateLimitedTransport<T> {
+    inner: T,
+    limiter: RateLimiter,
+}
+
+impl<T: WakuTransport> RateLimitedTransport<T> {
+    pub fn new(inner: T, limit: RateLimit) -> Self {
+        Self {
+            inner,
+            limiter: RateLimiter::new(limit),
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn limiter(&self) -> &RateLimiter {
+        &self.limiter
+    }
+}
+
+#[async_trait]
+impl<T: WakuTransport> WakuTransport for RateLimitedTransport<T> {
+    async fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
+        self.publish_prioritized(&WakuMessage::new(topic, payload), Priority::Normal)
+            .await
+    }
+
+    async fn publish_message(&self, message: &WakuMessage) -> Result<()> {
+        self.publish_prioritized(message, Priority::of(message))
+            .await
+    }
+
+    async fn publish_prioritized(&self, message: &WakuMessage, priority: Priority) -> Result<()> {
+        self.limiter.acquire(priority).await?;
+        let result = self.inner.publish_prioritized(message, priority).await;
+        if let Some(limited) = result
+            .as_ref()
+            .err()
+            .and_then(|e| e.downcast_ref::<RateLimited>())
+        {
+            eprintln!(
+                "[transport] Network rate limit hit, pausing publishes for {:.1}s",
+                limited.retry_after.as_secs_f64()
+            );
+            self.limiter.pause(limited.retry_after);
+        }
+        result
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<()> {
+        self.inner.subscribe(topic).await
+    }
+
+    async fn unsubscribe(&self, topic: &str) -> Result<()> {
+        self.inner.unsubscribe(topic).await
+    }
+
+    async fn poll(&self, topic: &str) -> Result<Vec<WakuMessage>> {
+        self.inner.poll(topic).await
+    }
+
+    async fn query_history(&self, query: &HistoryQuery) -> Result<HistoryPage> {
+        self.inner.query_history(query).await
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        self.inner.health_check().await
+    }
+
+    fn subscribe_stream<'a>(&'a self, topic: &str) -> BoxStream<'a, WakuMessage> {
+        self.inner.subscribe_stream(topic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimNetwork;
+    use std::sync::Arc;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_budget_per_epoch() {
+        let epoch = Duration::from_secs(10);
+        let limiter = RateLimiter::new(RateLimit::new(3, epoch).with_control_reserve(1));
+        let start = Instant::now();
+        // Epochs are aligned to Unix time, so one may end at any moment
+        let boundary = {
+            let mut state = limiter.state.lock().unwrap();
+            limiter.roll(&mut state, start);
+            limiter.epoch_start(state.epoch + 1)
+        };
+
+        assert!(limiter.try_acquire(Priority::Normal).is_ok());
+        assert!(limiter.try_acquire(Priority::Bulk).is_ok());
+        // The last token is reserved for control messages
+        let refused = limiter.try_acquire(Priority::Normal).unwrap_err();
+        assert_eq!(refused.retry_after, boundary - start);
+        assert!(limiter.try_acquire(Priority::Control).is_ok());
+        assert!(limiter.try_acquire(Priority::Control).is_err());
+        assert_eq!(limiter.remaining(), 0);
+
+        // Waits for the next epoch
+        limiter.acquire(Priority::Normal).await.unwrap();
+        // Timers have millisecond resolution
+        assert!(Instant::now() >= boundary);
+        assert!(Instant::now() - boundary < Duration::from_millis(1));
+        assert_eq!(limiter.remaining(), 2);
+
+        // Fails fast when the wait would be too long
+        let impatient = RateLimiter::new(RateLimit::new(1, epoch).with_max_wait(Duration::ZERO));
+        impatient.acquire(Priority::Control).await.unwrap();
+        let err = impatient.acquire(Priority::Control).await.unwrap_err();
+        assert!(err.retry_after <= epoch);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_control_before_bulk() {
+        let limiter = Arc::new(RateLimiter::new(
+            RateLimit::new(1, Duration::from_secs(1))
+                .with_control_reserve(0)
+                .with_max_wait(Duration::from_secs(60)),
+        ));
+        while limiter.try_acquire(Priority::Control).is_ok() {}
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut handles = Vec::new();
+        for priority in [Priority::Bulk, Priority::Normal, Priority::Control] {
+            let (limiter, order) = (limiter.clone(), order.clone());
+            handles.push(tokio::spawn(async move {
+                limiter.acquire(priority).await.unwrap();
+                order.lock().unwrap().push(priority);
+            }));
+            // Queued in this order
+            tokio::task::yield_now().await;
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(
+            *order.lock().unwrap(),
+            [Priority::Control, Priority::Normal, Priority::Bulk]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transport_pauses_on_network_limit() {
+        struct Limited(crate::sim::SimTransport);
+
+        #[async_trait]
+        impl WakuTransport for Limited {
+            async fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
+                if payload == b"too much" {
+                    return Err(RateLimited {
+                        retry_after: Duration::from_secs(5),
+                        reason: "nwaku answered 429".into(),
+                    }
+                    .into());
+                }
+                self.0.publish(topic, payload).await
+            }
+
+            async fn subscribe(&self, topic: &str) -> Result<()> {
+                self.0.subscribe(topic).await
+            }
+
+            async fn poll(&self, topic: &str) -> Result<Vec<WakuMessage>> {
+                self.0.poll(topic).await
+            }
+        }
+
+        let network = SimNetwork::new(1);
+        let transport = RateLimitedTransport::new(
+            Limited(network.join("a")),
+            RateLimit::new(100, Duration::from_secs(1)).with_max_wait(Duration::from_secs(60)),
+        );
+        let err = transport.publish("/t", b"too much").await.unwrap_err();
+        assert!(err.downcast_ref::<RateLimited>().is_some());
+
+        let start = Instant::now();
+        transport.publish("/t", b"ok").await.unwrap();
+        assert!(Instant::now() - start >= Duration::from_secs(5));
+        assert_eq!(network.published_by("a").len(), 1);
+    }
+}
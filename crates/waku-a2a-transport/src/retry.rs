@@ -0,0 +1,230 @@
+//! Retries with exponential backoff, and a circuit breaker.
+//!
+//! `RetryPolicy` spaces out retries of transient failures: the first waits
+//! `initial_backoff`, each further one twice as long up to `max_backoff`, and
+//! every delay is randomized by up to ±`jitter` so that clients that failed
+//! together don't retry in lockstep.
+//!
+//! `CircuitBreaker` stops calling a backend that keeps failing. After
+//! `failure_threshold` consecutive failures it opens and rejects calls for
+//! `open_duration`; then it lets one probe through (half-open), which closes
+//! it on success or opens it again on failure.
+
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Consecutive failures that open a `CircuitBreaker` by default.
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+
+/// How long an open `CircuitBreaker` rejects calls by default.
+pub const DEFAULT_OPEN_DURATION: Duration = Duration::from_secs(10);
+
+/// How often and how patiently to retry transient failures.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt.
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Random deviation of each delay, as a fraction of it (0.0–1.0).
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    /// 3 retries after 100ms, 200ms and 400ms, ±20%.
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Fail on the first error.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Delay before retry number `retry` (0 for the first).
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let base = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        let factor = 1.0 + self.jitter * (2.0 * random_unit() - 1.0);
+        base.mul_f64(factor.max(0.0))
+    }
+}
+
+/// Uniform random number in `[0, 1)`, from the random bits of a v4 UUID.
+fn random_unit() -> f64 {
+    const BITS: u32 = 53;
+    let bits = uuid::Uuid::new_v4().as_u128() as u64 & ((1 << BITS) - 1);
+    bits as f64 / (1u64 << BITS) as f64
+}
+
+/// Whether calls currently go through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Calls go through.
+    Closed,
+    /// Too many failures: calls are rejected.
+    Open,
+    /// One probe call is allowed to test recovery.
+    HalfOpen,
+}
+
+/// Rejects calls to a backend after repeated failures.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_duration: Duration,
+    inner: Mutex<BreakerInner>,
+}
+
+#[derive(Debug)]
+struct BreakerInner {
+    state: BreakerState,
+    failures: u32,
+    /// When the breaker opened, or when the probe was let through.
+    since: Instant,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(DEFAULT_FAILURE_THRESHOLD, DEFAULT_OPEN_DURATION)
+    }
+}
+
+impl CircuitBreaker {
+    /// Open after `failure_threshold` consecutive failures, for
+    /// `open_duration`.
+    pub fn new(failure_threshold: u32, open_duration: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            open_duration,
+            inner: Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                failures: 0,
+                since: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Whether a call may go ahead. Once an open breaker's time is up, the
+    /// next caller becomes the half-open probe; another probe is allowed if
+    /// its outcome isn't recorded within `open_duration`.
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open | BreakerState::HalfOpen => {
+                if inner.since.elapsed() < self.open_duration {
+                    return false;
+                }
+                inner.state = BreakerState::HalfOpen;
+                inner.since = Instant::now();
+                true
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state = BreakerState::Closed;
+        inner.failures = 0;
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.failures = inner.failures.saturating_add(1);
+        if inner.state == BreakerState::HalfOpen || inner.failures >= self.failure_threshold {
+            inner.state = BreakerState::Open;
+            inner.since = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_and_is_capped() {
+        let policy = RetryPolicy::default()
+            .with_backoff(Duration::from_millis(100), Duration::from_millis(350))
+            .with_jitter(0.0);
+        let delays: Vec<Duration> = (0..4).map(|i| policy.backoff(i)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 350, 350].map(Duration::from_millis).to_vec()
+        );
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(350));
+    }
+
+    #[test]
+    fn test_backoff_jitter_stays_in_bounds() {
+        let policy = RetryPolicy::default().with_jitter(0.5);
+        let delays: Vec<Duration> = (0..100).map(|_| policy.backoff(0)).collect();
+        assert!(delays
+            .iter()
+            .all(|d| *d >= Duration::from_millis(50) && *d <= Duration::from_millis(150)));
+        // Not all the same
+        assert!(delays.iter().any(|d| *d != delays[0]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(10));
+        breaker.record_failure();
+        assert!(breaker.allow());
+        breaker.record_success();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.allow());
+
+        // One probe after the open period; its failure reopens the breaker
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(!breaker.allow());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.allow());
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(breaker.allow());
+        breaker.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow());
+    }
+}
@@ -3,37 +3,63 @@
 //! Waku is fire-and-forget. For agent tasks we need delivery guarantees.
 //! This implements a minimal SDS-inspired protocol:
 //!
-//! - Each message has a UUID
-//! - Sender publishes and then polls for ACK on /waku-a2a/1/ack/{message_id}/proto
-//! - Receiver sends ACK after processing
+//! - Each message has a UUID; received messages are also deduplicated by
+//!   their Waku message hash, which covers payloads without an `id`
+//! - Sender publishes and then waits for an ACK on the scheme's ack topic
+//!   (`/waku-a2a/1/ack/{message_id}/proto` by default)
+//! - Receiver sends ACK after processing, as an ephemeral message so store
+//!   nodes don't keep it
 //! - If no ACK within timeout: retransmit up to MAX_RETRIES times
+//! - Fragmented messages: the receiver sends partial ACKs listing the
+//!   fragments it holds, and only the missing ones are retransmitted
+//! - For rate-limited transports, ACKs are `Priority::Control` and
+//!   retransmissions `Priority::Bulk`; a retransmission refused with
+//!   `RateLimited` is put off to the next round instead of failing the send
 //!
 //! TODO (Issue #2): Replace with the full SDS protocol spec.
 //! Reference: https://blog.waku.org/explanation-series-a-unified-stack-for-scalable-and-reliable-p2p-communication/
 
-use crate::WakuTransport;
+use crate::message::{SeenHashes, DEDUP_CAPACITY};
+use crate::rate_limit::RateLimited;
+use crate::{Priority, WakuMessage, WakuTransport};
 use anyhow::{Context, Result};
-use std::collections::HashSet;
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashSet};
 use std::sync::Mutex;
 use std::time::Duration;
+use waku_a2a_core::topics::TopicScheme;
+use waku_a2a_core::PROTOCOL_VERSION;
 
 const ACK_TIMEOUT: Duration = Duration::from_secs(10);
 const MAX_RETRIES: u32 = 3;
-const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Payloads `is_retransmission` remembers. Retransmissions arrive within
+/// `ACK_TIMEOUT * MAX_RETRIES` of the original, so only recent ones matter.
+const RETRANSMISSION_WINDOW: usize = 1024;
 
 /// Minimal SDS layer wrapping any WakuTransport.
 pub struct SdsTransport<T: WakuTransport> {
     inner: T,
+    /// Content topic layout used for ACK topics.
+    topics: TopicScheme,
     /// Bloom filter substitute: set of seen message IDs for deduplication.
     /// TODO (Issue #2): Replace with proper bloom filter from SDS spec.
     seen_ids: Mutex<HashSet<String>>,
+    /// Hashes of received Waku messages.
+    seen_hashes: Mutex<SeenHashes>,
+    /// Hashes of received topic and payload pairs, for `is_retransmission`.
+    seen_payloads: Mutex<SeenHashes>,
 }
 
 impl<T: WakuTransport> SdsTransport<T> {
     pub fn new(transport: T) -> Self {
         Self {
             inner: transport,
+            topics: TopicScheme::default(),
             seen_ids: Mutex::new(HashSet::new()),
+            seen_hashes: Mutex::new(SeenHashes::new(DEDUP_CAPACITY)),
+            seen_payloads: Mutex::new(SeenHashes::new(RETRANSMISSION_WINDOW)),
         }
     }
 
@@ -41,6 +67,14 @@ impl<T: WakuTransport> SdsTransport<T> {
         &self.inner
     }
 
+    pub fn topic_scheme(&self) -> &TopicScheme {
+        &self.topics
+    }
+
+    pub fn set_topic_scheme(&mut self, topics: TopicScheme) {
+        self.topics = topics;
+    }
+
     /// Publish with SDS reliability: retransmit up to MAX_RETRIES times
     /// if no ACK is received within ACK_TIMEOUT.
     pub async fn publish_reliable(
@@ -49,23 +83,82 @@ impl<T: WakuTransport> SdsTransport<T> {
         payload: &[u8],
         message_id: &str,
     ) -> Result<bool> {
-        let ack_topic = format!("/waku-a2a/1/ack/{}/proto", message_id);
+        self.publish_fragments_reliable(topic, &[payload], message_id)
+            .await
+    }
+
+    /// Publish the fragments of one message with SDS reliability. After each
+    /// ACK timeout only the fragments not listed in a partial ACK are
+    /// retransmitted. The ACK topic is unsubscribed again however this ends.
+    pub async fn publish_fragments_reliable<P: AsRef<[u8]>>(
+        &self,
+        topic: &str,
+        fragments: &[P],
+        message_id: &str,
+    ) -> Result<bool> {
+        let ack_topic = self.topics.ack_topic(message_id);
         self.inner.subscribe(&ack_topic).await?;
+        let result = self
+            .publish_until_acked(topic, fragments, message_id, &ack_topic)
+            .await;
+        if let Err(e) = self.inner.unsubscribe(&ack_topic).await {
+            tracing_log(&format!(
+                "SDS: failed to unsubscribe from {}: {:#}",
+                ack_topic, e
+            ));
+        }
+        result
+    }
 
+    /// Retransmission loop of `publish_fragments_reliable`, with the ACK
+    /// topic already subscribed.
+    async fn publish_until_acked<P: AsRef<[u8]>>(
+        &self,
+        topic: &str,
+        fragments: &[P],
+        message_id: &str,
+        ack_topic: &str,
+    ) -> Result<bool> {
+        let mut received = BTreeSet::new();
         for attempt in 0..=MAX_RETRIES {
+            let missing: Vec<usize> = (0..fragments.len())
+                .filter(|i| !received.contains(&(*i as u64)))
+                .collect();
             if attempt > 0 {
-                tracing_log(
-                    &format!("SDS: retransmit attempt {}/{} for {}", attempt, MAX_RETRIES, message_id),
-                );
+                tracing_log(&format!(
+                    "SDS: retransmit attempt {}/{} for {} ({} of {} fragments)",
+                    attempt,
+                    MAX_RETRIES,
+                    message_id,
+                    missing.len(),
+                    fragments.len()
+                ));
             }
 
-            self.inner
-                .publish(topic, payload)
-                .await
-                .context("SDS publish failed")?;
+            let priority = if attempt == 0 {
+                Priority::Normal
+            } else {
+                Priority::Bulk
+            };
+            for &i in &missing {
+                let message = WakuMessage::new(topic, fragments[i].as_ref());
+                match self.inner.publish_prioritized(&message, priority).await {
+                    Ok(()) => {}
+                    Err(e) if attempt > 0 && e.downcast_ref::<RateLimited>().is_some() => {
+                        tracing_log(&format!(
+                            "SDS: retransmission of {} put off: {:#}",
+                            message_id, e
+                        ));
+                        break;
+                    }
+                    Err(e) => return Err(e).context("SDS publish failed"),
+                }
+            }
 
-            // Poll for ACK
-            if self.wait_for_ack(&ack_topic, message_id).await? {
+            if self
+                .wait_for_ack(ack_topic, message_id, &mut received)
+                .await?
+            {
                 return Ok(true);
             }
         }
@@ -79,12 +172,34 @@ impl<T: WakuTransport> SdsTransport<T> {
 
     /// Send an ACK for a received message.
     pub async fn send_ack(&self, message_id: &str) -> Result<()> {
-        let ack_topic = format!("/waku-a2a/1/ack/{}/proto", message_id);
+        let ack_topic = self.topics.ack_topic(message_id);
         let ack_payload = serde_json::to_vec(&serde_json::json!({
+            "v": PROTOCOL_VERSION,
             "type": "ack",
             "message_id": message_id,
         }))?;
-        self.inner.publish(&ack_topic, &ack_payload).await
+        self.publish_ack(&ack_topic, ack_payload).await
+    }
+
+    /// Send a partial ACK for a fragmented message that is still incomplete,
+    /// listing the fragment indices received so far.
+    pub async fn send_fragment_ack(&self, message_id: &str, received: &[u32]) -> Result<()> {
+        let ack_topic = self.topics.ack_topic(message_id);
+        let ack_payload = serde_json::to_vec(&serde_json::json!({
+            "v": PROTOCOL_VERSION,
+            "type": "ack",
+            "message_id": message_id,
+            "fragments": received,
+        }))?;
+        self.publish_ack(&ack_topic, ack_payload).await
+    }
+
+    /// ACKs only matter while the sender waits for them, so they're ephemeral.
+    async fn publish_ack(&self, ack_topic: &str, payload: Vec<u8>) -> Result<()> {
+        let message = WakuMessage::new(ack_topic, payload).with_ephemeral(true);
+        self.inner
+            .publish_prioritized(&message, Priority::Control)
+            .await
     }
 
     /// Check if a message ID has been seen before (deduplication).
@@ -100,14 +215,22 @@ impl<T: WakuTransport> SdsTransport<T> {
     }
 
     /// Poll the inner transport, filtering duplicates.
-    pub async fn poll_dedup(&self, topic: &str) -> Result<Vec<Vec<u8>>> {
+    pub async fn poll_dedup(&self, topic: &str) -> Result<Vec<WakuMessage>> {
         let messages = self.inner.poll(topic).await?;
+        Ok(self.dedup(messages))
+    }
+
+    /// Drop messages whose hash or `id` was seen before and mark the rest
+    /// as seen. The hash catches relayed copies of one message, the `id`
+    /// retransmissions of it.
+    pub fn dedup(&self, messages: Vec<WakuMessage>) -> Vec<WakuMessage> {
         let mut result = Vec::new();
         for msg in messages {
+            if !self.seen_hashes.lock().unwrap().insert(msg.received_hash()) {
+                continue;
+            }
             // Try to extract message_id for dedup
-            if let Ok(envelope) =
-                serde_json::from_slice::<serde_json::Value>(&msg)
-            {
+            if let Ok(envelope) = serde_json::from_slice::<serde_json::Value>(&msg.payload) {
                 if let Some(id) = envelope.get("id").and_then(|v| v.as_str()) {
                     if self.is_duplicate(id) {
                         continue;
@@ -117,21 +240,45 @@ impl<T: WakuTransport> SdsTransport<T> {
             }
             result.push(msg);
         }
-        Ok(result)
+        result
+    }
+
+    /// Whether the same payload was received on the same topic before, and
+    /// mark it as received. Retransmissions repeat the payload byte for byte
+    /// but carry a new timestamp, so unlike `dedup` this catches resent
+    /// messages without a readable `id`, such as encrypted ones. The receiver
+    /// should ACK them again: the sender resent because no ACK arrived.
+    pub fn is_retransmission(&self, message: &WakuMessage) -> bool {
+        let mut hasher = Sha256::new();
+        hasher.update(message.content_topic.as_bytes());
+        hasher.update(&message.payload);
+        !self
+            .seen_payloads
+            .lock()
+            .unwrap()
+            .insert(hasher.finalize().into())
     }
 
-    async fn wait_for_ack(&self, ack_topic: &str, message_id: &str) -> Result<bool> {
+    /// Wait for a full ACK. Fragment indices from partial ACKs are added to
+    /// `received`.
+    async fn wait_for_ack(
+        &self,
+        ack_topic: &str,
+        message_id: &str,
+        received: &mut BTreeSet<u64>,
+    ) -> Result<bool> {
         let deadline = tokio::time::Instant::now() + ACK_TIMEOUT;
-        while tokio::time::Instant::now() < deadline {
-            let messages = self.inner.poll(ack_topic).await.unwrap_or_default();
-            for msg in messages {
-                if let Ok(val) = serde_json::from_slice::<serde_json::Value>(&msg) {
-                    if val.get("message_id").and_then(|v| v.as_str()) == Some(message_id) {
-                        return Ok(true);
-                    }
+        let mut acks = self.inner.subscribe_stream(ack_topic);
+        while let Ok(Some(msg)) = tokio::time::timeout_at(deadline, acks.next()).await {
+            if let Ok(val) = serde_json::from_slice::<serde_json::Value>(&msg.payload) {
+                if val.get("message_id").and_then(|v| v.as_str()) != Some(message_id) {
+                    continue;
+                }
+                match val.get("fragments").and_then(|f| f.as_array()) {
+                    Some(indices) => received.extend(indices.iter().filter_map(|i| i.as_u64())),
+                    None => return Ok(true),
                 }
             }
-            tokio::time::sleep(POLL_INTERVAL).await;
         }
         Ok(false)
     }
@@ -144,63 +291,13 @@ fn tracing_log(msg: &str) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sim::{LinkConfig, SimNetwork};
     use crate::WakuTransport;
-    use async_trait::async_trait;
-    use std::sync::{Arc, Mutex as StdMutex};
-
-    /// In-memory transport for testing.
-    struct MockTransport {
-        published: Arc<StdMutex<Vec<(String, Vec<u8>)>>>,
-        /// Messages to return on poll, keyed by topic.
-        poll_responses: Arc<StdMutex<Vec<(String, Vec<u8>)>>>,
-    }
-
-    impl MockTransport {
-        fn new() -> Self {
-            Self {
-                published: Arc::new(StdMutex::new(Vec::new())),
-                poll_responses: Arc::new(StdMutex::new(Vec::new())),
-            }
-        }
-
-        fn inject_message(&self, topic: &str, payload: Vec<u8>) {
-            let mut responses = self.poll_responses.lock().unwrap();
-            responses.push((topic.to_string(), payload));
-        }
-    }
-
-    #[async_trait]
-    impl WakuTransport for MockTransport {
-        async fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
-            let mut published = self.published.lock().unwrap();
-            published.push((topic.to_string(), payload.to_vec()));
-            Ok(())
-        }
-
-        async fn subscribe(&self, _topic: &str) -> Result<()> {
-            Ok(())
-        }
-
-        async fn poll(&self, topic: &str) -> Result<Vec<Vec<u8>>> {
-            let mut responses = self.poll_responses.lock().unwrap();
-            let mut result = Vec::new();
-            let mut remaining = Vec::new();
-            for (t, payload) in responses.drain(..) {
-                if t == topic {
-                    result.push(payload);
-                } else {
-                    remaining.push((t, payload));
-                }
-            }
-            *responses = remaining;
-            Ok(result)
-        }
-    }
+    use std::sync::Mutex as StdMutex;
 
     #[test]
     fn test_deduplication() {
-        let transport = MockTransport::new();
-        let sds = SdsTransport::new(transport);
+        let sds = SdsTransport::new(SimNetwork::new(1).join("a"));
 
         assert!(!sds.is_duplicate("msg-1"));
         sds.mark_seen("msg-1");
@@ -210,31 +307,46 @@ mod tests {
 
     #[tokio::test]
     async fn test_send_ack() {
-        let transport = MockTransport::new();
-        let published = transport.published.clone();
-        let sds = SdsTransport::new(transport);
+        let network = SimNetwork::new(1);
+        let sds = SdsTransport::new(network.join("a"));
 
         sds.send_ack("task-123").await.unwrap();
 
-        let msgs = published.lock().unwrap();
+        let msgs = network.published_by("a");
         assert_eq!(msgs.len(), 1);
-        assert_eq!(msgs[0].0, "/waku-a2a/1/ack/task-123/proto");
-        let val: serde_json::Value = serde_json::from_slice(&msgs[0].1).unwrap();
+        assert_eq!(msgs[0].content_topic, "/waku-a2a/1/ack/task-123/proto");
+        assert!(msgs[0].ephemeral);
+        let val: serde_json::Value = serde_json::from_slice(&msgs[0].payload).unwrap();
         assert_eq!(val["message_id"], "task-123");
     }
 
+    #[tokio::test]
+    async fn test_ack_topic_follows_scheme() {
+        let network = SimNetwork::new(1);
+        let mut sds = SdsTransport::new(network.join("a"));
+        sds.set_topic_scheme(TopicScheme::namespaced("staging").unwrap());
+
+        sds.send_ack("task-123").await.unwrap();
+        assert_eq!(
+            network.published_by("a")[0].content_topic,
+            "/waku-a2a/1/staging/ack/task-123/proto"
+        );
+    }
+
     #[tokio::test]
     async fn test_publish_reliable_with_ack() {
-        let transport = MockTransport::new();
+        let network = SimNetwork::new(1);
+        let sds = SdsTransport::new(network.join("a"));
         // Pre-inject an ACK response
         let ack_payload = serde_json::to_vec(&serde_json::json!({
             "type": "ack",
             "message_id": "msg-1",
         }))
         .unwrap();
-        transport.inject_message("/waku-a2a/1/ack/msg-1/proto", ack_payload);
-
-        let sds = SdsTransport::new(transport);
+        network.deliver(
+            "a",
+            WakuMessage::new("/waku-a2a/1/ack/msg-1/proto", ack_payload),
+        );
 
         let acked = sds
             .publish_reliable("/waku-a2a/1/task/somepubkey/proto", b"hello", "msg-1")
@@ -243,9 +355,90 @@ mod tests {
         assert!(acked);
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_publish_reliable_unsubscribes_ack_topic() {
+        let network = SimNetwork::new(1);
+        let sds = SdsTransport::new(network.join("a"));
+        sds.inner()
+            .subscribe("/waku-a2a/1/task/mine/proto")
+            .await
+            .unwrap();
+        let before = network.subscriptions("a");
+
+        let ack_payload = serde_json::to_vec(&serde_json::json!({
+            "type": "ack",
+            "message_id": "msg-1",
+        }))
+        .unwrap();
+        network.deliver(
+            "a",
+            WakuMessage::new("/waku-a2a/1/ack/msg-1/proto", ack_payload),
+        );
+        let topic = "/waku-a2a/1/task/somepubkey/proto";
+        assert!(sds
+            .publish_reliable(topic, b"hello", "msg-1")
+            .await
+            .unwrap());
+        assert_eq!(network.subscriptions("a"), before);
+
+        // Also after giving up without an ACK
+        assert!(!sds
+            .publish_reliable(topic, b"hello", "msg-2")
+            .await
+            .unwrap());
+        assert_eq!(network.subscriptions("a"), before);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retransmits_only_missing_fragments() {
+        let network = SimNetwork::new(1);
+        let sds = SdsTransport::new(network.join("a"));
+        let ack_topic = "/waku-a2a/1/ack/msg-1/proto";
+        // Receiver holds fragments 0 and 2 when the first ACK timeout expires
+        let partial = serde_json::to_vec(&serde_json::json!({
+            "type": "ack",
+            "message_id": "msg-1",
+            "fragments": [0, 2],
+        }))
+        .unwrap();
+        network.deliver("a", WakuMessage::new(ack_topic, partial));
+
+        let fragments = [b"f0".to_vec(), b"f1".to_vec(), b"f2".to_vec()];
+        let complete = async {
+            tokio::time::sleep(ACK_TIMEOUT + crate::STREAM_POLL_INTERVAL * 2).await;
+            let ack = serde_json::to_vec(&serde_json::json!({
+                "type": "ack",
+                "message_id": "msg-1",
+            }))
+            .unwrap();
+            network.deliver("a", WakuMessage::new(ack_topic, ack));
+        };
+        let (acked, ()) = tokio::join!(
+            sds.publish_fragments_reliable("topic-a", &fragments, "msg-1"),
+            complete
+        );
+        assert!(acked.unwrap());
+
+        let sent: Vec<Vec<u8>> = network
+            .published_by("a")
+            .into_iter()
+            .map(|m| m.payload)
+            .collect();
+        assert_eq!(
+            sent,
+            vec![
+                b"f0".to_vec(),
+                b"f1".to_vec(),
+                b"f2".to_vec(),
+                b"f1".to_vec()
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn test_poll_dedup() {
-        let transport = MockTransport::new();
+        let network = SimNetwork::new(1);
+        let sds = SdsTransport::new(network.join("a"));
 
         // Inject same message twice
         let msg = serde_json::to_vec(&serde_json::json!({
@@ -253,13 +446,88 @@ mod tests {
             "data": "hello"
         }))
         .unwrap();
-        transport.inject_message("topic-a", msg.clone());
-        transport.inject_message("topic-a", msg);
-
-        let sds = SdsTransport::new(transport);
+        network.deliver("a", WakuMessage::new("topic-a", msg.clone()));
+        network.deliver("a", WakuMessage::new("topic-a", msg));
 
         let result = sds.poll_dedup("topic-a").await.unwrap();
         // Second message should be deduped
         assert_eq!(result.len(), 1);
     }
+
+    #[test]
+    fn test_dedup_by_message_hash() {
+        let sds = SdsTransport::new(SimNetwork::new(1).join("a"));
+        // Binary payloads have no `id`; relayed copies still share a hash
+        let message = WakuMessage::new("topic-a", vec![0xa1, 0x01]).with_timestamp(1);
+        let resent = message.clone().with_timestamp(2);
+        let result = sds.dedup(vec![message.clone(), message.clone(), resent]);
+        assert_eq!(result.len(), 2);
+        assert!(sds.dedup(vec![message]).is_empty());
+    }
+
+    #[test]
+    fn test_is_retransmission() {
+        let sds = SdsTransport::new(SimNetwork::new(1).join("a"));
+        let message = WakuMessage::new("topic-a", vec![0xa1, 0x01]).with_timestamp(1);
+        assert!(!sds.is_retransmission(&message));
+        assert!(sds.is_retransmission(&message.clone().with_timestamp(2)));
+        assert!(!sds.is_retransmission(&WakuMessage::new("topic-b", vec![0xa1, 0x01])));
+    }
+
+    #[test]
+    fn test_dedup_hashes_are_bounded() {
+        let sds = SdsTransport::new(SimNetwork::new(1).join("a"));
+        let messages = (0..DEDUP_CAPACITY + 10)
+            .map(|i| WakuMessage::new("topic-a", i.to_be_bytes().to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(sds.dedup(messages).len(), DEDUP_CAPACITY + 10);
+        assert_eq!(sds.seen_hashes.lock().unwrap().len(), DEDUP_CAPACITY);
+    }
+
+    #[test]
+    fn test_retransmission_window_is_bounded() {
+        let sds = SdsTransport::new(SimNetwork::new(1).join("a"));
+        for i in 0..RETRANSMISSION_WINDOW * 2 {
+            let message = WakuMessage::new("topic-a", i.to_be_bytes().to_vec());
+            assert!(!sds.is_retransmission(&message));
+        }
+        assert_eq!(
+            sds.seen_payloads.lock().unwrap().len(),
+            RETRANSMISSION_WINDOW
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_publish_reliable_over_lossy_network() {
+        let link = LinkConfig::default()
+            .with_latency(Duration::from_millis(200))
+            .with_jitter(Duration::from_millis(100))
+            .with_loss(0.4)
+            .with_duplicate(0.3);
+        let network = SimNetwork::new(5).with_default_link(link);
+        let sender = SdsTransport::new(network.join("sender"));
+        let receiver = SdsTransport::new(network.join("receiver"));
+        let topic = "/waku-a2a/1/task/receiver/proto";
+        let payload = serde_json::to_vec(&serde_json::json!({ "id": "msg-1" })).unwrap();
+        receiver.inner().subscribe(topic).await.unwrap();
+
+        // The receiver ACKs every copy but hands the message on once
+        let delivered = StdMutex::new(0);
+        let receive = async {
+            let mut incoming = receiver.inner().subscribe_stream(topic);
+            while let Some(message) = incoming.next().await {
+                *delivered.lock().unwrap() += receiver.dedup(vec![message]).len();
+                receiver.send_ack("msg-1").await.unwrap();
+            }
+        };
+        let acked = tokio::select! {
+            acked = sender.publish_reliable(topic, &payload, "msg-1") => acked.unwrap(),
+            _ = receive => unreachable!(),
+        };
+        assert!(acked);
+        assert_eq!(*delivered.lock().unwrap(), 1);
+        let stats = network.stats();
+        assert!(stats.dropped > 0);
+        assert!(network.published_by("sender").len() > 1);
+    }
 }
@@ -0,0 +1,588 @@
+//! Simulated Waku network for tests and demos.
+//!
+//! `SimNetwork` connects any number of in-process nodes, each joined as a
+//! `SimTransport`. A published message is relayed to every node subscribed
+//! to its content topic at that moment, the publisher included, like Waku
+//! Relay. Non-ephemeral messages are also kept in a store for
+//! `query_history`.
+//!
+//! Every directed link between two nodes can add latency and jitter, lose,
+//! duplicate or reorder messages (`LinkConfig`), and groups of nodes can be
+//! partitioned from each other. All randomness comes from the seed passed to
+//! `SimNetwork::new`, and delays run on the Tokio clock, so a test with
+//! `#[tokio::test(start_paused = true)]` replays the same way every time.
+
+use crate::history::{HistoryPage, HistoryQuery};
+use crate::message::now_nanos;
+use crate::{WakuMessage, WakuTransport};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Extra delay of a reordered message, so later messages overtake it.
+pub const REORDER_DELAY: Duration = Duration::from_millis(100);
+
+/// Behavior of a directed link between two nodes. The default is a perfect
+/// link: no delay, nothing lost.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkConfig {
+    /// Fixed delay of every message.
+    pub latency: Duration,
+    /// Random extra delay, uniform in `0..=jitter`.
+    pub jitter: Duration,
+    /// Probability that a message is lost.
+    pub loss: f64,
+    /// Probability that a message is delivered twice.
+    pub duplicate: f64,
+    /// Probability that a message is held back by `REORDER_DELAY`.
+    pub reorder: f64,
+}
+
+impl LinkConfig {
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn with_loss(mut self, loss: f64) -> Self {
+        self.loss = loss;
+        self
+    }
+
+    pub fn with_duplicate(mut self, duplicate: f64) -> Self {
+        self.duplicate = duplicate;
+        self
+    }
+
+    pub fn with_reorder(mut self, reorder: f64) -> Self {
+        self.reorder = reorder;
+        self
+    }
+}
+
+/// Counters of what the network did with published messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimStats {
+    /// Messages published by any node.
+    pub published: u64,
+    /// Copies handed to subscribers, duplicates included.
+    pub delivered: u64,
+    /// Copies lost on a link or across a partition.
+    pub dropped: u64,
+    /// Extra copies created by duplication.
+    pub duplicated: u64,
+}
+
+/// SplitMix64: small, fast and good enough to drive fault injection.
+struct SimRng(u64);
+
+impl SimRng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// True with probability `p`.
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    /// Uniform in `0..=max`.
+    fn up_to(&mut self, max: Duration) -> Duration {
+        if max.is_zero() {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos(self.next_u64() % (max.as_nanos() as u64 + 1))
+    }
+}
+
+/// A message on its way to a node.
+struct InFlight {
+    deliver_at: Instant,
+    /// Breaks ties between messages due at the same time.
+    seq: u64,
+    message: WakuMessage,
+}
+
+#[derive(Default)]
+struct Endpoint {
+    subscriptions: HashSet<String>,
+    in_flight: Vec<InFlight>,
+    published: Vec<WakuMessage>,
+}
+
+struct NetState {
+    rng: SimRng,
+    default_link: LinkConfig,
+    links: HashMap<(String, String), LinkConfig>,
+    /// Directed links cut by a partition.
+    blocked: HashSet<(String, String)>,
+    /// Ordered by name, so deliveries draw randomness in a fixed order.
+    nodes: BTreeMap<String, Endpoint>,
+    store: Vec<WakuMessage>,
+    seq: u64,
+    stats: SimStats,
+}
+
+impl NetState {
+    fn endpoint(&mut self, name: &str) -> &mut Endpoint {
+        self.nodes
+            .get_mut(name)
+            .unwrap_or_else(|| panic!("{} hasn't joined the network", name))
+    }
+
+    fn enqueue(&mut self, to: &str, deliver_at: Instant, message: WakuMessage) {
+        self.seq += 1;
+        let seq = self.seq;
+        self.endpoint(to).in_flight.push(InFlight {
+            deliver_at,
+            seq,
+            message,
+        });
+    }
+
+    /// Relay `message` from `from` to every subscriber.
+    fn relay(&mut self, from: &str, message: &WakuMessage) {
+        let now = Instant::now();
+        let recipients: Vec<String> = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.subscriptions.contains(&message.content_topic))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for to in recipients {
+            let key = (from.to_string(), to.clone());
+            if self.blocked.contains(&key) {
+                self.stats.dropped += 1;
+                continue;
+            }
+            // A node hears its own messages without delay
+            let link = match self.links.get(&key) {
+                _ if from == to => LinkConfig::default(),
+                Some(link) => *link,
+                None => self.default_link,
+            };
+            if self.rng.chance(link.loss) {
+                self.stats.dropped += 1;
+                continue;
+            }
+            let copies = if self.rng.chance(link.duplicate) {
+                self.stats.duplicated += 1;
+                2
+            } else {
+                1
+            };
+            for _ in 0..copies {
+                let mut delay = link.latency + self.rng.up_to(link.jitter);
+                if self.rng.chance(link.reorder) {
+                    delay += REORDER_DELAY;
+                }
+                self.enqueue(&to, now + delay, message.clone());
+                self.stats.delivered += 1;
+            }
+        }
+    }
+}
+
+/// An in-process Waku network with fault injection. Cheap to clone; clones
+/// share the network.
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<NetState>>,
+    /// Signalled whenever messages are queued for any node.
+    queued: Arc<Notify>,
+}
+
+impl SimNetwork {
+    /// A network of perfect links whose faults are drawn from `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(NetState {
+                rng: SimRng(seed),
+                default_link: LinkConfig::default(),
+                links: HashMap::new(),
+                blocked: HashSet::new(),
+                nodes: BTreeMap::new(),
+                store: Vec::new(),
+                seq: 0,
+                stats: SimStats::default(),
+            })),
+            queued: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Use `link` between nodes that have no link of their own.
+    pub fn with_default_link(self, link: LinkConfig) -> Self {
+        self.state.lock().unwrap().default_link = link;
+        self
+    }
+
+    /// Add a node. Panics if `name` has already joined.
+    pub fn join(&self, name: &str) -> SimTransport {
+        let mut state = self.state.lock().unwrap();
+        assert!(
+            !state.nodes.contains_key(name),
+            "{} has already joined the network",
+            name
+        );
+        state.nodes.insert(name.to_string(), Endpoint::default());
+        SimTransport {
+            network: self.clone(),
+            name: name.to_string(),
+        }
+    }
+
+    /// Set the link from `from` to `to`. The way back is configured
+    /// separately.
+    pub fn set_link(&self, from: &str, to: &str, link: LinkConfig) {
+        self.state
+            .lock()
+            .unwrap()
+            .links
+            .insert((from.to_string(), to.to_string()), link);
+    }
+
+    /// Cut every link between a node of `a` and a node of `b`, both ways.
+    /// Messages already in flight still arrive.
+    pub fn partition(&self, a: &[&str], b: &[&str]) {
+        let mut state = self.state.lock().unwrap();
+        for x in a {
+            for y in b {
+                state.blocked.insert((x.to_string(), y.to_string()));
+                state.blocked.insert((y.to_string(), x.to_string()));
+            }
+        }
+    }
+
+    /// Remove all partitions.
+    pub fn heal(&self) {
+        self.state.lock().unwrap().blocked.clear();
+    }
+
+    /// Hand `message` to node `to` right away, whether or not it subscribed
+    /// to the topic and bypassing links and the store.
+    pub fn deliver(&self, to: &str, message: WakuMessage) {
+        self.state
+            .lock()
+            .unwrap()
+            .enqueue(to, Instant::now(), message);
+        self.queued.notify_waiters();
+    }
+
+    /// Put `message` in the store, as if published while nobody listened.
+    pub fn store(&self, message: WakuMessage) {
+        self.state.lock().unwrap().store.push(message);
+    }
+
+    /// Messages node `name` published, in order, before any faults.
+    pub fn published_by(&self, name: &str) -> Vec<WakuMessage> {
+        self.state.lock().unwrap().endpoint(name).published.clone()
+    }
+
+    /// Content topics node `name` is subscribed to, sorted.
+    pub fn subscriptions(&self, name: &str) -> Vec<String> {
+        let mut state = self.state.lock().unwrap();
+        let mut topics: Vec<String> = state.endpoint(name).subscriptions.iter().cloned().collect();
+        topics.sort();
+        topics
+    }
+
+    pub fn stats(&self) -> SimStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+/// A node's connection to a `SimNetwork`. Cheap to clone; clones are the
+/// same node.
+#[derive(Clone)]
+pub struct SimTransport {
+    network: SimNetwork,
+    name: String,
+}
+
+impl SimTransport {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn network(&self) -> &SimNetwork {
+        &self.network
+    }
+
+    /// When the next message on `topic` is due, if any is in flight.
+    fn next_due(&self, topic: &str) -> Option<Instant> {
+        let mut state = self.network.state.lock().unwrap();
+        state
+            .endpoint(&self.name)
+            .in_flight
+            .iter()
+            .filter(|f| f.message.content_topic == topic)
+            .map(|f| f.deliver_at)
+            .min()
+    }
+}
+
+#[async_trait]
+impl WakuTransport for SimTransport {
+    async fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
+        self.publish_message(&WakuMessage::new(topic, payload))
+            .await
+    }
+
+    /// Messages without a timestamp are stamped with the current time.
+    async fn publish_message(&self, message: &WakuMessage) -> Result<()> {
+        let mut message = message.clone();
+        message.timestamp.get_or_insert_with(now_nanos);
+        {
+            let mut state = self.network.state.lock().unwrap();
+            state.stats.published += 1;
+            state.endpoint(&self.name).published.push(message.clone());
+            if !message.ephemeral {
+                state.store.push(message.clone());
+            }
+            state.relay(&self.name, &message);
+        }
+        self.network.queued.notify_waiters();
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<()> {
+        let mut state = self.network.state.lock().unwrap();
+        state
+            .endpoint(&self.name)
+            .subscriptions
+            .insert(topic.to_string());
+        Ok(())
+    }
+
+    /// Messages still in flight on the topic are dropped.
+    async fn unsubscribe(&self, topic: &str) -> Result<()> {
+        let mut state = self.network.state.lock().unwrap();
+        let node = state.endpoint(&self.name);
+        node.subscriptions.remove(topic);
+        node.in_flight.retain(|f| f.message.content_topic != topic);
+        Ok(())
+    }
+
+    /// Messages that have arrived by now, in arrival order.
+    async fn poll(&self, topic: &str) -> Result<Vec<WakuMessage>> {
+        let now = Instant::now();
+        let mut state = self.network.state.lock().unwrap();
+        let node = state.endpoint(&self.name);
+        let (mut arrived, pending): (Vec<_>, Vec<_>) = node
+            .in_flight
+            .drain(..)
+            .partition(|f| f.message.content_topic == topic && f.deliver_at <= now);
+        node.in_flight = pending;
+        arrived.sort_by_key(|f| (f.deliver_at, f.seq));
+        Ok(arrived.into_iter().map(|f| f.message).collect())
+    }
+
+    /// Stored messages of the topic in publish order; the cursor is an
+    /// offset into the matches.
+    async fn query_history(&self, query: &HistoryQuery) -> Result<HistoryPage> {
+        let state = self.network.state.lock().unwrap();
+        let matches: Vec<&WakuMessage> = state
+            .store
+            .iter()
+            .filter(|m| m.content_topic == query.content_topic)
+            .filter(|m| query.start_time.is_none_or(|t| m.timestamp >= Some(t)))
+            .filter(|m| query.end_time.is_none_or(|t| m.timestamp <= Some(t)))
+            .collect();
+        let offset = match query.cursor {
+            Some(ref cursor) => match cursor.parse::<usize>() {
+                Ok(offset) => offset,
+                Err(_) => bail!("Invalid history cursor {}", cursor),
+            },
+            None => 0,
+        };
+        let end = match query.page_size {
+            Some(size) => (offset + size as usize).min(matches.len()),
+            None => matches.len(),
+        };
+        Ok(HistoryPage {
+            messages: matches[offset.min(end)..end]
+                .iter()
+                .map(|m| (*m).clone())
+                .collect(),
+            cursor: (end < matches.len()).then(|| end.to_string()),
+        })
+    }
+
+    /// Push delivery: wakes up when a message is published or falls due.
+    fn subscribe_stream<'a>(&'a self, topic: &str) -> BoxStream<'a, WakuMessage> {
+        let topic = topic.to_string();
+        Box::pin(futures::stream::unfold(
+            (VecDeque::new(), false),
+            move |(mut ready, mut subscribed)| {
+                let topic = topic.clone();
+                async move {
+                    loop {
+                        if let Some(message) = ready.pop_front() {
+                            return Some((message, (ready, subscribed)));
+                        }
+                        if !subscribed {
+                            self.subscribe(&topic).await.ok()?;
+                            subscribed = true;
+                        }
+                        // Register before polling so a publish in between isn't missed
+                        let queued = self.network.queued.notified();
+                        ready.extend(self.poll(&topic).await.unwrap_or_default());
+                        if !ready.is_empty() {
+                            continue;
+                        }
+                        match self.next_due(&topic) {
+                            Some(due) => {
+                                tokio::select! {
+                                    _ = queued => {}
+                                    _ = tokio::time::sleep_until(due) => {}
+                                }
+                            }
+                            None => queued.await,
+                        }
+                    }
+                }
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    const TOPIC: &str = "/waku-a2a/1/task/02ab/proto";
+
+    fn payloads(messages: Vec<WakuMessage>) -> Vec<Vec<u8>> {
+        messages.into_iter().map(|m| m.payload).collect()
+    }
+
+    #[tokio::test]
+    async fn test_relays_to_subscribers() {
+        let network = SimNetwork::new(1);
+        let a = network.join("a");
+        let b = network.join("b");
+        let c = network.join("c");
+        a.subscribe(TOPIC).await.unwrap();
+        b.subscribe(TOPIC).await.unwrap();
+
+        a.publish(TOPIC, b"hello").await.unwrap();
+        assert_eq!(payloads(a.poll(TOPIC).await.unwrap()), vec![b"hello"]);
+        assert_eq!(payloads(b.poll(TOPIC).await.unwrap()), vec![b"hello"]);
+        assert!(c.poll(TOPIC).await.unwrap().is_empty());
+        assert!(b.poll(TOPIC).await.unwrap().is_empty());
+
+        let published = network.published_by("a");
+        assert_eq!(published.len(), 1);
+        assert!(published[0].timestamp.is_some());
+        // Subscribing later doesn't replay relay traffic, but the store has it
+        c.subscribe(TOPIC).await.unwrap();
+        assert!(c.poll(TOPIC).await.unwrap().is_empty());
+        let page = c.query_history(&HistoryQuery::new(TOPIC)).await.unwrap();
+        assert_eq!(payloads(page.messages), vec![b"hello"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_latency_and_jitter() {
+        let link = LinkConfig::default()
+            .with_latency(Duration::from_millis(100))
+            .with_jitter(Duration::from_millis(50));
+        let network = SimNetwork::new(7).with_default_link(link);
+        let a = network.join("a");
+        let b = network.join("b");
+        b.subscribe(TOPIC).await.unwrap();
+
+        let start = Instant::now();
+        a.publish(TOPIC, b"hello").await.unwrap();
+        assert!(b.poll(TOPIC).await.unwrap().is_empty());
+        let mut incoming = b.subscribe_stream(TOPIC);
+        assert_eq!(incoming.next().await.unwrap().payload, b"hello");
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(elapsed <= Duration::from_millis(150));
+    }
+
+    /// Payloads `b` receives from 100 messages `a` publishes over `link`.
+    async fn run_link(seed: u64, link: LinkConfig) -> (Vec<Vec<u8>>, SimStats) {
+        let network = SimNetwork::new(seed);
+        let a = network.join("a");
+        let b = network.join("b");
+        network.set_link("a", "b", link);
+        b.subscribe(TOPIC).await.unwrap();
+        for i in 0..100u8 {
+            a.publish(TOPIC, &[i]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        (payloads(b.poll(TOPIC).await.unwrap()), network.stats())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_faults_are_deterministic() {
+        let link = LinkConfig::default()
+            .with_latency(Duration::from_millis(20))
+            .with_loss(0.2)
+            .with_duplicate(0.1)
+            .with_reorder(0.1);
+        let (received, stats) = run_link(42, link).await;
+        assert_eq!(run_link(42, link).await, (received.clone(), stats));
+        assert_ne!(run_link(43, link).await.0, received);
+
+        assert_eq!(stats.published, 100);
+        assert_eq!(stats.delivered, 100 - stats.dropped + stats.duplicated);
+        assert_eq!(received.len() as u64, stats.delivered);
+        assert!((5..=40).contains(&stats.dropped));
+        assert!(stats.duplicated > 0);
+        // Some messages were overtaken
+        assert!(received.windows(2).any(|w| w[0] > w[1]));
+    }
+
+    #[tokio::test]
+    async fn test_partition_and_heal() {
+        let network = SimNetwork::new(1);
+        let a = network.join("a");
+        let b = network.join("b");
+        b.subscribe(TOPIC).await.unwrap();
+
+        network.partition(&["a"], &["b"]);
+        a.publish(TOPIC, b"lost").await.unwrap();
+        assert!(b.poll(TOPIC).await.unwrap().is_empty());
+        assert_eq!(network.stats().dropped, 1);
+
+        network.heal();
+        a.publish(TOPIC, b"found").await.unwrap();
+        assert_eq!(payloads(b.poll(TOPIC).await.unwrap()), vec![b"found"]);
+    }
+
+    #[tokio::test]
+    async fn test_history_pages() {
+        let network = SimNetwork::new(1);
+        let a = network.join("a");
+        for i in 1..=5 {
+            network.store(WakuMessage::new(TOPIC, vec![i]).with_timestamp(i as i64));
+        }
+        a.publish_message(&WakuMessage::new(TOPIC, b"ack".to_vec()).with_ephemeral(true))
+            .await
+            .unwrap();
+
+        let query = HistoryQuery::new(TOPIC).since(2).with_page_size(2);
+        let page = a.query_history(&query).await.unwrap();
+        assert_eq!(payloads(page.messages), vec![vec![2], vec![3]]);
+        let all = crate::history::fetch_history(&a, &query).await.unwrap();
+        assert_eq!(payloads(all), vec![vec![2], vec![3], vec![4], vec![5]]);
+    }
+}
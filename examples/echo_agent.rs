@@ -8,6 +8,7 @@
 //!   cargo run --example echo_agent -- --encrypt
 
 use anyhow::Result;
+use futures::StreamExt;
 use waku_a2a::{NwakuRestTransport, WakuA2ANode};
 
 #[tokio::main]
@@ -55,32 +56,29 @@ async fn main() -> Result<()> {
         Err(e) => eprintln!("Warning: could not announce (nwaku not running?): {}", e),
     }
 
+    // Fetch tasks that arrived while we were offline
+    if let Err(e) = node.catch_up().await {
+        eprintln!("Warning: store catch-up failed: {}", e);
+    }
+
     println!("Listening for tasks... (Ctrl+C to stop)\n");
 
-    loop {
-        match node.poll_tasks().await {
-            Ok(tasks) => {
-                for task in &tasks {
-                    let text = task.text().unwrap_or("<no text>");
-                    println!(
-                        "[recv] Task {} from {}",
-                        task.id,
-                        &task.from[..12.min(task.from.len())]
-                    );
-                    println!("       Text: {}", text);
+    // Transport errors are logged and retried inside the stream
+    let mut tasks = std::pin::pin!(node.incoming_tasks());
+    while let Some(task) = tasks.next().await {
+        let text = task.text().unwrap_or("<no text>");
+        println!(
+            "[recv] Task {} from {}",
+            task.id,
+            &task.from[..12.min(task.from.len())]
+        );
+        println!("       Text: {}", text);
 
-                    let response = format!("Echo: {}", text);
-                    match node.respond(task, &response).await {
-                        Ok(()) => println!("       Replied: {}\n", response),
-                        Err(e) => eprintln!("       Reply failed: {}\n", e),
-                    }
-                }
-            }
-            Err(e) => {
-                // Silently retry — nwaku might not be running
-                eprintln!("[poll error] {}", e);
-            }
+        let response = format!("Echo: {}", text);
+        match node.respond(&task, &response).await {
+            Ok(()) => println!("       Replied: {}\n", response),
+            Err(e) => eprintln!("       Reply failed: {}\n", e),
         }
-        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
     }
+    Ok(())
 }
@@ -1,6 +1,13 @@
 pub use waku_a2a_core::*;
 pub use waku_a2a_crypto::{AgentIdentity, EncryptedPayload, IntroBundle, SessionKey};
 pub use waku_a2a_node::WakuA2ANode;
+pub use waku_a2a_transport::combinators::{
+    FallbackTransport, FanoutTransport, TopicRouterTransport,
+};
+#[cfg(unix)]
+pub use waku_a2a_transport::ipc::{IpcHub, IpcHubConfig, IpcTransport};
 pub use waku_a2a_transport::nwaku_rest::NwakuRestTransport;
+pub use waku_a2a_transport::rate_limit::{Priority, RateLimit, RateLimited, RateLimitedTransport};
 pub use waku_a2a_transport::sds::SdsTransport;
-pub use waku_a2a_transport::WakuTransport;
+pub use waku_a2a_transport::sim::{LinkConfig, SimNetwork, SimTransport};
+pub use waku_a2a_transport::{WakuMessage, WakuTransport};